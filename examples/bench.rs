@@ -0,0 +1,216 @@
+//! A load generator for comparing qp2p releases and configurations.
+//!
+//! Start an echo server:
+//!
+//! ```text
+//! cargo run --release --features structopt --example qp2p-bench -- server --listen 127.0.0.1:5000
+//! ```
+//!
+//! Then point one or more clients at it:
+//!
+//! ```text
+//! cargo run --release --features structopt --example qp2p-bench -- client 127.0.0.1:5000 \
+//!     --message-size 1024 --concurrency 4 --streams-per-connection 8 --duration 10
+//! ```
+//!
+//! Each client stream repeatedly sends a message and waits for it to be echoed back. When the run
+//! completes, the client prints the achieved throughput and round-trip latency percentiles.
+//!
+//! Both commands also accept the usual [`Config`] options (e.g. `--idle-timeout`), so the effect
+//! of different settings can be measured.
+
+use bytes::Bytes;
+use color_eyre::eyre::{eyre, Result};
+use futures::future;
+use qp2p::{Config, Endpoint};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "qp2p-bench")]
+enum Opt {
+    /// Run an echo server.
+    Server {
+        /// Address to listen on.
+        #[structopt(long, default_value = "127.0.0.1:0")]
+        listen: SocketAddr,
+
+        #[structopt(flatten)]
+        config: Config,
+    },
+
+    /// Generate load against an echo server.
+    Client {
+        /// Address of the echo server.
+        server: SocketAddr,
+
+        /// Size of each message, in bytes.
+        #[structopt(long, default_value = "1024")]
+        message_size: usize,
+
+        /// Number of connections to open to the server.
+        #[structopt(long, default_value = "1")]
+        concurrency: usize,
+
+        /// Number of bidirectional streams to run on each connection.
+        #[structopt(long, default_value = "1")]
+        streams_per_connection: usize,
+
+        /// How long to generate load for, in seconds.
+        #[structopt(long, default_value = "10")]
+        duration: u64,
+
+        #[structopt(flatten)]
+        config: Config,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+
+    match Opt::from_args() {
+        Opt::Server { listen, config } => server(listen, config).await,
+        Opt::Client {
+            server,
+            message_size,
+            concurrency,
+            streams_per_connection,
+            duration,
+            config,
+        } => {
+            client(
+                server,
+                message_size,
+                concurrency,
+                streams_per_connection,
+                Duration::from_secs(duration),
+                config,
+            )
+            .await
+        }
+    }
+}
+
+async fn server(listen: SocketAddr, config: Config) -> Result<()> {
+    let (node, mut incoming_conns, _contact) = Endpoint::new_peer(listen, &[], config).await?;
+    println!("Echo server listening on: {:?}", node.public_addr());
+
+    while let Some((connection, mut incoming_messages)) = incoming_conns.next().await {
+        let _ = tokio::spawn(async move {
+            while let Ok(Some((msg, stream))) = incoming_messages.next_with_stream().await {
+                let result = match stream {
+                    Some(stream) => stream.lock().await.send_user_msg(msg).await,
+                    None => connection.send(msg).await,
+                };
+                if let Err(error) = result {
+                    eprintln!(
+                        "Failed to echo to {}: {}",
+                        connection.remote_address(),
+                        error
+                    );
+                    break;
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn client(
+    server: SocketAddr,
+    message_size: usize,
+    concurrency: usize,
+    streams_per_connection: usize,
+    duration: Duration,
+    config: Config,
+) -> Result<()> {
+    let node = Endpoint::new_client(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)), config)?;
+    let msg = Bytes::from(vec![0; message_size]);
+
+    let mut workers = Vec::new();
+    for _ in 0..concurrency {
+        let (connection, _incoming) = node.connect_to(&server).await?;
+        for _ in 0..streams_per_connection {
+            let (send_stream, recv_stream) = connection.open_bi().await?;
+            workers.push(tokio::spawn(stream_worker(
+                send_stream,
+                recv_stream,
+                msg.clone(),
+                duration,
+            )));
+        }
+    }
+
+    let started = Instant::now();
+    let mut latencies = Vec::new();
+    for result in future::join_all(workers).await {
+        latencies.extend(result??);
+    }
+    let elapsed = started.elapsed();
+    node.close();
+
+    report(&latencies, message_size, elapsed)
+}
+
+// Send `msg` and wait for the echo until `duration` has passed, returning the round-trip times.
+async fn stream_worker(
+    mut send_stream: qp2p::SendStream,
+    mut recv_stream: qp2p::RecvStream,
+    msg: Bytes,
+    duration: Duration,
+) -> Result<Vec<Duration>> {
+    let mut latencies = Vec::new();
+    let deadline = Instant::now() + duration;
+
+    while Instant::now() < deadline {
+        let sent = Instant::now();
+        send_stream.send_user_msg(msg.clone()).await?;
+        let echo = recv_stream.next().await?;
+        latencies.push(sent.elapsed());
+
+        if echo.len() != msg.len() {
+            return Err(eyre!(
+                "echo was {} bytes, expected {}",
+                echo.len(),
+                msg.len()
+            ));
+        }
+    }
+
+    Ok(latencies)
+}
+
+fn report(latencies: &[Duration], message_size: usize, elapsed: Duration) -> Result<()> {
+    if latencies.is_empty() {
+        return Err(eyre!("no messages were echoed"));
+    }
+
+    let mut sorted = latencies.to_vec();
+    sorted.sort();
+    let percentile = |p: f64| sorted[((sorted.len() - 1) as f64 * p).round() as usize];
+
+    let secs = elapsed.as_secs_f64();
+    let messages = latencies.len() as f64;
+    // each message crosses the wire twice
+    let mib = messages * message_size as f64 * 2.0 / (1024.0 * 1024.0);
+
+    println!("messages:   {}", latencies.len());
+    println!("elapsed:    {:.2?}", elapsed);
+    println!(
+        "throughput: {:.0} msg/s, {:.2} MiB/s",
+        messages / secs,
+        mib / secs
+    );
+    println!("latency:");
+    println!("  p50:  {:?}", percentile(0.50));
+    println!("  p90:  {:?}", percentile(0.90));
+    println!("  p99:  {:?}", percentile(0.99));
+    println!("  max:  {:?}", sorted[sorted.len() - 1]);
+
+    Ok(())
+}
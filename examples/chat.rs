@@ -0,0 +1,320 @@
+//! A small chat overlay, demonstrating request/response over bidirectional streams, broadcast,
+//! and graceful shutdown.
+//!
+//! Start the first node with a name:
+//!
+//! ```text
+//! cargo run --example chat -- alice
+//! ```
+//!
+//! Then start more nodes, giving the address of any node already in the chat:
+//!
+//! ```text
+//! cargo run --example chat -- bob 127.0.0.1:<port>
+//! ```
+//!
+//! A joining node sends a hello over a bidirectional stream, and the node it's joining replies on
+//! the same stream with the addresses of the other nodes it knows, which the joining node then
+//! connects to in turn (peer exchange). Once connected, each line typed is broadcast to every
+//! node, and the following commands are understood:
+//!
+//! - `/msg <name> <text>` sends a direct message over a new bidirectional stream, and waits for
+//!   the recipient to confirm delivery on the same stream.
+//! - `/peers` lists the connected nodes.
+//! - `/quit` (or end of input) shuts down gracefully, letting peers know the node is leaving.
+
+use bytes::Bytes;
+use color_eyre::eyre::{bail, eyre, Result};
+use qp2p::{Config, Connection, ConnectionIncoming, Endpoint};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env,
+    io::{self, BufRead},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+// How long to give peers to close their connections when shutting down.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize, Deserialize)]
+enum ChatMsg {
+    // Introduce ourselves, asking for the nodes the recipient knows.
+    Hello {
+        name: String,
+    },
+    // The reply to a hello.
+    Welcome {
+        name: String,
+        peers: Vec<SocketAddr>,
+    },
+    // A message for everyone.
+    Say {
+        text: String,
+    },
+    // A message for the recipient only.
+    Direct {
+        text: String,
+    },
+    // The reply to a direct message.
+    Delivered,
+}
+
+// The nodes we're connected to, by address, with their names.
+type Peers = Arc<Mutex<HashMap<SocketAddr, (String, Connection)>>>;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+
+    let mut args = env::args().skip(1);
+    let name = args
+        .next()
+        .ok_or_else(|| eyre!("usage: chat <name> [address of a node to join]"))?;
+    let contact: Option<SocketAddr> = args.next().map(|arg| arg.parse()).transpose()?;
+
+    let (endpoint, mut incoming_conns, _contact) = Endpoint::new_peer(
+        SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        &[],
+        Config {
+            idle_timeout: Duration::from_secs(60 * 60).into(), // 1 hour idle timeout.
+            ..Default::default()
+        },
+    )
+    .await?;
+    println!("{} is listening on {}", name, endpoint.public_addr());
+
+    let peers = Peers::default();
+
+    // accept nodes joining us
+    let accept_peers = peers.clone();
+    let accept_name = name.clone();
+    let _ = tokio::spawn(async move {
+        while let Some((connection, incoming)) = incoming_conns.next().await {
+            let _ = tokio::spawn(receive(
+                accept_name.clone(),
+                accept_peers.clone(),
+                connection,
+                incoming,
+            ));
+        }
+    });
+
+    if let Some(contact) = contact {
+        join(&endpoint, &name, &peers, contact).await?;
+    }
+
+    // stdin is read on a thread of its own, since reading it blocks
+    let (line_tx, mut lines) = mpsc::channel(16);
+    let _ = thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if line_tx.blocking_send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Some(line) = lines.recv().await {
+        let line = line.trim();
+        if line == "/quit" {
+            break;
+        } else if line == "/peers" {
+            for (addr, (name, _)) in peers.lock().unwrap().iter() {
+                println!("* {} ({})", name, addr);
+            }
+        } else if let Some(rest) = line.strip_prefix("/msg ") {
+            let (to, text) = rest.split_once(' ').unwrap_or((rest, ""));
+            if let Err(error) = direct(&peers, to, text).await {
+                println!("* failed to message {}: {}", to, error);
+            }
+        } else if !line.is_empty() {
+            broadcast(&peers, line).await;
+        }
+    }
+
+    println!("* leaving");
+    endpoint.shutdown(SHUTDOWN_GRACE).await;
+
+    Ok(())
+}
+
+// Join the chat via `contact`, then connect to every other node it (and they) know of.
+async fn join(endpoint: &Endpoint, name: &str, peers: &Peers, contact: SocketAddr) -> Result<()> {
+    let mut to_join = vec![contact];
+    while let Some(addr) = to_join.pop() {
+        if addr == endpoint.public_addr() || peers.lock().unwrap().contains_key(&addr) {
+            continue;
+        }
+
+        let (connection, incoming) = endpoint.connect_to(&addr).await?;
+        let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+        send_stream
+            .send_user_msg(encode(&ChatMsg::Hello {
+                name: name.to_string(),
+            })?)
+            .await?;
+        let (peer_name, known) = match decode(&recv_stream.next().await?)? {
+            ChatMsg::Welcome { name, peers } => (name, peers),
+            msg => bail!("unexpected reply to hello from {}: {:?}", addr, msg),
+        };
+
+        println!("* joined {} ({})", peer_name, addr);
+        let _ = peers
+            .lock()
+            .unwrap()
+            .insert(addr, (peer_name, connection.clone()));
+        to_join.extend(known);
+        let _ = tokio::spawn(receive(
+            name.to_string(),
+            peers.clone(),
+            connection,
+            incoming,
+        ));
+    }
+
+    Ok(())
+}
+
+// Handle messages from a peer until it leaves.
+async fn receive(
+    name: String,
+    peers: Peers,
+    connection: Connection,
+    mut incoming: ConnectionIncoming,
+) {
+    let addr = connection.remote_address();
+    loop {
+        let (msg, stream) = match incoming.next_with_stream().await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => break,
+            Err(error) => {
+                println!("* error receiving from {}: {}", addr, error);
+                break;
+            }
+        };
+        let msg = match decode(&msg) {
+            Ok(msg) => msg,
+            Err(error) => {
+                println!("* invalid message from {}: {}", addr, error);
+                continue;
+            }
+        };
+        let sender = peers
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .map_or_else(|| addr.to_string(), |(name, _)| name.clone());
+
+        // requests on bidirectional streams are answered on the same stream
+        let reply = match (msg, stream) {
+            (ChatMsg::Hello { name: peer_name }, Some(stream)) => {
+                let known = {
+                    let mut peers = peers.lock().unwrap();
+                    let known = peers.keys().copied().collect();
+                    let _ = peers.insert(addr, (peer_name.clone(), connection.clone()));
+                    known
+                };
+                println!("* {} joined ({})", peer_name, addr);
+                Some((
+                    stream,
+                    ChatMsg::Welcome {
+                        name: name.clone(),
+                        peers: known,
+                    },
+                ))
+            }
+            (ChatMsg::Direct { text }, Some(stream)) => {
+                println!("[{} -> you] {}", sender, text);
+                Some((stream, ChatMsg::Delivered))
+            }
+            (ChatMsg::Say { text }, _) => {
+                println!("[{}] {}", sender, text);
+                None
+            }
+            (msg, _) => {
+                println!("* unexpected message from {}: {:?}", sender, msg);
+                None
+            }
+        };
+
+        if let Some((stream, reply)) = reply {
+            let result = match encode(&reply) {
+                Ok(reply) => stream
+                    .lock()
+                    .await
+                    .send_user_msg(reply)
+                    .await
+                    .map_err(Into::into),
+                Err(error) => Err(error),
+            };
+            if let Err(error) = result {
+                println!("* failed to reply to {}: {}", sender, error);
+            }
+        }
+    }
+
+    if let Some((name, _)) = peers.lock().unwrap().remove(&addr) {
+        println!("* {} left", name);
+    }
+}
+
+// Send a message to every peer.
+async fn broadcast(peers: &Peers, text: &str) {
+    let msg = match encode(&ChatMsg::Say {
+        text: text.to_string(),
+    }) {
+        Ok(msg) => msg,
+        Err(error) => {
+            println!("* failed to encode message: {}", error);
+            return;
+        }
+    };
+    let connections: Vec<_> = peers
+        .lock()
+        .unwrap()
+        .values()
+        .map(|(name, connection)| (name.clone(), connection.clone()))
+        .collect();
+    for (name, connection) in connections {
+        if let Err(error) = connection.send(msg.clone()).await {
+            println!("* failed to send to {}: {}", name, error);
+        }
+    }
+}
+
+// Send a message to one peer, waiting for it to confirm delivery.
+async fn direct(peers: &Peers, to: &str, text: &str) -> Result<()> {
+    let connection = peers
+        .lock()
+        .unwrap()
+        .values()
+        .find(|(name, _)| name == to)
+        .map(|(_, connection)| connection.clone())
+        .ok_or_else(|| eyre!("no such peer"))?;
+
+    let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+    send_stream
+        .send_user_msg(encode(&ChatMsg::Direct {
+            text: text.to_string(),
+        })?)
+        .await?;
+    match decode(&recv_stream.next().await?)? {
+        ChatMsg::Delivered => {
+            println!("* delivered to {}", to);
+            Ok(())
+        }
+        msg => bail!("unexpected reply: {:?}", msg),
+    }
+}
+
+fn encode(msg: &ChatMsg) -> Result<Bytes> {
+    Ok(bincode::serialize(msg)?.into())
+}
+
+fn decode(msg: &[u8]) -> Result<ChatMsg> {
+    Ok(bincode::deserialize(msg)?)
+}
@@ -0,0 +1,123 @@
+//! A diagnostic tool for working out why an endpoint can't connect to, or be reached by, its peers.
+//!
+//! Bind an endpoint and check it against one or more peers:
+//!
+//! ```text
+//! cargo run --features structopt --example qp2p-doctor -- 203.0.113.1:5000 203.0.113.2:5000
+//! ```
+//!
+//! The doctor binds an endpoint (with port forwarding, if `--forward-port` is given), then for each
+//! peer it connects, asks for the address the peer sees the connection coming from, and checks
+//! that the peer can connect back. Finally it prints a report classifying the endpoint's
+//! reachability. The peers can be any qp2p peer endpoints, such as the `p2p_node` example.
+//!
+//! The usual [`Config`] options (e.g. `--idle-timeout`) are accepted, so a peer's configuration
+//! can be reproduced.
+
+use color_eyre::eyre::Result;
+use qp2p::{Config, Endpoint, Reachability};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "qp2p-doctor")]
+struct Opt {
+    /// Addresses of peers to check against.
+    peers: Vec<SocketAddr>,
+
+    /// Address to bind the endpoint to.
+    #[structopt(long, default_value = "0.0.0.0:0")]
+    listen: SocketAddr,
+
+    #[structopt(flatten)]
+    config: Config,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    let opt = Opt::from_args();
+
+    println!("Binding endpoint to {}", opt.listen);
+    let (endpoint, _incoming_connections, _contact) =
+        Endpoint::new_peer(opt.listen, &[], opt.config).await?;
+    println!("  local addresses: {:?}", endpoint.local_addrs());
+    println!("  public address:  {}", endpoint.public_addr());
+    #[cfg(feature = "igd")]
+    match endpoint.port_mapping_status() {
+        Some(status) => println!("  port mapping:    {:?}", status),
+        None => println!("  port mapping:    not requested (see --forward-port)"),
+    }
+
+    if opt.peers.is_empty() {
+        println!("\nNo peers given, so there's nothing more to check.");
+        return Ok(());
+    }
+
+    for peer in &opt.peers {
+        println!("\nChecking {}", peer);
+
+        let start = Instant::now();
+        match endpoint.connect_to(peer).await {
+            Ok((connection, _)) => {
+                println!("  connect:  ok in {}", millis(start.elapsed()));
+                let info = connection.transport_info();
+                println!("  transport: {:?}", info);
+            }
+            Err(error) => {
+                println!(
+                    "  connect:  FAILED after {}: {}",
+                    millis(start.elapsed()),
+                    error
+                );
+                println!("            (is the peer running, and is UDP allowed to it?)");
+                continue;
+            }
+        }
+
+        let start = Instant::now();
+        match endpoint.is_reachable(peer).await {
+            Ok(()) => println!("  echo:     ok in {}", millis(start.elapsed())),
+            Err(error) => println!(
+                "  echo:     FAILED after {}: {}",
+                millis(start.elapsed()),
+                error
+            ),
+        }
+    }
+
+    println!("\nChecking whether peers can connect back");
+    match endpoint.check_reachability(&opt.peers).await {
+        Ok(reachability) => {
+            for observed in endpoint.observed_addresses().addrs() {
+                println!(
+                    "  observed as {} by {} peer(s)",
+                    observed.addr, observed.reporters
+                );
+            }
+            println!("  result: {}", describe(reachability));
+        }
+        Err(error) => println!("  FAILED: no peer could be queried: {}", error),
+    }
+
+    endpoint.close();
+    Ok(())
+}
+
+fn describe(reachability: Reachability) -> String {
+    match reachability {
+        Reachability::Public { addr } => format!("reachable at {}", addr),
+        Reachability::Mapped { addr } => format!("reachable at {}, via a port mapping", addr),
+        Reachability::Unreachable { nat } => format!(
+            "unreachable (NAT: {:?}); try --forward-port, or check firewall rules for UDP",
+            nat
+        ),
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{}ms", duration.as_millis())
+}
@@ -0,0 +1,221 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Mapping of peer identities to the addresses they can be reached at.
+
+use crate::peer_store::PeerStore;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+/// The identity of a peer, independent of the addresses it can be reached at.
+///
+/// qp2p does not interpret the identity – it's up to the application to decide how identities are
+/// assigned (e.g. a hash of a public key).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PeerId(pub [u8; 32]);
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PeerId({})", self)
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}{:02x}{:02x}..", self.0[0], self.0[1], self.0[2])
+    }
+}
+
+/// How a peer address is reached.
+///
+/// Variants are ordered by preference, so [`Lan`](Self::Lan) addresses are tried before
+/// [`Wan`](Self::Wan) addresses, which are tried before [`Relayed`](Self::Relayed) addresses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum AddressKind {
+    /// An address on the local network.
+    Lan,
+
+    /// A publicly routable address.
+    Wan,
+
+    /// An address of a relay that forwards traffic to the peer.
+    Relayed,
+}
+
+/// A known address of a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PeerAddress {
+    /// The socket address.
+    pub addr: SocketAddr,
+
+    /// How the address is reached.
+    pub kind: AddressKind,
+}
+
+/// Known addresses for peer identities.
+///
+/// Addresses for a peer are kept in the order they should be tried: by [`AddressKind`], and then
+/// with the most recently successful address first.
+///
+/// If the endpoint has a [`Config::peer_store`](crate::Config::peer_store), the address book is
+/// loaded from it, and changes are saved to it.
+#[derive(Debug, Default)]
+pub struct AddressBook {
+    peers: Mutex<HashMap<PeerId, Vec<PeerAddress>>>,
+    store: Option<Arc<dyn PeerStore>>,
+}
+
+impl AddressBook {
+    // An address book with the given peers, saving changes to `store`.
+    pub(crate) fn new(
+        peers: HashMap<PeerId, Vec<PeerAddress>>,
+        store: Option<Arc<dyn PeerStore>>,
+    ) -> Self {
+        Self {
+            peers: Mutex::new(peers),
+            store,
+        }
+    }
+
+    /// Add an address for `peer`.
+    ///
+    /// If the address is already known, its kind is updated.
+    pub fn insert(&self, peer: PeerId, addr: SocketAddr, kind: AddressKind) {
+        let mut peers = self.lock();
+        let addresses = peers.entry(peer).or_default();
+        addresses.retain(|known| known.addr != addr);
+        addresses.push(PeerAddress { addr, kind });
+        // stable sort, so more recently successful addresses stay ahead of others of the same kind
+        addresses.sort_by_key(|known| known.kind);
+        drop(peers);
+        self.save(peer);
+    }
+
+    /// Remove an address for `peer`.
+    pub fn remove_address(&self, peer: &PeerId, addr: &SocketAddr) {
+        let mut peers = self.lock();
+        if let Some(addresses) = peers.get_mut(peer) {
+            addresses.retain(|known| known.addr != *addr);
+            if addresses.is_empty() {
+                let _ = peers.remove(peer);
+            }
+        }
+        drop(peers);
+        self.save(*peer);
+    }
+
+    /// Remove all addresses for `peer`.
+    pub fn remove(&self, peer: &PeerId) {
+        let _ = self.lock().remove(peer);
+        self.save(*peer);
+    }
+
+    /// The known addresses for `peer`, in the order they should be tried.
+    pub fn addresses(&self, peer: &PeerId) -> Vec<PeerAddress> {
+        self.lock().get(peer).cloned().unwrap_or_default()
+    }
+
+    /// The identities of all peers with known addresses.
+    pub fn peers(&self) -> Vec<PeerId> {
+        self.lock().keys().copied().collect()
+    }
+
+    /// Find the peer a given address belongs to, if any.
+    pub fn peer_for(&self, addr: &SocketAddr) -> Option<PeerId> {
+        self.lock()
+            .iter()
+            .find(|(_, addresses)| addresses.iter().any(|known| known.addr == *addr))
+            .map(|(peer, _)| *peer)
+    }
+
+    // How `addr` is reached, if it's a known address of any peer.
+    pub(crate) fn kind_of(&self, addr: &SocketAddr) -> Option<AddressKind> {
+        self.lock()
+            .values()
+            .flatten()
+            .find(|known| known.addr == *addr)
+            .map(|known| known.kind)
+    }
+
+    // Move `addr` ahead of other addresses of the same kind, so it's tried first next time.
+    pub(crate) fn mark_successful(&self, peer: &PeerId, addr: &SocketAddr) {
+        let mut peers = self.lock();
+        if let Some(addresses) = peers.get_mut(peer) {
+            if let Some(position) = addresses.iter().position(|known| known.addr == *addr) {
+                let known = addresses.remove(position);
+                addresses.insert(0, known);
+                addresses.sort_by_key(|known| known.kind);
+            }
+        }
+        drop(peers);
+        self.save(*peer);
+    }
+
+    // Save the current addresses of `peer` to the store, if there is one.
+    fn save(&self, peer: PeerId) {
+        if let Some(store) = &self.store {
+            let addresses = self.addresses(&peer);
+            if let Err(error) = store.update(peer, &addresses) {
+                warn!("Failed to store addresses for {:?}: {}", peer, error);
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PeerId, Vec<PeerAddress>>> {
+        self.peers.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AddressBook, AddressKind, PeerId};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn addresses_are_ordered_by_preference() -> Result<(), std::net::AddrParseError> {
+        let book = AddressBook::default();
+        let peer = PeerId([1; 32]);
+
+        let relayed: SocketAddr = "10.0.0.1:1000".parse()?;
+        let wan1: SocketAddr = "1.2.3.4:1000".parse()?;
+        let wan2: SocketAddr = "1.2.3.5:1000".parse()?;
+        let lan: SocketAddr = "192.168.0.1:1000".parse()?;
+
+        book.insert(peer, relayed, AddressKind::Relayed);
+        book.insert(peer, wan1, AddressKind::Wan);
+        book.insert(peer, wan2, AddressKind::Wan);
+        book.insert(peer, lan, AddressKind::Lan);
+
+        let order = |book: &AddressBook| -> Vec<SocketAddr> {
+            book.addresses(&peer)
+                .iter()
+                .map(|known| known.addr)
+                .collect()
+        };
+        assert_eq!(order(&book), vec![lan, wan1, wan2, relayed]);
+
+        book.mark_successful(&peer, &wan2);
+        assert_eq!(order(&book), vec![lan, wan2, wan1, relayed]);
+
+        book.remove_address(&peer, &lan);
+        assert_eq!(book.peer_for(&wan1), Some(peer));
+        assert_eq!(book.peer_for(&lan), None);
+
+        book.remove(&peer);
+        assert!(book.addresses(&peer).is_empty());
+
+        Ok(())
+    }
+}
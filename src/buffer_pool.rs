@@ -0,0 +1,170 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Reusable buffers for encoding messages.
+//!
+//! Messages other than plain user messages are serialized into a buffer along with their header,
+//! so the whole frame can be written to the stream at once. Taking those buffers from a pool, and
+//! returning them once the frame is written, saves allocating a new one for every message.
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, MutexGuard,
+    },
+};
+
+/// Counts of the buffers taken from an endpoint's buffer pool, as returned by
+/// [`Endpoint::buffer_pool_stats`](crate::Endpoint::buffer_pool_stats).
+///
+/// See [`Config::buffer_pool_size`](crate::Config::buffer_pool_size) for how buffers are pooled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// The number of messages encoded into a buffer reused from the pool.
+    pub reused: u64,
+
+    /// The number of messages encoded into a newly allocated buffer, because the pool was empty.
+    pub allocated: u64,
+
+    /// The number of buffers dropped after use instead of being returned to the pool, because the
+    /// pool was full or the buffer had grown too large to keep.
+    pub discarded: u64,
+
+    /// The number of buffers currently in the pool.
+    pub idle: usize,
+}
+
+// A pool of buffers for encoding messages, shared by an endpoint's connections.
+#[derive(Debug, Default)]
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+    max_buffer_size: usize,
+    reused: AtomicU64,
+    allocated: AtomicU64,
+    discarded: AtomicU64,
+}
+
+impl BufferPool {
+    // A pool keeping up to `max_buffers` idle buffers, each with a capacity of at most
+    // `max_buffer_size` bytes. A pool of `0` buffers allocates a buffer for every message.
+    pub(crate) fn new(max_buffers: usize, max_buffer_size: usize) -> Self {
+        Self {
+            max_buffers,
+            max_buffer_size,
+            ..Self::default()
+        }
+    }
+
+    // Take an empty buffer from the pool, or allocate one if there are none. The buffer goes back
+    // to the pool when it's dropped.
+    pub(crate) fn get(&self) -> PooledBuffer<'_> {
+        let buffer = match self.lock().pop() {
+            Some(buffer) => {
+                let _ = self.reused.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                let _ = self.allocated.fetch_add(1, Ordering::Relaxed);
+                Vec::new()
+            }
+        };
+        PooledBuffer { buffer, pool: self }
+    }
+
+    pub(crate) fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            reused: self.reused.load(Ordering::Relaxed),
+            allocated: self.allocated.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+            idle: self.lock().len(),
+        }
+    }
+
+    fn put(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() <= self.max_buffer_size {
+            let mut buffers = self.lock();
+            if buffers.len() < self.max_buffers {
+                buffer.clear();
+                buffers.push(buffer);
+                return;
+            }
+        }
+        let _ = self.discarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Vec<u8>>> {
+        self.buffers
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+// A buffer taken from a `BufferPool`, returned to it on drop.
+pub(crate) struct PooledBuffer<'a> {
+    buffer: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buffer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferPool, BufferPoolStats};
+
+    #[test]
+    fn buffers_are_reused() {
+        let pool = BufferPool::new(1, 1024);
+
+        let mut first = pool.get();
+        first.extend_from_slice(b"hello");
+        let capacity = first.capacity();
+        let second = pool.get();
+        drop(first);
+        // the pool only keeps one buffer
+        drop(second);
+
+        let reused = pool.get();
+        assert!(reused.is_empty());
+        assert_eq!(reused.capacity(), capacity);
+        drop(reused);
+
+        // buffers that grew too large aren't kept
+        pool.get().resize(2048, 0);
+
+        assert_eq!(
+            pool.stats(),
+            BufferPoolStats {
+                reused: 2,
+                allocated: 2,
+                discarded: 2,
+                idle: 0,
+            }
+        );
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! A builder for the different kinds of endpoint.
+
+use crate::{
+    config::Config,
+    connection::{Connection, ConnectionIncoming},
+    endpoint::{Endpoint, IncomingConnections},
+    error::{ClientEndpointError, EndpointError},
+};
+use std::net::{Ipv4Addr, SocketAddr};
+
+/// Builds an endpoint, as returned by [`Endpoint::builder`].
+///
+/// The kind of endpoint is chosen by the final method:
+///
+/// - [`build`](Self::build) creates a [`PeerEndpoint`], which accepts incoming connections and
+///   bootstraps against the peers given to [`with_bootstrap`](Self::with_bootstrap).
+/// - [`build_server`](Self::build_server) creates a [`ServerEndpoint`], which accepts incoming
+///   connections but doesn't bootstrap.
+/// - [`build_client`](Self::build_client) creates a [`ClientEndpoint`], which can only make
+///   outgoing connections.
+#[derive(Debug, Default)]
+pub struct EndpointBuilder {
+    local_addr: Option<SocketAddr>,
+    config: Config,
+    bootstrap_nodes: Vec<SocketAddr>,
+}
+
+impl EndpointBuilder {
+    /// Set the address to bind to.
+    ///
+    /// If unspecified, this will default to `0.0.0.0:0`, i.e. any IPv4 interface and a random port.
+    pub fn listen(mut self, local_addr: impl Into<SocketAddr>) -> Self {
+        self.local_addr = Some(local_addr.into());
+        self
+    }
+
+    /// Set the endpoint's configuration.
+    ///
+    /// If unspecified, this will default to [`Config::default`].
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Set the peers to bootstrap against (see [`build`](Self::build)).
+    pub fn with_bootstrap(mut self, bootstrap_nodes: &[SocketAddr]) -> Self {
+        self.bootstrap_nodes = bootstrap_nodes.to_vec();
+        self
+    }
+
+    /// Create a peer endpoint, which accepts incoming connections and bootstraps against the
+    /// configured peers.
+    ///
+    /// See [`Endpoint::new_peer`] for details of bootstrapping and port forwarding.
+    pub async fn build(self) -> Result<PeerEndpoint, EndpointError> {
+        let (endpoint, incoming_connections, bootstrap_connection) =
+            Endpoint::new_peer(self.local_addr(), &self.bootstrap_nodes, self.config).await?;
+        Ok(PeerEndpoint {
+            endpoint,
+            incoming_connections,
+            bootstrap_connection,
+        })
+    }
+
+    /// Create a server endpoint, which accepts incoming connections.
+    ///
+    /// Any bootstrap peers are ignored, so the endpoint's [public
+    /// address](Endpoint::public_addr) is not verified to be reachable.
+    pub async fn build_server(self) -> Result<ServerEndpoint, EndpointError> {
+        let (endpoint, incoming_connections, _) =
+            Endpoint::new_peer(self.local_addr(), &[], self.config).await?;
+        Ok(ServerEndpoint {
+            endpoint,
+            incoming_connections,
+        })
+    }
+
+    /// Create a client endpoint, which can only make outgoing connections.
+    ///
+    /// Any bootstrap peers are ignored. See [`Endpoint::new_client`].
+    pub fn build_client(self) -> Result<ClientEndpoint, ClientEndpointError> {
+        let endpoint = Endpoint::new_client(self.local_addr(), self.config)?;
+        Ok(ClientEndpoint { endpoint })
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+            .unwrap_or_else(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+    }
+}
+
+/// An endpoint that accepts incoming connections and has bootstrapped against other peers.
+///
+/// See [`EndpointBuilder::build`].
+#[derive(Debug)]
+pub struct PeerEndpoint {
+    endpoint: Endpoint,
+    incoming_connections: IncomingConnections,
+    bootstrap_connection: Option<(Connection, ConnectionIncoming)>,
+}
+
+impl PeerEndpoint {
+    /// The underlying endpoint, for making outgoing connections.
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Wait for the next incoming connection.
+    ///
+    /// Returns `None` once the endpoint is closed.
+    pub async fn accept(&mut self) -> Option<(Connection, ConnectionIncoming)> {
+        self.incoming_connections.next().await
+    }
+
+    /// Take the connection to the first bootstrap peer that responded, if any.
+    ///
+    /// This returns `None` if there were no bootstrap peers, none could be reached, or the
+    /// connection has already been taken.
+    pub fn take_bootstrap_connection(&mut self) -> Option<(Connection, ConnectionIncoming)> {
+        self.bootstrap_connection.take()
+    }
+
+    /// Split into the parts returned by [`Endpoint::new_peer`].
+    pub fn into_parts(
+        self,
+    ) -> (
+        Endpoint,
+        IncomingConnections,
+        Option<(Connection, ConnectionIncoming)>,
+    ) {
+        (
+            self.endpoint,
+            self.incoming_connections,
+            self.bootstrap_connection,
+        )
+    }
+}
+
+/// An endpoint that accepts incoming connections, without bootstrapping.
+///
+/// See [`EndpointBuilder::build_server`].
+#[derive(Debug)]
+pub struct ServerEndpoint {
+    endpoint: Endpoint,
+    incoming_connections: IncomingConnections,
+}
+
+impl ServerEndpoint {
+    /// The underlying endpoint, for making outgoing connections.
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Wait for the next incoming connection.
+    ///
+    /// Returns `None` once the endpoint is closed.
+    pub async fn accept(&mut self) -> Option<(Connection, ConnectionIncoming)> {
+        self.incoming_connections.next().await
+    }
+
+    /// Split into the endpoint and its incoming connections.
+    pub fn into_parts(self) -> (Endpoint, IncomingConnections) {
+        (self.endpoint, self.incoming_connections)
+    }
+}
+
+/// An endpoint that can only make outgoing connections.
+///
+/// See [`EndpointBuilder::build_client`].
+#[derive(Debug)]
+pub struct ClientEndpoint {
+    endpoint: Endpoint,
+}
+
+impl ClientEndpoint {
+    /// The underlying endpoint, for making outgoing connections.
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Unwrap the underlying endpoint.
+    pub fn into_inner(self) -> Endpoint {
+        self.endpoint
+    }
+}
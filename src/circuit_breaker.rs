@@ -0,0 +1,161 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Per-peer circuit breakers for outgoing connection attempts.
+
+use crate::{config::RetryConfig, error::ConnectionError};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::warn;
+
+// Tracks consecutive connection failures for each peer, and rejects attempts to peers that have
+// failed too often until a cool-down has passed (see `RetryConfig::circuit_breaker_threshold`).
+//
+// Time follows tokio's clock, like retries.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    threshold: Option<u32>,
+    cooldown: Duration,
+    peers: HashMap<SocketAddr, PeerState>,
+}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(retry_config: &RetryConfig) -> Self {
+        Self {
+            state: Mutex::new(State {
+                threshold: retry_config.circuit_breaker_threshold,
+                cooldown: retry_config.circuit_breaker_cooldown,
+                peers: HashMap::new(),
+            }),
+        }
+    }
+
+    // Adopt the threshold and cool-down of `retry_config`.
+    //
+    // Failures counted so far are kept, and circuits that are already open stay open for the
+    // cool-down they were opened with.
+    pub(crate) fn configure(&self, retry_config: &RetryConfig) {
+        let mut state = self.lock();
+        state.threshold = retry_config.circuit_breaker_threshold;
+        state.cooldown = retry_config.circuit_breaker_cooldown;
+    }
+
+    // Check whether an attempt to connect to `addr` may go ahead.
+    //
+    // Once the cool-down has passed, attempts are let through again, but a single further failure
+    // re-opens the circuit.
+    pub(crate) fn check(&self, addr: &SocketAddr) -> Result<(), ConnectionError> {
+        let state = self.lock();
+        if state.threshold.is_none() {
+            return Ok(());
+        }
+
+        match state.peers.get(addr).and_then(|peer| peer.open_until) {
+            Some(open_until) if Instant::now() < open_until => {
+                Err(ConnectionError::CircuitOpen(*addr))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Record the outcome of an attempt to connect to `addr`.
+    pub(crate) fn record<T>(&self, addr: &SocketAddr, result: &Result<T, ConnectionError>) {
+        let mut state = self.lock();
+        let threshold = match state.threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let cooldown = state.cooldown;
+
+        match result {
+            Ok(_) => {
+                let _ = state.peers.remove(addr);
+            }
+            // the peer is reachable, so rejection isn't a reason to stop trying
+            Err(ConnectionError::Hello(_)) | Err(ConnectionError::CircuitOpen(_)) => {}
+            Err(error) => {
+                let peer = state.peers.entry(*addr).or_default();
+                peer.failures = peer.failures.saturating_add(1);
+                if peer.failures >= threshold {
+                    warn!(
+                        "Opening circuit breaker for {} for {:?} after {} consecutive failures: {}",
+                        addr, cooldown, peer.failures, error
+                    );
+                    peer.open_until = Some(Instant::now() + cooldown);
+                }
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // the lock is never held across anything that can panic
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CircuitBreaker;
+    use crate::{config::RetryConfig, error::ConnectionError};
+    use color_eyre::eyre::Result;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn opens_after_consecutive_failures() -> Result<()> {
+        tokio::time::pause();
+        let breaker = CircuitBreaker::new(&RetryConfig {
+            circuit_breaker_threshold: Some(2),
+            circuit_breaker_cooldown: Duration::from_secs(10),
+            ..RetryConfig::default()
+        });
+        let addr = "127.0.0.1:5000".parse()?;
+        let failure: Result<(), _> = Err(ConnectionError::TimedOut);
+
+        breaker.record(&addr, &failure);
+        assert_eq!(breaker.check(&addr), Ok(()));
+        breaker.record(&addr, &failure);
+        assert_eq!(
+            breaker.check(&addr),
+            Err(ConnectionError::CircuitOpen(addr))
+        );
+
+        // after the cool-down one attempt is let through, and another failure re-opens the circuit
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(breaker.check(&addr), Ok(()));
+        breaker.record(&addr, &failure);
+        assert_eq!(
+            breaker.check(&addr),
+            Err(ConnectionError::CircuitOpen(addr))
+        );
+
+        // a success closes it
+        tokio::time::advance(Duration::from_secs(10)).await;
+        breaker.record(&addr, &Ok(()));
+        breaker.record(&addr, &failure);
+        assert_eq!(breaker.check(&addr), Ok(()));
+
+        Ok(())
+    }
+}
@@ -11,9 +11,35 @@
 
 use quinn::IdleTimeout;
 
+#[cfg(feature = "dht")]
+use crate::dht::NodeId;
+use crate::{
+    hello::HelloProvider,
+    human_duration,
+    identity::PeerIdentifier,
+    interceptor::Interceptor,
+    observer::ConnectionObserver,
+    peer_store::PeerStore,
+    resolver::{Resolver, SystemResolver},
+    resumption::{ResumptionStats, SessionCaches, SessionStore},
+    retry::{self, RetryHook, RetryJitter},
+    scoring::PeerScoring,
+    signing::SigningKey,
+    sink::MessageSink,
+};
 use rustls::{Certificate, ClientConfig, ServerName};
 use serde::{Deserialize, Serialize};
-use std::{future::Future, net::IpAddr, sync::Arc, time::Duration};
+use std::{
+    fmt,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
 
 #[cfg(feature = "structopt")]
 use structopt::StructOpt;
@@ -21,6 +47,27 @@ use structopt::StructOpt;
 /// Default for [`Config::upnp_lease_duration`] (2 minutes).
 pub const DEFAULT_UPNP_LEASE_DURATION: Duration = Duration::from_secs(120);
 
+/// Default for [`Config::endpoint_verification_timeout`] (30 seconds).
+pub const DEFAULT_ENDPOINT_VERIFICATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default for [`Config::max_buffered_bytes_per_connection`] (64 MiB).
+pub const DEFAULT_MAX_BUFFERED_BYTES_PER_CONNECTION: usize = 64 * 1024 * 1024;
+
+/// Default for [`Config::message_split_streams`] (4 streams).
+pub const DEFAULT_MESSAGE_SPLIT_STREAMS: usize = 4;
+
+/// Default for [`Config::buffer_pool_size`] (64 buffers).
+pub const DEFAULT_BUFFER_POOL_SIZE: usize = 64;
+
+/// Default for [`Config::max_pooled_buffer_size`] (64 KiB).
+pub const DEFAULT_MAX_POOLED_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Default for [`Config::peer_exchange_interval`] (5 minutes).
+pub const DEFAULT_PEER_EXCHANGE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Default for [`Config::session_cache_size`] (256 sessions).
+pub const DEFAULT_SESSION_CACHE_SIZE: usize = 256;
+
 /// Default for [`RetryConfig::max_retry_interval`] (500 ms).
 ///
 /// Together with the default max and multiplier,
@@ -60,9 +107,23 @@ pub const DEFAULT_RETRYING_MAX_ELAPSED_TIME: Duration = Duration::from_secs(30);
 #[cfg(feature = "structopt")]
 const DEFAULT_RETRYING_MAX_ELAPSED_TIME_STR: &str = "30";
 
+/// Default for [`RetryConfig::circuit_breaker_cooldown`] (30 s).
+pub const DEFAULT_CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+#[cfg(feature = "structopt")]
+const DEFAULT_CIRCUIT_BREAKER_COOLDOWN_STR: &str = "30000";
+
 // We use a hard-coded server name for self-signed certificates.
 pub(crate) const SERVER_NAME: &str = "maidsafe.net";
 
+// Transport limits we advertise to peers. These are quinn's defaults, but we set them explicitly so
+// they can be reported by `Connection::transport_info`.
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+const STREAM_RECEIVE_WINDOW: u32 = 1_250_000;
+const RECEIVE_WINDOW: quinn::VarInt = quinn::VarInt::MAX;
+const SEND_WINDOW: u64 = 8 * STREAM_RECEIVE_WINDOW as u64;
+const CONGESTION_CONTROLLER: &str = "cubic";
+const ALLOW_MIGRATION: bool = true;
+
 // Convenience alias – not for export.
 type Result<T, E = ConfigError> = std::result::Result<T, E>;
 
@@ -75,12 +136,71 @@ pub enum ConfigError {
     /// Invalid idle timeout
     #[error("An error occurred parsing idle timeout duration")]
     InvalidIdleTimeout(#[from] quinn_proto::VarIntBoundsExceeded),
+    /// The keep-alive interval is zero, or not shorter than the idle timeout.
+    #[error(
+        "Keep-alive interval ({keep_alive_interval:?}) must be non-zero and shorter than the idle \
+        timeout ({idle_timeout:?})"
+    )]
+    InvalidKeepAliveInterval {
+        /// The configured keep-alive interval.
+        keep_alive_interval: Duration,
+        /// The effective idle timeout.
+        idle_timeout: Duration,
+    },
     /// rustls error
     #[error("An error occurred within rustls")]
     Rustls(#[from] rustls::Error),
     /// rustls error
     #[error("An error occurred generaeting client config certificates")]
     Webpki,
+    /// The DSCP value does not fit in 6 bits.
+    #[error("DSCP value ({0}) must be less than 64")]
+    InvalidDscp(u8),
+    /// [`Config::outgoing_ports`] has no ports in it.
+    #[error("Port range {0} is empty")]
+    EmptyPortRange(PortRange),
+    /// Both [`Config::outgoing_ports`] and a port in [`Config::outgoing_addr`] are given.
+    #[error("Outgoing port range ({ports}) conflicts with the port of outgoing address {addr}")]
+    OutgoingPortConflict {
+        /// The configured outgoing address.
+        addr: SocketAddr,
+        /// The configured outgoing port range.
+        ports: PortRange,
+    },
+    /// One of [`Config::quic_versions`] isn't implemented by quinn.
+    #[error("Unsupported QUIC version: {0:#010x}")]
+    UnsupportedQuicVersion(u32),
+    /// One of [`Config::tls_cipher_suites`] isn't offered by the
+    /// [crypto provider](Config::crypto_provider).
+    #[error("Unsupported TLS cipher suite: {0}")]
+    UnsupportedCipherSuite(String),
+    /// One of [`Config::kx_groups`] isn't offered by the [crypto provider](Config::crypto_provider).
+    #[error("Unsupported key exchange group: {0}")]
+    UnsupportedKxGroup(String),
+    /// The config file couldn't be read.
+    #[error("Failed to read config file {0:?}")]
+    ReadFile(PathBuf, #[source] std::io::Error),
+    /// The config file couldn't be parsed.
+    #[error("Failed to parse config file {0:?}")]
+    ParseFile(PathBuf, #[source] Box<dyn std::error::Error + Send + Sync>),
+    /// The config file's format isn't supported, judging by its extension.
+    ///
+    /// TOML (`.toml`) files are supported with the `config-toml` feature, and JSON (`.json`) files
+    /// with the `config-json` feature.
+    #[error("Unsupported config file format: {0:?}")]
+    UnsupportedFormat(PathBuf),
+    /// An environment variable's value couldn't be parsed as the setting it's named after.
+    ///
+    /// See [`Config::overlay_env`].
+    #[error("Invalid value {value:?} for environment variable {name}: {reason}")]
+    InvalidEnvVar {
+        /// The name of the variable.
+        name: String,
+        /// The variable's value.
+        value: String,
+        /// Why the value is invalid.
+        reason: String,
+    },
 }
 
 impl From<rcgen::RcgenError> for ConfigError {
@@ -101,8 +221,14 @@ pub struct CertificateGenerationError(
 );
 
 /// QuicP2p configurations
+///
+/// Configurations can be (de)serialized with serde, and loaded from a file with
+/// [`from_file`](Self::from_file). Settings that are missing take their default values, and
+/// hooks (such as [`peer_scoring`](Self::peer_scoring)) are skipped. In human-readable formats,
+/// durations are written like `"1m 30s"` or `"500ms"`.
 #[cfg_attr(feature = "structopt", derive(StructOpt))]
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     /// Specify if port forwarding via UPnP should be done or not. This can be set to false if the network
     /// is run locally on the network loopback or on a local area network.
@@ -110,6 +236,27 @@ pub struct Config {
     #[cfg_attr(feature = "structopt", structopt(long))]
     pub forward_port: bool,
 
+    /// The gateway to send NAT-PMP and PCP port mapping requests to.
+    ///
+    /// When [`forward_port`](Self::forward_port) is set, UPnP IGD is tried first, followed by PCP
+    /// and NAT-PMP. If unspecified, the default gateway is looked up in the system's routing table
+    /// (currently only supported on Linux), and PCP and NAT-PMP are skipped if none is found.
+    #[cfg(feature = "igd")]
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub port_mapping_gateway: Option<std::net::Ipv4Addr>,
+
+    /// Additional local addresses to bind, alongside the address given to the `Endpoint`
+    /// constructor.
+    ///
+    /// Incoming connections on any of the sockets are merged into the endpoint's
+    /// `IncomingConnections`, and outgoing connections are made from the socket best suited to the
+    /// peer's address (see [`Endpoint::local_addrs`](crate::Endpoint::local_addrs)). This allows a
+    /// multi-homed node to listen on several ports or interfaces with a single endpoint.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long = "additional-local-addr"))]
+    pub additional_local_addrs: Vec<SocketAddr>,
+
     /// External port number assigned to the socket address of the program.
     /// If this is provided, QP2p considers that the local port provided has been mapped to the
     /// provided external port number and automatic port forwarding will be skipped.
@@ -122,58 +269,1375 @@ pub struct Config {
     #[cfg_attr(feature = "structopt", structopt(long))]
     pub external_ip: Option<IpAddr>,
 
-    /// How long to wait to hear from a peer before timing out a connection.
-    ///
-    /// In the absence of any keep-alive messages, connections will be closed if they remain idle
-    /// for at least this duration.
+    /// How long to wait to hear from a peer before timing out a connection.
+    ///
+    /// In the absence of any keep-alive messages, connections will be closed if they remain idle
+    /// for at least this duration.
+    ///
+    /// If unspecified, this will default to [`DEFAULT_IDLE_TIMEOUT`].
+    #[serde(default, with = "human_duration::option")]
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
+    pub idle_timeout: Option<Duration>,
+
+    /// Interval at which to send keep-alives to maintain otherwise idle connections.
+    ///
+    /// Keep-alives prevent otherwise idle connections from timing out, so the interval must be
+    /// shorter than the [`idle_timeout`](Self::idle_timeout) (otherwise
+    /// [`ConfigError::InvalidKeepAliveInterval`] is returned). The idle timeout is negotiated with
+    /// peers, so an interval well below half of it is recommended.
+    ///
+    /// If unspecified, this will default to `None`, disabling keep-alives.
+    #[serde(default, with = "human_duration::option")]
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
+    pub keep_alive_interval: Option<Duration>,
+
+    /// How many bytes the peer may send on each stream before it's read by this side.
+    ///
+    /// This bounds the throughput of each stream to this many bytes per round trip, so links with
+    /// a high bandwidth-delay product need a larger window to be used fully. Larger windows also
+    /// allow each peer to make this side buffer more data.
+    ///
+    /// If unspecified, this will default to 1.25MB.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub stream_receive_window: Option<u32>,
+
+    /// How many bytes the peer may send across all streams of a connection before they're read by
+    /// this side.
+    ///
+    /// If unspecified, this will default to no limit beyond the per-stream
+    /// [`stream_receive_window`](Self::stream_receive_window).
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub receive_window: Option<u32>,
+
+    /// How many bytes to buffer for sending on each connection before sends wait for data to be
+    /// acknowledged.
+    ///
+    /// Like the peer's receive windows, this bounds each connection's upload throughput to this
+    /// many bytes per round trip.
+    ///
+    /// If unspecified, this will default to 10MB.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub send_window: Option<u64>,
+
+    /// How long to wait for the peer to allow a new stream to be opened.
+    ///
+    /// Opening a stream waits while the peer's limit on concurrent streams is exhausted. When this
+    /// is set, [`Connection::open_uni`](crate::Connection::open_uni),
+    /// [`Connection::open_bi`](crate::Connection::open_bi), and sends that open streams fail with
+    /// [`ConnectionError::StreamOpenTimedOut`](crate::ConnectionError::StreamOpenTimedOut) after
+    /// this long, rather than waiting indefinitely.
+    ///
+    /// If unspecified, this will default to `None`, waiting indefinitely.
+    #[serde(default, with = "human_duration::option")]
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
+    pub stream_open_timeout: Option<Duration>,
+
+    /// The most time to spend connecting to a peer, including retries.
+    ///
+    /// Each connection attempt is bounded by the idle timeout, and attempts are retried according
+    /// to [`retry_config`](Self::retry_config), so the time it takes for connecting to fail can add
+    /// up. When this is set, [`Endpoint::connect_to`](crate::Endpoint::connect_to) and
+    /// [`Endpoint::connect_to_peer`](crate::Endpoint::connect_to_peer) give up with
+    /// [`ConnectionError::ConnectTimedOut`](crate::ConnectionError::ConnectTimedOut) after this
+    /// long, however far they've got.
+    ///
+    /// If unspecified, this will default to `None`, bounded only by the retry configuration.
+    #[serde(default, with = "human_duration::option")]
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
+    pub connect_timeout: Option<Duration>,
+
+    /// Interval at which to send heartbeats on a dedicated control stream for each connection.
+    ///
+    /// When set, each new connection opens a control stream carrying heartbeats and control
+    /// frames, separately from messages, whose state is reported by
+    /// [`Connection::peer_state`](crate::Connection::peer_state). The peer adopts this interval,
+    /// so only one side needs to set it. Intervals shorter than 100ms are rounded up.
+    ///
+    /// If unspecified, this will default to `None`, opening no control stream.
+    #[serde(default, with = "human_duration::option")]
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
+    pub heartbeat_interval: Option<Duration>,
+
+    /// Deliver streams opened by peers as is, without qp2p's message framing.
+    ///
+    /// When set, streams opened by peers are returned by
+    /// [`ConnectionIncoming::take_raw_streams`](crate::ConnectionIncoming::take_raw_streams)
+    /// instead of being read as messages, so the endpoint can speak another protocol to its peers
+    /// (see [`Connection::open_bi_raw`](crate::Connection::open_bi_raw)). Since qp2p's own
+    /// requests are messages, peers of such an endpoint can't use it to verify their public
+    /// address, and it doesn't take part in the DHT or accept control streams.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub raw_streams: bool,
+
+    /// The maximum number of connections to keep open.
+    ///
+    /// When a connection is established that takes the endpoint over this limit, the least
+    /// important other connection is closed (see
+    /// [`Connection::set_class`](crate::Connection::set_class)). Among connections of the same
+    /// class, the one whose peer has the lowest [score](Self::peer_scoring) goes first. Critical
+    /// connections are never closed, so the limit may be exceeded if most connections are critical.
+    ///
+    /// If unspecified, this will default to `None`, allowing any number of connections.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub max_connections: Option<usize>,
+
+    /// The maximum number of incoming connections to accept per second, from all peers.
+    ///
+    /// Up to a second's worth of connections are accepted in a burst. Connections over the limit
+    /// are rejected before their handshake completes, so they cost little more than the initial
+    /// packet. The peer may not be told, and a handshake packet it retransmits later counts as a
+    /// new connection attempt. See also [`accept_rate_per_ip`](Self::accept_rate_per_ip).
+    ///
+    /// If unspecified, this will default to `None`, accepting connections as fast as they arrive.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub accept_rate: Option<u32>,
+
+    /// The maximum number of incoming connections to accept per second from each source IP
+    /// address.
+    ///
+    /// This is checked before [`accept_rate`](Self::accept_rate), so a single host that exceeds
+    /// its own limit can't use up the global limit. Note that source addresses can be spoofed
+    /// unless [`stateless_retry`](Self::stateless_retry) is also set.
+    ///
+    /// If unspecified, this will default to `None`, with no per-IP limit.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub accept_rate_per_ip: Option<u32>,
+
+    /// Validate the source address of each incoming connection with a QUIC retry token before
+    /// keeping any state for it.
+    ///
+    /// The endpoint answers each new connection attempt with a stateless retry, and only proceeds
+    /// when the peer echoes the token back from the same address. This stops floods of handshakes
+    /// from spoofed addresses, at the cost of an extra round trip for every incoming connection.
+    ///
+    /// It also stops the endpoint being used as a reflector in amplification attacks. QUIC already
+    /// limits what an endpoint sends to an unvalidated address to three times what it received
+    /// from it, but without a retry that's still enough for the endpoint's certificate, sent to
+    /// whoever's address was spoofed. With a retry, an unvalidated address only gets the small
+    /// retry packet, and no CPU is spent on the TLS handshake.
+    ///
+    /// quinn can't require a retry from some sources but not others, so this applies to every
+    /// incoming connection. Endpoints that would rather not pay the extra round trip all the time
+    /// can turn it on only while under attack, with
+    /// [`Endpoint::set_stateless_retry`](crate::Endpoint::set_stateless_retry).
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub stateless_retry: bool,
+
+    /// How long a retry token issued for [`stateless_retry`](Self::stateless_retry) is valid.
+    ///
+    /// Shorter lifetimes narrow the window in which a captured token can be replayed, but peers on
+    /// slow links need long enough to echo the token back.
+    ///
+    /// If unspecified, this will default to quinn's default of 15 seconds.
+    #[serde(default, with = "human_duration::option")]
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
+    pub retry_token_lifetime: Option<Duration>,
+
+    /// How long to allow for the handshake of each connection, incoming or outgoing.
+    ///
+    /// This covers the QUIC handshake and the [hello](Self::hello_provider) exchange. Handshakes
+    /// that take longer are abandoned: outgoing connection attempts fail with
+    /// [`ConnectionError::HandshakeTimedOut`](crate::ConnectionError::HandshakeTimedOut) (and may
+    /// be retried), and incoming connections are dropped. This stops peers that stall their
+    /// handshake from holding on to resources for the whole [`idle_timeout`](Self::idle_timeout).
+    ///
+    /// If unspecified, this will default to `None`, so handshakes are only limited by the idle
+    /// timeout.
+    #[serde(default, with = "human_duration::option")]
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
+    pub handshake_timeout: Option<Duration>,
+
+    /// How long a message may take to read or write before its stream is considered stalled.
+    ///
+    /// Reads are timed from the arrival of a message's first bytes, so streams left idle between
+    /// messages don't count. Stalled streams are logged as a warning with the peer's address,
+    /// reported to [`peer_scoring`](Self::peer_scoring) as
+    /// [`PeerEvent::SlowStream`](crate::PeerEvent::SlowStream), and counted in
+    /// [`Connection::message_stats`](crate::Connection::message_stats), which helps find peers
+    /// that trickle data to tie up resources. The read or write carries on regardless.
+    ///
+    /// If unspecified, this will default to `None`, so stalled streams are not detected.
+    #[serde(default, with = "human_duration::option")]
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
+    pub slow_stream_threshold: Option<Duration>,
+
+    /// How long to spend verifying that a peer is reachable, when it asks us to (e.g. while it
+    /// [checks its reachability](crate::Endpoint::check_reachability)).
+    ///
+    /// The peer is verified over an open connection we made to it if there is one, or else a new
+    /// connection. If verification takes longer, the peer is told it couldn't be reached.
+    ///
+    /// If unspecified, this will default to [`DEFAULT_ENDPOINT_VERIFICATION_TIMEOUT`].
+    #[serde(default, with = "human_duration::option")]
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
+    pub endpoint_verification_timeout: Option<Duration>,
+
+    /// The number of TLS sessions to cache for resumption, for outgoing and incoming connections
+    /// each.
+    ///
+    /// Reconnecting to a peer with a cached session skips the peer's certificate and the key
+    /// exchange round trip with it, which makes reconnects cheaper. Resumed connections don't
+    /// carry early (0-RTT) data, since it could be replayed. Sessions are cached per peer
+    /// address, and the least recently used are evicted once the cache is full. A size of `0`
+    /// disables resumption altogether. Counts of resumed sessions can be read with
+    /// [`Endpoint::resumption_stats`](crate::Endpoint::resumption_stats).
+    ///
+    /// If unspecified, this will default to [`DEFAULT_SESSION_CACHE_SIZE`].
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub session_cache_size: Option<usize>,
+
+    /// How messages sent on each connection are mapped onto QUIC streams.
+    ///
+    /// This can be changed for individual connections with
+    /// [`Connection::set_message_ordering`](crate::Connection::set_message_ordering).
+    ///
+    /// If unspecified, this will default to [`MessageOrdering::Unordered`].
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "unordered"))]
+    pub message_ordering: MessageOrdering,
+
+    /// The most bytes of incoming messages to buffer for each connection, until the application
+    /// receives them.
+    ///
+    /// Each connection also buffers at most 10,000 messages, however small. Messages that don't
+    /// fit are handled according to [`buffer_overflow`](Self::buffer_overflow). A message larger
+    /// than the limit is still buffered once the connection's buffer is empty. Messages passed to
+    /// [`Endpoint::messages_from`](crate::Endpoint::messages_from) receivers or a
+    /// [`MessageSink`] aren't buffered, and don't count towards the limit.
+    ///
+    /// If unspecified, this will default to [`DEFAULT_MAX_BUFFERED_BYTES_PER_CONNECTION`].
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub max_buffered_bytes_per_connection: Option<usize>,
+
+    /// The most bytes of incoming messages to buffer across all connections, until the
+    /// application receives them.
+    ///
+    /// Messages that don't fit are handled according to [`buffer_overflow`](Self::buffer_overflow),
+    /// as for [`max_buffered_bytes_per_connection`](Self::max_buffered_bytes_per_connection). The
+    /// buffered messages can be inspected with
+    /// [`Endpoint::incoming_buffer`](crate::Endpoint::incoming_buffer).
+    ///
+    /// If unspecified, this will default to `None`, only limiting each connection's buffer.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub max_buffered_bytes: Option<usize>,
+
+    /// What to do with incoming messages that don't fit in the buffer.
+    ///
+    /// If unspecified, this will default to [`OverflowPolicy::Wait`].
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "wait"))]
+    pub buffer_overflow: OverflowPolicy,
+
+    /// The most messages each connection sends at once.
+    ///
+    /// Further sends wait for their turn in the order they were made, however many tasks they
+    /// come from, so a task sending many messages can't starve the others. This applies to
+    /// [`Connection::send`](crate::Connection::send) and its variants, and the time spent waiting
+    /// counts towards their deadline. Priorities only order the messages being sent, not those
+    /// waiting their turn. A limit of `0` is treated as `1`.
+    ///
+    /// If unspecified, this will default to `None`, starting every send straight away.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub max_concurrent_sends: Option<usize>,
+
+    /// The size above which messages are split across several streams, sent in parallel.
+    ///
+    /// The throughput of a single stream is limited by its flow control window, which on links
+    /// with a high latency can be well below the link's bandwidth. Splitting large messages
+    /// across [`message_split_streams`](Self::message_split_streams) streams gets around this.
+    /// The peer puts the message back together before it's received, so this makes no difference
+    /// to the application. Only messages sent without [`MessageOrdering::Ordered`] are split, and
+    /// peers must run a version of qp2p that can put them back together. If sending any part of a
+    /// message fails, the peer drops the rest of it after a minute.
+    ///
+    /// If unspecified, this will default to `None`, sending every message on a single stream.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub message_split_threshold: Option<usize>,
+
+    /// The number of streams that messages over
+    /// [`message_split_threshold`](Self::message_split_threshold) are split across.
+    ///
+    /// If unspecified, this will default to [`DEFAULT_MESSAGE_SPLIT_STREAMS`].
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub message_split_streams: Option<usize>,
+
+    /// The most buffers to keep for encoding outgoing messages.
+    ///
+    /// Messages other than plain user messages (e.g. those sent with acknowledgements, chunks of
+    /// split messages and qp2p's own control messages) are encoded into a buffer along with their
+    /// header, so they can be written in one go. Buffers are returned to a pool shared by the
+    /// endpoint's connections once the message is written, and reused for later messages instead
+    /// of allocating new ones. A size of `0` disables pooling. Counts of reused buffers can be read
+    /// with [`Endpoint::buffer_pool_stats`](crate::Endpoint::buffer_pool_stats).
+    ///
+    /// If unspecified, this will default to [`DEFAULT_BUFFER_POOL_SIZE`].
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub buffer_pool_size: Option<usize>,
+
+    /// The largest buffer, in bytes, to return to the pool of
+    /// [`buffer_pool_size`](Self::buffer_pool_size) buffers.
+    ///
+    /// Buffers that grew larger to encode a large message are dropped once it's written, so a few
+    /// large messages don't leave the pool holding on to a lot of memory.
+    ///
+    /// If unspecified, this will default to [`DEFAULT_MAX_POOLED_BUFFER_SIZE`].
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub max_pooled_buffer_size: Option<usize>,
+
+    /// How long UPnP port mappings will last.
+    ///
+    /// Note that UPnP port mappings will be automatically renewed on this interval.
+    ///
+    /// If unspecified, this will default to [`DEFAULT_UPNP_LEASE_DURATION`], which should be
+    /// suitable in most cases but some routers may clear UPnP port mapping more frequently.
+    #[serde(default, with = "human_duration::option")]
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
+    pub upnp_lease_duration: Option<Duration>,
+
+    /// Retry configurations for establishing connections and sending messages.
+    /// Determines the retry behaviour of requests, by setting the back off strategy used.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub retry_config: RetryConfig,
+
+    /// Options for the endpoint's UDP sockets, such as buffer sizes.
+    ///
+    /// The options apply to every socket, including those bound for
+    /// [`additional_local_addrs`](Self::additional_local_addrs).
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(flatten))]
+    pub socket_config: SocketConfig,
+
+    /// The number of QUIC endpoints ('workers') to share a peer endpoint's local address.
+    ///
+    /// Each QUIC endpoint processes its packets on a single task, which can saturate a CPU core
+    /// well before the network. With more than one worker, each is bound to the local address with
+    /// `SO_REUSEPORT` (see [`SocketConfig::reuse_port`]), the operating system balances incoming
+    /// connections between them, and their connections are merged into the endpoint's
+    /// `IncomingConnections`.
+    ///
+    /// The operating system can't tell which worker made an outgoing connection, so outgoing
+    /// connections are instead made from one extra socket per worker (used in turn), bound to the
+    /// local IP with an ephemeral port (or as given by [`outgoing_addr`](Self::outgoing_addr) and
+    /// [`outgoing_ports`](Self::outgoing_ports)). Since peers see these connections come from a different
+    /// port, the public port is taken from the local address (or
+    /// [`external_port`](Self::external_port)) rather than from the bootstrap contact. A peer
+    /// whose address changes during a connection may also be moved to another worker, losing the
+    /// connection.
+    ///
+    /// Only supported on Unix platforms, and ignored by client endpoints. If unspecified, this
+    /// will default to a single worker.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub workers: Option<usize>,
+
+    /// The local address to make outgoing connections from, instead of the listening socket.
+    ///
+    /// By default, a peer endpoint makes outgoing connections from the socket it listens on, so
+    /// peers see them come from the same address and port as it advertises. This is what lets
+    /// endpoints behind a full-cone NAT be reached at the address their connections were mapped
+    /// to. Setting this binds a separate socket (one per [worker](Self::workers)) to dial from,
+    /// e.g. to route outgoing traffic through another interface, or to give it a port that a
+    /// firewall allows. Port `0` picks an ephemeral port, or one from
+    /// [`outgoing_ports`](Self::outgoing_ports).
+    ///
+    /// Since peers see outgoing connections come from a different port than the endpoint listens
+    /// on, the public port is then taken from the local address (or
+    /// [`external_port`](Self::external_port)) rather than from the bootstrap contact. Ignored by
+    /// client endpoints, whose only socket is bound to the address they're created with.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub outgoing_addr: Option<SocketAddr>,
+
+    /// The ports to bind outgoing sockets in, e.g. `40000-40099`.
+    ///
+    /// The first free port in the range is used, so a range with a port per
+    /// [worker](Self::workers) is needed to bind them all. For a peer endpoint, this binds a
+    /// separate socket to dial from as with [`outgoing_addr`](Self::outgoing_addr) (at the
+    /// listening IP, if that isn't given). For a client endpoint created at a local address with
+    /// port `0`, this chooses the port of its socket.
+    ///
+    /// If unspecified, outgoing sockets are bound to ephemeral ports chosen by the operating
+    /// system.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub outgoing_ports: Option<PortRange>,
+
+    /// Application protocols to offer during the TLS handshake, in order of preference.
+    ///
+    /// Connections will only be established if both sides have at least one protocol in common,
+    /// and the negotiated protocol can be read with
+    /// [`Connection::alpn_protocol`](crate::Connection::alpn_protocol). This allows several
+    /// protocols (or protocol versions) to share an endpoint. If unspecified, ALPN is not used.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long = "alpn-protocol"))]
+    pub alpn_protocols: Vec<String>,
+
+    /// QUIC versions to use, in order of preference.
+    ///
+    /// Outgoing connections use the first version, and incoming connections are accepted with any
+    /// of them, so pinning a single version makes connections fail fast against peers that don't
+    /// support it. Versions are numbered as on the wire, e.g. `1` for QUIC v1 (RFC 9000) or
+    /// `0xff00_001d` for draft 29, and must be among the versions implemented by quinn (see
+    /// [`quinn_proto::DEFAULT_SUPPORTED_VERSIONS`]), or creating the endpoint fails with
+    /// [`ConfigError::UnsupportedQuicVersion`]. The version of a connection can be read with
+    /// [`Connection::quic_version`](crate::Connection::quic_version).
+    ///
+    /// If unspecified, outgoing connections use QUIC v1, and all of quinn's versions are accepted.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long = "quic-version"))]
+    pub quic_versions: Vec<u32>,
+
+    /// The cryptographic algorithms connections may use for TLS.
+    ///
+    /// See [`CryptoProvider`] for the choices. Peers must share at least one algorithm of each
+    /// kind, or they can't connect.
+    ///
+    /// If unspecified, this will default to [`CryptoProvider::Ring`].
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "ring"))]
+    pub crypto_provider: CryptoProvider,
+
+    /// The TLS cipher suites connections may use, in order of preference.
+    ///
+    /// Suites are named as in the TLS registry, e.g. `TLS13_AES_256_GCM_SHA384`, and must be
+    /// offered by the [`crypto_provider`](Self::crypto_provider), or creating the endpoint fails
+    /// with [`ConfigError::UnsupportedCipherSuite`]. This lets operators disable suites their
+    /// policy doesn't approve.
+    ///
+    /// If unspecified, all of the crypto provider's suites are used.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long = "tls-cipher-suite"))]
+    pub tls_cipher_suites: Vec<String>,
+
+    /// The TLS key exchange groups connections may use, in order of preference.
+    ///
+    /// Groups are named as in the TLS registry, e.g. `X25519` or `secp384r1`, and must be offered
+    /// by the [`crypto_provider`](Self::crypto_provider), or creating the endpoint fails with
+    /// [`ConfigError::UnsupportedKxGroup`].
+    ///
+    /// If unspecified, all of the crypto provider's groups are used.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long = "kx-group"))]
+    pub kx_groups: Vec<String>,
+
+    /// Reputation tracker to notify of peer events.
+    ///
+    /// The tracker is also consulted before accepting incoming connections. If unspecified, no
+    /// scoring is performed and all peers are accepted.
+    #[serde(skip)]
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    pub peer_scoring: Option<Arc<dyn PeerScoring>>,
+
+    /// Provider of the hello payload exchanged when connections are established.
+    ///
+    /// If unspecified, no hello is exchanged. See [`HelloProvider`] for details.
+    #[serde(skip)]
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    pub hello_provider: Option<Arc<dyn HelloProvider>>,
+
+    /// Derives the identities of connections' peers, so connections can be found by peer identity.
+    ///
+    /// If unspecified, connections have no [`peer_id`](crate::Connection::peer_id). See
+    /// [`PeerIdentifier`] for details.
+    #[serde(skip)]
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    pub peer_identifier: Option<Arc<dyn PeerIdentifier>>,
+
+    /// Resolver used to look up host names passed to
+    /// [`Endpoint::connect_to`](crate::Endpoint::connect_to).
+    ///
+    /// If unspecified, this will default to [`SystemResolver`].
+    #[serde(skip)]
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    pub resolver: Option<Arc<dyn Resolver>>,
+
+    /// Storage for known peers, so they survive restarts.
+    ///
+    /// The endpoint's [`AddressBook`](crate::AddressBook) and the contacts it bootstrapped against
+    /// are loaded from the store when the endpoint is created, and saved to it as they change. See
+    /// [`PeerStore`] for details. If unspecified, peers are only kept in memory.
+    #[serde(skip)]
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    pub peer_store: Option<Arc<dyn PeerStore>>,
+
+    /// Callbacks to invoke as connections are established and closed.
+    #[serde(skip)]
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    pub connection_observer: Option<Arc<dyn ConnectionObserver>>,
+
+    /// Where to deliver received messages, instead of each connection's
+    /// [`ConnectionIncoming`](crate::ConnectionIncoming).
+    ///
+    /// See [`MessageSink`] for details. If unspecified, messages are delivered to the connections'
+    /// `ConnectionIncoming`.
+    #[serde(skip)]
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    pub message_sink: Option<Arc<dyn MessageSink>>,
+
+    /// Storage for the TLS sessions of outgoing connections, e.g. so they survive restarts.
+    ///
+    /// See [`SessionStore`] for details. If unspecified, sessions are cached in memory, up to
+    /// [`session_cache_size`](Self::session_cache_size). Sessions of incoming connections are
+    /// always cached in memory.
+    #[serde(skip)]
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    pub session_store: Option<Arc<dyn SessionStore>>,
+
+    /// Hooks to invoke on every user message sent and received, in order.
+    ///
+    /// See [`Interceptor`] for details.
+    #[serde(skip)]
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    pub interceptors: Vec<Arc<dyn Interceptor>>,
+
+    /// Key to sign user messages with, and verify the signatures of received messages.
+    ///
+    /// When set, every user message is sent with a signature, which survives the message being
+    /// forwarded, and messages without a valid signature are returned as
+    /// [`RecvError::InvalidSignature`](crate::RecvError::InvalidSignature). The identity of each
+    /// message's signer is returned by
+    /// [`ConnectionIncoming::next_with_signer`](crate::ConnectionIncoming::next_with_signer). Both
+    /// peers must set a key.
+    ///
+    /// If unspecified, messages are neither signed nor verified.
+    #[serde(skip)]
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    pub signing_key: Option<Arc<SigningKey>>,
+
+    /// Encrypt user messages end-to-end, with keys agreed with each peer during the hello exchange.
+    ///
+    /// TLS only protects messages between the two ends of a QUIC connection, so anything relaying
+    /// messages between peers can read them. When set, an ephemeral X25519 key share is exchanged
+    /// along with the [hello](crate::HelloProvider) (even if there's no hello provider), and user
+    /// messages are encrypted with the agreed keys. Both peers must set this, or their connections
+    /// fail with [`ConnectionError::Hello`](crate::ConnectionError::Hello).
+    ///
+    /// Key shares are only authenticated if they're signed: a peer with a
+    /// [`signing_key`](Self::signing_key) signs both key shares and both identities with it, so the
+    /// other peer knows whose keys it agreed to. Without a
+    /// [`peer_identifier`](Self::peer_identifier), the signer becomes the connection's
+    /// [`peer_id`](crate::Connection::peer_id). Unsigned key shares only protect against passive
+    /// observers: a relay that terminates TLS can substitute its own shares and read every message.
+    /// Connections made with [`Endpoint::connect_to_peer`](crate::Endpoint::connect_to_peer) or
+    /// [`Endpoint::upgrade_to_direct`](crate::Endpoint::upgrade_to_direct) require the peer to
+    /// sign as the expected identity, so only those are protected from such an intermediary.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub e2e_encryption: bool,
+
+    /// How long to remember received user messages, to drop identical messages from the same
+    /// peer.
+    ///
+    /// A send that fails ambiguously (e.g. the connection is lost before the peer acknowledges the
+    /// message) may have been delivered anyway, so retrying it can deliver a duplicate. When this
+    /// is set, a message from a peer whose content matches one received from the same address
+    /// within the window is dropped before it reaches the application (or any
+    /// [interceptors](Self::interceptors)). Applications that legitimately send identical messages
+    /// should make them distinct, e.g. with a sequence number, or leave this unset.
+    ///
+    /// If unspecified, this will default to `None`, delivering every message.
+    #[serde(default, with = "human_duration::option")]
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
+    pub dedup_window: Option<Duration>,
+
+    /// Share known-good peer addresses with connected peers, and periodically ask them for
+    /// theirs.
+    ///
+    /// Every [`peer_exchange_interval`](Self::peer_exchange_interval), a few connected peers are
+    /// asked for the addresses they know, as by
+    /// [`Endpoint::exchange_peers`](crate::Endpoint::exchange_peers). This lets a new node find
+    /// peers beyond its bootstrap contacts, and rejoin the network through them if its contacts go
+    /// away. Peers share the addresses they dialed themselves, and addresses other peers signed
+    /// with their [`signing_key`](Self::signing_key) (including their own, if it's set).
+    ///
+    /// If unspecified, this will default to `false`: requests from peers are answered with no
+    /// addresses, and peers are only asked when `exchange_peers` is called.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub peer_exchange: bool,
+
+    /// How often to ask connected peers for addresses, with
+    /// [`peer_exchange`](Self::peer_exchange).
+    ///
+    /// Peers answer at most one request per connection every 30 seconds, so shorter intervals are
+    /// rounded up. If unspecified, this will default to [`DEFAULT_PEER_EXCHANGE_INTERVAL`].
+    #[serde(default, with = "human_duration::option")]
+    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
+    pub peer_exchange_interval: Option<Duration>,
+
+    /// Identifier of this node in the DHT.
+    ///
+    /// If unspecified, a random identifier will be generated.
+    #[cfg(feature = "dht")]
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    pub dht_node_id: Option<NodeId>,
+}
+
+impl Config {
+    /// Load a configuration from a TOML or JSON file.
+    ///
+    /// The format is chosen by the file's extension, `.toml` (with the `config-toml` feature) or
+    /// `.json` (with the `config-json` feature). As when deserializing, settings missing from the
+    /// file take their default values, so a file need only contain what it changes:
+    ///
+    /// ```text
+    /// idle_timeout = "30s"
+    /// keep_alive_interval = "10s"
+    ///
+    /// [retry_config]
+    /// max_retry_interval = "1m"
+    /// ```
+    ///
+    /// The configuration is only validated when an endpoint is created with it.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        match path.extension().and_then(|extension| extension.to_str()) {
+            #[cfg(feature = "config-toml")]
+            Some("toml") => toml::from_str(&read_file(path)?)
+                .map_err(|error| ConfigError::ParseFile(path.to_path_buf(), error.into())),
+            #[cfg(feature = "config-json")]
+            Some("json") => serde_json::from_str(&read_file(path)?)
+                .map_err(|error| ConfigError::ParseFile(path.to_path_buf(), error.into())),
+            _ => Err(ConfigError::UnsupportedFormat(path.to_path_buf())),
+        }
+    }
+
+    /// A configuration for interactive traffic, which notices failures quickly.
+    ///
+    /// Connections time out after 10 seconds of silence, with keep-alives every 3 seconds, and
+    /// handshakes, opening streams, and retries give up sooner than usual, so that failures are
+    /// reported in time to try something else.
+    pub fn low_latency() -> Self {
+        Self {
+            idle_timeout: Some(Duration::from_secs(10)),
+            keep_alive_interval: Some(Duration::from_secs(3)),
+            handshake_timeout: Some(Duration::from_secs(3)),
+            connect_timeout: Some(Duration::from_secs(10)),
+            stream_open_timeout: Some(Duration::from_secs(2)),
+            retry_config: RetryConfig {
+                initial_retry_interval: Duration::from_millis(100),
+                max_retry_interval: Duration::from_secs(1),
+                retrying_max_elapsed_time: Duration::from_secs(5),
+                ..RetryConfig::default()
+            },
+            ..Self::default()
+        }
+    }
+
+    /// A configuration for moving large amounts of data, over links with a high
+    /// bandwidth-delay product.
+    ///
+    /// Flow control windows and socket buffers are large enough for a single stream to fill a
+    /// gigabit link with a 100ms round trip, at the cost of more memory per connection. Connections
+    /// are kept alive, so that slow consumers don't cause them to time out.
+    pub fn bulk_transfer() -> Self {
+        const MIB: u32 = 1024 * 1024;
+        Self {
+            idle_timeout: Some(Duration::from_secs(60)),
+            keep_alive_interval: Some(Duration::from_secs(20)),
+            stream_receive_window: Some(16 * MIB),
+            receive_window: Some(64 * MIB),
+            send_window: Some((64 * MIB).into()),
+            socket_config: SocketConfig {
+                send_buffer_size: Some(4 * MIB as usize),
+                recv_buffer_size: Some(4 * MIB as usize),
+                ..SocketConfig::default()
+            },
+            ..Self::default()
+        }
+    }
+
+    /// A configuration for devices on cellular or otherwise flaky networks.
+    ///
+    /// Connections survive a minute without connectivity (e.g. while switching networks), and
+    /// keep-alives every 15 seconds hold on to carrier NAT mappings, which often expire after 30
+    /// seconds. Handshakes are given longer, for radios waking up, and retries back off further and
+    /// keep going for longer. Flow control windows are kept small, to bound memory use and
+    /// bufferbloat.
+    pub fn mobile() -> Self {
+        const KIB: u32 = 1024;
+        Self {
+            idle_timeout: Some(Duration::from_secs(60)),
+            keep_alive_interval: Some(Duration::from_secs(15)),
+            handshake_timeout: Some(Duration::from_secs(20)),
+            stream_receive_window: Some(256 * KIB),
+            receive_window: Some(1024 * KIB),
+            send_window: Some((1024 * KIB).into()),
+            retry_config: RetryConfig {
+                initial_retry_interval: Duration::from_secs(1),
+                max_retry_interval: Duration::from_secs(30),
+                retrying_max_elapsed_time: Duration::from_secs(120),
+                ..RetryConfig::default()
+            },
+            ..Self::default()
+        }
+    }
+
+    /// The settings in which this configuration differs from `base`.
+    ///
+    /// Settings of [`retry_config`](Self::retry_config) and
+    /// [`socket_config`](Self::socket_config) are compared individually. Hooks (such as
+    /// [`peer_scoring`](Self::peer_scoring)) are only compared by whether they're set.
+    pub fn diff(&self, base: &Config) -> Vec<ConfigDiff> {
+        macro_rules! diff_fields {
+            ($prefix:literal, $config:expr, $base:expr, $diffs:ident, $($field:ident),+ $(,)?) => {
+                $(
+                    let value = format!("{:?}", $config.$field);
+                    let base = format!("{:?}", $base.$field);
+                    if value != base {
+                        $diffs.push(ConfigDiff {
+                            field: concat!($prefix, stringify!($field)),
+                            value,
+                            base,
+                        });
+                    }
+                )+
+            };
+        }
+
+        let mut diffs = Vec::new();
+        #[cfg(feature = "igd")]
+        diff_fields!("", self, base, diffs, forward_port, port_mapping_gateway);
+        diff_fields!(
+            "",
+            self,
+            base,
+            diffs,
+            additional_local_addrs,
+            external_port,
+            external_ip,
+            idle_timeout,
+            keep_alive_interval,
+            stream_receive_window,
+            receive_window,
+            send_window,
+            stream_open_timeout,
+            connect_timeout,
+            heartbeat_interval,
+            raw_streams,
+            max_connections,
+            accept_rate,
+            accept_rate_per_ip,
+            stateless_retry,
+            retry_token_lifetime,
+            handshake_timeout,
+            slow_stream_threshold,
+            endpoint_verification_timeout,
+            session_cache_size,
+            message_ordering,
+            max_buffered_bytes_per_connection,
+            max_buffered_bytes,
+            buffer_overflow,
+            max_concurrent_sends,
+            message_split_threshold,
+            message_split_streams,
+            buffer_pool_size,
+            max_pooled_buffer_size,
+            upnp_lease_duration,
+        );
+        diff_fields!(
+            "retry_config.",
+            self.retry_config,
+            base.retry_config,
+            diffs,
+            initial_retry_interval,
+            initial_retry_rtt_multiplier,
+            max_retry_interval,
+            retry_delay_multiplier,
+            retry_delay_rand_factor,
+            retrying_max_elapsed_time,
+            max_retry_attempts,
+            retry_jitter,
+            circuit_breaker_threshold,
+            circuit_breaker_cooldown,
+        );
+        diff_fields!(
+            "socket_config.",
+            self.socket_config,
+            base.socket_config,
+            diffs,
+            send_buffer_size,
+            recv_buffer_size,
+            dscp,
+            reuse_port,
+        );
+        diff_fields!(
+            "",
+            self,
+            base,
+            diffs,
+            workers,
+            outgoing_addr,
+            outgoing_ports,
+            alpn_protocols,
+            quic_versions,
+            crypto_provider,
+            tls_cipher_suites,
+            kx_groups,
+            e2e_encryption,
+            dedup_window,
+            peer_exchange,
+            peer_exchange_interval,
+        );
+        #[cfg(feature = "dht")]
+        diff_fields!("", self, base, diffs, dht_node_id);
+
+        for (field, value, base) in [
+            (
+                "peer_scoring",
+                self.peer_scoring.is_some(),
+                base.peer_scoring.is_some(),
+            ),
+            (
+                "hello_provider",
+                self.hello_provider.is_some(),
+                base.hello_provider.is_some(),
+            ),
+            (
+                "peer_identifier",
+                self.peer_identifier.is_some(),
+                base.peer_identifier.is_some(),
+            ),
+            ("resolver", self.resolver.is_some(), base.resolver.is_some()),
+            (
+                "peer_store",
+                self.peer_store.is_some(),
+                base.peer_store.is_some(),
+            ),
+            (
+                "connection_observer",
+                self.connection_observer.is_some(),
+                base.connection_observer.is_some(),
+            ),
+            (
+                "message_sink",
+                self.message_sink.is_some(),
+                base.message_sink.is_some(),
+            ),
+            (
+                "session_store",
+                self.session_store.is_some(),
+                base.session_store.is_some(),
+            ),
+            (
+                "interceptors",
+                !self.interceptors.is_empty(),
+                !base.interceptors.is_empty(),
+            ),
+            (
+                "signing_key",
+                self.signing_key.is_some(),
+                base.signing_key.is_some(),
+            ),
+            (
+                "retry_config.retry_hook",
+                self.retry_config.retry_hook.is_some(),
+                base.retry_config.retry_hook.is_some(),
+            ),
+        ] {
+            if value != base {
+                diffs.push(ConfigDiff {
+                    field,
+                    value: hook_state(value),
+                    base: hook_state(base),
+                });
+            }
+        }
+
+        diffs
+    }
+
+    /// The settings in which this configuration differs from the default.
+    ///
+    /// This is a quick way to review a configuration, e.g. by logging each difference. See
+    /// [`diff`](Self::diff).
+    pub fn diff_from_default(&self) -> Vec<ConfigDiff> {
+        self.diff(&Self::default())
+    }
+
+    /// The default configuration, overlaid with settings from environment variables (see
+    /// [`overlay_env`](Self::overlay_env)).
+    pub fn from_env(prefix: &str) -> Result<Self> {
+        Self::default().overlay_env(prefix)
+    }
+
+    /// Overlay settings from environment variables onto this configuration.
+    ///
+    /// Each variable is named after a setting, in upper case, after `prefix` and an underscore,
+    /// e.g. `QP2P_IDLE_TIMEOUT` for [`idle_timeout`](Self::idle_timeout) with the prefix `QP2P`.
+    /// Settings of [`retry_config`](Self::retry_config) and
+    /// [`socket_config`](Self::socket_config) include the name of the struct, e.g.
+    /// `QP2P_RETRY_CONFIG_MAX_RETRY_INTERVAL`. Settings whose variables aren't set are left as
+    /// they are, and hooks can't be set this way.
+    ///
+    /// Values are parsed as follows, and a value that can't be parsed fails with
+    /// [`ConfigError::InvalidEnvVar`]:
+    ///
+    /// - Durations are written like `30s`, `1m 30s`, or `500ms`.
+    /// - Lists (such as [`additional_local_addrs`](Self::additional_local_addrs)) are
+    ///   comma-separated.
+    /// - Optional settings are unset by an empty value.
+    /// - Everything else is parsed with its `FromStr` implementation, e.g. `true` or `ordered`.
+    pub fn overlay_env(mut self, prefix: &str) -> Result<Self> {
+        macro_rules! overlay_fields {
+            ($group:literal, $config:expr, $($field:ident),+ $(,)?) => {
+                $(
+                    if let Some(value) = env_setting(prefix, concat!($group, stringify!($field)))? {
+                        $config.$field = value;
+                    }
+                )+
+            };
+        }
+
+        #[cfg(feature = "igd")]
+        overlay_fields!("", self, forward_port, port_mapping_gateway);
+        overlay_fields!(
+            "",
+            self,
+            additional_local_addrs,
+            external_port,
+            external_ip,
+            idle_timeout,
+            keep_alive_interval,
+            stream_receive_window,
+            receive_window,
+            send_window,
+            stream_open_timeout,
+            connect_timeout,
+            heartbeat_interval,
+            raw_streams,
+            max_connections,
+            accept_rate,
+            accept_rate_per_ip,
+            stateless_retry,
+            retry_token_lifetime,
+            handshake_timeout,
+            slow_stream_threshold,
+            endpoint_verification_timeout,
+            session_cache_size,
+            message_ordering,
+            max_buffered_bytes_per_connection,
+            max_buffered_bytes,
+            buffer_overflow,
+            max_concurrent_sends,
+            message_split_threshold,
+            message_split_streams,
+            buffer_pool_size,
+            max_pooled_buffer_size,
+            upnp_lease_duration,
+            workers,
+            outgoing_addr,
+            outgoing_ports,
+            alpn_protocols,
+            quic_versions,
+            crypto_provider,
+            tls_cipher_suites,
+            kx_groups,
+            e2e_encryption,
+            dedup_window,
+            peer_exchange,
+            peer_exchange_interval,
+        );
+        overlay_fields!(
+            "retry_config_",
+            self.retry_config,
+            initial_retry_interval,
+            initial_retry_rtt_multiplier,
+            max_retry_interval,
+            retry_delay_multiplier,
+            retry_delay_rand_factor,
+            retrying_max_elapsed_time,
+            max_retry_attempts,
+            retry_jitter,
+            circuit_breaker_threshold,
+            circuit_breaker_cooldown,
+        );
+        overlay_fields!(
+            "socket_config_",
+            self.socket_config,
+            send_buffer_size,
+            recv_buffer_size,
+            dscp,
+            reuse_port,
+        );
+
+        Ok(self)
+    }
+}
+
+// Read and parse the environment variable for the setting `name`, if it's set.
+fn env_setting<T: EnvValue>(prefix: &str, name: &str) -> Result<Option<T>> {
+    let name = if prefix.is_empty() {
+        name.to_uppercase()
+    } else {
+        format!("{}_{}", prefix, name.to_uppercase())
+    };
+    let value = match std::env::var(&name) {
+        Ok(value) => value,
+        Err(std::env::VarError::NotPresent) => return Ok(None),
+        Err(std::env::VarError::NotUnicode(value)) => {
+            return Err(ConfigError::InvalidEnvVar {
+                value: value.to_string_lossy().into_owned(),
+                name,
+                reason: "not valid unicode".to_string(),
+            })
+        }
+    };
+    match T::parse_env(&value) {
+        Ok(parsed) => Ok(Some(parsed)),
+        Err(reason) => Err(ConfigError::InvalidEnvVar {
+            name,
+            value,
+            reason,
+        }),
+    }
+}
+
+// How settings are parsed from environment variables.
+trait EnvValue: Sized {
+    fn parse_env(value: &str) -> Result<Self, String>;
+}
+
+macro_rules! env_value_from_str {
+    ($($ty:ty),+ $(,)?) => {
+        $(
+            impl EnvValue for $ty {
+                fn parse_env(value: &str) -> Result<Self, String> {
+                    value.parse().map_err(|error| format!("{}", error))
+                }
+            }
+        )+
+    };
+}
+
+env_value_from_str!(
+    bool,
+    u8,
+    u16,
+    u32,
+    u64,
+    usize,
+    f64,
+    String,
+    IpAddr,
+    std::net::Ipv4Addr,
+    SocketAddr,
+    MessageOrdering,
+    OverflowPolicy,
+    CryptoProvider,
+    RetryJitter,
+    PortRange,
+);
+
+impl EnvValue for Duration {
+    fn parse_env(value: &str) -> Result<Self, String> {
+        humantime::parse_duration(value).map_err(|error| error.to_string())
+    }
+}
+
+impl<T: EnvValue> EnvValue for Option<T> {
+    fn parse_env(value: &str) -> Result<Self, String> {
+        if value.is_empty() {
+            Ok(None)
+        } else {
+            T::parse_env(value).map(Some)
+        }
+    }
+}
+
+impl<T: EnvValue> EnvValue for Vec<T> {
+    fn parse_env(value: &str) -> Result<Self, String> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(T::parse_env)
+            .collect()
+    }
+}
+
+#[cfg(any(feature = "config-toml", feature = "config-json"))]
+fn read_file(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|error| ConfigError::ReadFile(path.to_path_buf(), error))
+}
+
+fn hook_state(set: bool) -> String {
+    if set { "set" } else { "unset" }.to_string()
+}
+
+/// A setting that differs between two configurations, as returned by [`Config::diff`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigDiff {
+    /// The name of the setting, e.g. `idle_timeout` or `retry_config.max_retry_interval`.
+    pub field: &'static str,
+
+    /// The setting's value in the configuration that was compared, formatted with `Debug`.
+    pub value: String,
+
+    /// The setting's value in the configuration it was compared against, formatted with `Debug`.
+    pub base: String,
+}
+
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} (instead of {})",
+            self.field, self.value, self.base
+        )
+    }
+}
+
+/// Settings to change on a running endpoint, with
+/// [`Endpoint::update_config`](crate::Endpoint::update_config).
+///
+/// Each setting replaces the [`Config`] setting of the same name, or is left as it is if `None`.
+/// For settings that are themselves optional, `Some(None)` unsets them.
+#[derive(Clone, Debug, Default)]
+pub struct PartialConfig {
+    /// Replaces [`Config::retry_config`], including its circuit breaker settings.
+    ///
+    /// The new config applies to connection attempts and sends started after the update, on new
+    /// and existing connections alike.
+    pub retry_config: Option<RetryConfig>,
+
+    /// Replaces [`Config::accept_rate`].
+    pub accept_rate: Option<Option<u32>>,
+
+    /// Replaces [`Config::accept_rate_per_ip`].
+    pub accept_rate_per_ip: Option<Option<u32>>,
+
+    /// Replaces [`Config::keep_alive_interval`].
+    ///
+    /// The new interval applies to connections made after the update. As at start-up, it must be
+    /// shorter than the idle timeout.
+    pub keep_alive_interval: Option<Option<Duration>>,
+}
+
+#[cfg(feature = "structopt")]
+fn parse_millis(millis: &str) -> Result<Duration, std::num::ParseIntError> {
+    Ok(Duration::from_millis(millis.parse()?))
+}
+
+/// How messages sent with [`Connection::send`](crate::Connection::send) are mapped onto QUIC
+/// streams.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MessageOrdering {
+    /// Each message is sent on its own stream.
     ///
-    /// If unspecified, this will default to [`DEFAULT_IDLE_TIMEOUT`].
-    #[serde(default)]
-    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
-    pub idle_timeout: Option<Duration>,
+    /// Messages are delivered independently, so a lost packet only delays the message it belongs
+    /// to. However, messages may be received in a different order than they were sent.
+    #[default]
+    Unordered,
 
-    /// Interval at which to send keep-alives to maintain otherwise idle connections.
+    /// All messages are sent on a single stream, so they are received in the order they were sent.
     ///
-    /// Keep-alives prevent otherwise idle connections from timing out.
+    /// A lost packet delays every message after it (head-of-line blocking), and per-message
+    /// priorities are ignored.
+    Ordered,
+}
+
+impl std::str::FromStr for MessageOrdering {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unordered" => Ok(Self::Unordered),
+            "ordered" => Ok(Self::Ordered),
+            _ => Err(format!(
+                "invalid message ordering '{}', expected 'ordered' or 'unordered'",
+                s
+            )),
+        }
+    }
+}
+
+/// What to do with an incoming message that doesn't fit in the buffer of messages waiting to be
+/// received, as [`Config::buffer_overflow`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OverflowPolicy {
+    /// Stop reading from the peer until the application has received enough messages.
     ///
-    /// If unspecified, this will default to `None`, disabling keep-alives.
-    #[serde(default)]
-    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
-    pub keep_alive_interval: Option<Duration>,
+    /// No messages are lost, and the peer is slowed down by flow control. However, a connection
+    /// whose messages aren't received holds up every connection while the endpoint's buffer is
+    /// full.
+    #[default]
+    Wait,
 
-    /// How long UPnP port mappings will last.
+    /// Discard the connection's oldest buffered messages to make room.
     ///
-    /// Note that UPnP port mappings will be automatically renewed on this interval.
+    /// Only the connection's own messages are discarded, so the new message is discarded instead
+    /// if the endpoint's buffer is full of other connections' messages.
+    DropOldest,
+
+    /// Discard the new message.
+    DropNewest,
+
+    /// Discard the new message, and report [`RecvError::BufferFull`](crate::RecvError::BufferFull)
+    /// in its place.
+    Error,
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wait" => Ok(Self::Wait),
+            "drop-oldest" => Ok(Self::DropOldest),
+            "drop-newest" => Ok(Self::DropNewest),
+            "error" => Ok(Self::Error),
+            _ => Err(format!(
+                "invalid overflow policy '{}', expected 'wait', 'drop-oldest', 'drop-newest' or 'error'",
+                s
+            )),
+        }
+    }
+}
+
+/// The cryptographic algorithms used for TLS, as [`Config::crypto_provider`].
+///
+/// rustls only supports *ring* as its cryptography backend, so every choice uses *ring*, and none
+/// is a FIPS-validated module.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CryptoProvider {
+    /// Every TLS 1.3 cipher suite and key exchange group *ring* implements.
+    #[default]
+    Ring,
+
+    /// Only FIPS-approved algorithms: AES-GCM cipher suites, and key exchange with the NIST P-256
+    /// and P-384 curves.
     ///
-    /// If unspecified, this will default to [`DEFAULT_UPNP_LEASE_DURATION`], which should be
-    /// suitable in most cases but some routers may clear UPnP port mapping more frequently.
-    #[serde(default)]
-    #[cfg_attr(feature = "structopt", structopt(long, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
-    pub upnp_lease_duration: Option<Duration>,
+    /// This is for deployments that must not use other algorithms (e.g. ChaCha20-Poly1305 or
+    /// X25519), but the implementation is still *ring*'s.
+    FipsApproved,
+}
 
-    /// Retry configurations for establishing connections and sending messages.
-    /// Determines the retry behaviour of requests, by setting the back off strategy used.
-    #[serde(default)]
-    #[cfg_attr(feature = "structopt", structopt(flatten))]
-    pub retry_config: RetryConfig,
+impl CryptoProvider {
+    fn cipher_suites(self) -> Vec<rustls::SupportedCipherSuite> {
+        match self {
+            Self::Ring => rustls::DEFAULT_CIPHER_SUITES.to_vec(),
+            Self::FipsApproved => vec![
+                rustls::cipher_suite::TLS13_AES_256_GCM_SHA384,
+                rustls::cipher_suite::TLS13_AES_128_GCM_SHA256,
+            ],
+        }
+    }
+
+    fn kx_groups(self) -> Vec<&'static rustls::SupportedKxGroup> {
+        match self {
+            Self::Ring => rustls::ALL_KX_GROUPS.to_vec(),
+            Self::FipsApproved => vec![&rustls::kx_group::SECP384R1, &rustls::kx_group::SECP256R1],
+        }
+    }
 }
 
-#[cfg(feature = "structopt")]
-fn parse_millis(millis: &str) -> Result<Duration, std::num::ParseIntError> {
-    Ok(Duration::from_millis(millis.parse()?))
+impl std::str::FromStr for CryptoProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ring" => Ok(Self::Ring),
+            "fips-approved" => Ok(Self::FipsApproved),
+            _ => Err(format!(
+                "invalid crypto provider '{}', expected 'ring' or 'fips-approved'",
+                s
+            )),
+        }
+    }
+}
+
+// The TLS algorithms connections may use, in order of preference.
+#[derive(Clone, Debug)]
+struct TlsAlgorithms {
+    cipher_suites: Vec<rustls::SupportedCipherSuite>,
+    kx_groups: Vec<&'static rustls::SupportedKxGroup>,
+}
+
+impl TlsAlgorithms {
+    // The crypto provider's algorithms, restricted to those named in `config` (if any).
+    fn new(config: &Config) -> Result<Self> {
+        let mut cipher_suites = config.crypto_provider.cipher_suites();
+        if !config.tls_cipher_suites.is_empty() {
+            cipher_suites = config
+                .tls_cipher_suites
+                .iter()
+                .map(|name| {
+                    cipher_suites
+                        .iter()
+                        .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                        .copied()
+                        .ok_or_else(|| ConfigError::UnsupportedCipherSuite(name.clone()))
+                })
+                .collect::<Result<_>>()?;
+        }
+
+        let mut kx_groups = config.crypto_provider.kx_groups();
+        if !config.kx_groups.is_empty() {
+            kx_groups = config
+                .kx_groups
+                .iter()
+                .map(|name| {
+                    kx_groups
+                        .iter()
+                        .find(|group| format!("{:?}", group.name).eq_ignore_ascii_case(name))
+                        .copied()
+                        .ok_or_else(|| ConfigError::UnsupportedKxGroup(name.clone()))
+                })
+                .collect::<Result<_>>()?;
+        }
+
+        Ok(Self {
+            cipher_suites,
+            kx_groups,
+        })
+    }
 }
 
 /// Retry configurations for establishing connections and sending messages.
 /// Determines the retry behaviour of requests, by setting the back off strategy used.
 #[cfg_attr(feature = "structopt", derive(StructOpt))]
-#[derive(Clone, Debug, Copy, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RetryConfig {
     /// The initial retry interval.
     ///
     /// This is the first delay before a retry, for establishing connections and sending messages.
     /// The subsequent delay will be decided by the `retry_delay_multiplier`.
+    #[serde(with = "human_duration")]
     #[cfg_attr(feature = "structopt", structopt(long, default_value = DEFAULT_INITIAL_RETRY_INTERVAL_STR, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
     pub initial_retry_interval: Duration,
+    /// A multiple of the connection's round-trip time to use as the initial retry interval when
+    /// sending messages, in place of `initial_retry_interval`.
+    ///
+    /// Starting from a few round trips (e.g. `3.0`) retries quickly on fast links, without
+    /// retrying too soon on slow ones. The interval is still limited by `max_retry_interval`.
+    /// Connection attempts have no round-trip time to go on, so they always start from
+    /// `initial_retry_interval`. The resulting intervals can be inspected with
+    /// [`Connection::retry_schedule`](crate::Connection::retry_schedule).
+    ///
+    /// If unspecified, this will default to `None`, always starting from `initial_retry_interval`.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub initial_retry_rtt_multiplier: Option<f64>,
     /// The maximum value of the back off period. Once the retry interval reaches this
     /// value it stops increasing.
     ///
@@ -182,6 +1646,7 @@ pub struct RetryConfig {
     /// Retrying continues even after the duration times have reached this duration.
     /// The number of retries before that happens, will be decided by the `retry_delay_multiplier`.
     /// The number of retries after that, will be decided by the `retrying_max_elapsed_time`.
+    #[serde(with = "human_duration")]
     #[cfg_attr(feature = "structopt", structopt(long, default_value = DEFAULT_MAX_RETRY_INTERVAL_STR, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
     pub max_retry_interval: Duration,
     /// The value to multiply the current interval with for each retry attempt.
@@ -197,8 +1662,52 @@ pub struct RetryConfig {
     ///
     /// Retrying continues until this time has elapsed.
     /// The number of retries before that happens, will be decided by the other retry config options.
+    #[serde(with = "human_duration")]
     #[cfg_attr(feature = "structopt", structopt(long, default_value = DEFAULT_RETRYING_MAX_ELAPSED_TIME_STR, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
     pub retrying_max_elapsed_time: Duration,
+    /// The maximum number of retries, after the first attempt.
+    ///
+    /// Retrying stops after this many retries, or once `retrying_max_elapsed_time` has elapsed,
+    /// whichever comes first.
+    ///
+    /// If unspecified, this will default to `None`, limiting retries by time alone.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub max_retry_attempts: Option<u32>,
+    /// How retry delays are randomised.
+    ///
+    /// If unspecified, this will default to [`RetryJitter::Proportional`], using
+    /// `retry_delay_rand_factor`.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = "proportional"))]
+    pub retry_jitter: RetryJitter,
+    /// A hook invoked before each retry, e.g. to log retries or give up early.
+    ///
+    /// See [`RetryHook`] for details.
+    #[serde(skip)]
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    pub retry_hook: Option<Arc<dyn RetryHook>>,
+    /// The number of consecutive failed attempts to connect to a peer after which the circuit
+    /// breaker for that peer opens.
+    ///
+    /// While the circuit is open, connecting to the peer fails immediately with
+    /// [`ConnectionError::CircuitOpen`](crate::ConnectionError::CircuitOpen), including any
+    /// remaining retries, rather than backing off until `retrying_max_elapsed_time`. After
+    /// `circuit_breaker_cooldown` attempts are let through again, but a single further failure
+    /// re-opens the circuit. A successful connection resets the count.
+    ///
+    /// If unspecified, this will default to `None`, disabling the circuit breaker.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub circuit_breaker_threshold: Option<u32>,
+    /// How long the circuit breaker for a peer stays open (see `circuit_breaker_threshold`).
+    #[serde(default = "default_circuit_breaker_cooldown", with = "human_duration")]
+    #[cfg_attr(feature = "structopt", structopt(long, default_value = DEFAULT_CIRCUIT_BREAKER_COOLDOWN_STR, parse(try_from_str = parse_millis), value_name = "MILLIS"))]
+    pub circuit_breaker_cooldown: Duration,
+}
+
+fn default_circuit_breaker_cooldown() -> Duration {
+    DEFAULT_CIRCUIT_BREAKER_COOLDOWN
 }
 
 impl RetryConfig {
@@ -207,20 +1716,50 @@ impl RetryConfig {
     // Note that `backoff::Error<E>` implements `From<E>` for any `E` by creating a
     // `backoff::Error::Transient`, meaning that errors will be retried unless explicitly returning
     // `backoff::Error::Permanent`.
+    //
+    // Delays and elapsed time follow tokio's clock, so they stay consistent when it's paused or
+    // advanced manually (e.g. in tests).
     pub(crate) fn retry<R, E, Fn, Fut>(&self, op: Fn) -> impl Future<Output = Result<R, E>>
     where
         Fn: FnMut() -> Fut,
         Fut: Future<Output = Result<R, backoff::Error<E>>>,
+        E: fmt::Debug,
     {
-        let backoff = backoff::ExponentialBackoff {
-            initial_interval: self.initial_retry_interval,
-            randomization_factor: self.retry_delay_rand_factor,
-            multiplier: self.retry_delay_multiplier,
-            max_interval: self.max_retry_interval,
-            max_elapsed_time: Some(self.retrying_max_elapsed_time),
-            ..Default::default()
-        };
-        backoff::future::retry(backoff, op)
+        retry::retry(self.clone(), None, op)
+    }
+
+    // As `retry`, but for an operation on a connection with the given round-trip time.
+    pub(crate) fn retry_with_rtt<R, E, Fn, Fut>(
+        &self,
+        rtt: Duration,
+        op: Fn,
+    ) -> impl Future<Output = Result<R, E>>
+    where
+        Fn: FnMut() -> Fut,
+        Fut: Future<Output = Result<R, backoff::Error<E>>>,
+        E: fmt::Debug,
+    {
+        retry::retry(self.clone(), Some(rtt), op)
+    }
+
+    /// The intervals between retries, before jitter, for an operation on a connection with the
+    /// given round-trip time (or `None` for connection attempts).
+    ///
+    /// The intervals stop once they add up to more than `retrying_max_elapsed_time`, or after
+    /// `max_retry_attempts`. Fewer retries may be made, since the time the attempts themselves
+    /// take counts towards `retrying_max_elapsed_time` too.
+    pub fn schedule(&self, rtt: Option<Duration>) -> Vec<Duration> {
+        retry::schedule(self, rtt)
+    }
+
+    // The first interval between retries (see `initial_retry_rtt_multiplier`).
+    pub(crate) fn initial_interval(&self, rtt: Option<Duration>) -> Duration {
+        match (self.initial_retry_rtt_multiplier, rtt) {
+            (Some(multiplier), Some(rtt)) => rtt
+                .mul_f64(multiplier.max(0.0))
+                .min(self.max_retry_interval),
+            _ => self.initial_retry_interval,
+        }
     }
 }
 
@@ -228,10 +1767,108 @@ impl Default for RetryConfig {
     fn default() -> Self {
         Self {
             initial_retry_interval: DEFAULT_INITIAL_RETRY_INTERVAL,
+            initial_retry_rtt_multiplier: None,
             max_retry_interval: DEFAULT_MAX_RETRY_INTERVAL,
             retry_delay_multiplier: DEFAULT_RETRY_INTERVAL_MULTIPLIER,
             retry_delay_rand_factor: DEFAULT_RETRY_DELAY_RAND_FACTOR,
             retrying_max_elapsed_time: DEFAULT_RETRYING_MAX_ELAPSED_TIME,
+            circuit_breaker_threshold: None,
+            circuit_breaker_cooldown: DEFAULT_CIRCUIT_BREAKER_COOLDOWN,
+            max_retry_attempts: None,
+            retry_jitter: RetryJitter::default(),
+            retry_hook: None,
+        }
+    }
+}
+
+/// Options for an endpoint's UDP sockets.
+///
+/// Unspecified options are left at the operating system's defaults.
+#[cfg_attr(feature = "structopt", derive(StructOpt))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SocketConfig {
+    /// The size of the socket's send buffer, in bytes (`SO_SNDBUF`).
+    ///
+    /// The operating system may adjust the size (e.g. Linux doubles it, and limits it to
+    /// `net.core.wmem_max`).
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub send_buffer_size: Option<usize>,
+
+    /// The size of the socket's receive buffer, in bytes (`SO_RCVBUF`).
+    ///
+    /// Larger buffers reduce packet loss under bursts of traffic at high throughput. The operating
+    /// system may adjust the size (e.g. Linux doubles it, and limits it to
+    /// `net.core.rmem_max`).
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub recv_buffer_size: Option<usize>,
+
+    /// The Differentiated Services Code Point to mark outgoing packets with, from 0 to 63.
+    ///
+    /// This sets the upper 6 bits of the IPv4 TOS field, for networks that prioritise traffic by
+    /// DSCP. It is only supported on IPv4 sockets (it's ignored, with a warning, for IPv6 sockets),
+    /// and some platforms may clear it when QUIC sets ECN bits on outgoing packets.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub dscp: Option<u8>,
+
+    /// Allow several sockets to bind the same address (`SO_REUSEPORT`), so that processes can
+    /// share a port.
+    ///
+    /// The operating system distributes incoming packets between the sockets (on Linux, by hashing
+    /// the peer's address, so each connection stays with one socket). Only supported on Unix
+    /// platforms.
+    #[serde(default)]
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    pub reuse_port: bool,
+}
+
+/// A range of ports, from `start` to `end` inclusive, as [`Config::outgoing_ports`].
+///
+/// Parsed from `start-end`, or a single port.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortRange {
+    /// The first port in the range.
+    pub start: u16,
+    /// The last port in the range.
+    pub end: u16,
+}
+
+impl PortRange {
+    /// The ports in the range, in order.
+    pub fn ports(&self) -> std::ops::RangeInclusive<u16> {
+        self.start..=self.end
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+impl std::str::FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |port: &str| {
+            port.trim()
+                .parse()
+                .map_err(|_| format!("invalid port range '{}', expected e.g. '40000-40099'", s))
+        };
+        match s.split_once('-') {
+            Some((start, end)) => Ok(Self {
+                start: parse(start)?,
+                end: parse(end)?,
+            }),
+            None => {
+                let port = parse(s)?;
+                Ok(Self {
+                    start: port,
+                    end: port,
+                })
+            }
         }
     }
 }
@@ -243,13 +1880,61 @@ impl Default for RetryConfig {
 pub(crate) struct InternalConfig {
     pub(crate) client: quinn::ClientConfig,
     pub(crate) server: quinn::ServerConfig,
+    pub(crate) server_tls: ServerTls,
     #[cfg(feature = "igd")]
     pub(crate) forward_port: bool,
+    #[cfg(feature = "igd")]
+    pub(crate) port_mapping_gateway: Option<std::net::Ipv4Addr>,
+    pub(crate) additional_local_addrs: Vec<SocketAddr>,
     pub(crate) external_port: Option<u16>,
     pub(crate) external_ip: Option<IpAddr>,
     #[allow(dead_code)]
     pub(crate) upnp_lease_duration: Duration,
     pub(crate) retry_config: Arc<RetryConfig>,
+    pub(crate) socket_config: SocketConfig,
+    pub(crate) workers: usize,
+    pub(crate) outgoing_addr: Option<SocketAddr>,
+    pub(crate) outgoing_ports: Option<PortRange>,
+    pub(crate) peer_scoring: Option<Arc<dyn PeerScoring>>,
+    pub(crate) hello_provider: Option<Arc<dyn HelloProvider>>,
+    pub(crate) peer_identifier: Option<Arc<dyn PeerIdentifier>>,
+    pub(crate) connection_observer: Option<Arc<dyn ConnectionObserver>>,
+    pub(crate) message_sink: Option<Arc<dyn MessageSink>>,
+    pub(crate) interceptors: Arc<[Arc<dyn Interceptor>]>,
+    pub(crate) signing_key: Option<Arc<SigningKey>>,
+    pub(crate) e2e_encryption: bool,
+    pub(crate) dedup_window: Option<Duration>,
+    pub(crate) peer_exchange: bool,
+    pub(crate) peer_exchange_interval: Duration,
+    pub(crate) resolver: Arc<dyn Resolver>,
+    pub(crate) peer_store: Option<Arc<dyn PeerStore>>,
+    pub(crate) message_ordering: MessageOrdering,
+    pub(crate) max_buffered_bytes_per_connection: Option<usize>,
+    pub(crate) max_buffered_bytes: Option<usize>,
+    pub(crate) buffer_overflow: OverflowPolicy,
+    pub(crate) max_concurrent_sends: Option<usize>,
+    pub(crate) message_split_threshold: Option<usize>,
+    pub(crate) message_split_streams: Option<usize>,
+    pub(crate) buffer_pool_size: usize,
+    pub(crate) max_pooled_buffer_size: usize,
+    pub(crate) stream_open_timeout: Option<Duration>,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) handshake_timeout: Option<Duration>,
+    pub(crate) slow_stream_threshold: Option<Duration>,
+    pub(crate) endpoint_verification_timeout: Option<Duration>,
+    pub(crate) heartbeat_interval: Option<Duration>,
+    pub(crate) raw_streams: bool,
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) accept_rate: Option<u32>,
+    pub(crate) accept_rate_per_ip: Option<u32>,
+    pub(crate) transport: LiveTransport,
+    pub(crate) endpoint: quinn::EndpointConfig,
+    // in order of preference, so the first is used for outgoing connections
+    pub(crate) quic_versions: Arc<[u32]>,
+    // interval for keep-alives on critical connections, if they're not already enabled for all
+    pub(crate) critical_keep_alive_interval: Option<Duration>,
+    #[cfg(feature = "dht")]
+    pub(crate) dht_node_id: NodeId,
 }
 
 impl InternalConfig {
@@ -266,16 +1951,83 @@ impl InternalConfig {
             .upnp_lease_duration
             .unwrap_or(DEFAULT_UPNP_LEASE_DURATION);
         let keep_alive_interval = config.keep_alive_interval;
+        check_keep_alive_interval(
+            keep_alive_interval,
+            config.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT),
+        )?;
+
+        if let Some(dscp) = config.socket_config.dscp {
+            if dscp >= 64 {
+                return Err(ConfigError::InvalidDscp(dscp));
+            }
+        }
+
+        if let Some(ports) = config.outgoing_ports {
+            if ports.start > ports.end {
+                return Err(ConfigError::EmptyPortRange(ports));
+            }
+            if let Some(addr) = config.outgoing_addr.filter(|addr| addr.port() != 0) {
+                return Err(ConfigError::OutgoingPortConflict { addr, ports });
+            }
+        }
+
+        let algorithms = TlsAlgorithms::new(&config)?;
+
+        let quic_versions: Arc<[u32]> = if config.quic_versions.is_empty() {
+            quinn_proto::DEFAULT_SUPPORTED_VERSIONS.into()
+        } else {
+            config.quic_versions.into()
+        };
+        if let Some(version) = quic_versions
+            .iter()
+            .find(|version| !quinn_proto::DEFAULT_SUPPORTED_VERSIONS.contains(version))
+        {
+            return Err(ConfigError::UnsupportedQuicVersion(*version));
+        }
+        let mut endpoint = quinn::EndpointConfig::default();
+        let _ = endpoint.supported_versions(quic_versions.to_vec());
 
-        let transport = Self::new_transport_config(idle_timeout, keep_alive_interval);
+        let critical_keep_alive_interval = match keep_alive_interval {
+            Some(_) => None,
+            None => Some(config.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT) / 3),
+        };
+
+        let windows = Windows {
+            stream_receive: config
+                .stream_receive_window
+                .map_or(STREAM_RECEIVE_WINDOW.into(), quinn::VarInt::from),
+            receive: config
+                .receive_window
+                .map_or(RECEIVE_WINDOW, quinn::VarInt::from),
+            send: config.send_window.unwrap_or(SEND_WINDOW),
+        };
+        let transport = Self::new_transport_config(idle_timeout, keep_alive_interval, &windows);
+        let transport_params = TransportParams {
+            idle_timeout: config.idle_timeout.unwrap_or(DEFAULT_IDLE_TIMEOUT),
+            keep_alive_interval,
+            max_concurrent_bidi_streams: MAX_CONCURRENT_STREAMS.into(),
+            max_concurrent_uni_streams: MAX_CONCURRENT_STREAMS.into(),
+            stream_receive_window: windows.stream_receive.into_inner(),
+            receive_window: windows.receive.into_inner(),
+            congestion_controller: CONGESTION_CONTROLLER,
+            migration: ALLOW_MIGRATION,
+        };
 
         // setup certificates
         let mut roots = rustls::RootCertStore::empty();
         let (cert, key) = Self::generate_cert()?;
         roots.add(&cert).map_err(|_e| ConfigError::Webpki)?;
 
+        let alpn_protocols: Vec<_> = config
+            .alpn_protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+
         let mut client_crypto = ClientConfig::builder()
-            .with_safe_defaults()
+            .with_cipher_suites(&algorithms.cipher_suites)
+            .with_kx_groups(&algorithms.kx_groups)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
             .with_root_certificates(roots)
             .with_no_client_auth();
 
@@ -283,33 +2035,119 @@ impl InternalConfig {
         client_crypto
             .dangerous()
             .set_certificate_verifier(Arc::new(SkipCertificateVerification));
+        client_crypto.alpn_protocols = alpn_protocols.clone();
+
+        let session_cache_size = config
+            .session_cache_size
+            .unwrap_or(DEFAULT_SESSION_CACHE_SIZE);
+        if session_cache_size == 0 && config.session_store.is_none() {
+            client_crypto.enable_tickets = false;
+        }
+        let sessions = SessionCaches::new(session_cache_size, config.session_store);
+        let client_crypto = Arc::new(client_crypto);
 
-        let mut server = quinn::ServerConfig::with_single_cert(vec![cert], key)?;
-        server.transport = transport.clone();
+        let server_tls = ServerTls {
+            transport: Arc::new(Mutex::new(transport.clone())),
+            alpn_protocols,
+            algorithms,
+            stateless_retry: Arc::new(AtomicBool::new(config.stateless_retry)),
+            retry_token_lifetime: config.retry_token_lifetime,
+            sessions: sessions.clone(),
+            current: Arc::default(),
+        };
+        let server = server_tls.server_config(vec![cert], key)?;
 
-        let mut client = quinn::ClientConfig::new(Arc::new(client_crypto));
+        let mut client = quinn::ClientConfig::new(client_crypto.clone());
         client.transport = transport;
+        let _ = client.version(quic_versions[0]);
+        let transport = LiveTransport {
+            windows,
+            client_crypto,
+            sessions,
+            state: Arc::new(Mutex::new(TransportState {
+                client: client.clone(),
+                params: transport_params,
+            })),
+        };
 
         Ok(Self {
             client,
             server,
+            server_tls,
             #[cfg(feature = "igd")]
             forward_port: config.forward_port,
+            #[cfg(feature = "igd")]
+            port_mapping_gateway: config.port_mapping_gateway,
+            additional_local_addrs: config.additional_local_addrs,
             external_port: config.external_port,
             external_ip: config.external_ip,
             upnp_lease_duration,
             retry_config: Arc::new(config.retry_config),
+            socket_config: config.socket_config,
+            workers: config.workers.unwrap_or(1).max(1),
+            outgoing_addr: config.outgoing_addr,
+            outgoing_ports: config.outgoing_ports,
+            peer_scoring: config.peer_scoring,
+            hello_provider: config.hello_provider,
+            peer_identifier: config.peer_identifier,
+            connection_observer: config.connection_observer,
+            message_sink: config.message_sink,
+            interceptors: config.interceptors.into(),
+            signing_key: config.signing_key,
+            e2e_encryption: config.e2e_encryption,
+            dedup_window: config.dedup_window,
+            peer_exchange: config.peer_exchange,
+            peer_exchange_interval: config
+                .peer_exchange_interval
+                .unwrap_or(DEFAULT_PEER_EXCHANGE_INTERVAL),
+            resolver: config.resolver.unwrap_or_else(|| Arc::new(SystemResolver)),
+            peer_store: config.peer_store,
+            message_ordering: config.message_ordering,
+            max_buffered_bytes_per_connection: config.max_buffered_bytes_per_connection,
+            max_buffered_bytes: config.max_buffered_bytes,
+            buffer_overflow: config.buffer_overflow,
+            max_concurrent_sends: config.max_concurrent_sends,
+            message_split_threshold: config.message_split_threshold,
+            message_split_streams: config.message_split_streams,
+            buffer_pool_size: config.buffer_pool_size.unwrap_or(DEFAULT_BUFFER_POOL_SIZE),
+            max_pooled_buffer_size: config
+                .max_pooled_buffer_size
+                .unwrap_or(DEFAULT_MAX_POOLED_BUFFER_SIZE),
+            stream_open_timeout: config.stream_open_timeout,
+            connect_timeout: config.connect_timeout,
+            handshake_timeout: config.handshake_timeout,
+            slow_stream_threshold: config.slow_stream_threshold,
+            endpoint_verification_timeout: config.endpoint_verification_timeout,
+            heartbeat_interval: config.heartbeat_interval,
+            raw_streams: config.raw_streams,
+            max_connections: config.max_connections,
+            accept_rate: config.accept_rate,
+            accept_rate_per_ip: config.accept_rate_per_ip,
+            transport,
+            endpoint,
+            quic_versions,
+            critical_keep_alive_interval,
+            #[cfg(feature = "dht")]
+            dht_node_id: config.dht_node_id.unwrap_or_else(NodeId::random),
         })
     }
 
     fn new_transport_config(
         idle_timeout: IdleTimeout,
         keep_alive_interval: Option<Duration>,
+        windows: &Windows,
     ) -> Arc<quinn::TransportConfig> {
         let mut config = quinn::TransportConfig::default();
 
         let _ = config.max_idle_timeout(Some(idle_timeout));
         let _ = config.keep_alive_interval(keep_alive_interval);
+        let _ = config
+            .max_concurrent_bidi_streams(MAX_CONCURRENT_STREAMS.into())
+            .max_concurrent_uni_streams(MAX_CONCURRENT_STREAMS.into())
+            .stream_receive_window(windows.stream_receive)
+            .receive_window(windows.receive)
+            .send_window(windows.send)
+            .congestion_controller_factory(Arc::new(quinn::congestion::CubicConfig::default()));
 
         Arc::new(config)
     }
@@ -326,6 +2164,194 @@ impl InternalConfig {
     }
 }
 
+fn check_keep_alive_interval(
+    keep_alive_interval: Option<Duration>,
+    idle_timeout: Duration,
+) -> Result<()> {
+    match keep_alive_interval {
+        Some(keep_alive_interval)
+            if keep_alive_interval.is_zero() || keep_alive_interval >= idle_timeout =>
+        {
+            Err(ConfigError::InvalidKeepAliveInterval {
+                keep_alive_interval,
+                idle_timeout,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+// Flow control windows, with defaults filled in.
+#[derive(Clone, Copy, Debug)]
+struct Windows {
+    stream_receive: quinn::VarInt,
+    receive: quinn::VarInt,
+    send: u64,
+}
+
+// The transport parameters this endpoint uses for its connections.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct TransportParams {
+    pub(crate) idle_timeout: Duration,
+    pub(crate) keep_alive_interval: Option<Duration>,
+    pub(crate) max_concurrent_bidi_streams: u64,
+    pub(crate) max_concurrent_uni_streams: u64,
+    pub(crate) stream_receive_window: u64,
+    pub(crate) receive_window: u64,
+    pub(crate) congestion_controller: &'static str,
+    pub(crate) migration: bool,
+}
+
+// The transport settings for new connections, which can be changed while the endpoint is running.
+// Clones share the settings.
+#[derive(Clone, Debug)]
+pub(crate) struct LiveTransport {
+    windows: Windows,
+    client_crypto: Arc<ClientConfig>,
+    sessions: SessionCaches,
+    state: Arc<Mutex<TransportState>>,
+}
+
+#[derive(Debug)]
+struct TransportState {
+    client: quinn::ClientConfig,
+    params: TransportParams,
+}
+
+impl LiveTransport {
+    // The client config to make new connections with.
+    pub(crate) fn client_config(&self) -> quinn::ClientConfig {
+        self.lock_state().client.clone()
+    }
+
+    // The client config to connect to `peer` with, resuming a session cached for it.
+    pub(crate) fn client_config_for(&self, peer: SocketAddr) -> quinn::ClientConfig {
+        let mut client = self.client_config();
+        client.crypto = Arc::new(self.sessions.client_crypto_for(&self.client_crypto, peer));
+        client
+    }
+
+    pub(crate) fn resumption_stats(&self) -> ResumptionStats {
+        self.sessions.stats()
+    }
+
+    // The parameters of new connections.
+    pub(crate) fn params(&self) -> TransportParams {
+        self.lock_state().params
+    }
+
+    // Rebuild the transport config with a different keep-alive interval, returning it for use in
+    // the server config too.
+    pub(crate) fn set_keep_alive_interval(
+        &self,
+        keep_alive_interval: Option<Duration>,
+    ) -> Result<Arc<quinn::TransportConfig>> {
+        let mut state = self.lock_state();
+        let idle_timeout = state.params.idle_timeout;
+        check_keep_alive_interval(keep_alive_interval, idle_timeout)?;
+
+        let transport = InternalConfig::new_transport_config(
+            IdleTimeout::try_from(idle_timeout)?,
+            keep_alive_interval,
+            &self.windows,
+        );
+        state.client.transport = transport.clone();
+        state.params.keep_alive_interval = keep_alive_interval;
+        Ok(transport)
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, TransportState> {
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+/// Settings needed to (re)build the server config with a given certificate.
+#[derive(Clone, Debug)]
+pub(crate) struct ServerTls {
+    // shared by clones, so a change of keep-alive interval survives reloads
+    transport: Arc<Mutex<Arc<quinn::TransportConfig>>>,
+    alpn_protocols: Vec<Vec<u8>>,
+    algorithms: TlsAlgorithms,
+    // shared by clones, so the setting survives reloads
+    stateless_retry: Arc<AtomicBool>,
+    retry_token_lifetime: Option<Duration>,
+    // shared by clones, so cached sessions survive reloads
+    sessions: SessionCaches,
+    // the config last built, to rebuild with a different retry setting
+    current: Arc<Mutex<Option<quinn::ServerConfig>>>,
+}
+
+impl ServerTls {
+    pub(crate) fn server_config(
+        &self,
+        cert_chain: Vec<Certificate>,
+        key: rustls::PrivateKey,
+    ) -> Result<quinn::ServerConfig> {
+        // equivalent to `quinn::ServerConfig::with_single_cert`, but with our ALPN protocols, and
+        // without accepting early (0-RTT) data: messages sent in early data can be replayed by an
+        // attacker, and nothing tells applications which messages are safe to handle twice
+        let mut server_crypto = rustls::ServerConfig::builder()
+            .with_cipher_suites(&self.algorithms.cipher_suites)
+            .with_kx_groups(&self.algorithms.kx_groups)
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_single_cert(cert_chain, key)?;
+        server_crypto.max_early_data_size = 0;
+        server_crypto.alpn_protocols = self.alpn_protocols.clone();
+        self.sessions.configure_server(&mut server_crypto);
+
+        let mut server = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
+        server.transport = self
+            .transport
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .clone();
+        let _ = server.migration(ALLOW_MIGRATION);
+        let _ = server.use_retry(self.stateless_retry.load(Ordering::Relaxed));
+        if let Some(lifetime) = self.retry_token_lifetime {
+            let _ = server.retry_token_lifetime(lifetime);
+        }
+
+        *self.lock_current() = Some(server.clone());
+        Ok(server)
+    }
+
+    // Change whether stateless retries are required, returning the updated server config (if one
+    // has been built).
+    pub(crate) fn set_stateless_retry(&self, enabled: bool) -> Option<quinn::ServerConfig> {
+        self.stateless_retry.store(enabled, Ordering::Relaxed);
+        let mut current = self.lock_current();
+        let server = current.as_mut()?;
+        let _ = server.use_retry(enabled);
+        Some(server.clone())
+    }
+
+    // Change the transport config, returning the updated server config (if one has been built).
+    pub(crate) fn set_transport(
+        &self,
+        transport: Arc<quinn::TransportConfig>,
+    ) -> Option<quinn::ServerConfig> {
+        *self
+            .transport
+            .lock()
+            .unwrap_or_else(|error| error.into_inner()) = transport.clone();
+        let mut current = self.lock_current();
+        let server = current.as_mut()?;
+        server.transport = transport;
+        Some(server.clone())
+    }
+
+    pub(crate) fn stateless_retry(&self) -> bool {
+        self.stateless_retry.load(Ordering::Relaxed)
+    }
+
+    fn lock_current(&self) -> MutexGuard<'_, Option<quinn::ServerConfig>> {
+        self.current
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
 struct SkipCertificateVerification;
 
 impl rustls::client::ServerCertVerifier for SkipCertificateVerification {
@@ -341,3 +2367,319 @@ impl rustls::client::ServerCertVerifier for SkipCertificateVerification {
         Ok(rustls::client::ServerCertVerified::assertion())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Config, ConfigError, CryptoProvider, InternalConfig, MessageOrdering, PortRange,
+        RetryConfig, SkipCertificateVerification, DEFAULT_IDLE_TIMEOUT, SERVER_NAME,
+    };
+    use crate::{resolver::SystemResolver, tests::local_addr};
+    use color_eyre::eyre::{eyre, Result};
+    use futures::StreamExt;
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn keep_alive_interval_must_be_shorter_than_idle_timeout() {
+        let config = |keep_alive_interval, idle_timeout| Config {
+            keep_alive_interval,
+            idle_timeout,
+            ..Config::default()
+        };
+
+        assert!(InternalConfig::try_from_config(config(None, None)).is_ok());
+        assert!(
+            InternalConfig::try_from_config(config(Some(Duration::from_secs(5)), None)).is_ok()
+        );
+
+        for (keep_alive_interval, idle_timeout) in [
+            (DEFAULT_IDLE_TIMEOUT, None),
+            (Duration::from_secs(10), Some(Duration::from_secs(10))),
+            (Duration::ZERO, None),
+        ] {
+            assert!(matches!(
+                InternalConfig::try_from_config(config(Some(keep_alive_interval), idle_timeout)),
+                Err(ConfigError::InvalidKeepAliveInterval { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn outgoing_ports() {
+        assert_eq!(
+            "40000-40099".parse(),
+            Ok(PortRange {
+                start: 40000,
+                end: 40099
+            })
+        );
+        assert_eq!(
+            "40000".parse(),
+            Ok(PortRange {
+                start: 40000,
+                end: 40000
+            })
+        );
+        assert!("40000-".parse::<PortRange>().is_err());
+
+        let config = |outgoing_addr: &str, outgoing_ports: &str| Config {
+            outgoing_addr: Some(outgoing_addr.parse().expect("invalid address")),
+            outgoing_ports: Some(outgoing_ports.parse().expect("invalid port range")),
+            ..Config::default()
+        };
+        assert!(InternalConfig::try_from_config(config("127.0.0.1:0", "40000-40099")).is_ok());
+        assert!(matches!(
+            InternalConfig::try_from_config(config("127.0.0.1:0", "40099-40000")),
+            Err(ConfigError::EmptyPortRange(_))
+        ));
+        assert!(matches!(
+            InternalConfig::try_from_config(config("127.0.0.1:40000", "40000-40099")),
+            Err(ConfigError::OutgoingPortConflict { .. })
+        ));
+    }
+
+    #[test]
+    fn presets_are_valid() {
+        for (name, config) in [
+            ("low_latency", Config::low_latency()),
+            ("bulk_transfer", Config::bulk_transfer()),
+            ("mobile", Config::mobile()),
+        ] {
+            assert!(
+                InternalConfig::try_from_config(config).is_ok(),
+                "invalid {} preset",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn diff_from_default() {
+        assert!(Config::default().diff_from_default().is_empty());
+
+        let config = Config {
+            idle_timeout: Some(Duration::from_secs(5)),
+            resolver: Some(Arc::new(SystemResolver)),
+            ..Config::low_latency()
+        };
+        let diffs = config.diff_from_default();
+        let fields: Vec<_> = diffs.iter().map(|diff| diff.field).collect();
+        assert!(fields.contains(&"idle_timeout"));
+        assert!(fields.contains(&"keep_alive_interval"));
+        assert!(fields.contains(&"retry_config.initial_retry_interval"));
+        assert!(fields.contains(&"resolver"));
+        assert!(!fields.contains(&"socket_config.reuse_port"));
+
+        let idle_timeout = &diffs[fields
+            .iter()
+            .position(|field| *field == "idle_timeout")
+            .unwrap()];
+        assert_eq!(
+            idle_timeout.to_string(),
+            "idle_timeout: Some(5s) (instead of None)"
+        );
+
+        // comparing against another config only reports what differs from it
+        assert_eq!(
+            config.diff(&Config::low_latency()).len(),
+            2,
+            "{:?}",
+            config.diff(&Config::low_latency())
+        );
+    }
+
+    #[test]
+    fn human_friendly_durations() -> Result<(), serde_json::Error> {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "idle_timeout": "1m 30s",
+                "keep_alive_interval": { "secs": 10, "nanos": 0 },
+                "retry_config": { "max_retry_interval": "500ms" }
+            }"#,
+        )?;
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(90)));
+        assert_eq!(config.keep_alive_interval, Some(Duration::from_secs(10)));
+        assert_eq!(
+            config.retry_config.max_retry_interval,
+            Duration::from_millis(500)
+        );
+        // everything else takes the defaults
+        assert_eq!(
+            config.retry_config.initial_retry_interval,
+            RetryConfig::default().initial_retry_interval
+        );
+        assert_eq!(config.connect_timeout, None);
+
+        let json = serde_json::to_value(&config)?;
+        assert_eq!(json["idle_timeout"], "1m 30s");
+        assert_eq!(json["retry_config"]["max_retry_interval"], "500ms");
+        let reloaded: Config = serde_json::from_value(json)?;
+        assert!(reloaded.diff(&config).is_empty());
+
+        assert!(serde_json::from_str::<Config>(r#"{ "idle_timeout": "soon" }"#).is_err());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "config-json")]
+    #[test]
+    fn from_file() {
+        let path = std::env::temp_dir().join(format!("qp2p-config-{}.json", rand::random::<u64>()));
+        std::fs::write(&path, r#"{ "idle_timeout": "30s" }"#).expect("failed to write config");
+        let config = Config::from_file(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            config.expect("failed to load config").idle_timeout,
+            Some(Duration::from_secs(30))
+        );
+
+        assert!(matches!(
+            Config::from_file(path.with_extension("yaml")),
+            Err(ConfigError::UnsupportedFormat(_))
+        ));
+    }
+
+    #[test]
+    fn overlay_env() {
+        // a prefix of its own, since the environment is shared by all tests
+        let var = |name: &str| format!("QP2P_TEST_OVERLAY_{}", name);
+        std::env::set_var(var("IDLE_TIMEOUT"), "45s");
+        std::env::set_var(var("MAX_CONNECTIONS"), "12");
+        std::env::set_var(var("KEEP_ALIVE_INTERVAL"), "");
+        std::env::set_var(var("ALPN_PROTOCOLS"), "a, b");
+        std::env::set_var(var("MESSAGE_ORDERING"), "ordered");
+        std::env::set_var(var("RETRY_CONFIG_MAX_RETRY_ATTEMPTS"), "3");
+        std::env::set_var(var("SOCKET_CONFIG_REUSE_PORT"), "true");
+
+        let config = Config::low_latency()
+            .overlay_env("QP2P_TEST_OVERLAY")
+            .expect("failed to overlay environment");
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(45)));
+        assert_eq!(config.max_connections, Some(12));
+        assert_eq!(config.keep_alive_interval, None);
+        assert_eq!(config.alpn_protocols, ["a", "b"]);
+        assert_eq!(config.message_ordering, MessageOrdering::Ordered);
+        assert_eq!(config.retry_config.max_retry_attempts, Some(3));
+        assert!(config.socket_config.reuse_port);
+        // settings without variables are left alone
+        assert_eq!(
+            config.handshake_timeout,
+            Config::low_latency().handshake_timeout
+        );
+
+        std::env::set_var(var("ACCEPT_RATE"), "lots");
+        match Config::from_env("QP2P_TEST_OVERLAY") {
+            Err(ConfigError::InvalidEnvVar { name, value, .. }) => {
+                assert_eq!(name, "QP2P_TEST_OVERLAY_ACCEPT_RATE");
+                assert_eq!(value, "lots");
+            }
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn early_data_is_rejected() -> Result<()> {
+        let config = InternalConfig::try_from_config(Config::default())?;
+        let (server, mut incoming) = quinn::Endpoint::server(config.server.clone(), local_addr())?;
+        let _ = tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Some(connecting) = incoming.next().await {
+                connections.extend(connecting.await);
+            }
+        });
+
+        // a client that would send early data, if the server allowed it
+        let mut client_crypto = (*config.transport.client_crypto).clone();
+        client_crypto.enable_early_data = true;
+        let client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
+        let client = quinn::Endpoint::client(local_addr())?;
+
+        let connection = client
+            .connect_with(client_config.clone(), server.local_addr()?, SERVER_NAME)?
+            .await?;
+        // give the session ticket time to arrive
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        connection.connection.close(0u8.into(), b"");
+
+        let connecting = client.connect_with(client_config, server.local_addr()?, SERVER_NAME)?;
+        let connecting = match connecting.into_0rtt() {
+            Ok(_) => return Err(eyre!("early data was allowed")),
+            Err(connecting) => connecting,
+        };
+        let _connection = connecting.await?;
+        assert_eq!(config.transport.resumption_stats().resumed, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fips_approved_algorithms() -> Result<()> {
+        let config = InternalConfig::try_from_config(Config {
+            crypto_provider: CryptoProvider::FipsApproved,
+            ..Config::default()
+        })?;
+        let (server, mut incoming) = quinn::Endpoint::server(config.server.clone(), local_addr())?;
+        let _ = tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Some(connecting) = incoming.next().await {
+                connections.extend(connecting.await);
+            }
+        });
+        let client = quinn::Endpoint::client(local_addr())?;
+
+        let _connection = client
+            .connect_with(config.client.clone(), server.local_addr()?, SERVER_NAME)?
+            .await?;
+
+        // a client that only offers X25519 key exchange has nothing in common with the server
+        let mut x25519_crypto = rustls::ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_kx_groups(&[&rustls::kx_group::X25519])
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        x25519_crypto
+            .dangerous()
+            .set_certificate_verifier(Arc::new(SkipCertificateVerification));
+        let x25519_config = quinn::ClientConfig::new(Arc::new(x25519_crypto));
+        assert!(client
+            .connect_with(x25519_config, server.local_addr()?, SERVER_NAME)?
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn tls_algorithm_restrictions() {
+        let config = |tls_cipher_suites: &[&str], kx_groups: &[&str]| Config {
+            crypto_provider: CryptoProvider::FipsApproved,
+            tls_cipher_suites: tls_cipher_suites
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            kx_groups: kx_groups.iter().map(|name| name.to_string()).collect(),
+            ..Config::default()
+        };
+
+        assert!(InternalConfig::try_from_config(config(
+            &["TLS13_AES_256_GCM_SHA384"],
+            &["secp384r1"]
+        ))
+        .is_ok());
+
+        // names must be offered by the crypto provider
+        assert!(matches!(
+            InternalConfig::try_from_config(config(&["TLS13_CHACHA20_POLY1305_SHA256"], &[])),
+            Err(ConfigError::UnsupportedCipherSuite(name)) if name == "TLS13_CHACHA20_POLY1305_SHA256"
+        ));
+        assert!(matches!(
+            InternalConfig::try_from_config(config(&["TLS_RSA_WITH_NULL_SHA"], &[])),
+            Err(ConfigError::UnsupportedCipherSuite(_))
+        ));
+        assert!(matches!(
+            InternalConfig::try_from_config(config(&[], &["X25519"])),
+            Err(ConfigError::UnsupportedKxGroup(name)) if name == "X25519"
+        ));
+    }
+}
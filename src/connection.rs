@@ -1,36 +1,204 @@
 //! A message-oriented API wrapping the underlying QUIC library (`quinn`).
 
+#[cfg(feature = "dht")]
+use crate::dht::{Contact, Dht, NodeId};
 use crate::{
-    config::{RetryConfig, SERVER_NAME},
-    error::{ConnectionError, RecvError, RpcError, SendError, SerializationError, StreamError},
+    address_book::{AddressBook, PeerId},
+    buffer_pool::BufferPool,
+    config::{
+        LiveTransport, MessageOrdering, RetryConfig, TransportParams,
+        DEFAULT_ENDPOINT_VERIFICATION_TIMEOUT, DEFAULT_MESSAGE_SPLIT_STREAMS,
+    },
+    control::{self, Control, Frame, PeerState},
+    dedup::Dedup,
+    endpoint::dial,
+    error::{
+        Close, ConnectionError, ErrorContext, PeerError, RecvError, RpcError, SendError,
+        SerializationError, StreamError,
+    },
+    extensions::Extensions,
+    hello::{Exchange, HelloProvider},
+    identity::PeerIdentifier,
+    inbox::{self, BufferUsage, InboxLimits, InboxReceiver, InboxSender},
+    interceptor::{Intercepted, Interception, Interceptor, MessageContext},
+    observed::{self, AddressObservations},
+    observer::ConnectionObserver,
+    pause::IncomingPause,
+    peer_messages::PeerRouter,
+    pex::Pex,
+    rate_limit::AcceptLimiter,
+    raw::{IncomingRawStreams, RawRecvStream, RawSendStream, RawStream},
+    registry::{
+        ConnectionClass, ConnectionInfo, ConnectionPath, ConnectionRegistry, Metadata, Registration,
+    },
+    retry::SharedRetryConfig,
+    scheduler::{PriorityClass, Scheduler},
+    scoring::{self, PeerEvent, PeerScoring},
+    signing::SigningKey,
+    sink::MessageSink,
+    split::{self, Reassembly},
+    stats::MessageStats,
+    transfer::IncomingTransfers,
     wire_msg::WireMsg,
 };
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use futures::{
     future,
     stream::{self, Stream, StreamExt, TryStream, TryStreamExt},
 };
-use std::{fmt, net::SocketAddr, pin::Pin, sync::Arc, task, time::Duration};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task,
+    time::Duration,
+};
 use tokio::{
-    sync::{mpsc, watch, Mutex},
-    time::timeout,
+    sync::{mpsc, oneshot, watch, Mutex, Semaphore},
+    time::{timeout, timeout_at, Instant},
 };
 use tracing::{error, trace, warn};
 
-// TODO: this seems arbitrary - it may need tuned or made configurable.
-const INCOMING_MESSAGE_BUFFER_LEN: usize = 10_000;
+// The number of raw streams that can wait to be received.
+const INCOMING_RAW_STREAM_BUFFER_LEN: usize = 64;
 
-// TODO: this seems arbitrary - it may need tuned or made configurable.
-const ENDPOINT_VERIFICATION_TIMEOUT: Duration = Duration::from_secs(30);
+// The number of offered transfers that can wait to be received. Further offers are dropped.
+const INCOMING_TRANSFER_BUFFER_LEN: usize = 16;
 
 // Error reason for closing a connection when triggered manually by qp2p apis
 const QP2P_CLOSED_CONNECTION: &str = "The connection was closed intentionally by qp2p.";
 
+/// Endpoint-wide services used by a connection and its background listeners.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConnectionServices {
+    pub(crate) peer_scoring: Option<Arc<dyn PeerScoring>>,
+    pub(crate) hello_provider: Option<Arc<dyn HelloProvider>>,
+    pub(crate) peer_identifier: Option<Arc<dyn PeerIdentifier>>,
+    pub(crate) connection_observer: Option<Arc<dyn ConnectionObserver>>,
+    pub(crate) message_sink: Option<Arc<dyn MessageSink>>,
+    pub(crate) quic_versions: Arc<[u32]>,
+    pub(crate) interceptors: Arc<[Arc<dyn Interceptor>]>,
+    pub(crate) signing_key: Option<Arc<SigningKey>>,
+    pub(crate) e2e_encryption: bool,
+    pub(crate) dedup: Option<Arc<Dedup>>,
+    pub(crate) message_ordering: MessageOrdering,
+    pub(crate) inbox: Arc<InboxLimits>,
+    pub(crate) max_concurrent_sends: Option<usize>,
+    pub(crate) message_split_threshold: Option<usize>,
+    pub(crate) message_split_streams: Option<usize>,
+    pub(crate) stream_open_timeout: Option<Duration>,
+    pub(crate) handshake_timeout: Option<Duration>,
+    pub(crate) slow_stream_threshold: Option<Duration>,
+    pub(crate) endpoint_verification_timeout: Option<Duration>,
+    pub(crate) heartbeat_interval: Option<Duration>,
+    pub(crate) raw_streams: bool,
+    pub(crate) scheduler: Option<Arc<Scheduler>>,
+    pub(crate) connections: Option<Arc<ConnectionRegistry>>,
+    pub(crate) observations: Option<Arc<AddressObservations>>,
+    pub(crate) address_book: Option<Arc<AddressBook>>,
+    pub(crate) accept_limiter: Option<Arc<AcceptLimiter>>,
+    pub(crate) pause: IncomingPause,
+    pub(crate) peer_router: Arc<PeerRouter>,
+    pub(crate) transport: Option<LiveTransport>,
+    pub(crate) pex: Option<Arc<Pex>>,
+    pub(crate) buffer_pool: Arc<BufferPool>,
+    #[cfg(feature = "dht")]
+    pub(crate) dht: Option<Arc<Dht>>,
+}
+
+/// Transport parameters of a connection, as returned by [`Connection::transport_info`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransportInfo {
+    /// The idle timeout this endpoint advertised. The effective timeout is the lower of this and
+    /// the peer's.
+    pub idle_timeout: Duration,
+
+    /// The interval at which keep-alives are sent, if enabled.
+    pub keep_alive_interval: Option<Duration>,
+
+    /// The number of concurrent bidirectional streams the peer may open.
+    pub max_concurrent_bidi_streams: u64,
+
+    /// The number of concurrent unidirectional streams the peer may open.
+    pub max_concurrent_uni_streams: u64,
+
+    /// The maximum number of unacknowledged bytes the peer may send on a single stream.
+    pub stream_receive_window: u64,
+
+    /// The maximum number of unacknowledged bytes the peer may send across all streams.
+    pub receive_window: u64,
+
+    /// The congestion controller used for sending.
+    pub congestion_controller: &'static str,
+
+    /// Whether the peer may migrate the connection to a new address (when this endpoint accepted
+    /// the connection).
+    pub migration: bool,
+
+    /// The current estimate of the connection's round trip time.
+    pub rtt: Duration,
+
+    /// The largest datagram that can currently be sent on the connection, as limited by the path
+    /// MTU, or `None` if the peer doesn't support datagrams.
+    pub max_datagram_size: Option<usize>,
+}
+
+/// Options for sending a message, as accepted by [`Connection::send_with_opts`].
+///
+/// Construct these with struct update syntax (`..SendOptions::default()`), so that options added
+/// in future don't break your code.
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    /// The priority of the message's stream. Streams with higher priority are sent first.
+    ///
+    /// Defaults to `0`. This has no effect on messages sent on the connection's ordered stream,
+    /// which keeps the priority it was opened with.
+    pub priority: i32,
+
+    /// The time by which the message must have been sent, including any retries.
+    ///
+    /// If the deadline passes first, the send fails with [`SendError::DeadlineExceeded`], and any
+    /// part of the message already written is discarded by the peer. Defaults to no deadline.
+    pub deadline: Option<Instant>,
+
+    /// Whether to send the message on the connection's ordered stream, overriding the
+    /// connection's [`MessageOrdering`] for this message.
+    ///
+    /// Defaults to `None`, which follows the connection's ordering.
+    pub ordered: Option<bool>,
+
+    /// How to retry the send. Defaults to `None`, which uses the
+    /// [`Config::retry_config`](crate::Config::retry_config) of the endpoint.
+    pub retry: Option<RetryConfig>,
+}
+
 /// The sending API for a connection.
 #[derive(Clone)]
 pub struct Connection {
     inner: quinn::Connection,
-    default_retry_config: Option<Arc<RetryConfig>>,
+    default_retry_config: Option<SharedRetryConfig>,
+    services: ConnectionServices,
+    // the parameters in force when the connection was made
+    transport_params: TransportParams,
+    peer_hello: Option<Bytes>,
+    peer_id: Option<PeerId>,
+    ordered_send: Arc<OrderedSend>,
+    // ids of split messages, unique per connection (see `Config::message_split_threshold`)
+    split_ids: Arc<AtomicU64>,
+    // turns for sending, handed out in the order they're asked for (see
+    // `Config::max_concurrent_sends`)
+    send_turns: Option<Arc<Semaphore>>,
+    metadata: Arc<Metadata>,
+    registration: Option<Arc<Registration>>,
+    control: Arc<Control>,
+    extensions: Arc<Extensions>,
+    interception: Interception,
 
     // A reference to the 'alive' marker for the connection. This isn't read by `Connection`, but
     // must be held to keep background listeners alive until both halves of the connection are
@@ -41,30 +209,153 @@ pub struct Connection {
 impl Connection {
     pub(crate) fn new(
         endpoint: quinn::Endpoint,
-        default_retry_config: Option<Arc<RetryConfig>>,
+        default_retry_config: Option<SharedRetryConfig>,
+        services: ConnectionServices,
         connection: quinn::NewConnection,
+        exchange: Option<Exchange>,
     ) -> (Connection, ConnectionIncoming) {
+        let Exchange {
+            peer_hello,
+            session,
+        } = exchange.unwrap_or_default();
         // this channel serves to keep the background message listener alive so long as one side of
         // the connection API is alive.
         let (alive_tx, alive_rx) = watch::channel(());
         let alive_tx = Arc::new(alive_tx);
         let peer_address = connection.connection.remote_address();
+        let context = ErrorContext {
+            peer: peer_address,
+            connection_id: connection.connection.stable_id(),
+        };
+        let path = ConnectionPath::of(
+            services
+                .address_book
+                .as_ref()
+                .and_then(|address_book| address_book.kind_of(&peer_address)),
+        );
+        let metadata = Arc::new(Metadata::new(path));
+        let control = Arc::new(Control::default());
+        let extensions = Arc::new(Extensions::default());
+        let interception = Interception::new(
+            services.interceptors.clone(),
+            services.signing_key.clone(),
+            session.clone(),
+            MessageContext::new(peer_address, context.connection_id, extensions.clone()),
+        );
 
-        (
+        let (close_tx, close_rx) = oneshot::channel();
+        let uni_streams = WatchClose {
+            inner: connection.uni_streams,
+            close_tx: Some(close_tx),
+        };
+
+        let mut connection = (
             Self {
                 inner: connection.connection,
                 default_retry_config,
+                services: services.clone(),
+                transport_params: services
+                    .transport
+                    .as_ref()
+                    .map(LiveTransport::params)
+                    .unwrap_or_default(),
+                peer_hello,
+                peer_id: None,
+                ordered_send: Arc::new(OrderedSend {
+                    enabled: AtomicBool::new(services.message_ordering == MessageOrdering::Ordered),
+                    stream: Mutex::new(None),
+                }),
+                split_ids: Arc::default(),
+                send_turns: services
+                    .max_concurrent_sends
+                    .map(|max| Arc::new(Semaphore::new(max.max(1)))),
+                metadata: metadata.clone(),
+                registration: None,
+                control: control.clone(),
+                extensions,
+                interception: interception.clone(),
                 _alive_tx: Arc::clone(&alive_tx),
             },
             ConnectionIncoming::new(
                 endpoint,
-                peer_address,
-                connection.uni_streams,
+                context,
+                services,
+                uni_streams,
                 connection.bi_streams,
+                metadata,
+                control,
+                interception,
                 alive_tx,
-                alive_rx,
+                alive_rx.clone(),
             ),
-        )
+        );
+
+        connection.0.peer_id = match &connection.0.services.peer_identifier {
+            Some(identifier) => identifier.identify(&connection.0),
+            None => session.and_then(|session| session.peer()),
+        };
+        connection.0.metadata.set_peer_id(connection.0.peer_id);
+
+        // background tasks get handles from before the connection is registered, so they don't
+        // keep it registered once the application has dropped its handles
+        if let Some(observations) = &connection.0.services.observations {
+            observed::observe(connection.0.clone(), observations.clone());
+        }
+
+        if let Some(heartbeat_interval) = connection.0.services.heartbeat_interval {
+            control::open(
+                connection.0.clone(),
+                connection.0.control.clone(),
+                heartbeat_interval,
+                alive_rx,
+            );
+        }
+
+        let registry = connection.0.services.connections.clone();
+        if let Some(registry) = &registry {
+            connection.0.registration = Some(registry.insert(connection.0.clone()));
+        }
+
+        let observer = connection.0.services.connection_observer.clone();
+        if observer.is_some() || registry.is_some() {
+            let id = connection.0.id();
+            let handle = observer.as_ref().map(|_| connection.0.clone());
+            let _ = tokio::spawn(async move {
+                if let (Some(observer), Some(handle)) = (&observer, handle) {
+                    observer.on_connect(handle).await;
+                }
+                let reason = close_rx
+                    .await
+                    .unwrap_or(ConnectionError::Closed(Close::Local));
+                if let Some(registry) = registry {
+                    registry.remove(id);
+                }
+                if let Some(observer) = observer {
+                    observer.on_disconnect(id, peer_address, reason).await;
+                }
+            });
+        }
+
+        connection
+    }
+
+    // A copy of this handle holding the given registration (see `ConnectionRegistry`).
+    pub(crate) fn with_registration(&self, registration: Arc<Registration>) -> Self {
+        Self {
+            registration: Some(registration),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub(crate) fn error_context(&self) -> ErrorContext {
+        ErrorContext {
+            peer: self.remote_address(),
+            connection_id: self.id(),
+        }
     }
 
     /// A stable identifier for the connection.
@@ -76,63 +367,447 @@ impl Connection {
         self.inner.stable_id()
     }
 
+    /// Metadata about the connection, such as its age and the amount of data transferred.
+    pub fn info(&self) -> ConnectionInfo {
+        let stats = self.inner.stats();
+        ConnectionInfo {
+            id: self.id(),
+            remote_address: self.remote_address(),
+            age: self.metadata.age(),
+            idle: self.metadata.idle(),
+            bytes_sent: stats.udp_tx.bytes,
+            bytes_received: stats.udp_rx.bytes,
+        }
+    }
+
+    /// The path the connection takes to the peer.
+    ///
+    /// A connection is [`Relayed`](ConnectionPath::Relayed) if the peer's address is in the
+    /// endpoint's [address book](crate::Endpoint::address_book) as
+    /// [`AddressKind::Relayed`](crate::AddressKind::Relayed), whichever side connected, and
+    /// [`Direct`](ConnectionPath::Direct) otherwise.
+    pub fn path(&self) -> ConnectionPath {
+        self.metadata.path()
+    }
+
+    /// How important the connection is.
+    ///
+    /// New connections are [`ConnectionClass::Normal`].
+    pub fn class(&self) -> ConnectionClass {
+        self.metadata.class()
+    }
+
+    /// Change how important the connection is.
+    ///
+    /// This affects all handles to the connection. When the endpoint has more than
+    /// [`Config::max_connections`](crate::Config::max_connections), the least important
+    /// connection is closed, preferring [`Background`](ConnectionClass::Background) connections
+    /// and then those idle for longest. [`Critical`](ConnectionClass::Critical) connections are
+    /// never closed this way, and are kept alive while idle even if
+    /// [`Config::keep_alive_interval`](crate::Config::keep_alive_interval) is not set.
+    pub fn set_class(&self, class: ConnectionClass) {
+        self.metadata.set_class(class);
+    }
+
+    /// The transport parameters in use on the connection, for debugging.
+    ///
+    /// Note that quinn does not expose the parameters advertised by the peer, so the limits
+    /// reported are those this endpoint advertised, i.e. the limits the peer must respect.
+    pub fn transport_info(&self) -> TransportInfo {
+        let params = self.transport_params;
+        TransportInfo {
+            idle_timeout: params.idle_timeout,
+            keep_alive_interval: params.keep_alive_interval,
+            max_concurrent_bidi_streams: params.max_concurrent_bidi_streams,
+            max_concurrent_uni_streams: params.max_concurrent_uni_streams,
+            stream_receive_window: params.stream_receive_window,
+            receive_window: params.receive_window,
+            congestion_controller: params.congestion_controller,
+            migration: params.migration,
+            rtt: self.inner.rtt(),
+            max_datagram_size: self.inner.max_datagram_size(),
+        }
+    }
+
+    /// Transport statistics for the connection, such as packet loss and congestion events.
+    ///
+    /// The path MTU is not discovered with the version of quinn used by qp2p (packets are limited
+    /// to the QUIC minimum of 1200 bytes), so black-holed packets show up here as loss rather than
+    /// as a reduced MTU.
+    pub fn stats(&self) -> quinn_proto::ConnectionStats {
+        self.inner.stats()
+    }
+
+    /// Distributions of the sizes of messages exchanged on the connection, and of how long they
+    /// took to send and receive.
+    ///
+    /// These are shared by every handle to the connection. Comparing them across connections, along
+    /// with [`MessageStats::slow_streams`], helps find peers that send oversized messages or
+    /// trickle data.
+    pub fn message_stats(&self) -> MessageStats {
+        self.metadata.message_stats().snapshot()
+    }
+
+    /// The hello sent by the peer when the connection was established.
+    ///
+    /// This is `None` unless the endpoint was configured with a
+    /// [`Config::hello_provider`](crate::Config::hello_provider).
+    pub fn peer_hello(&self) -> Option<&Bytes> {
+        self.peer_hello.as_ref()
+    }
+
+    /// The identity of the peer, as derived by the endpoint's
+    /// [`Config::peer_identifier`](crate::Config::peer_identifier), or the key the peer signed its
+    /// key share with, if there's no identifier and
+    /// [`Config::e2e_encryption`](crate::Config::e2e_encryption) is set.
+    ///
+    /// This is `None` if the peer couldn't be identified.
+    pub fn peer_id(&self) -> Option<PeerId> {
+        self.peer_id
+    }
+
+    // The identity the peer signed its end-to-end encryption key share with, if it did.
+    pub(crate) fn e2e_signer(&self) -> Option<PeerId> {
+        self.interception.e2e_signer()
+    }
+
+    // The number of sends that could start now without waiting for a turn, if sends are limited.
+    #[cfg(test)]
+    pub(crate) fn free_send_turns(&self) -> Option<usize> {
+        self.send_turns
+            .as_ref()
+            .map(|turns| turns.available_permits())
+    }
+
+    // The certificate chain presented by the peer, if it presented one.
+    pub(crate) fn peer_certificates(&self) -> Option<Vec<rustls::Certificate>> {
+        self.inner
+            .peer_identity()?
+            .downcast::<Vec<rustls::Certificate>>()
+            .ok()
+            .map(|certificates| *certificates)
+    }
+
+    /// Data attached to the connection by the application.
+    ///
+    /// The extensions are shared by every handle to the connection, including those returned by
+    /// [`Endpoint::get_connection_by_addr`](crate::Endpoint::get_connection_by_addr) and passed to
+    /// a [`ConnectionObserver`](crate::ConnectionObserver).
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
     /// The address of the remote peer.
     pub fn remote_address(&self) -> SocketAddr {
         self.inner.remote_address()
     }
 
+    /// The application protocol negotiated during the handshake, if any.
+    ///
+    /// This will be one of the endpoint's [`Config::alpn_protocols`](crate::Config::alpn_protocols),
+    /// or `None` if none were configured.
+    pub fn alpn_protocol(&self) -> Option<String> {
+        let handshake_data = self
+            .inner
+            .handshake_data()?
+            .downcast::<quinn::crypto::rustls::HandshakeData>()
+            .ok()?;
+        handshake_data
+            .protocol
+            .and_then(|protocol| String::from_utf8(protocol).ok())
+    }
+
+    /// The QUIC version in use on the connection, if known.
+    ///
+    /// Outgoing connections use the first of the endpoint's
+    /// [`Config::quic_versions`](crate::Config::quic_versions). quinn doesn't expose the version
+    /// of incoming connections, so it's only known if the endpoint accepts a single version.
+    pub fn quic_version(&self) -> Option<u32> {
+        match (self.is_outgoing(), &*self.services.quic_versions) {
+            (true, [preferred, ..]) => Some(*preferred),
+            (false, [only]) => Some(*only),
+            _ => None,
+        }
+    }
+
+    // Whether this endpoint initiated the connection. Only servers learn the name the other side
+    // connected to, since qp2p always connects with `SERVER_NAME`.
+    fn is_outgoing(&self) -> bool {
+        self.inner
+            .handshake_data()
+            .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+            .is_some_and(|data| data.server_name.is_none())
+    }
+
+    /// The state of the peer, as reported on the connection's control stream.
+    ///
+    /// The control stream is opened when either side sets
+    /// [`Config::heartbeat_interval`](crate::Config::heartbeat_interval), and carries heartbeats
+    /// separately from messages, so a peer that's busy with other traffic isn't mistaken for an
+    /// unresponsive one (or vice versa).
+    pub fn peer_state(&self) -> PeerState {
+        self.control.peer_state()
+    }
+
+    /// Ask the peer not to send new requests until [`resume`](Self::resume) is called.
+    ///
+    /// This is advisory: the peer sees [`PeerState::Paused`] from its
+    /// [`peer_state`](Self::peer_state), and it's up to the application to respect it. Fails with
+    /// [`ConnectionError::NoControlStream`] if the connection has no control stream.
+    pub async fn pause(&self) -> Result<(), ConnectionError> {
+        self.control.send(Frame::Pause).await
+    }
+
+    /// Tell the peer that it may send new requests again, after [`pause`](Self::pause).
+    pub async fn resume(&self) -> Result<(), ConnectionError> {
+        self.control.send(Frame::Resume).await
+    }
+
+    /// Tell the peer that this side is going away, so it should not send new requests.
+    ///
+    /// The peer sees [`PeerState::GoingAway`] from its [`peer_state`](Self::peer_state). The
+    /// connection stays open, so requests already in progress can complete before it's closed.
+    pub async fn go_away(&self) -> Result<(), ConnectionError> {
+        self.control.send(Frame::GoAway).await
+    }
+
+    // Tell the peer that this endpoint is shutting down (see `Endpoint::shutdown`).
+    pub(crate) async fn send_go_away(&self) -> Result<(), SendError> {
+        let mut send_stream = self.open_uni().await.map_err(PeerError::into_inner)?;
+        send_stream.send_wire_msg(WireMsg::EndpointGoAway).await?;
+        send_stream.finish_stream().await
+    }
+
+    /// How messages sent on this connection are mapped onto QUIC streams.
+    ///
+    /// This defaults to the endpoint's [`Config::message_ordering`](crate::Config::message_ordering).
+    pub fn message_ordering(&self) -> MessageOrdering {
+        if self.ordered_send.enabled.load(Ordering::Relaxed) {
+            MessageOrdering::Ordered
+        } else {
+            MessageOrdering::Unordered
+        }
+    }
+
+    /// Change how messages sent on this connection are mapped onto QUIC streams.
+    ///
+    /// This affects all handles to the connection. When switching from
+    /// [`Ordered`](MessageOrdering::Ordered) to [`Unordered`](MessageOrdering::Unordered), the
+    /// ordered stream is finished, so messages sent afterwards may be received before messages
+    /// sent on it.
+    pub async fn set_message_ordering(
+        &self,
+        ordering: MessageOrdering,
+    ) -> Result<(), PeerError<SendError>> {
+        let ordered = ordering == MessageOrdering::Ordered;
+        // hold the lock so we don't race with an in-progress ordered send
+        let mut stream = self.ordered_send.stream.lock().await;
+        self.ordered_send.enabled.store(ordered, Ordering::Relaxed);
+
+        match stream.take() {
+            Some(mut send_stream) if !ordered => send_stream.finish().await,
+            send_stream => {
+                *stream = send_stream;
+                Ok(())
+            }
+        }
+    }
+
     /// Send a message to the peer with default retry configuration.
     ///
-    /// The message will be sent on a unidirectional QUIC stream, meaning the application is
+    /// With the default [`MessageOrdering::Unordered`], the message will be sent on its own
+    /// unidirectional QUIC stream. Messages may therefore be received in a different order than
+    /// they were sent, e.g. if packets are lost. With [`MessageOrdering::Ordered`], all messages
+    /// are sent on a single stream, and will be received in order. Either way, the application is
     /// responsible for correlating any anticipated responses from incoming streams.
     ///
     /// The priority will be `0` and retry behaviour will be determined by the
     /// [`Config`](crate::Config) that was used to construct the [`Endpoint`] this connection
     /// belongs to. See [`send_with`](Self::send_with) if you want to send a message with specific
     /// configuration.
-    pub async fn send(&self, msg: Bytes) -> Result<(), SendError> {
+    ///
+    /// Messages sent concurrently, e.g. from different tasks, are sent at once and share the
+    /// connection by priority, so many sends from one task can hold up those from others. With
+    /// [`Config::max_concurrent_sends`](crate::Config::max_concurrent_sends), sends instead take
+    /// turns in the order they were made.
+    pub async fn send(&self, msg: Bytes) -> Result<(), PeerError<SendError>> {
         self.send_with(msg, 0, None).await
     }
 
     /// Send a message to the peer using the given configuration.
     ///
-    /// See [`send`](Self::send) if you want to send with the default configuration.
+    /// See [`send`](Self::send) if you want to send with the default configuration, or
+    /// [`send_with_opts`](Self::send_with_opts) for further options.
     pub async fn send_with(
         &self,
         msg: Bytes,
         priority: i32,
         retry_config: Option<&RetryConfig>,
-    ) -> Result<(), SendError> {
-        match retry_config.or_else(|| self.default_retry_config.as_deref()) {
-            Some(retry_config) => {
-                retry_config
-                    .retry(|| async {
-                        self.send_uni(msg.clone(), priority)
-                            .await
-                            .map_err(|error| match &error {
-                                // don't retry on connection loss, since we can't recover that from here
-                                SendError::ConnectionLost(_) => {
-                                    error!("Connection failed on send {:?}", error);
-                                    backoff::Error::Permanent(error)
-                                }
-                                _ => backoff::Error::Transient(error),
-                            })
-                    })
-                    .await?;
+    ) -> Result<(), PeerError<SendError>> {
+        let opts = SendOptions {
+            priority,
+            retry: retry_config.cloned(),
+            ..SendOptions::default()
+        };
+        self.send_with_opts(msg, &opts).await
+    }
+
+    /// Send a message to the peer using the given [`SendOptions`].
+    ///
+    /// See [`send`](Self::send) if you want to send with the default options.
+    pub async fn send_with_opts(
+        &self,
+        msg: Bytes,
+        opts: &SendOptions,
+    ) -> Result<(), PeerError<SendError>> {
+        let msg = match self.interception.outgoing(msg) {
+            Intercepted::Continue(msg) => msg,
+            Intercepted::Drop => return Ok(()),
+            Intercepted::Reject(reason) => {
+                return Err(self.error_context().wrap(SendError::Rejected(reason)))
             }
-            None => {
-                self.send_uni(msg, priority).await?;
+        };
+        let ordered = opts
+            .ordered
+            .unwrap_or_else(|| self.ordered_send.enabled.load(Ordering::Relaxed));
+        let default_retry_config = self
+            .default_retry_config
+            .as_ref()
+            .map(SharedRetryConfig::get);
+        let send = async {
+            // the semaphore is never closed
+            let _turn = match &self.send_turns {
+                Some(turns) => turns.acquire().await.ok(),
+                None => None,
+            };
+            match opts.retry.as_ref().or(default_retry_config.as_deref()) {
+                Some(retry_config) => {
+                    retry_config
+                        .retry_with_rtt(self.inner.rtt(), || async {
+                            self.send_uni(msg.clone(), opts.priority, ordered)
+                                .await
+                                .map_err(|error| match &error {
+                                    // don't retry on connection loss, since we can't recover that from here
+                                    SendError::ConnectionLost(_) => {
+                                        error!("Connection failed on send {:?}", error);
+                                        backoff::Error::Permanent(error)
+                                    }
+                                    _ => backoff::Error::Transient(error),
+                                })
+                        })
+                        .await
+                }
+                None => self.send_uni(msg.clone(), opts.priority, ordered).await,
             }
+        };
+        let result = match opts.deadline {
+            // don't start sending if there's no time left
+            Some(deadline) if deadline <= Instant::now() => Err(SendError::DeadlineExceeded),
+            Some(deadline) => timeout_at(deadline, send)
+                .await
+                .unwrap_or(Err(SendError::DeadlineExceeded)),
+            None => send.await,
+        };
+
+        match result {
+            Ok(()) => self.metadata.touch(),
+            Err(_) => scoring::report(
+                &self.services.peer_scoring,
+                self.remote_address(),
+                self.peer_id,
+                PeerEvent::SendFailed,
+            ),
+        }
+
+        result.map_err(|error| self.error_context().wrap(error))
+    }
+
+    /// The intervals between retries of a failed send, before jitter, given the connection's
+    /// current round-trip time.
+    ///
+    /// This uses the endpoint's retry config (see [`RetryConfig::schedule`]), e.g. for logging how
+    /// sends will be retried when [`RetryConfig::initial_retry_rtt_multiplier`] is set. This is
+    /// empty if sends on the connection aren't retried.
+    pub fn retry_schedule(&self) -> Vec<Duration> {
+        self.default_retry_config
+            .as_ref()
+            .map(|config| config.get().schedule(Some(self.inner.rtt())))
+            .unwrap_or_default()
+    }
+
+    /// Send a message to the peer via the endpoint's outgoing message queue.
+    ///
+    /// Rather than mapping priorities straight onto QUIC streams (as [`send_with`](Self::send_with)
+    /// does), queued messages are dispatched by the endpoint in order of their [`PriorityClass`],
+    /// with connections taking turns within each class. This prevents bulk transfers from starving
+    /// more important messages, either on this connection or others. The depth of the queues can be
+    /// inspected with [`Endpoint::queue_depth`](crate::Endpoint::queue_depth).
+    ///
+    /// Retry behaviour is the same as for [`send`](Self::send).
+    pub async fn send_queued(
+        &self,
+        msg: Bytes,
+        class: PriorityClass,
+    ) -> Result<(), PeerError<SendError>> {
+        match &self.services.scheduler {
+            Some(scheduler) => scheduler.send(self.clone(), msg, class).await,
+            None => self.send_with(msg, class.stream_priority(), None).await,
         }
-        Ok(())
+    }
+
+    /// Send a message to the peer and wait for it to be acknowledged.
+    ///
+    /// Unlike [`send`](Self::send), which completes once the message has been written to QUIC, this
+    /// completes once the peer has delivered the message to its [`ConnectionIncoming`] channel. The
+    /// message is sent on a dedicated bidirectional stream, over which the peer replies with an
+    /// acknowledgement.
+    ///
+    /// The message is not retried, since a failure after sending leaves it ambiguous whether the
+    /// message was delivered. There is also no timeout, since delivery will wait for the receiver
+    /// to have capacity in its channel – callers may wish to wrap this in their own timeout.
+    pub async fn send_with_ack(&self, msg: Bytes) -> Result<(), PeerError<RpcError>> {
+        let result: Result<(), RpcError> = async {
+            let msg = match self.interception.outgoing(msg) {
+                Intercepted::Continue(msg) => msg,
+                Intercepted::Drop => return Ok(()),
+                Intercepted::Reject(reason) => return Err(SendError::Rejected(reason).into()),
+            };
+            let (mut send_stream, mut recv_stream) =
+                self.open_bi().await.map_err(PeerError::into_inner)?;
+            self.write_user_msg(&mut send_stream, WireMsg::UserMsgWithAck(msg))
+                .await?;
+
+            match recv_stream.next_wire_msg().await? {
+                Some(WireMsg::UserMsgAck) => {
+                    self.metadata.touch();
+                    Ok(())
+                }
+                msg => Err(RecvError::from(SerializationError::unexpected(&msg)).into()),
+            }
+        }
+        .await;
+
+        result.map_err(|error| self.error_context().wrap(error))
     }
 
     /// Open a unidirection stream to the peer.
     ///
     /// Messages sent over the stream will arrive at the peer in the order they were sent.
-    pub async fn open_uni(&self) -> Result<SendStream, ConnectionError> {
-        let send_stream = self.inner.open_uni().await?;
-        Ok(SendStream::new(send_stream))
+    ///
+    /// If [`Config::stream_open_timeout`](crate::Config::stream_open_timeout) is set, this fails
+    /// with [`ConnectionError::StreamOpenTimedOut`] if the stream can't be opened in time.
+    pub async fn open_uni(&self) -> Result<SendStream, PeerError<ConnectionError>> {
+        let context = self.error_context();
+        let send_stream = self
+            .with_open_timeout(self.inner.open_uni())
+            .await
+            .and_then(|result| result.map_err(ConnectionError::from))
+            .map_err(|error| context.wrap(error))?;
+        Ok(SendStream::new(
+            send_stream,
+            context,
+            self.services.buffer_pool.clone(),
+        ))
     }
 
     /// Open a bidirectional stream to the peer.
@@ -141,9 +816,74 @@ impl Connection {
     /// automatically correlate response messages, for example.
     ///
     /// Messages sent over the stream will arrive at the peer in the order they were sent.
-    pub async fn open_bi(&self) -> Result<(SendStream, RecvStream), ConnectionError> {
-        let (send_stream, recv_stream) = self.inner.open_bi().await?;
-        Ok((SendStream::new(send_stream), RecvStream::new(recv_stream)))
+    ///
+    /// If [`Config::stream_open_timeout`](crate::Config::stream_open_timeout) is set, this fails
+    /// with [`ConnectionError::StreamOpenTimedOut`] if the stream can't be opened in time.
+    pub async fn open_bi(&self) -> Result<(SendStream, RecvStream), PeerError<ConnectionError>> {
+        let context = self.error_context();
+        let (send_stream, recv_stream) = self
+            .with_open_timeout(self.inner.open_bi())
+            .await
+            .and_then(|result| result.map_err(ConnectionError::from))
+            .map_err(|error| context.wrap(error))?;
+        Ok((
+            SendStream::new(send_stream, context, self.services.buffer_pool.clone()),
+            RecvStream::new(recv_stream, context),
+        ))
+    }
+
+    /// Open a unidirectional stream to the peer, without qp2p's message framing.
+    ///
+    /// Data written to the stream is delivered to the peer as is, so it can be used to speak
+    /// another protocol. A qp2p peer must set [`Config::raw_streams`](crate::Config::raw_streams)
+    /// to accept raw streams, since it would otherwise reject the data as an invalid message.
+    pub async fn open_uni_raw(&self) -> Result<RawSendStream, PeerError<ConnectionError>> {
+        let context = self.error_context();
+        let send_stream = self
+            .with_open_timeout(self.inner.open_uni())
+            .await
+            .and_then(|result| result.map_err(ConnectionError::from))
+            .map_err(|error| context.wrap(error))?;
+        Ok(RawSendStream::new(send_stream, context))
+    }
+
+    /// Open a bidirectional stream to the peer, without qp2p's message framing.
+    ///
+    /// See [`open_uni_raw`](Self::open_uni_raw).
+    pub async fn open_bi_raw(
+        &self,
+    ) -> Result<(RawSendStream, RawRecvStream), PeerError<ConnectionError>> {
+        let context = self.error_context();
+        let (send_stream, recv_stream) = self
+            .with_open_timeout(self.inner.open_bi())
+            .await
+            .and_then(|result| result.map_err(ConnectionError::from))
+            .map_err(|error| context.wrap(error))?;
+        Ok((
+            RawSendStream::new(send_stream, context),
+            RawRecvStream::new(recv_stream, context),
+        ))
+    }
+
+    // Apply the configured stream open timeout, if any, to `open`.
+    async fn with_open_timeout<F: Future>(&self, open: F) -> Result<F::Output, ConnectionError> {
+        match self.services.stream_open_timeout {
+            Some(duration) => timeout(duration, open)
+                .await
+                .map_err(|_| ConnectionError::StreamOpenTimedOut),
+            None => Ok(open.await),
+        }
+    }
+
+    // Send an empty stream to keep the connection from going idle. The peer's listener treats this
+    // as a stream with no messages. A bi-stream is used since the peer handles those concurrently,
+    // so this isn't held up behind an ordered uni-stream.
+    pub(crate) async fn keep_alive(&self) -> Result<(), SendError> {
+        let (mut send_stream, _recv_stream) = self
+            .open_bi()
+            .await
+            .map_err(|error| SendError::ConnectionLost(error.into_inner()))?;
+        send_stream.finish().await.map_err(PeerError::into_inner)
     }
 
     /// Close the connection immediately.
@@ -156,23 +896,121 @@ impl Connection {
         self.inner.close(0u8.into(), &reason.into_bytes());
     }
 
-    /// Opens a uni directional stream and sends message on this stream
-    async fn send_uni(&self, msg: Bytes, priority: i32) -> Result<(), SendError> {
-        let mut send_stream = self.open_uni().await.map_err(SendError::ConnectionLost)?;
+    /// Opens a uni directional stream and sends message on this stream, or sends it on the
+    /// connection's ordered stream if `ordered` is set
+    async fn send_uni(&self, msg: Bytes, priority: i32, ordered: bool) -> Result<(), SendError> {
+        if ordered {
+            return self.send_ordered(msg).await;
+        }
+
+        match self.services.message_split_threshold {
+            Some(threshold) if msg.len() > threshold => self.send_split(msg, priority).await,
+            _ => {
+                self.send_on_new_stream(WireMsg::UserMsg(msg), priority)
+                    .await
+            }
+        }
+    }
+
+    // Send a message split into chunks, each on its own stream, all at once (see
+    // `Config::message_split_threshold`).
+    async fn send_split(&self, msg: Bytes, priority: i32) -> Result<(), SendError> {
+        let streams = self
+            .services
+            .message_split_streams
+            .unwrap_or(DEFAULT_MESSAGE_SPLIT_STREAMS);
+        let id = self.split_ids.fetch_add(1, Ordering::Relaxed);
+        let started = Instant::now();
+        let sends = split::chunks(&msg, streams).map(|(index, count, data)| {
+            let chunk = WireMsg::UserMsgChunk {
+                id,
+                index,
+                count,
+                data,
+            };
+            self.send_on_new_stream(chunk, priority)
+        });
+        let _ = future::try_join_all(sends).await?;
+
+        self.metadata
+            .message_stats()
+            .record_sent(msg.len(), started.elapsed());
+        Ok(())
+    }
+
+    // Open a uni directional stream, and send `msg` on it.
+    async fn send_on_new_stream(&self, msg: WireMsg, priority: i32) -> Result<(), SendError> {
+        let mut send_stream = self
+            .open_uni()
+            .await
+            .map_err(|error| SendError::ConnectionLost(error.into_inner()))?;
         send_stream.set_priority(priority);
 
-        send_stream.send_user_msg(msg.clone()).await?;
+        self.write_user_msg(&mut send_stream, msg).await?;
 
         // We try to make sure the stream is gracefully closed and the bytes get sent, but if it
         // was already closed (perhaps by the peer) then we ignore the error.
         // TODO: we probably shouldn't ignore the error...
-        send_stream.finish().await.or_else(|err| match err {
+        send_stream.finish_stream().await.or_else(|err| match err {
             SendError::StreamLost(StreamError::Stopped(_)) => Ok(()),
             _ => Err(err),
         })?;
 
         Ok(())
     }
+
+    /// Sends a message on the connection's ordered stream, opening it if necessary
+    async fn send_ordered(&self, msg: Bytes) -> Result<(), SendError> {
+        let mut stream = self.ordered_send.stream.lock().await;
+        let mut send_stream = match stream.take() {
+            Some(send_stream) => send_stream,
+            None => self
+                .open_uni()
+                .await
+                .map_err(|error| SendError::ConnectionLost(error.into_inner()))?,
+        };
+
+        // if the send fails, the stream is dropped and a new one is opened for the next message
+        self.write_user_msg(&mut send_stream, WireMsg::UserMsg(msg))
+            .await?;
+        *stream = Some(send_stream);
+
+        Ok(())
+    }
+
+    // Write a user message to a stream, recording its size and how long it took in the
+    // connection's stats. Chunks of split messages are recorded together, once they're all sent.
+    async fn write_user_msg(
+        &self,
+        send_stream: &mut SendStream,
+        msg: WireMsg,
+    ) -> Result<(), SendError> {
+        let len = match &msg {
+            WireMsg::UserMsg(msg) | WireMsg::UserMsgWithAck(msg) => Some(msg.len()),
+            _ => None,
+        };
+        let started = Instant::now();
+        watch_stall(
+            &self.services,
+            &self.metadata,
+            self.error_context(),
+            "write",
+            send_stream.send_wire_msg(msg),
+        )
+        .await?;
+        if let Some(len) = len {
+            self.metadata
+                .message_stats()
+                .record_sent(len, started.elapsed());
+        }
+        Ok(())
+    }
+}
+
+// A persistent stream used for sending messages in order (see `MessageOrdering::Ordered`).
+struct OrderedSend {
+    enabled: AtomicBool,
+    stream: Mutex<Option<SendStream>>,
 }
 
 impl fmt::Debug for Connection {
@@ -187,11 +1025,17 @@ impl fmt::Debug for Connection {
 /// The sending API for a QUIC stream.
 pub struct SendStream {
     inner: quinn::SendStream,
+    context: ErrorContext,
+    buffer_pool: Arc<BufferPool>,
 }
 
 impl SendStream {
-    fn new(inner: quinn::SendStream) -> Self {
-        Self { inner }
+    fn new(inner: quinn::SendStream, context: ErrorContext, buffer_pool: Arc<BufferPool>) -> Self {
+        Self {
+            inner,
+            context,
+            buffer_pool,
+        }
     }
 
     /// Set the priority of the send stream.
@@ -208,23 +1052,91 @@ impl SendStream {
         let _ = self.inner.set_priority(priority);
     }
 
+    /// The ID of the stream.
+    ///
+    /// IDs are unique within a connection, and are the same for both peers, so they can be used to
+    /// refer to a stream in messages or logs.
+    pub fn id(&self) -> u64 {
+        stream_id(self.inner.id())
+    }
+
+    /// Abandon the stream, telling the peer with `error_code`.
+    ///
+    /// Data that hasn't been sent yet is discarded, and the peer's reads of the stream fail with
+    /// [`StreamError::Stopped`] and `error_code`. The rest of the connection is unaffected, so this
+    /// can be used to cancel a single transfer.
+    pub fn reset(&mut self, error_code: u64) -> Result<(), PeerError<StreamError>> {
+        let error_code = error_code_varint(error_code).map_err(|error| self.context.wrap(error))?;
+        self.inner
+            .reset(error_code)
+            .map_err(|_| self.context.wrap(StreamError::Gone))
+    }
+
     /// Send a message over the stream to the peer.
     ///
     /// Messages sent over the stream will arrive at the peer in the order they were sent.
-    pub async fn send_user_msg(&mut self, msg: Bytes) -> Result<(), SendError> {
-        WireMsg::UserMsg(msg).write_to_stream(&mut self.inner).await
+    pub async fn send_user_msg(&mut self, msg: Bytes) -> Result<(), PeerError<SendError>> {
+        let context = self.context;
+        self.send_wire_msg(WireMsg::UserMsg(msg))
+            .await
+            .map_err(|error| context.wrap(error))
+    }
+
+    /// Serialize `value` and send it over the stream to the peer.
+    ///
+    /// The value is encoded with `bincode` and framed like any other message, so the peer can
+    /// receive it with [`RecvStream::next_as`] (or as raw bytes with [`RecvStream::next`]).
+    pub async fn send_as<T: Serialize>(&mut self, value: &T) -> Result<(), PeerError<SendError>> {
+        let msg =
+            bincode::serialize(value).map_err(|error| self.context.wrap(SendError::from(error)))?;
+        self.send_user_msg(Bytes::from(msg)).await
+    }
+
+    /// End a streamed response.
+    ///
+    /// A streamed response is any number of messages, sent with
+    /// [`send_user_msg`](Self::send_user_msg), followed by this end-of-response marker. The peer
+    /// reads the messages with [`RecvStream::next_response`], which returns `None` at the marker.
+    /// Unlike [`finish`](Self::finish), this leaves the stream open, so further requests can be
+    /// answered on it.
+    pub async fn end_response(&mut self) -> Result<(), PeerError<SendError>> {
+        let context = self.context;
+        self.send_wire_msg(WireMsg::EndOfResponse)
+            .await
+            .map_err(|error| context.wrap(error))
+    }
+
+    /// Tell the peer that no more messages will be sent, then shut down the send stream gracefully.
+    ///
+    /// Streams that simply [finish](Self::finish) look the same to the peer as streams that were
+    /// cut off, e.g. by this side crashing between messages. The marker sent here lets the peer's
+    /// [`RecvStream::try_next`] tell the two apart.
+    pub async fn finish_messages(&mut self) -> Result<(), PeerError<SendError>> {
+        let context = self.context;
+        self.send_wire_msg(WireMsg::Fin)
+            .await
+            .map_err(|error| context.wrap(error))?;
+        self.finish().await
     }
 
     /// Shut down the send stream gracefully.
     ///
     /// The returned future will complete once the peer has acknowledged all sent data.
-    pub async fn finish(&mut self) -> Result<(), SendError> {
+    pub async fn finish(&mut self) -> Result<(), PeerError<SendError>> {
+        let context = self.context;
+        self.finish_stream()
+            .await
+            .map_err(|error| context.wrap(error))
+    }
+
+    pub(crate) async fn finish_stream(&mut self) -> Result<(), SendError> {
         self.inner.finish().await?;
         Ok(())
     }
 
     pub(crate) async fn send_wire_msg(&mut self, msg: WireMsg) -> Result<(), SendError> {
-        msg.write_to_stream(&mut self.inner).await
+        msg.write_to_stream(&mut self.inner, &self.buffer_pool)
+            .await
     }
 }
 
@@ -234,22 +1146,165 @@ impl fmt::Debug for SendStream {
     }
 }
 
+/// Writes raw bytes to the stream, without qp2p's message framing.
+///
+/// The peer must read them as raw bytes too (e.g. with [`RecvStream`]'s `AsyncRead`
+/// implementation), and they must not be interleaved with messages on the same stream.
+#[cfg(feature = "async-io")]
+impl tokio::io::AsyncWrite for SendStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        ctx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> task::Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(ctx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        ctx: &mut task::Context<'_>,
+    ) -> task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(ctx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        ctx: &mut task::Context<'_>,
+    ) -> task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(ctx)
+    }
+}
+
 /// The receiving API for a bidirectional QUIC stream.
 pub struct RecvStream {
     inner: quinn::RecvStream,
+    context: ErrorContext,
 }
 
 impl RecvStream {
-    fn new(inner: quinn::RecvStream) -> Self {
-        Self { inner }
+    fn new(inner: quinn::RecvStream, context: ErrorContext) -> Self {
+        Self { inner, context }
+    }
+
+    /// The ID of the stream.
+    ///
+    /// This is the same as the [`id`](SendStream::id) of the stream's sending side.
+    pub fn id(&self) -> u64 {
+        stream_id(self.inner.id())
+    }
+
+    /// Stop receiving from the stream, telling the peer with `error_code`.
+    ///
+    /// Data the peer hasn't sent yet is discarded, and the peer's writes to the stream fail with
+    /// [`StreamError::Stopped`] and `error_code`. The rest of the connection is unaffected.
+    pub fn stop(&mut self, error_code: u64) -> Result<(), PeerError<StreamError>> {
+        let error_code = error_code_varint(error_code).map_err(|error| self.context.wrap(error))?;
+        self.inner
+            .stop(error_code)
+            .map_err(|_| self.context.wrap(StreamError::Gone))
     }
 
     /// Get the next message sent by the peer over this stream.
-    pub async fn next(&mut self) -> Result<Bytes, RecvError> {
-        match self.next_wire_msg().await? {
-            Some(WireMsg::UserMsg(msg)) => Ok(msg),
-            msg => Err(SerializationError::unexpected(&msg).into()),
+    pub async fn next(&mut self) -> Result<Bytes, PeerError<RecvError>> {
+        let context = self.context;
+        match self.next_wire_msg().await {
+            Ok(Some(WireMsg::UserMsg(msg))) => Ok(msg),
+            Ok(msg) => Err(context.wrap(SerializationError::unexpected(&msg).into())),
+            Err(error) => Err(context.wrap(error)),
+        }
+    }
+
+    /// Get the next message sent by the peer over this stream, or `None` once the peer has
+    /// finished sending messages.
+    ///
+    /// Unlike [`next`](Self::next), this tells a stream the peer finished with
+    /// [`SendStream::finish_messages`] apart from one that was cut off: the former returns `None`,
+    /// while the latter fails with [`RecvError::Truncated`].
+    pub async fn try_next(&mut self) -> Result<Option<Bytes>, PeerError<RecvError>> {
+        let context = self.context;
+        match self.next_wire_msg().await {
+            Ok(Some(WireMsg::UserMsg(msg))) => Ok(Some(msg)),
+            Ok(Some(WireMsg::Fin)) => Ok(None),
+            Ok(None) => Err(context.wrap(RecvError::Truncated)),
+            Ok(msg) => Err(context.wrap(SerializationError::unexpected(&msg).into())),
+            Err(error) => Err(context.wrap(error)),
+        }
+    }
+
+    /// Get the next message of a streamed response, or `None` once the response has ended.
+    ///
+    /// The response ends when the peer sends an end-of-response marker (with
+    /// [`SendStream::end_response`]) or finishes the stream (with or without
+    /// [`SendStream::finish_messages`]). After the marker, the stream can be
+    /// used for another request and response. [`next`](Self::next), by contrast, fails if it
+    /// receives the marker.
+    pub async fn next_response(&mut self) -> Result<Option<Bytes>, PeerError<RecvError>> {
+        let context = self.context;
+        match self.next_wire_msg().await {
+            Ok(Some(WireMsg::UserMsg(msg))) => Ok(Some(msg)),
+            Ok(Some(WireMsg::EndOfResponse | WireMsg::Fin)) | Ok(None) => Ok(None),
+            Ok(msg) => Err(context.wrap(SerializationError::unexpected(&msg).into())),
+            Err(error) => Err(context.wrap(error)),
+        }
+    }
+
+    /// Get the next message sent by the peer over this stream, deserialized as `T`.
+    ///
+    /// This is the receiving counterpart of [`SendStream::send_as`]. The message is deserialized
+    /// directly from the received frame.
+    pub async fn next_as<T: DeserializeOwned>(&mut self) -> Result<T, PeerError<RecvError>> {
+        let msg = self.next().await?;
+        bincode::deserialize(&msg).map_err(|error| self.context.wrap(RecvError::from(error)))
+    }
+
+    /// Get the next message sent by the peer over this stream, waiting at most `duration`.
+    ///
+    /// Fails with [`RecvError::TimedOut`] if no complete message arrives in time. Part of the
+    /// message may already have been read by then, so the stream shouldn't be read from again
+    /// afterwards (it can be [stopped](Self::stop) instead).
+    pub async fn next_timeout(
+        &mut self,
+        duration: Duration,
+    ) -> Result<Bytes, PeerError<RecvError>> {
+        let context = self.context;
+        timeout(duration, self.next())
+            .await
+            .map_err(|_| context.wrap(RecvError::TimedOut))?
+    }
+
+    /// Read exactly `len` bytes of raw stream data.
+    ///
+    /// Unlike [`next`](Self::next), this doesn't expect the data to be framed as qp2p messages,
+    /// which allows interoperating with peers that speak another protocol over the stream. Fails
+    /// if the stream ends before `len` bytes have been received.
+    pub async fn read_exact(&mut self, len: usize) -> Result<Bytes, PeerError<RecvError>> {
+        let mut buf = vec![0; len];
+        self.inner
+            .read_exact(&mut buf)
+            .await
+            .map_err(|error| self.context.wrap(RecvError::from(error)))?;
+        Ok(Bytes::from(buf))
+    }
+
+    /// Read raw stream data until the peer finishes the stream.
+    ///
+    /// Fails with [`RecvError::TooLong`] if the peer sends more than `limit` bytes. Like
+    /// [`read_exact`](Self::read_exact), the data isn't expected to be framed as qp2p messages.
+    pub async fn read_to_end(&mut self, limit: usize) -> Result<Bytes, PeerError<RecvError>> {
+        let context = self.context;
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = self
+            .inner
+            .read_chunk(usize::MAX, true)
+            .await
+            .map_err(|error| context.wrap(RecvError::from(error)))?
+        {
+            if chunk.bytes.len() > limit - buf.len() {
+                return Err(context.wrap(RecvError::TooLong(limit)));
+            }
+            buf.extend_from_slice(&chunk.bytes);
         }
+        Ok(buf.freeze())
     }
 
     pub(crate) async fn next_wire_msg(&mut self) -> Result<Option<WireMsg>, RecvError> {
@@ -263,43 +1318,91 @@ impl fmt::Debug for RecvStream {
     }
 }
 
+/// Reads raw bytes from the stream, without qp2p's message framing.
+///
+/// Note that [`read_exact`](RecvStream::read_exact) and [`read_to_end`](RecvStream::read_to_end)
+/// take precedence over the `tokio::io::AsyncReadExt` methods of the same names.
+#[cfg(feature = "async-io")]
+impl tokio::io::AsyncRead for RecvStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        ctx: &mut task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(ctx, buf)
+    }
+}
+
+fn stream_id(id: quinn::StreamId) -> u64 {
+    quinn::VarInt::from(id).into_inner()
+}
+
+fn error_code_varint(error_code: u64) -> Result<quinn::VarInt, StreamError> {
+    quinn::VarInt::from_u64(error_code).map_err(|_| StreamError::InvalidErrorCode(error_code))
+}
+
+// A message passed from the listeners to `ConnectionIncoming`, along with the stream to respond on
+// and the message's signer, if any.
+type IncomingMsg = (Bytes, Option<Arc<Mutex<SendStream>>>, Option<PeerId>);
+
 /// The receiving API for a connection.
 #[derive(Debug)]
 pub struct ConnectionIncoming {
-    message_rx: mpsc::Receiver<Result<(Bytes, Option<Arc<Mutex<SendStream>>>), RecvError>>,
+    message_rx: InboxReceiver<IncomingMsg>,
+    transfers: Option<IncomingTransfers>,
+    raw_streams: Option<IncomingRawStreams>,
+    metadata: Arc<Metadata>,
+    context: ErrorContext,
     _alive_tx: Arc<watch::Sender<()>>,
 }
 
 impl ConnectionIncoming {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         endpoint: quinn::Endpoint,
-        peer_addr: SocketAddr,
-        uni_streams: quinn::IncomingUniStreams,
+        context: ErrorContext,
+        services: ConnectionServices,
+        uni_streams: UniStreams,
         bi_streams: quinn::IncomingBiStreams,
+        metadata: Arc<Metadata>,
+        control: Arc<Control>,
+        interception: Interception,
         alive_tx: Arc<watch::Sender<()>>,
         alive_rx: watch::Receiver<()>,
     ) -> Self {
-        let (message_tx, message_rx) = mpsc::channel(INCOMING_MESSAGE_BUFFER_LEN);
+        let (message_tx, message_rx) = inbox::channel(services.inbox.clone());
+        let (transfer_tx, transfer_rx) = mpsc::channel(INCOMING_TRANSFER_BUFFER_LEN);
+        let (raw_tx, raw_rx) = mpsc::channel(INCOMING_RAW_STREAM_BUFFER_LEN);
 
         // offload the actual message handling to a background task - the task will exit when
         // `alive_tx` is dropped, which would be when both sides of the connection are dropped.
         start_message_listeners(
             endpoint,
-            peer_addr,
+            context,
+            services,
             uni_streams,
             bi_streams,
+            metadata.clone(),
+            control,
+            interception,
             alive_rx,
             message_tx,
+            transfer_tx,
+            raw_tx,
         );
 
         Self {
             message_rx,
+            transfers: Some(IncomingTransfers::new(transfer_rx)),
+            raw_streams: Some(IncomingRawStreams::new(raw_rx)),
+            metadata,
+            context,
             _alive_tx: alive_tx,
         }
     }
 
     /// Get the next message sent by the peer, over any stream.
-    pub async fn next(&mut self) -> Result<Option<Bytes>, RecvError> {
+    pub async fn next(&mut self) -> Result<Option<Bytes>, PeerError<RecvError>> {
         if let Some((bytes, _opt)) = self.next_with_stream().await? {
             Ok(Some(bytes))
         } else {
@@ -308,10 +1411,67 @@ impl ConnectionIncoming {
     }
 
     /// Get the next message sent by the peer, over any stream along with the stream to respond with.
+    ///
+    /// The stream is `Some` exactly when the message arrived on a bidirectional stream, and `None`
+    /// when it arrived on a unidirectional stream, which can't be responded to.
     pub async fn next_with_stream(
         &mut self,
-    ) -> Result<Option<(Bytes, Option<Arc<Mutex<SendStream>>>)>, RecvError> {
-        self.message_rx.recv().await.transpose()
+    ) -> Result<Option<(Bytes, Option<Arc<Mutex<SendStream>>>)>, PeerError<RecvError>> {
+        let result = self.next_msg().await?;
+        Ok(result.map(|(msg, stream, _)| (msg, stream)))
+    }
+
+    /// Get the next message sent by the peer, along with the identity of its signer.
+    ///
+    /// The signer is the public key of the [`SigningKey`](crate::SigningKey) the message was
+    /// signed with, if [`Config::signing_key`](crate::Config::signing_key) is set. Otherwise, it's
+    /// always `None`.
+    pub async fn next_with_signer(
+        &mut self,
+    ) -> Result<Option<(Bytes, Option<PeerId>)>, PeerError<RecvError>> {
+        let result = self.next_msg().await?;
+        Ok(result.map(|(msg, _, signer)| (msg, signer)))
+    }
+
+    async fn next_msg(
+        &mut self,
+    ) -> Result<Option<(Bytes, Option<Arc<Mutex<SendStream>>>, Option<PeerId>)>, PeerError<RecvError>>
+    {
+        let result = self.message_rx.recv().await.transpose();
+        if let Ok(Some(_)) = &result {
+            self.metadata.touch();
+        }
+        result.map_err(|error| self.context.wrap(error))
+    }
+
+    /// The peer's messages buffered until they're received.
+    ///
+    /// See [`Config::max_buffered_bytes_per_connection`](crate::Config::max_buffered_bytes_per_connection),
+    /// and [`Endpoint::incoming_buffer`](crate::Endpoint::incoming_buffer) for the buffers of all
+    /// connections together.
+    pub fn buffered(&self) -> BufferUsage {
+        self.message_rx.usage()
+    }
+
+    /// Take the receiver for files the peer sends with [`send_file`](crate::transfer::send_file).
+    ///
+    /// Transfers use their own streams, so they don't interleave with messages returned by
+    /// [`next`](Self::next). Offers wait to be received until the returned value is polled, and
+    /// once more than a few are waiting, further offers are dropped. This returns `None` if the
+    /// receiver has already been taken.
+    pub fn take_transfers(&mut self) -> Option<IncomingTransfers> {
+        self.transfers.take()
+    }
+
+    /// Take the receiver for streams the peer opens, if [`Config::raw_streams`] is set.
+    ///
+    /// In that case, streams the peer opens are delivered as is, without qp2p's message framing,
+    /// and no messages are returned by [`next`](Self::next). This returns `None` if the receiver
+    /// has already been taken.
+    ///
+    /// [`Config::raw_streams`]: crate::Config::raw_streams
+    pub fn take_raw_streams(&mut self) -> Option<IncomingRawStreams> {
+        self.raw_streams.take()
     }
 }
 
@@ -320,58 +1480,170 @@ impl ConnectionIncoming {
 //
 // `alive_tx` is used to detect when all connection handles are dropped.
 // `message_tx` is used to exfiltrate messages and stream errors.
+#[allow(clippy::too_many_arguments)]
 fn start_message_listeners(
     endpoint: quinn::Endpoint,
-    peer_addr: SocketAddr,
-    uni_streams: quinn::IncomingUniStreams,
+    context: ErrorContext,
+    services: ConnectionServices,
+    uni_streams: UniStreams,
     bi_streams: quinn::IncomingBiStreams,
+    metadata: Arc<Metadata>,
+    control: Arc<Control>,
+    interception: Interception,
     alive_rx: watch::Receiver<()>,
-    message_tx: mpsc::Sender<Result<(Bytes, Option<Arc<Mutex<SendStream>>>), RecvError>>,
+    message_tx: InboxSender<IncomingMsg>,
+    transfer_tx: mpsc::Sender<(SendStream, RecvStream)>,
+    raw_tx: mpsc::Sender<RawStream>,
 ) {
+    let uni_streams = services.pause.pausable(FilterBenignClose(uni_streams));
+    let bi_streams = services.pause.pausable(FilterBenignClose(bi_streams));
+
+    if services.raw_streams {
+        let _ = tokio::spawn(listen_on_raw_streams(
+            context,
+            uni_streams,
+            bi_streams,
+            alive_rx,
+            raw_tx,
+        ));
+        return;
+    }
+
     let _ = tokio::spawn(listen_on_uni_streams(
-        peer_addr,
-        FilterBenignClose(uni_streams),
+        context,
+        services.clone(),
+        metadata.clone(),
+        control.clone(),
+        interception.clone(),
+        uni_streams,
         alive_rx.clone(),
         message_tx.clone(),
     ));
 
     let _ = tokio::spawn(listen_on_bi_streams(
         endpoint,
-        peer_addr,
-        FilterBenignClose(bi_streams),
+        context,
+        services,
+        bi_streams,
+        metadata,
+        control,
+        interception,
         alive_rx,
         message_tx,
+        transfer_tx,
     ));
 }
 
+// Deliver every stream the peer opens, as is.
+async fn listen_on_raw_streams(
+    context: ErrorContext,
+    uni_streams: IncomingStreams<quinn::RecvStream>,
+    bi_streams: IncomingStreams<(quinn::SendStream, quinn::RecvStream)>,
+    mut alive_rx: watch::Receiver<()>,
+    raw_tx: mpsc::Sender<RawStream>,
+) {
+    let peer_addr = context.peer;
+    trace!("Started listener for raw streams from {}", peer_addr);
+
+    let mut streams = stream::select(
+        uni_streams.map_ok(move |recv| RawStream::Uni(RawRecvStream::new(recv, context))),
+        bi_streams.map_ok(move |(send, recv)| {
+            RawStream::Bi(
+                RawSendStream::new(send, context),
+                RawRecvStream::new(recv, context),
+            )
+        }),
+    );
+    let mut alive = Box::pin(alive_rx.changed());
+
+    loop {
+        let result = match future::select(streams.next(), &mut alive).await {
+            future::Either::Left((Some(result), _)) => result,
+            future::Either::Left((None, _)) => break,
+            future::Either::Right((Ok(_), pending_stream)) => match pending_stream.await {
+                Some(result) => result,
+                None => break,
+            },
+            // the connection has been dropped
+            future::Either::Right((Err(_), _)) => break,
+        };
+        match result {
+            Ok(stream) => {
+                if raw_tx.send(stream).await.is_err() {
+                    // the receiver is gone, so streams are dropped as they're opened
+                    trace!("Receiver gone, dropping raw stream from {}", peer_addr);
+                }
+            }
+            Err(error) => {
+                trace!("Raw stream listener for {} stopped: {}", peer_addr, error);
+                break;
+            }
+        }
+    }
+    trace!("Stopped listener for raw streams from {}", peer_addr);
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn listen_on_uni_streams(
-    peer_addr: SocketAddr,
-    uni_streams: FilterBenignClose<quinn::IncomingUniStreams>,
+    context: ErrorContext,
+    services: ConnectionServices,
+    metadata: Arc<Metadata>,
+    control: Arc<Control>,
+    interception: Interception,
+    uni_streams: IncomingStreams<quinn::RecvStream>,
     mut alive_rx: watch::Receiver<()>,
-    message_tx: mpsc::Sender<Result<(Bytes, Option<Arc<Mutex<SendStream>>>), RecvError>>,
+    message_tx: InboxSender<IncomingMsg>,
 ) {
+    let peer_addr = context.peer;
     trace!(
         "Started listener for incoming uni-streams from {}",
         peer_addr
     );
 
-    let mut uni_messages = Box::pin(
-        uni_streams
-            .map_ok(|recv_stream| {
-                trace!("Handling incoming uni-stream from {}", peer_addr);
-
-                stream::try_unfold(recv_stream, |mut recv_stream| async move {
-                    WireMsg::read_from_stream(&mut recv_stream)
-                        .await
-                        .and_then(|msg| match msg {
-                            Some(WireMsg::UserMsg(msg)) => Ok(Some((msg, recv_stream))),
-                            None => Ok(None),
-                            _ => Err(SerializationError::unexpected(&msg).into()),
-                        })
-                })
-            })
-            .try_flatten(),
-    );
+    let reassembly = Arc::new(std::sync::Mutex::new(Reassembly::default()));
+    let mut uni_messages = Box::pin(try_flatten_concurrent(uni_streams.map_ok(|recv_stream| {
+        trace!("Handling incoming uni-stream from {}", peer_addr);
+
+        let services = services.clone();
+        let metadata = metadata.clone();
+        let control = control.clone();
+        let reassembly = reassembly.clone();
+        stream::try_unfold(recv_stream, move |mut recv_stream| {
+            let services = services.clone();
+            let metadata = metadata.clone();
+            let control = control.clone();
+            let reassembly = reassembly.clone();
+            async move {
+                loop {
+                    match read_monitored(&mut recv_stream, &services, &metadata, context).await? {
+                        Some(WireMsg::UserMsg(msg)) => return Ok(Some((msg, recv_stream))),
+                        Some(WireMsg::UserMsgChunk {
+                            id,
+                            index,
+                            count,
+                            data,
+                        }) => {
+                            let complete = reassembly
+                                .lock()
+                                .unwrap_or_else(|error| error.into_inner())
+                                .add(id, index, count, data)?;
+                            if let Some((msg, started)) = complete {
+                                metadata
+                                    .message_stats()
+                                    .record_received(msg.len(), started.elapsed());
+                                return Ok(Some((msg, recv_stream)));
+                            }
+                        }
+                        Some(WireMsg::EndpointGoAway) => {
+                            handle_go_away(context, &services, &control)
+                        }
+                        Some(WireMsg::Fin) | None => return Ok(None),
+                        msg => return Err(SerializationError::unexpected(&msg).into()),
+                    }
+                }
+            }
+        })
+    })));
 
     // it's a shame to allocate, but there are `Pin` errors otherwise – and we should only be doing
     // this once (per connection).
@@ -395,14 +1667,49 @@ async fn listen_on_uni_streams(
             }
         }
     } {
+        let result = match result {
+            Ok(msg) => match intercept_incoming(
+                &services,
+                &interception,
+                peer_addr,
+                metadata.peer_id(),
+                msg,
+            ) {
+                Some(result) => result,
+                None => continue,
+            },
+            Err(error) => Err(error),
+        };
         let mut break_ = false;
 
-        if let Err(RecvError::ConnectionLost(_)) = &result {
-            // if the connection is lost, we should stop processing (after sending the error)
-            break_ = true;
+        match &result {
+            Ok((msg, _)) => scoring::report(
+                &services.peer_scoring,
+                peer_addr,
+                metadata.peer_id(),
+                PeerEvent::MessageReceived { len: msg.len() },
+            ),
+            Err(RecvError::Serialization(_)) => scoring::report(
+                &services.peer_scoring,
+                peer_addr,
+                metadata.peer_id(),
+                PeerEvent::ProtocolViolation,
+            ),
+            Err(RecvError::ConnectionLost(_)) => {
+                // if the connection is lost, we should stop processing (after sending the error)
+                break_ = true;
+            }
+            Err(_) => {}
         }
 
-        if message_tx.send(result.map(|b| (b, None))).await.is_err() {
+        if !deliver(
+            &services,
+            &interception,
+            &message_tx,
+            result.map(|(msg, signer)| (msg, None, signer)),
+        )
+        .await
+        {
             // if we can't send the result, the receiving end is closed so we should stop processing
             break_ = true;
         }
@@ -417,37 +1724,102 @@ async fn listen_on_uni_streams(
     );
 }
 
+// Like `TryStreamExt::try_flatten`, but reading the inner streams concurrently rather than one after
+// another, so a message that's slow to arrive doesn't hold up messages on other streams. In
+// particular, the chunks of a split message are read in parallel.
+fn try_flatten_concurrent<S, I, T, E, F>(streams: S) -> impl Stream<Item = Result<T, F>>
+where
+    S: Stream<Item = Result<I, E>> + Unpin,
+    I: Stream<Item = Result<T, F>>,
+    F: From<E>,
+{
+    stream::unfold(
+        (Some(streams), stream::SelectAll::new()),
+        |(mut streams, mut inner)| async move {
+            loop {
+                let next = match (&mut streams, inner.is_empty()) {
+                    (None, true) => return None,
+                    (None, false) => future::Either::Right(inner.next().await),
+                    (Some(outer), true) => future::Either::Left(outer.next().await),
+                    (Some(outer), false) => {
+                        match future::select(outer.next(), inner.next()).await {
+                            future::Either::Left((item, _)) => future::Either::Left(item),
+                            future::Either::Right((item, _)) => future::Either::Right(item),
+                        }
+                    }
+                };
+                match next {
+                    future::Either::Left(Some(Ok(stream))) => inner.push(Box::pin(stream)),
+                    future::Either::Left(Some(Err(error))) => {
+                        return Some((Err(error.into()), (streams, inner)))
+                    }
+                    future::Either::Left(None) => streams = None,
+                    future::Either::Right(Some(item)) => return Some((item, (streams, inner))),
+                    // only returned once every inner stream has finished
+                    future::Either::Right(None) => {}
+                }
+            }
+        },
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn listen_on_bi_streams(
     endpoint: quinn::Endpoint,
-    peer_addr: SocketAddr,
-    bi_streams: FilterBenignClose<quinn::IncomingBiStreams>,
+    context: ErrorContext,
+    services: ConnectionServices,
+    bi_streams: IncomingStreams<(quinn::SendStream, quinn::RecvStream)>,
+    metadata: Arc<Metadata>,
+    control: Arc<Control>,
+    interception: Interception,
     mut alive_rx: watch::Receiver<()>,
-    message_tx: mpsc::Sender<Result<(Bytes, Option<Arc<Mutex<SendStream>>>), RecvError>>,
+    message_tx: InboxSender<IncomingMsg>,
+    transfer_tx: mpsc::Sender<(SendStream, RecvStream)>,
 ) {
+    let peer_addr = context.peer;
     trace!(
         "Started listener for incoming bi-streams from {}",
         peer_addr
     );
 
+    // control streams outlive this listener's handling of them, so they watch a copy
+    let control_alive_rx = alive_rx.clone();
     let streaming = bi_streams.try_for_each_concurrent(None, |(send_stream, mut recv_stream)| {
         let endpoint = &endpoint;
         let message_tx = &message_tx;
+        let transfer_tx = &transfer_tx;
+        let services = &services;
+        let metadata = &metadata;
+        let control = &control;
+        let interception = &interception;
+        let alive_rx = control_alive_rx.clone();
         async move {
             trace!("Handling incoming bi-stream from {}", peer_addr);
-            let arc_mutex = Arc::new(Mutex::new(SendStream::new(send_stream)));
+            let arc_mutex = Arc::new(Mutex::new(SendStream::new(
+                send_stream,
+                context,
+                services.buffer_pool.clone(),
+            )));
 
             loop {
-                match WireMsg::read_from_stream(&mut recv_stream).await {
+                match read_monitored(&mut recv_stream, services, metadata, context).await {
                     Err(error) => {
                         let mut break_ = false;
 
-                        if let RecvError::ConnectionLost(_) = &error {
-                            break_ = true;
+                        match &error {
+                            RecvError::Serialization(_) => scoring::report(
+                                &services.peer_scoring,
+                                peer_addr,
+                                metadata.peer_id(),
+                                PeerEvent::ProtocolViolation,
+                            ),
+                            RecvError::ConnectionLost(_) => break_ = true,
+                            _ => {}
                         }
 
-                        if let Err(error) = message_tx.send(Err(error)).await {
+                        if !message_tx.send(Err(error), 0).await {
                             // if we can't send the result, the receiving end is closed so we should stop
-                            trace!("Receiver gone, dropping error: {:?}", error);
+                            trace!("Receiver gone, dropping error");
                             break_ = true;
                         }
 
@@ -455,21 +1827,75 @@ async fn listen_on_bi_streams(
                             break;
                         }
                     }
-                    Ok(None) => {
+                    Ok(None) | Ok(Some(WireMsg::Fin)) => {
                         break;
                     }
                     Ok(Some(WireMsg::UserMsg(msg))) => {
-                        if let Err(msg) = message_tx.send(Ok((msg, Some(arc_mutex.clone())))).await
-                        {
+                        scoring::report(
+                            &services.peer_scoring,
+                            peer_addr,
+                            metadata.peer_id(),
+                            PeerEvent::MessageReceived { len: msg.len() },
+                        );
+                        let result = match intercept_incoming(
+                            services,
+                            interception,
+                            peer_addr,
+                            metadata.peer_id(),
+                            msg,
+                        ) {
+                            Some(result) => {
+                                result.map(|(msg, signer)| (msg, Some(arc_mutex.clone()), signer))
+                            }
+                            None => continue,
+                        };
+                        if !deliver(services, interception, message_tx, result).await {
                             // if we can't send the result, the receiving end is closed so we should stop
-                            trace!("Receiver gone, dropping message: {:?}", msg);
+                            trace!("Receiver gone, dropping message from {}", peer_addr);
                             break;
                         }
                     }
+                    Ok(Some(WireMsg::UserMsgWithAck(msg))) => {
+                        scoring::report(
+                            &services.peer_scoring,
+                            peer_addr,
+                            metadata.peer_id(),
+                            PeerEvent::MessageReceived { len: msg.len() },
+                        );
+                        let result = intercept_incoming(
+                            services,
+                            interception,
+                            peer_addr,
+                            metadata.peer_id(),
+                            msg,
+                        );
+                        // rejected messages aren't acknowledged, so the sender sees they weren't
+                        // delivered
+                        let rejected = matches!(result, Some(Err(_)));
+                        if let Some(result) = result {
+                            let result = result.map(|(msg, signer)| (msg, None, signer));
+                            if !deliver(services, interception, message_tx, result).await {
+                                // if we can't send the result, the receiving end is closed so we
+                                // should stop
+                                trace!("Receiver gone, dropping message from {}", peer_addr);
+                                break;
+                            }
+                        }
+                        if rejected {
+                            continue;
+                        }
+                        if let Err(error) = arc_mutex
+                            .lock()
+                            .await
+                            .send_wire_msg(WireMsg::UserMsgAck)
+                            .await
+                        {
+                            warn!("Error acknowledging message from {}: {}", peer_addr, error);
+                        }
+                    }
                     Ok(Some(WireMsg::EndpointEchoReq)) => {
                         if let Err(error) =
-                            handle_endpoint_echo(&mut arc_mutex.lock().await.inner, peer_addr)
-                                .await
+                            handle_endpoint_echo(&mut *arc_mutex.lock().await, peer_addr).await
                         {
                             // TODO: consider more carefully how to handle this
                             warn!("Error handling endpoint echo request: {}", error);
@@ -478,7 +1904,8 @@ async fn listen_on_bi_streams(
                     Ok(Some(WireMsg::EndpointVerificationReq(addr))) => {
                         if let Err(error) = handle_endpoint_verification(
                             endpoint,
-                            &mut arc_mutex.lock().await.inner,
+                            services,
+                            &mut *arc_mutex.lock().await,
                             addr,
                         )
                         .await
@@ -487,7 +1914,91 @@ async fn listen_on_bi_streams(
                             warn!("Error handling endpoint verification request: {}", error);
                         }
                     }
+                    Ok(Some(WireMsg::TransferReq)) => {
+                        // the rest of the stream belongs to the transfer, so it can only be
+                        // requested before any messages have been received on the stream
+                        let send_stream = match Arc::try_unwrap(arc_mutex) {
+                            Ok(send_stream) => send_stream.into_inner(),
+                            Err(_) => {
+                                scoring::report(
+                                    &services.peer_scoring,
+                                    peer_addr,
+                                    metadata.peer_id(),
+                                    PeerEvent::ProtocolViolation,
+                                );
+                                warn!(
+                                    "Ignoring transfer request from {} on a used stream",
+                                    peer_addr
+                                );
+                                break;
+                            }
+                        };
+                        match transfer_tx
+                            .try_send((send_stream, RecvStream::new(recv_stream, context)))
+                        {
+                            Ok(()) => {}
+                            Err(mpsc::error::TrySendError::Full(_)) => warn!(
+                                "Dropping transfer from {}: too many transfers waiting",
+                                peer_addr
+                            ),
+                            Err(mpsc::error::TrySendError::Closed(_)) => {
+                                trace!("Dropping transfer from {}: receiver gone", peer_addr)
+                            }
+                        }
+                        break;
+                    }
+                    Ok(Some(WireMsg::ControlReq)) => {
+                        // like transfers, control streams are only requested on unused streams
+                        match Arc::try_unwrap(arc_mutex) {
+                            Ok(send_stream) => control::accept(
+                                send_stream.into_inner(),
+                                RecvStream::new(recv_stream, context),
+                                control.clone(),
+                                alive_rx,
+                            ),
+                            Err(_) => {
+                                scoring::report(
+                                    &services.peer_scoring,
+                                    peer_addr,
+                                    metadata.peer_id(),
+                                    PeerEvent::ProtocolViolation,
+                                );
+                                warn!(
+                                    "Ignoring control stream request from {} on a used stream",
+                                    peer_addr
+                                );
+                            }
+                        }
+                        break;
+                    }
+                    Ok(Some(WireMsg::PexReq)) => {
+                        if let Err(error) =
+                            handle_pex_req(services, &mut *arc_mutex.lock().await, peer_addr).await
+                        {
+                            warn!("Error handling peer exchange request: {}", error);
+                        }
+                    }
+                    #[cfg(feature = "dht")]
+                    Ok(Some(WireMsg::DhtFindNodeReq { sender, target })) => {
+                        if let Err(error) = handle_dht_find_node(
+                            services.dht.as_deref(),
+                            &mut *arc_mutex.lock().await,
+                            peer_addr,
+                            sender,
+                            target,
+                        )
+                        .await
+                        {
+                            warn!("Error handling DHT find node request: {}", error);
+                        }
+                    }
                     Ok(msg) => {
+                        scoring::report(
+                            &services.peer_scoring,
+                            peer_addr,
+                            metadata.peer_id(),
+                            PeerEvent::ProtocolViolation,
+                        );
                         // TODO: consider more carefully how to handle this
                         warn!(
                             "Error on bi-stream: {}",
@@ -531,41 +2042,208 @@ async fn listen_on_bi_streams(
     }
 }
 
+// Verify an incoming message's signature (if signing is enabled), and pass it through the
+// connection's interceptors. Returns the message and its signer, or `None` if it's dropped.
+fn intercept_incoming(
+    services: &ConnectionServices,
+    interception: &Interception,
+    peer_addr: SocketAddr,
+    peer_id: Option<PeerId>,
+    msg: Bytes,
+) -> Option<Result<(Bytes, Option<PeerId>), RecvError>> {
+    let (msg, signer) = match interception.verify(msg) {
+        Ok(verified) => verified,
+        Err(error) => {
+            scoring::report(
+                &services.peer_scoring,
+                peer_addr,
+                peer_id,
+                PeerEvent::ProtocolViolation,
+            );
+            return Some(Err(error));
+        }
+    };
+    if let Some(dedup) = &services.dedup {
+        if dedup.is_duplicate(peer_addr, &msg) {
+            trace!("Dropping duplicate message from {}", peer_addr);
+            return None;
+        }
+    }
+    match interception.incoming(msg) {
+        Intercepted::Continue(msg) => Some(Ok((msg, signer))),
+        Intercepted::Drop => None,
+        Intercepted::Reject(reason) => Some(Err(RecvError::Rejected(reason))),
+    }
+}
+
+// Deliver an incoming message to the application.
+//
+// Messages go to the peer's own receiver, if there is one (see `Endpoint::messages_from`), then to
+// the endpoint's `MessageSink`, if there is one, and otherwise to the connection's channel. Errors
+// always go to the connection's channel. Returns `false` if the receiver is gone.
+async fn deliver(
+    services: &ConnectionServices,
+    interception: &Interception,
+    message_tx: &InboxSender<IncomingMsg>,
+    result: Result<IncomingMsg, RecvError>,
+) -> bool {
+    let context = interception.context();
+    let result = match result {
+        Ok((msg, stream, signer)) => {
+            match services
+                .peer_router
+                .route(context.peer(), (msg, stream))
+                .await
+            {
+                Some((msg, stream)) => match &services.message_sink {
+                    Some(sink) => return sink.deliver(context, msg, stream, signer),
+                    None => Ok((msg, stream, signer)),
+                },
+                None => return true,
+            }
+        }
+        Err(error) => Err(error),
+    };
+    let len = match &result {
+        Ok((msg, _, _)) => msg.len(),
+        Err(_) => 0,
+    };
+    message_tx.send(result, len).await
+}
+
+// Read the next message from a peer's stream, as with `WireMsg::read_from_stream`, recording user
+// messages in the connection's stats. The read is timed from the arrival of the message's first
+// bytes, so that waiting for the peer to send it doesn't count.
+async fn read_monitored(
+    recv_stream: &mut quinn::RecvStream,
+    services: &ConnectionServices,
+    metadata: &Metadata,
+    context: ErrorContext,
+) -> Result<Option<WireMsg>, RecvError> {
+    let start = match WireMsg::read_start(recv_stream).await? {
+        Some(start) => start,
+        None => return Ok(None),
+    };
+    let started = Instant::now();
+    let msg = watch_stall(
+        services,
+        metadata,
+        context,
+        "read",
+        start.finish(recv_stream),
+    )
+    .await?;
+    if let WireMsg::UserMsg(msg) | WireMsg::UserMsgWithAck(msg) = &msg {
+        metadata
+            .message_stats()
+            .record_received(msg.len(), started.elapsed());
+    }
+    Ok(Some(msg))
+}
+
+// Complete a stream operation, reporting the stream as stalled if it takes longer than
+// `services.slow_stream_threshold`. The operation carries on regardless.
+async fn watch_stall<F: Future>(
+    services: &ConnectionServices,
+    metadata: &Metadata,
+    context: ErrorContext,
+    operation: &'static str,
+    f: F,
+) -> F::Output {
+    let threshold = match services.slow_stream_threshold {
+        Some(threshold) => threshold,
+        None => return f.await,
+    };
+    let mut f = Box::pin(f);
+    match timeout(threshold, &mut f).await {
+        Ok(output) => output,
+        Err(_) => {
+            warn!(
+                peer = %context.peer,
+                connection_id = context.connection_id,
+                operation,
+                threshold = ?threshold,
+                "Stream with {} stalled: {} has taken longer than {:?}",
+                context.peer,
+                operation,
+                threshold
+            );
+            metadata.message_stats().record_slow_stream();
+            scoring::report(
+                &services.peer_scoring,
+                context.peer,
+                metadata.peer_id(),
+                PeerEvent::SlowStream,
+            );
+            f.await
+        }
+    }
+}
+
+// The peer's endpoint is shutting down (see `Endpoint::shutdown`).
+fn handle_go_away(context: ErrorContext, services: &ConnectionServices, control: &Control) {
+    trace!("{} is going away", context.peer);
+    control.go_away();
+    if let Some(observer) = services.connection_observer.clone() {
+        let _ = tokio::spawn(observer.on_go_away(context.connection_id, context.peer));
+    }
+}
+
 async fn handle_endpoint_echo(
-    send_stream: &mut quinn::SendStream,
+    send_stream: &mut SendStream,
     peer_addr: SocketAddr,
 ) -> Result<(), SendError> {
     trace!("Replying to EndpointEchoReq from {}", peer_addr);
-    WireMsg::EndpointEchoResp(peer_addr)
-        .write_to_stream(send_stream)
+    send_stream
+        .send_wire_msg(WireMsg::EndpointEchoResp(peer_addr))
         .await
 }
 
 async fn handle_endpoint_verification(
     endpoint: &quinn::Endpoint,
-    send_stream: &mut quinn::SendStream,
+    services: &ConnectionServices,
+    send_stream: &mut SendStream,
     addr: SocketAddr,
 ) -> Result<(), SendError> {
     trace!("Performing endpoint verification for {}", addr);
 
     let verify = async {
-        trace!(
-            "EndpointVerificationReq: opening new connection to {}",
-            addr
-        );
-        let connection = endpoint
-            .connect(addr, SERVER_NAME)
-            .map_err(ConnectionError::from)?
-            .await?;
+        // a connection we opened to `addr` shows it's reachable, so long as it's still open
+        let reusable = services
+            .connections
+            .as_ref()
+            .and_then(|connections| connections.get_dialed(&addr));
+        let connection = match reusable {
+            Some(connection) => {
+                trace!(
+                    "EndpointVerificationReq: reusing connection {} to {}",
+                    connection.id(),
+                    addr
+                );
+                connection
+            }
+            None => {
+                trace!(
+                    "EndpointVerificationReq: opening new connection to {}",
+                    addr
+                );
+                let client_config = services
+                    .transport
+                    .as_ref()
+                    .map(|transport| transport.client_config_for(addr));
+                let (connection, _) = dial(endpoint, client_config, None, services, &addr).await?;
+                connection
+            }
+        };
 
-        let (mut send_stream, mut recv_stream) = connection.connection.open_bi().await?;
+        let (mut send_stream, mut recv_stream) = connection.inner.open_bi().await?;
         trace!(
             "EndpointVerificationReq: sending EndpointEchoReq to {} over connection {}",
             addr,
-            connection.connection.stable_id()
+            connection.id()
         );
         WireMsg::EndpointEchoReq
-            .write_to_stream(&mut send_stream)
+            .write_to_stream(&mut send_stream, &services.buffer_pool)
             .await?;
 
         match WireMsg::read_from_stream(&mut recv_stream).await? {
@@ -580,7 +2258,10 @@ async fn handle_endpoint_verification(
         }
     };
 
-    let verified: Result<_, RpcError> = timeout(ENDPOINT_VERIFICATION_TIMEOUT, verify)
+    let verification_timeout = services
+        .endpoint_verification_timeout
+        .unwrap_or(DEFAULT_ENDPOINT_VERIFICATION_TIMEOUT);
+    let verified: Result<_, RpcError> = timeout(verification_timeout, verify)
         .await
         .unwrap_or_else(|error| Err(error.into()));
 
@@ -588,13 +2269,114 @@ async fn handle_endpoint_verification(
         warn!("Endpoint verification for {} failed: {:?}", addr, error);
     }
 
-    WireMsg::EndpointVerificationResp(verified.is_ok())
-        .write_to_stream(send_stream)
+    send_stream
+        .send_wire_msg(WireMsg::EndpointVerificationResp(verified.is_ok()))
         .await?;
 
     Ok(())
 }
 
+// Reply to a peer exchange request with the addresses we dialed, which we know to be reachable.
+async fn handle_pex_req(
+    services: &ConnectionServices,
+    send_stream: &mut SendStream,
+    peer_addr: SocketAddr,
+) -> Result<(), SendError> {
+    let records = match &services.pex {
+        Some(pex) => {
+            let dialed = services
+                .connections
+                .as_ref()
+                .map(|connections| connections.all())
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|connection| connection.metadata().dialed())
+                .map(|connection| connection.remote_address());
+            pex.respond(peer_addr, dialed)
+        }
+        None => Vec::new(),
+    };
+
+    trace!(
+        "Replying to PexReq from {} with {} records",
+        peer_addr,
+        records.len()
+    );
+    send_stream.send_wire_msg(WireMsg::PexResp(records)).await
+}
+
+#[cfg(feature = "dht")]
+async fn handle_dht_find_node(
+    dht: Option<&Dht>,
+    send_stream: &mut SendStream,
+    peer_addr: SocketAddr,
+    sender: Option<NodeId>,
+    target: NodeId,
+) -> Result<(), SendError> {
+    let dht = match dht {
+        Some(dht) => dht,
+        None => {
+            trace!("Ignoring DhtFindNodeReq from {}: DHT disabled", peer_addr);
+            return Ok(());
+        }
+    };
+
+    trace!("Replying to DhtFindNodeReq({}) from {}", target, peer_addr);
+    let from = sender.map(|id| Contact {
+        id,
+        addr: peer_addr,
+    });
+
+    send_stream
+        .send_wire_msg(WireMsg::DhtFindNodeResp {
+            responder: dht.local_id(),
+            contacts: dht.handle_find_node(from, &target),
+        })
+        .await
+}
+
+type UniStreams = WatchClose<quinn::IncomingUniStreams>;
+
+// The streams a peer opens, held back while the endpoint's incoming traffic is paused.
+type IncomingStreams<T> = stream::BoxStream<'static, Result<T, ConnectionError>>;
+
+// Reports the reason the connection closed, as observed by the end of its incoming stream. If the
+// stream is dropped first (when all connection handles have been dropped), the connection is
+// reported as closed locally.
+struct WatchClose<S> {
+    inner: S,
+    close_tx: Option<oneshot::Sender<ConnectionError>>,
+}
+
+impl<S> Stream for WatchClose<S>
+where
+    S: Stream<Item = Result<S::Ok, S::Error>> + TryStream + Unpin,
+    S::Error: Into<ConnectionError> + Clone,
+{
+    type Item = Result<S::Ok, S::Error>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut task::Context,
+    ) -> task::Poll<Option<Self::Item>> {
+        let next = futures::ready!(self.inner.poll_next_unpin(ctx));
+        if let Some(Err(error)) = &next {
+            if let Some(close_tx) = self.close_tx.take() {
+                let _ = close_tx.send(error.clone().into());
+            }
+        }
+        task::Poll::Ready(next)
+    }
+}
+
+impl<S> Drop for WatchClose<S> {
+    fn drop(&mut self) {
+        if let Some(close_tx) = self.close_tx.take() {
+            let _ = close_tx.send(ConnectionError::Closed(Close::Local));
+        }
+    }
+}
+
 struct FilterBenignClose<S>(S);
 
 impl<S> Stream for FilterBenignClose<S>
@@ -629,7 +2411,7 @@ mod tests {
     use super::Connection;
     use crate::{
         config::{Config, InternalConfig, SERVER_NAME},
-        error::{ConnectionError, SendError},
+        error::{ConnectionError, PeerError, SendError},
         tests::local_addr,
         wire_msg::WireMsg,
     };
@@ -654,12 +2436,14 @@ mod tests {
             let (p1_tx, mut p1_rx) = Connection::new(
                 peer1.clone(),
                 None,
+                Default::default(),
                 peer1.connect(peer2.local_addr()?, SERVER_NAME)?.await?,
+                None,
             );
 
             let (p2_tx, mut p2_rx) =
                 if let Some(connection) = timeout(peer2_incoming.then(|c| c).try_next()).await?? {
-                    Connection::new(peer2.clone(), None, connection)
+                    Connection::new(peer2.clone(), None, Default::default(), connection, None)
                 } else {
                     bail!("did not receive incoming connection when one was expected");
                 };
@@ -714,12 +2498,14 @@ mod tests {
         let (p1_tx, _) = Connection::new(
             peer1.clone(),
             None,
+            Default::default(),
             peer1.connect(peer2.local_addr()?, SERVER_NAME)?.await?,
+            None,
         );
 
         let (_, mut p2_rx) =
             if let Some(connection) = timeout(peer2_incoming.then(|c| c).try_next()).await?? {
-                Connection::new(peer2.clone(), None, connection)
+                Connection::new(peer2.clone(), None, Default::default(), connection, None)
             } else {
                 bail!("did not receive incoming connection when one was expected");
             };
@@ -727,9 +2513,13 @@ mod tests {
         // let 2 * idle timeout pass
         tokio::time::sleep(Duration::from_secs(2)).await;
 
-        // trying to send a message should fail with an error
+        // trying to send a message should fail with an error, attributed to the connection
         match p1_tx.send(b"hello"[..].into()).await {
-            Err(SendError::ConnectionLost(ConnectionError::TimedOut)) => {}
+            Err(PeerError {
+                peer,
+                connection_id,
+                error: SendError::ConnectionLost(ConnectionError::TimedOut),
+            }) if peer == p1_tx.remote_address() && connection_id == p1_tx.id() => {}
             res => bail!("unexpected send result: {:?}", res),
         }
 
@@ -756,13 +2546,15 @@ mod tests {
             let (p1_tx, _) = Connection::new(
                 peer1.clone(),
                 None,
+                Default::default(),
                 peer1.connect(peer2.local_addr()?, SERVER_NAME)?.await?,
+                None,
             );
 
             // we need to accept the connection on p2, or the message won't be processed
             let _p2_handle =
                 if let Some(connection) = timeout(peer2_incoming.then(|c| c).try_next()).await?? {
-                    Connection::new(peer2.clone(), None, connection)
+                    Connection::new(peer2.clone(), None, Default::default(), connection, None)
                 } else {
                     bail!("did not receive incoming connection when one was expected");
                 };
@@ -808,13 +2600,15 @@ mod tests {
             let (p1_tx, _) = Connection::new(
                 peer1.clone(),
                 None,
+                Default::default(),
                 peer1.connect(peer2.local_addr()?, SERVER_NAME)?.await?,
+                None,
             );
 
             // we need to accept the connection on p2, or the message won't be processed
             let _p2_handle =
                 if let Some(connection) = timeout(peer2_incoming.then(|c| c).try_next()).await?? {
-                    Connection::new(peer2.clone(), None, connection)
+                    Connection::new(peer2.clone(), None, Default::default(), connection, None)
                 } else {
                     bail!("did not receive incoming connection when one was expected");
                 };
@@ -827,7 +2621,7 @@ mod tests {
             // we need to accept the connection on p1, or the message won't be processed
             let _p1_handle =
                 if let Some(connection) = timeout(peer1_incoming.then(|c| c).try_next()).await?? {
-                    Connection::new(peer1.clone(), None, connection)
+                    Connection::new(peer1.clone(), None, Default::default(), connection, None)
                 } else {
                     bail!("did not receive incoming connection when one was expected");
                 };
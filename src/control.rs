@@ -0,0 +1,300 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! A dedicated stream per connection for heartbeats and control frames.
+//!
+//! When [`Config::heartbeat_interval`](crate::Config::heartbeat_interval) is set, each new
+//! connection opens a bidirectional stream, marked with `WireMsg::ControlReq`, and offers the
+//! interval. Both sides then send a heartbeat whenever they've sent nothing else on the stream for
+//! an interval, along with any pause, resume, or go away frames requested through the
+//! [`Connection`](crate::Connection). Only one side needs the option, since the other adopts the
+//! offered interval.
+
+use crate::{
+    connection::{Connection, RecvStream, SendStream},
+    error::ConnectionError,
+    wire_msg::WireMsg,
+};
+use futures::future;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, watch},
+    time::{timeout, Instant},
+};
+use tracing::{debug, trace};
+
+// The number of heartbeat intervals without a frame after which the peer is unresponsive.
+const MISSED_HEARTBEATS: u32 = 3;
+
+// The shortest heartbeat interval, whether configured or offered by a peer.
+const MIN_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+// The number of control frames that can wait to be sent.
+const FRAME_BUFFER_LEN: usize = 8;
+
+/// The state of a connection's peer, as reported on the connection's control stream.
+///
+/// See [`Connection::peer_state`](crate::Connection::peer_state).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerState {
+    /// There is no control stream, so the peer's state is unknown.
+    ///
+    /// This is the case if neither side set
+    /// [`Config::heartbeat_interval`](crate::Config::heartbeat_interval), before the stream has
+    /// been opened, and after it has closed.
+    Unknown,
+
+    /// The peer is sending heartbeats.
+    Active,
+
+    /// The peer asked not to be sent new requests until it resumes.
+    Paused,
+
+    /// Nothing has been received on the control stream for several heartbeat intervals, although
+    /// the connection hasn't (yet) timed out.
+    Unresponsive,
+
+    /// The peer is going away, and asked not to be sent new requests.
+    ///
+    /// This is reported when the peer calls
+    /// [`Connection::go_away`](crate::Connection::go_away) or
+    /// [`Endpoint::shutdown`](crate::Endpoint::shutdown), even if there's no control stream.
+    /// Requests already in progress may still complete.
+    GoingAway,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) enum Frame {
+    Open { heartbeat_interval: Duration },
+    Heartbeat,
+    Pause,
+    Resume,
+    GoAway,
+}
+
+// A connection's control stream state, shared by its handles and the control stream's tasks.
+#[derive(Debug, Default)]
+pub(crate) struct Control {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // the sender for frames to the current control stream, if there is one
+    frame_tx: Option<mpsc::Sender<Frame>>,
+    heartbeat_interval: Duration,
+    last_received: Option<Instant>,
+    paused: bool,
+    going_away: bool,
+}
+
+impl Control {
+    pub(crate) fn peer_state(&self) -> PeerState {
+        let state = self.lock();
+        if state.going_away {
+            PeerState::GoingAway
+        } else if state.frame_tx.is_none() {
+            PeerState::Unknown
+        } else if state
+            .last_received
+            .is_some_and(|at| at.elapsed() > state.heartbeat_interval * MISSED_HEARTBEATS)
+        {
+            PeerState::Unresponsive
+        } else if state.paused {
+            PeerState::Paused
+        } else {
+            PeerState::Active
+        }
+    }
+
+    // Queue a frame to send to the peer.
+    pub(crate) async fn send(&self, frame: Frame) -> Result<(), ConnectionError> {
+        let frame_tx = self
+            .lock()
+            .frame_tx
+            .clone()
+            .ok_or(ConnectionError::NoControlStream)?;
+        frame_tx
+            .send(frame)
+            .await
+            .map_err(|_| ConnectionError::NoControlStream)
+    }
+
+    // Record that the peer is going away, other than by a control frame.
+    pub(crate) fn go_away(&self) {
+        self.lock().going_away = true;
+    }
+
+    fn receive(&self, frame: Frame) {
+        let mut state = self.lock();
+        state.last_received = Some(Instant::now());
+        match frame {
+            Frame::Open { .. } | Frame::Heartbeat => {}
+            Frame::Pause => state.paused = true,
+            Frame::Resume => state.paused = false,
+            Frame::GoAway => state.going_away = true,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+// Open a control stream on a new connection, in the background.
+//
+// The task only holds the connection until the stream is open, and stops when `alive_rx` reports
+// that every handle to the connection has been dropped.
+pub(crate) fn open(
+    connection: Connection,
+    control: Arc<Control>,
+    heartbeat_interval: Duration,
+    alive_rx: watch::Receiver<()>,
+) {
+    let heartbeat_interval = heartbeat_interval.max(MIN_HEARTBEAT_INTERVAL);
+    let _ = tokio::spawn(async move {
+        let peer = connection.remote_address();
+        let result = async {
+            let (mut send, recv) = connection.open_bi().await?;
+            drop(connection);
+            send.send_wire_msg(WireMsg::ControlReq).await?;
+            send.send_as(&Frame::Open { heartbeat_interval }).await?;
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>((send, recv))
+        }
+        .await;
+
+        match result {
+            Ok((send, recv)) => {
+                trace!("Opened control stream to {}", peer);
+                run(send, recv, control, heartbeat_interval, alive_rx).await;
+            }
+            Err(error) => debug!("Failed to open control stream to {}: {}", peer, error),
+        }
+    });
+}
+
+// Run a control stream opened by the peer, in the background.
+pub(crate) fn accept(
+    send: SendStream,
+    mut recv: RecvStream,
+    control: Arc<Control>,
+    alive_rx: watch::Receiver<()>,
+) {
+    let _ = tokio::spawn(async move {
+        match recv.next_as::<Frame>().await {
+            Ok(Frame::Open { heartbeat_interval }) => {
+                let heartbeat_interval = heartbeat_interval.max(MIN_HEARTBEAT_INTERVAL);
+                run(send, recv, control, heartbeat_interval, alive_rx).await;
+            }
+            Ok(frame) => debug!("Expected control stream to open, got {:?}", frame),
+            Err(error) => debug!("Failed to accept control stream: {}", error),
+        }
+    });
+}
+
+async fn run(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    control: Arc<Control>,
+    heartbeat_interval: Duration,
+    mut alive_rx: watch::Receiver<()>,
+) {
+    let (frame_tx, mut frame_rx) = mpsc::channel(FRAME_BUFFER_LEN);
+    {
+        let mut state = control.lock();
+        state.frame_tx = Some(frame_tx.clone());
+        state.heartbeat_interval = heartbeat_interval;
+        state.last_received = Some(Instant::now());
+    }
+
+    let sending = async {
+        loop {
+            let frame = match timeout(heartbeat_interval, frame_rx.recv()).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(_) => Frame::Heartbeat,
+            };
+            if let Err(error) = send.send_as(&frame).await {
+                debug!("Failed to send control frame: {}", error);
+                break;
+            }
+        }
+    };
+    let receiving = async {
+        loop {
+            match recv.next_as::<Frame>().await {
+                Ok(frame) => control.receive(frame),
+                Err(error) => {
+                    debug!("Control stream closed: {}", error);
+                    break;
+                }
+            }
+        }
+    };
+    let alive = async {
+        // nothing is sent on the alive channel, so this only returns once it's closed
+        while alive_rx.changed().await.is_ok() {}
+    };
+
+    let _ = future::select(
+        Box::pin(future::select(Box::pin(sending), Box::pin(receiving))),
+        Box::pin(alive),
+    )
+    .await;
+
+    // only forget the stream if a newer one hasn't replaced it
+    let mut state = control.lock();
+    if state
+        .frame_tx
+        .as_ref()
+        .is_some_and(|current| current.same_channel(&frame_tx))
+    {
+        state.frame_tx = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Control, Frame, PeerState, MISSED_HEARTBEATS};
+    use std::time::Duration;
+    use tokio::{sync::mpsc, time::Instant};
+
+    #[test]
+    fn peer_state() {
+        let control = Control::default();
+        assert_eq!(control.peer_state(), PeerState::Unknown);
+
+        let (frame_tx, _frame_rx) = mpsc::channel(1);
+        {
+            let mut state = control.lock();
+            state.frame_tx = Some(frame_tx);
+            state.heartbeat_interval = Duration::from_secs(1);
+        }
+        control.receive(Frame::Heartbeat);
+        assert_eq!(control.peer_state(), PeerState::Active);
+
+        control.receive(Frame::Pause);
+        assert_eq!(control.peer_state(), PeerState::Paused);
+        control.receive(Frame::Resume);
+        assert_eq!(control.peer_state(), PeerState::Active);
+
+        control.lock().last_received =
+            Instant::now().checked_sub(Duration::from_secs(u64::from(MISSED_HEARTBEATS) + 1));
+        assert_eq!(control.peer_state(), PeerState::Unresponsive);
+
+        // going away outlasts the stream
+        control.receive(Frame::GoAway);
+        control.lock().frame_tx = None;
+        assert_eq!(control.peer_state(), PeerState::GoingAway);
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Suppression of duplicate incoming messages.
+
+use std::{
+    collections::{hash_map::RandomState, HashSet, VecDeque},
+    hash::BuildHasher,
+    net::SocketAddr,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+// The most messages remembered at once. If more than this many messages arrive within the window,
+// the oldest are forgotten early.
+const MAX_ENTRIES: usize = 64 * 1024;
+
+// A message, as identified by its sender and a hash of its content.
+type Key = (SocketAddr, u64);
+
+// Recently received messages, so that identical messages from the same peer can be dropped.
+//
+// Messages are remembered by a keyed hash of their content, rather than the content itself, so
+// the memory used is bounded by `MAX_ENTRIES` regardless of message size.
+#[derive(Debug)]
+pub(crate) struct Dedup {
+    window: Duration,
+    hasher: RandomState,
+    seen: Mutex<Seen>,
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    keys: HashSet<Key>,
+    // keys in the order they were first seen, for expiry
+    order: VecDeque<(Instant, Key)>,
+}
+
+impl Dedup {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            hasher: RandomState::new(),
+            seen: Mutex::default(),
+        }
+    }
+
+    // Record a message from `peer`, returning whether it's a duplicate of one received within the
+    // window.
+    pub(crate) fn is_duplicate(&self, peer: SocketAddr, msg: &[u8]) -> bool {
+        let key = (peer, self.hasher.hash_one(msg));
+
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap_or_else(|error| error.into_inner());
+        seen.expire(now.checked_sub(self.window));
+        if seen.keys.contains(&key) {
+            return true;
+        }
+
+        if seen.order.len() >= MAX_ENTRIES {
+            if let Some((_, oldest)) = seen.order.pop_front() {
+                let _ = seen.keys.remove(&oldest);
+            }
+        }
+        let _ = seen.keys.insert(key);
+        seen.order.push_back((now, key));
+        false
+    }
+}
+
+impl Seen {
+    // Forget messages first seen before `cutoff`.
+    fn expire(&mut self, cutoff: Option<Instant>) {
+        let cutoff = match cutoff {
+            Some(cutoff) => cutoff,
+            None => return,
+        };
+        while let Some((seen_at, key)) = self.order.front().copied() {
+            if seen_at >= cutoff {
+                break;
+            }
+            let _ = self.order.pop_front();
+            let _ = self.keys.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Dedup;
+    use std::{net::SocketAddr, thread, time::Duration};
+
+    #[test]
+    fn duplicates_within_window() {
+        let dedup = Dedup::new(Duration::from_millis(100));
+        let peer1: SocketAddr = ([127, 0, 0, 1], 1000).into();
+        let peer2: SocketAddr = ([127, 0, 0, 1], 2000).into();
+
+        assert!(!dedup.is_duplicate(peer1, b"hello"));
+        assert!(dedup.is_duplicate(peer1, b"hello"));
+        assert!(!dedup.is_duplicate(peer1, b"world"));
+
+        // the same message from another peer isn't a duplicate
+        assert!(!dedup.is_duplicate(peer2, b"hello"));
+
+        // messages are forgotten once the window has passed
+        thread::sleep(Duration::from_millis(150));
+        assert!(!dedup.is_duplicate(peer1, b"hello"));
+    }
+}
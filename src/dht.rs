@@ -0,0 +1,247 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! A Kademlia-style routing table for discovering peers beyond the bootstrap contacts.
+
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, fmt, net::SocketAddr, sync::Mutex};
+
+/// Maximum number of contacts held in each bucket of the routing table.
+pub const BUCKET_SIZE: usize = 20;
+
+/// Number of queries sent concurrently during an iterative lookup.
+pub(crate) const LOOKUP_CONCURRENCY: usize = 3;
+
+const NODE_ID_LEN: usize = 32;
+
+/// The identifier of a node in the DHT.
+///
+/// Distances between nodes are measured by the XOR of their identifiers.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct NodeId(pub [u8; NODE_ID_LEN]);
+
+impl NodeId {
+    /// Generate a random `NodeId`.
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    /// The XOR distance between two identifiers.
+    pub fn distance(&self, other: &NodeId) -> NodeId {
+        let mut distance = [0; NODE_ID_LEN];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        NodeId(distance)
+    }
+
+    // Index of the bucket `other` belongs in, relative to `self`. This is the number of leading
+    // bits the two identifiers share, so `None` is only returned when they are identical.
+    fn bucket_index(&self, other: &NodeId) -> Option<usize> {
+        let distance = self.distance(other);
+        let mut leading_zeros = 0;
+        for byte in distance.0.iter() {
+            if *byte == 0 {
+                leading_zeros += 8;
+            } else {
+                leading_zeros += byte.leading_zeros() as usize;
+                return Some(leading_zeros);
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NodeId({})", self)
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}{:02x}{:02x}..", self.0[0], self.0[1], self.0[2])
+    }
+}
+
+/// A node known to the DHT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Contact {
+    /// The node's identifier.
+    pub id: NodeId,
+
+    /// The address the node can be reached at.
+    pub addr: SocketAddr,
+}
+
+// Buckets of contacts, ordered from least- to most-recently seen.
+#[derive(Debug)]
+struct RoutingTable {
+    local_id: NodeId,
+    buckets: Vec<VecDeque<Contact>>,
+}
+
+impl RoutingTable {
+    fn new(local_id: NodeId) -> Self {
+        Self {
+            local_id,
+            buckets: (0..NODE_ID_LEN * 8).map(|_| VecDeque::new()).collect(),
+        }
+    }
+
+    // Mark `contact` as seen. New contacts are dropped if their bucket is already full, in line
+    // with Kademlia's preference for long-lived nodes. Stale contacts are evicted when queries to
+    // them fail (see `remove`).
+    fn insert(&mut self, contact: Contact) {
+        let bucket = match self.local_id.bucket_index(&contact.id) {
+            Some(index) => &mut self.buckets[index],
+            None => return,
+        };
+
+        if let Some(position) = bucket.iter().position(|c| c.id == contact.id) {
+            let _ = bucket.remove(position);
+            bucket.push_back(contact);
+        } else if bucket.len() < BUCKET_SIZE {
+            bucket.push_back(contact);
+        }
+    }
+
+    fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.local_id.bucket_index(id) {
+            self.buckets[index].retain(|c| c.id != *id);
+        }
+    }
+
+    fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        let mut contacts: Vec<_> = self.buckets.iter().flatten().copied().collect();
+        contacts.sort_by_key(|c| c.id.distance(target));
+        contacts.truncate(count);
+        contacts
+    }
+}
+
+/// DHT state shared by an endpoint and its connections.
+#[derive(Debug)]
+pub(crate) struct Dht {
+    local_id: NodeId,
+    table: Mutex<RoutingTable>,
+}
+
+impl Dht {
+    pub(crate) fn new(local_id: NodeId) -> Self {
+        Self {
+            local_id,
+            table: Mutex::new(RoutingTable::new(local_id)),
+        }
+    }
+
+    pub(crate) fn local_id(&self) -> NodeId {
+        self.local_id
+    }
+
+    pub(crate) fn observe(&self, contact: Contact) {
+        self.table
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .insert(contact)
+    }
+
+    pub(crate) fn remove(&self, id: &NodeId) {
+        self.table
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .remove(id)
+    }
+
+    pub(crate) fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        self.table
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .closest(target, count)
+    }
+
+    // Answer a `DhtFindNodeReq`, recording the requester if it is routable.
+    pub(crate) fn handle_find_node(&self, from: Option<Contact>, target: &NodeId) -> Vec<Contact> {
+        if let Some(from) = from {
+            self.observe(from);
+        }
+        self.closest(target, BUCKET_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Contact, Dht, NodeId, BUCKET_SIZE};
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    fn contact(id: NodeId, port: u16) -> Contact {
+        Contact {
+            id,
+            addr: SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+        }
+    }
+
+    #[test]
+    fn closest_contacts_are_sorted_by_distance() {
+        let local = NodeId([0; 32]);
+        let dht = Dht::new(local);
+
+        for i in 1..=100u8 {
+            let mut id = [0; 32];
+            id[31] = i;
+            dht.observe(contact(NodeId(id), i.into()));
+        }
+
+        let mut target = [0; 32];
+        target[31] = 7;
+        let closest = dht.closest(&NodeId(target), 3);
+
+        assert_eq!(closest[0].id, NodeId(target));
+        assert!(closest.windows(2).all(
+            |pair| pair[0].id.distance(&NodeId(target)) <= pair[1].id.distance(&NodeId(target))
+        ));
+    }
+
+    #[test]
+    fn full_buckets_keep_existing_contacts() {
+        let local = NodeId([0; 32]);
+        let dht = Dht::new(local);
+
+        // all of these share no leading bits with `local`, so land in the same bucket
+        let ids: Vec<_> = (0..=BUCKET_SIZE as u8)
+            .map(|i| {
+                let mut id = [0; 32];
+                id[0] = 0x80;
+                id[31] = i;
+                NodeId(id)
+            })
+            .collect();
+        for (port, id) in ids.iter().enumerate() {
+            dht.observe(contact(*id, port as u16));
+        }
+
+        let known = dht.closest(&ids[0], BUCKET_SIZE * 2);
+        assert_eq!(known.len(), BUCKET_SIZE);
+        assert!(!known.iter().any(|c| c.id == ids[BUCKET_SIZE]));
+
+        dht.remove(&ids[0]);
+        dht.observe(contact(ids[BUCKET_SIZE], 0));
+        assert!(dht
+            .closest(&ids[BUCKET_SIZE], 1)
+            .iter()
+            .any(|c| c.id == ids[BUCKET_SIZE]));
+
+        // ourselves is never inserted
+        dht.observe(contact(local, 0));
+        assert!(!dht
+            .closest(&local, BUCKET_SIZE * 2)
+            .iter()
+            .any(|c| c.id == local));
+    }
+}
@@ -0,0 +1,503 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! End-to-end encryption of user messages.
+//!
+//! When [`Config::e2e_encryption`](crate::Config::e2e_encryption) is set, the side opening the
+//! connection (the initiator) appends an ephemeral X25519 key share to its hello, and the other
+//! side (the responder) answers with its own:
+//!
+//! ```text
+//! initiator:    | hello | key share (32 bytes) | 0 |
+//!               | hello | key share (32 bytes) | public key (32 bytes) | 2 |
+//! responder:    | hello | key share (32 bytes) | 0 |
+//!               | hello | key share (32 bytes) | public key (32 bytes) | signature (64 bytes) | 1 |
+//! confirmation: | public key (32 bytes) | signature (64 bytes) |
+//! ```
+//!
+//! A side with a [`Config::signing_key`](crate::Config::signing_key) signs the handshake
+//! transcript: both key shares and both public keys. The responder signs in its reply. The
+//! initiator announces its public key in its hello, and signs in a confirmation sent as a second
+//! hello once it has the reply. This binds the agreed keys to the signers' identities, so an
+//! intermediary that terminates TLS, such as a relay, can't substitute its own key shares without
+//! the signatures failing.
+//!
+//! Both sides derive a key for each direction from the shared secret with HKDF-SHA256, and user
+//! messages are encrypted with ChaCha20-Poly1305 behind an 8 byte counter (the nonce). Unsigned key
+//! shares aren't authenticated, so they only keep payloads from passive observers: an intermediary
+//! can agree keys with each side in turn and read everything. Messages may be delivered out of
+//! order, so there's no protection against an intermediary replaying them.
+
+use crate::{
+    address_book::PeerId,
+    signing::{self, SigningKey},
+};
+use bytes::{Bytes, BytesMut};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305},
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519},
+    hkdf::{Salt, HKDF_SHA256},
+    rand::SystemRandom,
+};
+use std::{
+    convert::TryInto,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+const KEY_SHARE_LEN: usize = 32;
+const PUBLIC_KEY_LEN: usize = 32;
+const COUNTER_LEN: usize = 8;
+const UNSIGNED: u8 = 0;
+const SIGNED: u8 = 1;
+// an initiator's key share, to be signed in a confirmation
+const TO_BE_SIGNED: u8 = 2;
+// the length of the public key and signature appended by `SigningKey::sign`
+const SIGNATURE_LEN: usize = PUBLIC_KEY_LEN + 64;
+
+const TRANSCRIPT_LABEL: &[u8] = b"qp2p e2e transcript";
+const INITIATOR_INFO: &[u8] = b"qp2p e2e initiator";
+const RESPONDER_INFO: &[u8] = b"qp2p e2e responder";
+
+// Our half of a key agreement.
+pub(crate) struct KeyShare {
+    private_key: EphemeralPrivateKey,
+    public_key: [u8; KEY_SHARE_LEN],
+}
+
+impl KeyShare {
+    pub(crate) fn generate() -> Result<Self, String> {
+        let private_key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new())
+            .map_err(|_| "failed to generate key share".to_string())?;
+        let public_key = private_key
+            .compute_public_key()
+            .map_err(|_| "failed to generate key share".to_string())?
+            .as_ref()
+            .try_into()
+            .map_err(|_| "invalid key share".to_string())?;
+        Ok(Self {
+            private_key,
+            public_key,
+        })
+    }
+
+    // As the initiator, append our share to `hello`, announcing our public key if we'll sign the
+    // transcript.
+    pub(crate) fn offer(&self, hello: Bytes, signing_key: Option<&SigningKey>) -> Bytes {
+        let mut offer = BytesMut::from(&hello[..]);
+        offer.extend_from_slice(&self.public_key);
+        match signing_key {
+            Some(signing_key) => {
+                offer.extend_from_slice(&signing_key.peer_id().0);
+                offer.extend_from_slice(&[TO_BE_SIGNED]);
+            }
+            None => offer.extend_from_slice(&[UNSIGNED]),
+        }
+        offer.freeze()
+    }
+
+    // As the responder, append our share to `hello`, along with a signature over the transcript if
+    // we have a signing key.
+    pub(crate) fn reply(
+        &self,
+        hello: Bytes,
+        offer: &PeerShare,
+        signing_key: Option<&SigningKey>,
+    ) -> Bytes {
+        let mut reply = BytesMut::from(&hello[..]);
+        reply.extend_from_slice(&self.public_key);
+        match signing_key {
+            Some(signing_key) => {
+                let transcript = transcript(
+                    RESPONDER_INFO,
+                    &offer.public_key,
+                    &self.public_key,
+                    offer.signer,
+                    Some(signing_key.peer_id()),
+                );
+                reply.extend_from_slice(&signing_key.sign(&transcript)[transcript.len()..]);
+                reply.extend_from_slice(&[SIGNED]);
+            }
+            None => reply.extend_from_slice(&[UNSIGNED]),
+        }
+        reply.freeze()
+    }
+
+    // As the initiator, sign the transcript once we have the responder's `reply`, if we announced
+    // a public key in our offer.
+    pub(crate) fn confirm(
+        &self,
+        reply: &PeerShare,
+        signing_key: Option<&SigningKey>,
+    ) -> Option<Bytes> {
+        let signing_key = signing_key?;
+        let transcript = transcript(
+            INITIATOR_INFO,
+            &self.public_key,
+            &reply.public_key,
+            Some(signing_key.peer_id()),
+            reply.signer,
+        );
+        Some(signing_key.sign(&transcript).slice(transcript.len()..))
+    }
+
+    // As the responder, verify the initiator's confirmation of its `offer`, after which its
+    // identity is trusted.
+    pub(crate) fn verify_confirmation(
+        &self,
+        offer: &mut PeerShare,
+        confirmation: &[u8],
+        signing_key: Option<&SigningKey>,
+    ) -> Result<(), String> {
+        let claimed = offer
+            .signer
+            .ok_or_else(|| "unexpected confirmation of key share".to_string())?;
+        let transcript = transcript(
+            INITIATOR_INFO,
+            &offer.public_key,
+            &self.public_key,
+            Some(claimed),
+            signing_key.map(SigningKey::peer_id),
+        );
+        let mut signed = BytesMut::from(&transcript[..]);
+        signed.extend_from_slice(confirmation);
+        match signing::verify(&signed.freeze()) {
+            Some((msg, signer)) if signer == claimed && msg[..] == transcript[..] => {
+                offer.verified = true;
+                Ok(())
+            }
+            _ => Err("invalid signature on key share".to_string()),
+        }
+    }
+
+    // Agree keys with the peer's share, as parsed by `accept_offer` or `accept_reply`.
+    pub(crate) fn agree(self, peer: PeerShare, initiator: bool) -> Result<Session, String> {
+        let (initiator_share, responder_share) = if initiator {
+            (self.public_key, peer.public_key)
+        } else {
+            (peer.public_key, self.public_key)
+        };
+        let salt = Salt::new(HKDF_SHA256, &[initiator_share, responder_share].concat());
+
+        let (send_info, recv_info) = if initiator {
+            (INITIATOR_INFO, RESPONDER_INFO)
+        } else {
+            (RESPONDER_INFO, INITIATOR_INFO)
+        };
+        let (send_key, recv_key) = agreement::agree_ephemeral(
+            self.private_key,
+            &UnparsedPublicKey::new(&X25519, peer.public_key),
+            "key agreement failed".to_string(),
+            |secret| {
+                let prk = salt.extract(secret);
+                let key = |info: &[u8]| {
+                    prk.expand(&[info], &CHACHA20_POLY1305)
+                        .map(|okm| LessSafeKey::new(UnboundKey::from(okm)))
+                        .map_err(|_| "key derivation failed".to_string())
+                };
+                Ok((key(send_info)?, key(recv_info)?))
+            },
+        )?;
+
+        Ok(Session {
+            send_key,
+            recv_key,
+            send_counter: AtomicU64::new(0),
+            peer: peer.signer.filter(|_| peer.verified),
+        })
+    }
+}
+
+// The peer's half of a key agreement.
+#[derive(Debug)]
+pub(crate) struct PeerShare {
+    public_key: [u8; KEY_SHARE_LEN],
+    // the identity the peer signs as, which is only trusted once `verified`
+    signer: Option<PeerId>,
+    verified: bool,
+}
+
+impl PeerShare {
+    // Whether the peer announced a public key, and will confirm its share with a signature.
+    pub(crate) fn awaits_confirmation(&self) -> bool {
+        self.signer.is_some() && !self.verified
+    }
+}
+
+// Split the initiator's key share from its hello (see `KeyShare::offer`), returning the hello as
+// sent by the application.
+pub(crate) fn accept_offer(hello: &Bytes) -> Result<(Bytes, PeerShare), String> {
+    let missing = || "hello has no key share".to_string();
+    let (flag, rest) = hello.split_last().ok_or_else(missing)?;
+    let share_len = match *flag {
+        UNSIGNED => KEY_SHARE_LEN,
+        TO_BE_SIGNED => KEY_SHARE_LEN + PUBLIC_KEY_LEN,
+        _ => return Err(missing()),
+    };
+    let hello_len = rest.len().checked_sub(share_len).ok_or_else(missing)?;
+    let (public_key, signer) = rest[hello_len..].split_at(KEY_SHARE_LEN);
+    let signer = match signer.try_into() {
+        Ok(signer) => Some(PeerId(signer)),
+        Err(_) => None,
+    };
+
+    Ok((
+        hello.slice(..hello_len),
+        PeerShare {
+            public_key: public_key.try_into().map_err(|_| missing())?,
+            signer,
+            verified: false,
+        },
+    ))
+}
+
+// Split the responder's key share from its hello (see `KeyShare::reply`), verifying its signature
+// over the transcript if it's signed.
+pub(crate) fn accept_reply(
+    hello: &Bytes,
+    ours: &KeyShare,
+    signing_key: Option<&SigningKey>,
+) -> Result<(Bytes, PeerShare), String> {
+    let missing = || "hello has no key share".to_string();
+    let (flag, rest) = hello.split_last().ok_or_else(missing)?;
+    let share_len = match *flag {
+        UNSIGNED => KEY_SHARE_LEN,
+        SIGNED => KEY_SHARE_LEN + SIGNATURE_LEN,
+        _ => return Err(missing()),
+    };
+    let hello_len = rest.len().checked_sub(share_len).ok_or_else(missing)?;
+    let (public_key, signature) = rest[hello_len..].split_at(KEY_SHARE_LEN);
+    let public_key: [u8; KEY_SHARE_LEN] = public_key.try_into().map_err(|_| missing())?;
+
+    let signer = if signature.is_empty() {
+        None
+    } else {
+        let claimed = PeerId(
+            signature[..PUBLIC_KEY_LEN]
+                .try_into()
+                .map_err(|_| missing())?,
+        );
+        let transcript = transcript(
+            RESPONDER_INFO,
+            &ours.public_key,
+            &public_key,
+            signing_key.map(SigningKey::peer_id),
+            Some(claimed),
+        );
+        let mut signed = BytesMut::from(&transcript[..]);
+        signed.extend_from_slice(signature);
+        match signing::verify(&signed.freeze()) {
+            Some((_, signer)) if signer == claimed => Some(signer),
+            _ => return Err("invalid signature on key share".to_string()),
+        }
+    };
+
+    Ok((
+        hello.slice(..hello_len),
+        PeerShare {
+            public_key,
+            signer,
+            verified: true,
+        },
+    ))
+}
+
+// What each side signs: both key shares and both identities (zeroes for a side without one),
+// labelled with the signer's role so one side's signature can't be passed off as the other's.
+fn transcript(
+    role: &[u8],
+    initiator_share: &[u8; KEY_SHARE_LEN],
+    responder_share: &[u8; KEY_SHARE_LEN],
+    initiator: Option<PeerId>,
+    responder: Option<PeerId>,
+) -> Vec<u8> {
+    let identity = |id: Option<PeerId>| id.map_or([0; PUBLIC_KEY_LEN], |id| id.0);
+    [
+        TRANSCRIPT_LABEL,
+        role,
+        initiator_share,
+        responder_share,
+        &identity(initiator),
+        &identity(responder),
+    ]
+    .concat()
+}
+
+// Keys for encrypting messages on a connection.
+pub(crate) struct Session {
+    send_key: LessSafeKey,
+    recv_key: LessSafeKey,
+    send_counter: AtomicU64,
+    peer: Option<PeerId>,
+}
+
+impl Session {
+    // The identity the peer signed its key share with, if it did.
+    pub(crate) fn peer(&self) -> Option<PeerId> {
+        self.peer
+    }
+
+    // Encrypt `msg`, returning `None` if it's too large.
+    pub(crate) fn seal(&self, msg: &[u8]) -> Option<Bytes> {
+        let counter = self.send_counter.fetch_add(1, Ordering::Relaxed);
+        let mut payload = msg.to_vec();
+        self.send_key
+            .seal_in_place_append_tag(nonce(counter), Aad::empty(), &mut payload)
+            .ok()?;
+
+        let mut sealed = BytesMut::with_capacity(COUNTER_LEN + payload.len());
+        sealed.extend_from_slice(&counter.to_be_bytes());
+        sealed.extend_from_slice(&payload);
+        Some(sealed.freeze())
+    }
+
+    pub(crate) fn open(&self, sealed: &[u8]) -> Option<Bytes> {
+        if sealed.len() < COUNTER_LEN {
+            return None;
+        }
+        let (counter, payload) = sealed.split_at(COUNTER_LEN);
+        let counter = u64::from_be_bytes(counter.try_into().ok()?);
+
+        let mut payload = payload.to_vec();
+        let msg = self
+            .recv_key
+            .open_in_place(nonce(counter), Aad::empty(), &mut payload)
+            .ok()?;
+        Some(Bytes::copy_from_slice(msg))
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("peer", &self.peer)
+            .finish_non_exhaustive()
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; aead::NONCE_LEN];
+    nonce[aead::NONCE_LEN - COUNTER_LEN..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept_offer, accept_reply, KeyShare};
+    use crate::SigningKey;
+    use bytes::Bytes;
+    use color_eyre::eyre::{eyre, Result};
+
+    #[test]
+    fn key_agreement() -> Result<()> {
+        let signing_key = SigningKey::generate()?;
+        let initiator = KeyShare::generate().map_err(|e| eyre!(e))?;
+        let responder = KeyShare::generate().map_err(|e| eyre!(e))?;
+
+        let offer = initiator.offer(Bytes::from_static(b"hi"), Some(&signing_key));
+        let (hello, mut initiator_share) = accept_offer(&offer).map_err(|e| eyre!(e))?;
+        assert_eq!(&hello[..], b"hi");
+        assert!(initiator_share.awaits_confirmation());
+
+        let reply = responder.reply(Bytes::new(), &initiator_share, None);
+        let (hello, responder_share) =
+            accept_reply(&reply, &initiator, Some(&signing_key)).map_err(|e| eyre!(e))?;
+        assert!(hello.is_empty());
+
+        let confirmation = initiator
+            .confirm(&responder_share, Some(&signing_key))
+            .ok_or_else(|| eyre!("no confirmation"))?;
+        responder
+            .verify_confirmation(&mut initiator_share, &confirmation, None)
+            .map_err(|e| eyre!(e))?;
+
+        let initiator = initiator
+            .agree(responder_share, true)
+            .map_err(|e| eyre!(e))?;
+        let responder = responder
+            .agree(initiator_share, false)
+            .map_err(|e| eyre!(e))?;
+        assert_eq!(initiator.peer(), None);
+        assert_eq!(responder.peer(), Some(signing_key.peer_id()));
+
+        // each direction has its own key, and messages can be opened in any order
+        let first = initiator
+            .seal(b"first")
+            .ok_or_else(|| eyre!("failed to seal"))?;
+        let second = initiator
+            .seal(b"second")
+            .ok_or_else(|| eyre!("failed to seal"))?;
+        assert_eq!(responder.open(&second).as_deref(), Some(&b"second"[..]));
+        assert_eq!(responder.open(&first).as_deref(), Some(&b"first"[..]));
+        assert_eq!(initiator.open(&first), None);
+
+        let reply = responder
+            .seal(b"reply")
+            .ok_or_else(|| eyre!("failed to seal"))?;
+        assert_eq!(initiator.open(&reply).as_deref(), Some(&b"reply"[..]));
+
+        let mut tampered = reply.to_vec();
+        tampered[super::COUNTER_LEN] ^= 1;
+        assert_eq!(initiator.open(&tampered), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn substituted_key_shares() -> Result<()> {
+        let initiator_key = SigningKey::generate()?;
+        let responder_key = SigningKey::generate()?;
+        let initiator = KeyShare::generate().map_err(|e| eyre!(e))?;
+        let responder = KeyShare::generate().map_err(|e| eyre!(e))?;
+        let intermediary = KeyShare::generate().map_err(|e| eyre!(e))?;
+
+        // an intermediary passes on the initiator's offer with its own share, but the responder's
+        // signature covers the share it received, so the initiator rejects the reply
+        let offer = initiator.offer(Bytes::new(), Some(&initiator_key));
+        let (_, initiator_share) = accept_offer(&offer).map_err(|e| eyre!(e))?;
+        let substituted = intermediary.offer(Bytes::new(), Some(&initiator_key));
+        let (_, mut substituted_share) = accept_offer(&substituted).map_err(|e| eyre!(e))?;
+        let reply = responder.reply(Bytes::new(), &substituted_share, Some(&responder_key));
+        assert!(accept_reply(&reply, &initiator, Some(&initiator_key)).is_err());
+
+        // nor can it get the responder to trust its share under the initiator's identity, as the
+        // initiator's confirmation covers the share the initiator sent
+        let reply = responder.reply(Bytes::new(), &initiator_share, Some(&responder_key));
+        let (_, responder_share) =
+            accept_reply(&reply, &initiator, Some(&initiator_key)).map_err(|e| eyre!(e))?;
+        let confirmation = initiator
+            .confirm(&responder_share, Some(&initiator_key))
+            .ok_or_else(|| eyre!("no confirmation"))?;
+        assert!(responder
+            .verify_confirmation(&mut substituted_share, &confirmation, Some(&responder_key))
+            .is_err());
+        assert!(substituted_share.awaits_confirmation());
+
+        // a confirmation can't be passed off as a reply
+        let reflected = [
+            &intermediary.public_key[..],
+            &confirmation,
+            &[super::SIGNED],
+        ]
+        .concat();
+        assert!(accept_reply(&reflected.into(), &initiator, Some(&initiator_key)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn missing_key_share() -> Result<()> {
+        assert!(accept_offer(&Bytes::from_static(b"hello")).is_err());
+        assert!(accept_offer(&Bytes::new()).is_err());
+
+        let ours = KeyShare::generate().map_err(|e| eyre!(e))?;
+        assert!(accept_reply(&Bytes::from_static(b"hello"), &ours, None).is_err());
+        Ok(())
+    }
+}
@@ -7,27 +7,69 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+#[cfg(feature = "dht")]
+use super::dht::{Contact, Dht, NodeId, BUCKET_SIZE, LOOKUP_CONCURRENCY};
 #[cfg(feature = "igd")]
-use super::igd::{forward_port, IgdError};
+use super::port_mapping::{
+    default_backends, forward_port, PortMappingError, PortMappingEvents, PortMappingStatus,
+};
 use super::wire_msg::WireMsg;
 use super::{
-    config::{Config, InternalConfig, RetryConfig, SERVER_NAME},
-    connection::{Connection, ConnectionIncoming},
+    address_book::{AddressBook, AddressKind, PeerId},
+    buffer_pool::{BufferPool, BufferPoolStats},
+    builder::EndpointBuilder,
+    circuit_breaker::CircuitBreaker,
+    config::{
+        Config, ConfigError, InternalConfig, LiveTransport, PartialConfig, ServerTls, SocketConfig,
+        DEFAULT_MAX_BUFFERED_BYTES_PER_CONNECTION, SERVER_NAME,
+    },
+    connection::{Connection, ConnectionIncoming, ConnectionServices},
+    dedup::Dedup,
     error::{
-        ClientEndpointError, ConnectionError, EndpointError, RecvError, RpcError,
-        SerializationError,
+        ClientEndpointError, ConnectionError, EndpointError, PeerError, RecvError, RpcError,
+        SendError, SerializationError,
     },
+    hello,
+    inbox::{BufferUsage, InboxLimits},
+    observed::ObservedAddresses,
+    pause::IncomingPause,
+    peer_messages::PeerMessages,
+    peer_store::{self, PeerStore},
+    pex::{self, ExchangedPeer, Pex},
+    rate_limit::AcceptLimiter,
+    reachability::{self, Reachability},
+    reconnect::{ReconnectingConnection, ReconnectingIncoming},
+    registry::{ConnectionPath, ConnectionRegistry, Traffic},
+    resolver::{PeerAddrs, Resolver, ToPeerAddrs},
+    resumption::ResumptionStats,
+    retry::SharedRetryConfig,
+    scheduler::{QueueDepth, Scheduler},
+    scoring::{self, PeerEvent, PeerScoring, ScoredPeer},
+    socket, utils,
 };
-use futures::StreamExt;
+use bytes::Bytes;
+use futures::{future, StreamExt};
 use quinn::Endpoint as QuinnEndpoint;
+#[cfg(feature = "dht")]
+use std::collections::HashSet;
 use std::{
+    collections::HashMap,
+    future::Future,
     net::{IpAddr, SocketAddr},
-    sync::Arc,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
 };
 use tokio::sync::broadcast::{self, Sender};
 use tokio::sync::mpsc::{self, error::TryRecvError, Receiver as MpscReceiver};
-use tokio::time::{timeout, Duration};
-use tracing::{error, info, trace, warn};
+#[cfg(feature = "igd")]
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{error::Elapsed, timeout, Duration};
+use tracing::{debug, error, info, trace, warn};
 
 // Number of seconds before timing out the IGD request to forward a port.
 #[cfg(feature = "igd")]
@@ -36,9 +78,15 @@ const PORT_FORWARD_TIMEOUT: Duration = Duration::from_secs(30);
 // Number of seconds before timing out the echo service query.
 const ECHO_SERVICE_QUERY_TIMEOUT: Duration = Duration::from_secs(30);
 
+// How often to check whether peers have closed their connections during a shutdown.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
 /// Standard size of our channel bounds
 const STANDARD_CHANNEL_SIZE: usize = 10000;
 
+// The number of connected peers asked for addresses in each round of peer exchange.
+const PEER_EXCHANGE_FANOUT: usize = 3;
+
 /// Channel on which incoming connections are notified on
 #[derive(Debug)]
 pub struct IncomingConnections(pub(crate) MpscReceiver<(Connection, ConnectionIncoming)>);
@@ -63,11 +111,40 @@ pub struct Endpoint {
     local_addr: SocketAddr,
     public_addr: Option<SocketAddr>,
     quinn_endpoint: QuinnEndpoint,
-    retry_config: Arc<RetryConfig>,
+    secondary_endpoints: Vec<(SocketAddr, QuinnEndpoint)>,
+    // further endpoints sharing `local_addr` (see `Config::workers`)
+    workers: Vec<QuinnEndpoint>,
+    // endpoints to connect from in place of `quinn_endpoint`, if there are workers or outgoing
+    // sockets are configured (see `Config::outgoing_addr`)
+    outgoing_endpoints: Vec<QuinnEndpoint>,
+    next_outgoing: Arc<AtomicUsize>,
+    // connections dialed by `send_to`, held so they stay open for reuse
+    dialed: Arc<Mutex<HashMap<SocketAddr, DialSlot>>>,
+    // where connections dialed by `send_to` are delivered, once `pooled_incoming` is called
+    pooled_tx: Arc<Mutex<Option<PooledSender>>>,
+    retry_config: SharedRetryConfig,
+    connect_timeout: Option<Duration>,
+    transport: LiveTransport,
+    circuit_breaker: Arc<CircuitBreaker>,
+    server_tls: Option<ServerTls>,
+    services: ConnectionServices,
+    address_book: Arc<AddressBook>,
+    peer_store: Option<Arc<dyn PeerStore>>,
+    resolver: Arc<dyn Resolver>,
+    #[cfg(feature = "dht")]
+    dht: Arc<Dht>,
+    #[cfg(feature = "igd")]
+    port_mapping: Option<watch::Receiver<PortMappingStatus>>,
 
     termination_tx: Sender<()>,
 }
 
+// A connection held by `Endpoint::send_to`, locked while it's dialed.
+type DialSlot = Arc<tokio::sync::Mutex<Option<Connection>>>;
+
+// Where `Endpoint::pooled_incoming` receives the connections dialed by `send_to`.
+type PooledSender = mpsc::Sender<(Connection, ConnectionIncoming)>;
+
 impl std::fmt::Debug for Endpoint {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Endpoint")
@@ -79,10 +156,18 @@ impl std::fmt::Debug for Endpoint {
 }
 
 impl Endpoint {
+    /// Start building an endpoint.
+    ///
+    /// The builder can create peer, server, or client endpoints, and is an alternative to
+    /// [`new_peer`](Self::new_peer) and [`new_client`](Self::new_client) that names each setting.
+    pub fn builder() -> EndpointBuilder {
+        EndpointBuilder::default()
+    }
+
     /// Create a peer endpoint at the given address.
     ///
     /// A peer endpoint, unlike a [client](Self::new_client) endpoint, can receive incoming
-    /// connections.
+    /// connections. See also [`builder`](Self::builder).
     ///
     /// # Bootstrapping
     ///
@@ -94,15 +179,21 @@ impl Endpoint {
     /// check to validate that this endpoint can be reached at its
     /// [`public_addr`](Self::public_addr).
     ///
+    /// If the endpoint has a [`Config::peer_store`], the contacts it last bootstrapped against
+    /// successfully are tried as well.
+    ///
     /// **Note:** if no contacts are given, the [`public_addr`](Self::public_addr) of the endpoint
     /// will not have been validated to be reachable by anyone
     ///
     /// # Port forwarding (UPnP)
     ///
     /// If configured (via `config.forward_port`), an external port mapping will be set up (using
-    /// the IGD UPnP protocol). The established external port will be reflected in
-    /// [`public_addr`](Self::public_addr), and the lease will be renewed automatically every
-    /// `config.upnp_lease_duration`.
+    /// the IGD UPnP protocol, or else PCP or NAT-PMP). The established external port will be reflected in
+    /// [`public_addr`](Self::public_addr), and the lease will be renewed automatically before
+    /// `config.upnp_lease_duration` expires. If the mapping is lost (e.g. because the gateway
+    /// restarted), it will be restored. The state of the mapping can be monitored with
+    /// [`port_mapping_status`](Self::port_mapping_status) and
+    /// [`port_mapping_events`](Self::port_mapping_events).
     pub async fn new_peer(
         local_addr: impl Into<SocketAddr>,
         contacts: &[SocketAddr],
@@ -118,25 +209,180 @@ impl Endpoint {
         let config = InternalConfig::try_from_config(config)?;
         let local_addr = local_addr.into();
 
+        // bootstrap against previously successful contacts too
+        let stored = peer_store::load(config.peer_store.as_deref());
+        let mut contacts = contacts.to_vec();
+        for contact in &stored.bootstrap_contacts {
+            if !contacts.contains(contact) {
+                contacts.push(*contact);
+            }
+        }
+
         let (termination_tx, termination_rx) = broadcast::channel(1);
+        let scheduler = Scheduler::start(termination_tx.subscribe());
+        let connections = ConnectionRegistry::start(
+            config.max_connections,
+            config.peer_scoring.clone(),
+            config.critical_keep_alive_interval,
+            termination_tx.subscribe(),
+        );
 
-        let (mut quinn_endpoint, quinn_incoming) =
-            QuinnEndpoint::server(config.server.clone(), local_addr)?;
+        // workers share the port, so every one of their sockets needs `SO_REUSEPORT`
+        let listen_config = SocketConfig {
+            reuse_port: config.socket_config.reuse_port || config.workers > 1,
+            ..config.socket_config
+        };
+        let (mut quinn_endpoint, quinn_incoming) = socket::server(
+            local_addr,
+            config.endpoint.clone(),
+            config.server.clone(),
+            &listen_config,
+        )?;
 
         let quinn_endpoint_socket_addr = quinn_endpoint.local_addr()?;
 
         // set client config used for any outgoing connections
-        quinn_endpoint.set_default_client_config(config.client);
+        quinn_endpoint.set_default_client_config(config.client.clone());
+
+        let mut workers = Vec::new();
+        let mut worker_incoming = Vec::new();
+        if config.workers > 1 {
+            for _ in 1..config.workers {
+                let (quinn_endpoint, quinn_incoming) = socket::server(
+                    quinn_endpoint_socket_addr,
+                    config.endpoint.clone(),
+                    config.server.clone(),
+                    &listen_config,
+                )?;
+                workers.push(quinn_endpoint);
+                worker_incoming.push(quinn_incoming);
+            }
+            debug!(
+                "Started {} workers on {}",
+                config.workers, quinn_endpoint_socket_addr
+            );
+        }
+
+        // outgoing connections are made from the listening socket, unless other sockets are
+        // configured or workers share its port
+        let mut outgoing_endpoints = Vec::new();
+        if config.workers > 1 || config.outgoing_addr.is_some() || config.outgoing_ports.is_some() {
+            let outgoing_addr = config
+                .outgoing_addr
+                .unwrap_or_else(|| SocketAddr::new(quinn_endpoint_socket_addr.ip(), 0));
+            for _ in 0..config.workers {
+                let mut quinn_endpoint = socket::client_in_range(
+                    outgoing_addr,
+                    config.outgoing_ports,
+                    config.endpoint.clone(),
+                    &config.socket_config,
+                )?;
+                quinn_endpoint.set_default_client_config(config.client.clone());
+                outgoing_endpoints.push(quinn_endpoint);
+            }
+        }
+
+        let mut secondary_endpoints = Vec::new();
+        let mut secondary_incoming = Vec::new();
+        for addr in &config.additional_local_addrs {
+            let (mut quinn_endpoint, quinn_incoming) = socket::server(
+                *addr,
+                config.endpoint.clone(),
+                config.server.clone(),
+                &config.socket_config,
+            )?;
+            quinn_endpoint.set_default_client_config(config.client.clone());
+            secondary_endpoints.push((quinn_endpoint.local_addr()?, quinn_endpoint));
+            secondary_incoming.push(quinn_incoming);
+        }
 
+        #[cfg(feature = "dht")]
+        let dht = Arc::new(Dht::new(config.dht_node_id));
+
+        let address_book = Arc::new(AddressBook::new(stored.peers, config.peer_store.clone()));
+        let pex = Arc::new(Pex::new(config.peer_exchange, config.signing_key.clone()));
         let mut endpoint = Self {
             local_addr: quinn_endpoint_socket_addr,
             public_addr: None, // we'll set this below
             quinn_endpoint,
-            retry_config: config.retry_config,
+            secondary_endpoints,
+            workers,
+            outgoing_endpoints,
+            next_outgoing: Arc::default(),
+            dialed: Arc::default(),
+            pooled_tx: Arc::default(),
+            circuit_breaker: Arc::new(CircuitBreaker::new(&config.retry_config)),
+            retry_config: SharedRetryConfig::new(config.retry_config),
+            connect_timeout: config.connect_timeout,
+            transport: config.transport.clone(),
+            server_tls: Some(config.server_tls),
+            services: ConnectionServices {
+                peer_scoring: config.peer_scoring,
+                hello_provider: config.hello_provider,
+                peer_identifier: config.peer_identifier,
+                connection_observer: config.connection_observer,
+                message_sink: config.message_sink,
+                quic_versions: config.quic_versions.clone(),
+                interceptors: config.interceptors,
+                signing_key: config.signing_key,
+                e2e_encryption: config.e2e_encryption,
+                dedup: config
+                    .dedup_window
+                    .map(|window| Arc::new(Dedup::new(window))),
+                message_ordering: config.message_ordering,
+                inbox: Arc::new(InboxLimits::new(
+                    config
+                        .max_buffered_bytes_per_connection
+                        .unwrap_or(DEFAULT_MAX_BUFFERED_BYTES_PER_CONNECTION),
+                    config.max_buffered_bytes,
+                    config.buffer_overflow,
+                )),
+                max_concurrent_sends: config.max_concurrent_sends,
+                message_split_threshold: config.message_split_threshold,
+                message_split_streams: config.message_split_streams,
+                stream_open_timeout: config.stream_open_timeout,
+                handshake_timeout: config.handshake_timeout,
+                slow_stream_threshold: config.slow_stream_threshold,
+                endpoint_verification_timeout: config.endpoint_verification_timeout,
+                heartbeat_interval: config.heartbeat_interval,
+                raw_streams: config.raw_streams,
+                scheduler: Some(scheduler),
+                connections: Some(connections),
+                observations: Some(Arc::default()),
+                address_book: Some(address_book.clone()),
+                accept_limiter: Some(Arc::new(AcceptLimiter::new(
+                    config.accept_rate,
+                    config.accept_rate_per_ip,
+                ))),
+                pause: IncomingPause::default(),
+                peer_router: Arc::default(),
+                transport: Some(config.transport),
+                pex: Some(pex.clone()),
+                buffer_pool: Arc::new(BufferPool::new(
+                    config.buffer_pool_size,
+                    config.max_pooled_buffer_size,
+                )),
+                #[cfg(feature = "dht")]
+                dht: Some(dht.clone()),
+            },
+            address_book,
+            peer_store: config.peer_store.clone(),
+            resolver: config.resolver,
+            #[cfg(feature = "dht")]
+            dht,
+            #[cfg(feature = "igd")]
+            port_mapping: None,
             termination_tx,
         };
 
-        let contact = endpoint.connect_to_any(contacts).await;
+        let contact = endpoint.connect_to_any(&contacts).await;
+        if let (Some(store), Some((contact, _))) = (&config.peer_store, &contact) {
+            peer_store::remember_contact(
+                store.as_ref(),
+                &stored.bootstrap_contacts,
+                contact.remote_address(),
+            );
+        }
 
         let public_addr = endpoint
             .resolve_public_addr(
@@ -147,20 +393,23 @@ impl Endpoint {
             .await?;
 
         endpoint.public_addr = Some(public_addr);
+        pex.set_public_addr(public_addr);
 
         #[cfg(feature = "igd")]
         if config.forward_port {
-            timeout(
+            let port_mapping = timeout(
                 PORT_FORWARD_TIMEOUT,
                 forward_port(
                     public_addr.port(),
                     endpoint.local_addr(),
                     config.upnp_lease_duration,
+                    default_backends(config.port_mapping_gateway),
                     termination_rx,
                 ),
             )
             .await
-            .map_err(|_| IgdError::TimedOut)??;
+            .map_err(|_| PortMappingError::TimedOut)??;
+            endpoint.port_mapping = Some(port_mapping);
         }
 
         #[cfg(not(feature = "igd"))]
@@ -170,10 +419,32 @@ impl Endpoint {
 
         listen_for_incoming_connections(
             quinn_incoming,
-            connection_tx,
+            connection_tx.clone(),
             endpoint.quinn_endpoint.clone(),
             endpoint.retry_config.clone(),
+            endpoint.services.clone(),
         );
+        for ((_, quinn_endpoint), quinn_incoming) in
+            endpoint.secondary_endpoints.iter().zip(secondary_incoming)
+        {
+            listen_for_incoming_connections(
+                quinn_incoming,
+                connection_tx.clone(),
+                quinn_endpoint.clone(),
+                endpoint.retry_config.clone(),
+                endpoint.services.clone(),
+            );
+        }
+        for (quinn_endpoint, quinn_incoming) in endpoint.workers.iter().zip(worker_incoming) {
+            listen_for_incoming_connections(
+                quinn_incoming,
+                connection_tx.clone(),
+                quinn_endpoint.clone(),
+                endpoint.retry_config.clone(),
+                endpoint.services.clone(),
+            );
+        }
+        drop(connection_tx);
 
         if let Some((contact, _)) = contact.as_ref() {
             let valid = endpoint
@@ -188,6 +459,17 @@ impl Endpoint {
             }
         }
 
+        #[cfg(feature = "dht")]
+        if !contacts.is_empty() {
+            let endpoint = endpoint.clone();
+            let contacts = contacts.to_vec();
+            let _ = tokio::spawn(async move { endpoint.dht_bootstrap(&contacts).await });
+        }
+
+        if pex.enabled() {
+            endpoint.start_peer_exchange(config.peer_exchange_interval);
+        }
+
         Ok((endpoint, IncomingConnections(connection_rx), contact))
     }
 
@@ -196,6 +478,11 @@ impl Endpoint {
     /// A client endpoint cannot receive incoming connections, as such they also do not need to be
     /// publicly reachable. They can still communicate over outgoing connections and receive
     /// incoming streams, since QUIC allows for either side of a connection to initiate streams.
+    ///
+    /// Streams and messages on connections opened by [`connect_to`](Self::connect_to) arrive on
+    /// the [`ConnectionIncoming`] it returns. For connections the endpoint opens itself, e.g. by
+    /// [`send_to`](Self::send_to) or [`preconnect`](Self::preconnect), use
+    /// [`pooled_incoming`](Self::pooled_incoming).
     pub fn new_client(
         local_addr: impl Into<SocketAddr>,
         config: Config,
@@ -203,24 +490,118 @@ impl Endpoint {
         let config = InternalConfig::try_from_config(config)?;
 
         let (termination_tx, _termination_rx) = broadcast::channel(1);
+        let scheduler = Scheduler::start(termination_tx.subscribe());
+        let connections = ConnectionRegistry::start(
+            config.max_connections,
+            config.peer_scoring.clone(),
+            config.critical_keep_alive_interval,
+            termination_tx.subscribe(),
+        );
 
         let local_addr = local_addr.into();
+        let stored = peer_store::load(config.peer_store.as_deref());
 
-        let mut quinn_endpoint = QuinnEndpoint::client(local_addr)?;
+        let mut quinn_endpoint = socket::client_in_range(
+            local_addr,
+            config.outgoing_ports,
+            config.endpoint.clone(),
+            &config.socket_config,
+        )?;
 
         // retrieve the actual used socket addr
         let local_quinn_socket_addr = quinn_endpoint.local_addr()?;
 
-        quinn_endpoint.set_default_client_config(config.client);
+        quinn_endpoint.set_default_client_config(config.client.clone());
+
+        let mut secondary_endpoints = Vec::new();
+        for addr in &config.additional_local_addrs {
+            let mut quinn_endpoint =
+                socket::client(*addr, config.endpoint.clone(), &config.socket_config)?;
+            quinn_endpoint.set_default_client_config(config.client.clone());
+            secondary_endpoints.push((quinn_endpoint.local_addr()?, quinn_endpoint));
+        }
+
+        #[cfg(feature = "dht")]
+        let dht = Arc::new(Dht::new(config.dht_node_id));
 
+        let address_book = Arc::new(AddressBook::new(stored.peers, config.peer_store.clone()));
+        let pex = Arc::new(Pex::new(config.peer_exchange, config.signing_key.clone()));
         let endpoint = Self {
             local_addr: local_quinn_socket_addr,
             public_addr: None, // we're a client
             quinn_endpoint,
-            retry_config: config.retry_config,
+            secondary_endpoints,
+            workers: Vec::new(),
+            outgoing_endpoints: Vec::new(),
+            next_outgoing: Arc::default(),
+            dialed: Arc::default(),
+            pooled_tx: Arc::default(),
+            circuit_breaker: Arc::new(CircuitBreaker::new(&config.retry_config)),
+            retry_config: SharedRetryConfig::new(config.retry_config),
+            connect_timeout: config.connect_timeout,
+            transport: config.transport.clone(),
+            server_tls: None,
+            services: ConnectionServices {
+                peer_scoring: config.peer_scoring,
+                hello_provider: config.hello_provider,
+                peer_identifier: config.peer_identifier,
+                connection_observer: config.connection_observer,
+                message_sink: config.message_sink,
+                quic_versions: config.quic_versions.clone(),
+                interceptors: config.interceptors,
+                signing_key: config.signing_key,
+                e2e_encryption: config.e2e_encryption,
+                dedup: config
+                    .dedup_window
+                    .map(|window| Arc::new(Dedup::new(window))),
+                message_ordering: config.message_ordering,
+                inbox: Arc::new(InboxLimits::new(
+                    config
+                        .max_buffered_bytes_per_connection
+                        .unwrap_or(DEFAULT_MAX_BUFFERED_BYTES_PER_CONNECTION),
+                    config.max_buffered_bytes,
+                    config.buffer_overflow,
+                )),
+                max_concurrent_sends: config.max_concurrent_sends,
+                message_split_threshold: config.message_split_threshold,
+                message_split_streams: config.message_split_streams,
+                stream_open_timeout: config.stream_open_timeout,
+                handshake_timeout: config.handshake_timeout,
+                slow_stream_threshold: config.slow_stream_threshold,
+                endpoint_verification_timeout: config.endpoint_verification_timeout,
+                heartbeat_interval: config.heartbeat_interval,
+                raw_streams: config.raw_streams,
+                scheduler: Some(scheduler),
+                connections: Some(connections),
+                observations: Some(Arc::default()),
+                address_book: Some(address_book.clone()),
+                // clients don't accept connections
+                accept_limiter: None,
+                pause: IncomingPause::default(),
+                peer_router: Arc::default(),
+                transport: Some(config.transport),
+                pex: Some(pex.clone()),
+                buffer_pool: Arc::new(BufferPool::new(
+                    config.buffer_pool_size,
+                    config.max_pooled_buffer_size,
+                )),
+                #[cfg(feature = "dht")]
+                dht: Some(dht.clone()),
+            },
+            address_book,
+            peer_store: config.peer_store.clone(),
+            resolver: config.resolver,
+            #[cfg(feature = "dht")]
+            dht,
+            #[cfg(feature = "igd")]
+            port_mapping: None,
             termination_tx,
         };
 
+        if pex.enabled() {
+            endpoint.start_peer_exchange(config.peer_exchange_interval);
+        }
+
         Ok(endpoint)
     }
 
@@ -229,16 +610,102 @@ impl Endpoint {
         self.local_addr
     }
 
+    /// The local addresses of all the endpoint's sockets.
+    ///
+    /// The first address is the one given to the constructor (i.e. [`local_addr`](Self::local_addr)),
+    /// followed by any [`Config::additional_local_addrs`].
+    ///
+    /// Outgoing connections are made from the first socket with the same IP version as the peer,
+    /// preferring sockets bound to a loopback address for loopback peers, and to other addresses
+    /// otherwise.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        std::iter::once(self.local_addr)
+            .chain(self.secondary_endpoints.iter().map(|(addr, _)| *addr))
+            .collect()
+    }
+
+    /// The local addresses outgoing connections are made from, for peers whose address matches
+    /// [`local_addr`](Self::local_addr).
+    ///
+    /// This is just `local_addr`, unless there are [workers](crate::Config::workers) or an
+    /// [outgoing address](crate::Config::outgoing_addr) or
+    /// [port range](crate::Config::outgoing_ports) is configured, in which case it's the
+    /// addresses of the sockets bound for outgoing connections, used in turn.
+    pub fn outgoing_addrs(&self) -> Vec<SocketAddr> {
+        if self.outgoing_endpoints.is_empty() {
+            return vec![self.local_addr];
+        }
+        self.outgoing_endpoints
+            .iter()
+            .filter_map(|quinn_endpoint| quinn_endpoint.local_addr().ok())
+            .collect()
+    }
+
     /// Get the public address of the endpoint.
     pub fn public_addr(&self) -> SocketAddr {
         self.public_addr.unwrap_or(self.local_addr)
     }
 
+    /// The addresses other peers currently observe for the endpoint.
+    ///
+    /// Unlike [`public_addr`](Self::public_addr), which is determined when the endpoint is
+    /// created, this is updated as connections are made, so it can be used to detect a change of
+    /// address (e.g. a NAT rebinding) at runtime. See [`ObservedAddresses::consensus`].
+    pub fn observed_addresses(&self) -> ObservedAddresses {
+        self.services
+            .observations
+            .as_ref()
+            .map(|observations| observations.snapshot())
+            .unwrap_or_default()
+    }
+
+    /// The current state of the endpoint's UPnP port mapping.
+    ///
+    /// Returns `None` if port forwarding was not configured (via `config.forward_port`). Note that
+    /// [`public_addr`](Self::public_addr) is not updated if the gateway's external address changes.
+    #[cfg(feature = "igd")]
+    pub fn port_mapping_status(&self) -> Option<PortMappingStatus> {
+        self.port_mapping
+            .as_ref()
+            .map(|status| status.borrow().clone())
+    }
+
+    /// Subscribe to changes to the endpoint's UPnP port mapping, such as the external address
+    /// changing or the mapping being lost.
+    ///
+    /// Returns `None` if port forwarding was not configured (via `config.forward_port`).
+    #[cfg(feature = "igd")]
+    pub fn port_mapping_events(&self) -> Option<PortMappingEvents> {
+        self.port_mapping.clone().map(PortMappingEvents)
+    }
+
+    /// Get the current score of a peer, as reported by the configured
+    /// [`PeerScoring`](crate::PeerScoring).
+    ///
+    /// The peer's identity is passed to the scoring too, if there's an open connection to
+    /// `peer_addr` that identified it (see [`Connection::peer_id`]).
+    ///
+    /// Returns `None` if no scoring was configured for this endpoint.
+    pub fn peer_score(&self, peer_addr: &SocketAddr) -> Option<i64> {
+        let scoring = self.services.peer_scoring.as_ref()?;
+        let id = self
+            .get_connection_by_addr(peer_addr)
+            .and_then(|connection| connection.peer_id());
+        Some(scoring.score(&ScoredPeer {
+            addr: *peer_addr,
+            id,
+        }))
+    }
+
     /// Connect to a peer.
     ///
     /// Atttempts to connect to a peer at the given address. Connection attempts are retried based
     /// on the [`Config::retry_config`] used to create the endpoint.
     ///
+    /// The address may be a socket address or a host name with a port (see [`ToPeerAddrs`]). Host
+    /// names are resolved with the endpoint's [`Config::resolver`], and every resolved address is
+    /// tried in turn.
+    ///
     /// Returns a [`Connection`], which is a handle representing the underlying connection. There's
     /// presently little that you can do with a `Connection` itself, besides obtaining the
     /// [`remote_address`](Connection::remote_address) which can then be used with other `Endpoint`
@@ -251,15 +718,62 @@ impl Endpoint {
     ///
     /// # Connection pooling
     ///
-    /// Connections are stored in an internal pool and reused if possible. A connection remains in
-    /// the pool until either side closes the connection (including due to timeouts or errors). This
-    /// method will check the pool before opening a new connection. If a new connection is opened,
-    /// it will be added to the pool.
+    /// Every connection, whether opened by this endpoint or the peer, is stored in an internal pool
+    /// and reused if possible, e.g. by [`send_to`](Self::send_to). A connection remains in the
+    /// pool until either side closes the connection (including due to timeouts or errors). Since
+    /// the returned [`ConnectionIncoming`] receives everything the peer sends on the connection,
+    /// this method always opens a new connection, which is added to the pool.
+    ///
+    /// If [`Config::connect_timeout`] is set, the whole process (including resolving the peer's
+    /// address and retries) is abandoned after that long.
     pub async fn connect_to(
         &self,
-        node_addr: &SocketAddr,
+        peer: impl ToPeerAddrs,
     ) -> Result<(Connection, ConnectionIncoming), ConnectionError> {
-        self.new_connection(node_addr).await
+        self.within_connect_timeout(self.connect_to_addrs(peer))
+            .await
+    }
+
+    async fn connect_to_addrs(
+        &self,
+        peer: impl ToPeerAddrs,
+    ) -> Result<(Connection, ConnectionIncoming), ConnectionError> {
+        let addrs = match peer.to_peer_addrs()? {
+            PeerAddrs::Resolved(addrs) => addrs,
+            PeerAddrs::Unresolved { host, port } => self
+                .resolver
+                .resolve(&host, port)
+                .await
+                .map_err(|error| ConnectionError::Resolve(format!("{}: {}", host, error)))?,
+        };
+
+        match addrs.as_slice() {
+            [] => Err(ConnectionError::Resolve(
+                "no addresses to connect to".to_string(),
+            )),
+            [node_addr] => self.new_connection(node_addr).await,
+            addrs => self
+                .connect_to_first(addrs)
+                .await
+                .map(|(_, connection)| connection),
+        }
+    }
+
+    /// Connect to a peer, returning a connection that re-dials the peer if it's lost.
+    ///
+    /// The connection is made as with [`connect_to`](Self::connect_to), and so are reconnections
+    /// (retrying according to the endpoint's [`Config::retry_config`]). See
+    /// [`ReconnectingConnection`] for details.
+    pub async fn connect_reconnecting(
+        &self,
+        peer: impl ToPeerAddrs,
+    ) -> Result<(ReconnectingConnection, ReconnectingIncoming), ConnectionError> {
+        let (connection, incoming) = self.connect_to(peer).await?;
+        Ok(ReconnectingConnection::new(
+            self.clone(),
+            connection,
+            incoming,
+        ))
     }
 
     /// Connect to any of the given peers.
@@ -291,7 +805,7 @@ impl Endpoint {
             .iter()
             .map(|addr| Box::pin(self.new_connection(addr)));
 
-        match futures::future::select_ok(tasks).await {
+        match future::select_ok(tasks).await {
             Ok((connection, _)) => Some(connection),
             Err(error) => {
                 error!("Failed to bootstrap to the network, last error: {}", error);
@@ -300,6 +814,493 @@ impl Endpoint {
         }
     }
 
+    /// Receive the messages from `addr` separately from other peers' messages.
+    ///
+    /// For as long as the returned receiver exists, user messages received from `addr` on any
+    /// connection are delivered to it instead of the connection's [`ConnectionIncoming`]. This
+    /// suits applications that handle each peer in a task of its own, since messages from the peer
+    /// stay in the order they were received, without a single consumer dispatching them. Calling
+    /// this again for the same address replaces the previous receiver.
+    pub fn messages_from(&self, addr: SocketAddr) -> PeerMessages {
+        self.services.peer_router.subscribe(addr)
+    }
+
+    /// Send a message to `addr`, connecting to it first if there's no open connection.
+    ///
+    /// An open connection to `addr` (whether it was opened by us or the peer) is reused if there is
+    /// one. Otherwise, a new connection is made as with [`connect_to`](Self::connect_to), and kept
+    /// open for later sends. Concurrent sends to the same address share a single new connection.
+    ///
+    /// Since the [`ConnectionIncoming`] of a connection opened by this method isn't returned, any
+    /// messages the peer sends on it are discarded, unless they're received with
+    /// [`pooled_incoming`](Self::pooled_incoming) or [`messages_from`](Self::messages_from).
+    pub async fn send_to(&self, addr: &SocketAddr, msg: Bytes) -> Result<(), SendError> {
+        let connection = self.pooled_connection(addr).await?;
+        connection.send(msg).await.map_err(PeerError::into_inner)
+    }
+
+    /// Ask the peer at `addr` for the peer addresses it knows, connecting to it first if there's
+    /// no open connection.
+    ///
+    /// Peers share the addresses they dialed themselves, and addresses other peers signed with
+    /// their [`Config::signing_key`](crate::Config::signing_key). The addresses are added to the
+    /// bootstrap contacts of the [`Config::peer_store`](crate::Config::peer_store) (after the
+    /// contacts that were bootstrapped against), so they're tried when the endpoint is next
+    /// created. Signed addresses are also added to the [`AddressBook`] under their signer's
+    /// [`PeerId`].
+    ///
+    /// Only peers with [`Config::peer_exchange`](crate::Config::peer_exchange) enabled share
+    /// addresses, and they answer at most one request per connection every 30 seconds. Other
+    /// requests are answered with no addresses.
+    pub async fn exchange_peers(&self, addr: &SocketAddr) -> Result<Vec<ExchangedPeer>, RpcError> {
+        let connection = self.pooled_connection(addr).await?;
+        self.request_peers(&connection).await
+    }
+
+    // Perform the peer exchange RPC on `connection`, remembering the peers it returns.
+    async fn request_peers(&self, connection: &Connection) -> Result<Vec<ExchangedPeer>, RpcError> {
+        let peer_addr = connection.remote_address();
+        let (mut send, mut recv) = connection.open_bi().await.map_err(PeerError::into_inner)?;
+        send.send_wire_msg(WireMsg::PexReq).await?;
+
+        let records = match self.timeout_rpc(connection, recv.next_wire_msg()).await?? {
+            Some(WireMsg::PexResp(records)) => records,
+            msg => {
+                return Err(RecvError::Serialization(SerializationError::unexpected(&msg)).into())
+            }
+        };
+        let peers = match &self.services.pex {
+            Some(pex) => pex.receive(records),
+            None => Vec::new(),
+        };
+        trace!("Learned {} peers from {}", peers.len(), peer_addr);
+
+        for peer in &peers {
+            if let Some(peer_id) = peer.peer_id {
+                self.address_book
+                    .insert(peer_id, peer.addr, peer.address_kind());
+            }
+        }
+        if let Some(store) = &self.peer_store {
+            let addrs: Vec<_> = peers.iter().map(|peer| peer.addr).collect();
+            peer_store::add_contacts(store.as_ref(), &addrs);
+        }
+
+        Ok(peers)
+    }
+
+    // Ask a few random connected peers for addresses every `interval`, until the endpoint is
+    // closed.
+    fn start_peer_exchange(&self, interval: Duration) {
+        let endpoint = self.clone();
+        let interval = interval.max(pex::MIN_REQUEST_INTERVAL);
+        let mut termination_rx = self.termination_tx.subscribe();
+
+        let exchange = async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                let _ = ticks.tick().await;
+                let mut connections = endpoint
+                    .services
+                    .connections
+                    .as_ref()
+                    .map(|connections| connections.all())
+                    .unwrap_or_default();
+                utils::shuffle(&mut connections);
+
+                let requests = connections
+                    .iter()
+                    .take(PEER_EXCHANGE_FANOUT)
+                    .map(|connection| endpoint.request_peers(connection));
+                for result in future::join_all(requests).await {
+                    if let Err(error) = result {
+                        debug!("Peer exchange failed: {}", error);
+                    }
+                }
+            }
+        };
+
+        let _ = tokio::spawn(async move {
+            let _ = future::select(Box::pin(exchange), Box::pin(termination_rx.recv())).await;
+        });
+    }
+
+    /// Receive the connections this endpoint opens for [`send_to`](Self::send_to),
+    /// [`preconnect`](Self::preconnect) and its other pooled dials, with their
+    /// [`ConnectionIncoming`].
+    ///
+    /// This gives an endpoint the same [`IncomingConnections`] stream for the connections it
+    /// dials as [`new_peer`](Self::new_peer) gives for the connections it accepts, so a
+    /// [client endpoint](Self::new_client) can receive the streams and messages peers send on
+    /// them. Only connections opened after this is called are delivered. Calling it again ends
+    /// the stream returned previously.
+    ///
+    /// Dials are not held back for a slow receiver: once 10,000 connections are waiting to be
+    /// received, the `ConnectionIncoming` of further connections is dropped, as it would be
+    /// without a receiver.
+    pub fn pooled_incoming(&self) -> IncomingConnections {
+        let (tx, rx) = mpsc::channel(STANDARD_CHANNEL_SIZE);
+        *self
+            .pooled_tx
+            .lock()
+            .unwrap_or_else(|error| error.into_inner()) = Some(tx);
+        IncomingConnections(rx)
+    }
+
+    // Deliver a connection dialed by `pooled_connection` to the `pooled_incoming` receiver, if
+    // there is one.
+    fn deliver_pooled(&self, connection: Connection, incoming: ConnectionIncoming) {
+        let mut pooled_tx = self
+            .pooled_tx
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        let tx = match pooled_tx.as_ref() {
+            Some(tx) => tx,
+            None => return,
+        };
+        match tx.try_send((connection, incoming)) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                warn!("Dropping incoming messages of a pooled connection: receiver is full");
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => *pooled_tx = None,
+        }
+    }
+
+    /// Connect to each of `addrs` in the background, so that later sends to them don't wait for a
+    /// handshake.
+    ///
+    /// The peers are dialed concurrently, as by [`send_to`](Self::send_to): addresses with an open
+    /// connection are skipped, dials already in progress are shared rather than repeated, and new
+    /// connections are held open for reuse by `send_to` and
+    /// [`get_connection_by_addr`](Self::get_connection_by_addr). Failures are only logged, since
+    /// the connection will be attempted again when it's needed.
+    ///
+    /// So as not to evict connections in use, no further peers are dialed once the endpoint has
+    /// [`Config::max_connections`](crate::Config::max_connections).
+    ///
+    /// The returned handle resolves to the number of peers connected, for callers that want to
+    /// wait for the warm-up. Dropping it leaves the dials running.
+    pub fn preconnect(&self, addrs: &[SocketAddr]) -> JoinHandle<usize> {
+        let endpoint = self.clone();
+        let addrs = addrs.to_vec();
+        tokio::spawn(async move {
+            let dials = addrs.iter().map(|addr| endpoint.preconnect_one(addr));
+            let connected = future::join_all(dials).await;
+            connected.into_iter().filter(|connected| *connected).count()
+        })
+    }
+
+    // Connect to `addr` for `preconnect`, returning whether there's a connection.
+    async fn preconnect_one(&self, addr: &SocketAddr) -> bool {
+        if self.get_connection_by_addr(addr).is_some() {
+            return true;
+        }
+        let full = self
+            .services
+            .connections
+            .as_ref()
+            .is_some_and(|connections| connections.is_full());
+        if full {
+            debug!("Not preconnecting to {}: at the connection limit", addr);
+            return false;
+        }
+
+        match self.pooled_connection(addr).await {
+            Ok(_) => {
+                trace!("Preconnected to {}", addr);
+                true
+            }
+            Err(error) => {
+                debug!("Failed to preconnect to {}: {}", addr, error);
+                false
+            }
+        }
+    }
+
+    /// The number of messages waiting in the outgoing message queue.
+    ///
+    /// See [`Connection::send_queued`].
+    pub fn queue_depth(&self) -> QueueDepth {
+        self.services
+            .scheduler
+            .as_ref()
+            .map(|scheduler| scheduler.depth())
+            .unwrap_or_default()
+    }
+
+    /// The incoming messages buffered across all connections, until the application receives them.
+    ///
+    /// See [`Config::max_buffered_bytes`](crate::Config::max_buffered_bytes), and
+    /// [`ConnectionIncoming::buffered`] for a single connection's buffer.
+    pub fn incoming_buffer(&self) -> BufferUsage {
+        self.services.inbox.usage()
+    }
+
+    /// Counts of the buffers the endpoint's connections have used to encode messages.
+    ///
+    /// See [`Config::buffer_pool_size`](crate::Config::buffer_pool_size) for how buffers are
+    /// pooled.
+    pub fn buffer_pool_stats(&self) -> BufferPoolStats {
+        self.services.buffer_pool.stats()
+    }
+
+    /// Get an open connection to `addr`, if there is one.
+    ///
+    /// Connections are tracked for as long as they're open and there is a [`Connection`] handle to
+    /// them outside of the endpoint. If there are several connections to `addr`, the most recently
+    /// established is returned.
+    pub fn get_connection_by_addr(&self, addr: &SocketAddr) -> Option<Connection> {
+        self.services.connections.as_ref()?.get_by_addr(addr)
+    }
+
+    /// Get an open connection to the peer with the given identity, if there is one.
+    ///
+    /// Connections are identified by the endpoint's
+    /// [`Config::peer_identifier`](crate::Config::peer_identifier), so this finds a peer's
+    /// connection regardless of the address it connected from. If there are several connections
+    /// to the peer, the one with the best [path](Connection::path) is returned, and then the most
+    /// recently established.
+    pub fn get_connection_by_peer(&self, peer: &PeerId) -> Option<Connection> {
+        self.services.connections.as_ref()?.get_by_peer(peer)
+    }
+
+    /// Get every open connection to the peer with the given identity, in the order
+    /// [`get_connection_by_peer`](Self::get_connection_by_peer) prefers them.
+    ///
+    /// A peer may be connected over several paths at once, e.g. via a relay while a direct
+    /// connection is being established.
+    pub fn get_connections_by_peer(&self, peer: &PeerId) -> Vec<Connection> {
+        self.services
+            .connections
+            .as_ref()
+            .map(|connections| connections.all_for_peer(peer))
+            .unwrap_or_default()
+    }
+
+    /// Try to replace a relayed connection to a peer with a direct one.
+    ///
+    /// If the peer has no [direct](ConnectionPath::Direct) connection, each of its non-relayed
+    /// addresses in the [`address_book`](Self::address_book) is tried once, without retries, e.g.
+    /// after arranging a hole punch with the peer via the relay. Returns the new connection, or
+    /// `None` if there already was a direct connection.
+    ///
+    /// Once a direct connection to a peer is established (by this method, or by the peer
+    /// connecting to this endpoint), the peer's relayed connections are closed, so the direct
+    /// connection is used from then on. This relies on both connections being identified as the
+    /// same peer, by [`Config::peer_identifier`](crate::Config::peer_identifier) or
+    /// [`Config::e2e_encryption`](crate::Config::e2e_encryption).
+    ///
+    /// As with [`connect_to_peer`](Self::connect_to_peer), end-to-end encryption requires the peer
+    /// to sign its key share as `peer`.
+    ///
+    /// Returns [`ConnectionError::UnknownPeer`] if the peer has no known direct addresses.
+    pub async fn upgrade_to_direct(
+        &self,
+        peer: &PeerId,
+    ) -> Result<Option<(Connection, ConnectionIncoming)>, ConnectionError> {
+        if self
+            .get_connection_by_peer(peer)
+            .is_some_and(|connection| connection.path() == ConnectionPath::Direct)
+        {
+            return Ok(None);
+        }
+
+        let mut last_error = ConnectionError::UnknownPeer(*peer);
+        for address in self.address_book.addresses(peer) {
+            if address.kind == AddressKind::Relayed {
+                continue;
+            }
+            match self
+                .within_connect_timeout(self.attempt_connection(&address.addr))
+                .await
+                .and_then(|connection| {
+                    self.check_e2e_signer(&connection.0, peer)?;
+                    Ok(connection)
+                }) {
+                Ok(connection) => {
+                    self.address_book.mark_successful(peer, &address.addr);
+                    return Ok(Some(connection));
+                }
+                Err(error) => {
+                    trace!("Failed to connect directly to {}: {}", address.addr, error);
+                    last_error = error;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Get the open connection with the given [`id`](Connection::id), if there is one.
+    ///
+    /// See [`get_connection_by_addr`](Self::get_connection_by_addr) for which connections are
+    /// tracked.
+    pub fn get_connection_by_id(&self, id: usize) -> Option<Connection> {
+        self.services.connections.as_ref()?.get(id)
+    }
+
+    /// A snapshot of the endpoint's open connections, e.g. to send a message to every connected
+    /// peer.
+    ///
+    /// Metadata about each connection is available from [`Connection::info`]. See
+    /// [`get_connection_by_addr`](Self::get_connection_by_addr) for which connections are
+    /// tracked.
+    pub fn connections(&self) -> Vec<Connection> {
+        self.services
+            .connections
+            .as_ref()
+            .map(|connections| connections.all())
+            .unwrap_or_default()
+    }
+
+    /// The number of bytes exchanged with `addr` since the totals were last
+    /// [reset](Self::reset_traffic).
+    ///
+    /// Unlike [`Connection::info`], this counts every connection with the peer, including those
+    /// that have since closed, so it is not affected by reconnections.
+    pub fn traffic_for(&self, addr: &SocketAddr) -> Traffic {
+        self.services
+            .connections
+            .as_ref()
+            .map(|connections| connections.traffic_for(addr))
+            .unwrap_or_default()
+    }
+
+    /// The number of bytes exchanged with every peer since the totals were last
+    /// [reset](Self::reset_traffic).
+    pub fn traffic(&self) -> HashMap<SocketAddr, Traffic> {
+        self.services
+            .connections
+            .as_ref()
+            .map(|connections| connections.traffic())
+            .unwrap_or_default()
+    }
+
+    /// Reset the traffic totals, returning the totals until now.
+    ///
+    /// Totals are kept for every peer the endpoint has exchanged data with, so this should be
+    /// called periodically (e.g. at the end of each accounting period) to bound their memory use.
+    pub fn reset_traffic(&self) -> HashMap<SocketAddr, Traffic> {
+        self.services
+            .connections
+            .as_ref()
+            .map(|connections| connections.reset_traffic())
+            .unwrap_or_default()
+    }
+
+    /// Check whether other peers can connect to this endpoint.
+    ///
+    /// Each of `peers` is asked which address our connection to it came from, and to connect back to
+    /// our [`public_addr`](Self::public_addr) (as is done when a peer endpoint is created with
+    /// contacts). If no peer can connect back, the addresses they saw are used to estimate the type
+    /// of NAT the endpoint is behind, which is more accurate with more peers.
+    ///
+    /// Open connections to `peers` are reused, and new ones are held open for reuse, as by
+    /// [`send_to`](Self::send_to). If none of `peers` could be queried, the last error is returned.
+    pub async fn check_reachability(&self, peers: &[SocketAddr]) -> Result<Reachability, RpcError> {
+        let public_addr = self.public_addr();
+        let results = future::join_all(peers.iter().map(|peer| async move {
+            let connection = self.pooled_connection(peer).await?;
+            let observed_addr = self.endpoint_echo(&connection).await?;
+            let verified = self.endpoint_verification(&connection, public_addr).await?;
+            Ok::<_, RpcError>((observed_addr, verified))
+        }))
+        .await;
+
+        let mut observed_addrs = Vec::new();
+        let mut verified = false;
+        let mut last_error = None;
+        for (peer, result) in peers.iter().zip(results) {
+            match result {
+                Ok((observed_addr, peer_verified)) => {
+                    observed_addrs.push(observed_addr);
+                    verified |= peer_verified;
+                }
+                Err(error) => {
+                    warn!("Failed to check reachability with {}: {}", peer, error);
+                    last_error = Some(error);
+                }
+            }
+        }
+
+        if let (true, Some(error)) = (observed_addrs.is_empty(), last_error) {
+            return Err(error);
+        }
+
+        #[cfg(feature = "igd")]
+        let mapped = matches!(
+            self.port_mapping_status(),
+            Some(PortMappingStatus::Active { .. })
+        );
+        #[cfg(not(feature = "igd"))]
+        let mapped = false;
+
+        Ok(reachability::classify(
+            &self.local_addrs(),
+            public_addr,
+            mapped,
+            &observed_addrs,
+            verified,
+        ))
+    }
+
+    /// Connect to a peer by identity.
+    ///
+    /// The peer's known addresses are looked up in the [`address_book`](Self::address_book) and
+    /// tried in order of preference, with the first successful connection being returned.
+    /// Connection attempts across all the addresses are retried based on the
+    /// [`Config::retry_config`] used to create the endpoint.
+    ///
+    /// With [`Config::e2e_encryption`], the peer must sign its key share as `peer`, or the
+    /// connection is closed and [`ConnectionError::Hello`] returned, since anything between the
+    /// endpoints could otherwise have agreed keys in the peer's place.
+    ///
+    /// Returns [`ConnectionError::UnknownPeer`] if there are no known addresses for the peer, and
+    /// [`ConnectionError::ConnectTimedOut`] if connecting takes longer than
+    /// [`Config::connect_timeout`].
+    pub async fn connect_to_peer(
+        &self,
+        peer: &PeerId,
+    ) -> Result<(Connection, ConnectionIncoming), ConnectionError> {
+        let addresses = self.address_book.addresses(peer);
+        if addresses.is_empty() {
+            return Err(ConnectionError::UnknownPeer(*peer));
+        }
+
+        let addrs: Vec<_> = addresses.iter().map(|address| address.addr).collect();
+        let (addr, connection) = self
+            .within_connect_timeout(self.connect_to_first(&addrs))
+            .await?;
+        self.check_e2e_signer(&connection.0, peer)?;
+        self.address_book.mark_successful(peer, &addr);
+
+        Ok(connection)
+    }
+
+    // With end-to-end encryption, check that a connection dialed to reach `peer` agreed keys with
+    // that identity. Otherwise, something between us and the address (such as a relay) could have
+    // substituted its own key share, so the connection is closed.
+    fn check_e2e_signer(
+        &self,
+        connection: &Connection,
+        peer: &PeerId,
+    ) -> Result<(), ConnectionError> {
+        if !self.services.e2e_encryption || connection.e2e_signer() == Some(*peer) {
+            return Ok(());
+        }
+        connection.close(Some("unexpected key share signer".to_string()));
+        Err(ConnectionError::Hello(format!(
+            "key share was not signed by {}",
+            peer
+        )))
+    }
+
+    /// The address book used by [`connect_to_peer`](Self::connect_to_peer).
+    pub fn address_book(&self) -> &AddressBook {
+        &self.address_book
+    }
+
     /// Verify if an address is publicly reachable. This will attempt to create
     /// a new connection and use it to exchange a message and verify that the node
     /// can be reached.
@@ -314,11 +1315,15 @@ impl Endpoint {
         trace!("Checking is reachable");
 
         let (connection, _) = self.new_connection(peer_addr).await?;
-        let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+        let (mut send_stream, mut recv_stream) =
+            connection.open_bi().await.map_err(PeerError::into_inner)?;
 
         send_stream.send_wire_msg(WireMsg::EndpointEchoReq).await?;
 
-        match timeout(ECHO_SERVICE_QUERY_TIMEOUT, recv_stream.next_wire_msg()).await?? {
+        match self
+            .timeout_rpc(&connection, recv_stream.next_wire_msg())
+            .await??
+        {
             Some(WireMsg::EndpointEchoResp(_)) => Ok(()),
             Some(other) => {
                 info!(
@@ -337,13 +1342,298 @@ impl Endpoint {
         }
     }
 
+    /// The identifier of this endpoint in the DHT.
+    #[cfg(feature = "dht")]
+    pub fn node_id(&self) -> NodeId {
+        self.dht.local_id()
+    }
+
+    /// Find the address of the peer with the given DHT identifier.
+    ///
+    /// This performs an iterative Kademlia lookup, starting from the closest peers in the local
+    /// routing table and querying progressively closer peers until no closer peers are returned.
+    /// Peers that respond are added to the routing table, and peers that fail to respond are
+    /// evicted from it.
+    ///
+    /// Returns `None` if the peer could not be found.
+    ///
+    /// # Connection pooling
+    ///
+    /// Peers are queried over open connections if there are any. Otherwise, connections are opened
+    /// and held for reuse, as by [`send_to`](Self::send_to).
+    #[cfg(feature = "dht")]
+    pub async fn find_peer(&self, id: NodeId) -> Option<SocketAddr> {
+        if id == self.dht.local_id() {
+            return Some(self.public_addr());
+        }
+
+        self.dht_lookup(id)
+            .await
+            .into_iter()
+            .find(|contact| contact.id == id)
+            .map(|contact| contact.addr)
+    }
+
+    /// Replace the TLS certificate presented to connecting peers.
+    ///
+    /// Existing connections are unaffected – only connections accepted after the reload will use
+    /// the new certificate. `cert_chain` and `key` must be DER-encoded, with the end-entity
+    /// certificate first in the chain.
+    ///
+    /// This has no effect on client endpoints, since they don't accept connections.
+    pub fn reload_tls(&self, cert_chain: Vec<Vec<u8>>, key: Vec<u8>) -> Result<(), ConfigError> {
+        let server_tls = match &self.server_tls {
+            Some(server_tls) => server_tls,
+            None => return Ok(()),
+        };
+
+        let cert_chain = cert_chain.into_iter().map(rustls::Certificate).collect();
+        let server_config = server_tls.server_config(cert_chain, rustls::PrivateKey(key))?;
+        self.set_server_config(server_config);
+
+        trace!("Reloaded TLS certificate");
+        Ok(())
+    }
+
+    /// Change whether incoming connections must validate their address with a stateless retry.
+    ///
+    /// See [`Config::stateless_retry`] for why this matters. It can be turned on when the endpoint
+    /// sees more incoming handshakes than expected (e.g. it's being used as a reflector), and off
+    /// again once they subside. Connections already being accepted are unaffected.
+    ///
+    /// This has no effect on client endpoints, since they don't accept connections.
+    pub fn set_stateless_retry(&self, enabled: bool) {
+        let server_config = match &self.server_tls {
+            Some(server_tls) => server_tls.set_stateless_retry(enabled),
+            None => return,
+        };
+        if let Some(server_config) = server_config {
+            self.set_server_config(server_config);
+            debug!(
+                "Stateless retry {}",
+                if enabled { "enabled" } else { "disabled" }
+            );
+        }
+    }
+
+    /// Whether incoming connections must validate their address with a stateless retry.
+    ///
+    /// See [`set_stateless_retry`](Self::set_stateless_retry). This is always `false` for client
+    /// endpoints.
+    pub fn stateless_retry(&self) -> bool {
+        self.server_tls
+            .as_ref()
+            .is_some_and(|server_tls| server_tls.stateless_retry())
+    }
+
+    /// Counts of TLS sessions resumed by the endpoint's connections.
+    ///
+    /// See [`Config::session_cache_size`] for how sessions are cached. This is the only indication
+    /// of resumption, since quinn doesn't reveal whether an individual connection was resumed.
+    pub fn resumption_stats(&self) -> ResumptionStats {
+        self.transport.resumption_stats()
+    }
+
+    /// Stop accepting incoming connections, e.g. to shed load while the endpoint is overloaded.
+    ///
+    /// Connections are rejected as they arrive, as when the [accept rate](Config::accept_rate) is
+    /// exceeded. If `streams` is set, existing connections stop accepting new streams too: streams
+    /// already being read carry on, but peers can only open as many new streams as QUIC's stream
+    /// limit allows before they have to wait, so they're slowed down rather than disconnected.
+    /// Established connections are kept either way.
+    ///
+    /// Call [`resume_incoming`](Self::resume_incoming) to undo.
+    pub fn pause_incoming(&self, streams: bool) {
+        self.services.pause.pause(streams);
+        debug!(
+            "Paused incoming connections{}",
+            if streams { " and streams" } else { "" }
+        );
+    }
+
+    /// Accept incoming connections and streams again, after
+    /// [`pause_incoming`](Self::pause_incoming).
+    pub fn resume_incoming(&self) {
+        self.services.pause.resume();
+        debug!("Resumed incoming connections and streams");
+    }
+
+    /// Whether incoming connections are paused. See [`pause_incoming`](Self::pause_incoming).
+    pub fn is_incoming_paused(&self) -> bool {
+        self.services.pause.connections_paused()
+    }
+
+    /// Change some of the endpoint's settings while it's running.
+    ///
+    /// See [`PartialConfig`] for the settings that can be changed, and what they apply to. This
+    /// lets e.g. retries be loosened during an incident without restarting the endpoint.
+    ///
+    /// If the update is invalid, nothing is changed. Accept rates have no effect on client
+    /// endpoints, since they don't accept connections.
+    pub fn update_config(&self, update: PartialConfig) -> Result<(), ConfigError> {
+        if let Some(keep_alive_interval) = update.keep_alive_interval {
+            let transport = self
+                .transport
+                .set_keep_alive_interval(keep_alive_interval)?;
+            let server_config = self
+                .server_tls
+                .as_ref()
+                .and_then(|server_tls| server_tls.set_transport(transport));
+            if let Some(server_config) = server_config {
+                self.set_server_config(server_config);
+            }
+            debug!("Keep-alive interval changed to {:?}", keep_alive_interval);
+        }
+
+        if let Some(retry_config) = update.retry_config {
+            self.circuit_breaker.configure(&retry_config);
+            self.retry_config.set(retry_config);
+            debug!("Retry config changed");
+        }
+
+        if let Some(accept_limiter) = &self.services.accept_limiter {
+            if let Some(rate) = update.accept_rate {
+                accept_limiter.set_global_rate(rate);
+                debug!("Accept rate changed to {:?}", rate);
+            }
+            if let Some(rate) = update.accept_rate_per_ip {
+                accept_limiter.set_per_ip_rate(rate);
+                debug!("Per-IP accept rate changed to {:?}", rate);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Use `server_config` for connections accepted from now on, on every socket.
+    fn set_server_config(&self, server_config: quinn::ServerConfig) {
+        for (_, quinn_endpoint) in &self.secondary_endpoints {
+            quinn_endpoint.set_server_config(Some(server_config.clone()));
+        }
+        for quinn_endpoint in &self.workers {
+            quinn_endpoint.set_server_config(Some(server_config.clone()));
+        }
+        self.quinn_endpoint.set_server_config(Some(server_config));
+    }
+
+    /// Watch a certificate and key on disk, calling [`reload_tls`](Self::reload_tls) whenever
+    /// they change.
+    ///
+    /// Both files must be DER-encoded, with the certificate file containing a single certificate.
+    /// The files are checked for modifications every `interval`, starting immediately. Failures to
+    /// read or load the files are logged, and the previous certificate is kept. Watching stops
+    /// when the endpoint is [`close`](Self::close)d.
+    pub fn watch_tls(
+        &self,
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+        interval: Duration,
+    ) {
+        let endpoint = self.clone();
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let mut termination_rx = self.termination_tx.subscribe();
+
+        let watch = async move {
+            let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified());
+            let mut loaded: Option<(SystemTime, SystemTime)> = None;
+
+            loop {
+                match (modified(&cert_path), modified(&key_path)) {
+                    (Ok(cert_modified), Ok(key_modified)) => {
+                        if loaded != Some((cert_modified, key_modified)) {
+                            let result = std::fs::read(&cert_path)
+                                .and_then(|cert| Ok((cert, std::fs::read(&key_path)?)));
+                            match result {
+                                Ok((cert, key)) => match endpoint.reload_tls(vec![cert], key) {
+                                    Ok(()) => loaded = Some((cert_modified, key_modified)),
+                                    Err(error) => {
+                                        warn!("Failed to reload TLS certificate: {}", error)
+                                    }
+                                },
+                                Err(error) => warn!("Failed to read TLS certificate: {}", error),
+                            }
+                        }
+                    }
+                    (Err(error), _) | (_, Err(error)) => {
+                        warn!("Failed to check TLS certificate for changes: {}", error)
+                    }
+                }
+
+                tokio::time::sleep(interval).await;
+            }
+        };
+
+        let _ = tokio::spawn(async move {
+            let _ = future::select(Box::pin(watch), Box::pin(termination_rx.recv())).await;
+        });
+    }
+
     /// Close all the connections of this endpoint immediately and stop accepting new connections.
     pub fn close(&self) {
         trace!("Closing endpoint");
         let _ = self.termination_tx.send(());
+        let others = self
+            .secondary_endpoints
+            .iter()
+            .map(|(_, quinn_endpoint)| quinn_endpoint);
+        for quinn_endpoint in others.chain(&self.workers).chain(&self.outgoing_endpoints) {
+            quinn_endpoint.close(0_u32.into(), b"Endpoint closed");
+        }
         self.quinn_endpoint.close(0_u32.into(), b"Endpoint closed")
     }
 
+    /// Drain the endpoint's connections, then [`close`](Self::close) it.
+    ///
+    /// New connections are refused, and every connected peer is told that the endpoint is going
+    /// away, so it can stop sending new requests straight away rather than waiting for the
+    /// connection to time out. Peers see [`PeerState::GoingAway`](crate::PeerState::GoingAway)
+    /// from [`Connection::peer_state`], and their
+    /// [`ConnectionObserver::on_go_away`](crate::ConnectionObserver::on_go_away) is called.
+    ///
+    /// The endpoint is closed once the peers have closed every connection, or after `grace`,
+    /// whichever comes first.
+    pub async fn shutdown(&self, grace: Duration) {
+        trace!("Shutting down endpoint");
+        let others = self
+            .secondary_endpoints
+            .iter()
+            .map(|(_, quinn_endpoint)| quinn_endpoint);
+        for quinn_endpoint in others.chain(&self.workers) {
+            quinn_endpoint.set_server_config(None);
+        }
+        self.quinn_endpoint.set_server_config(None);
+
+        if let Some(registry) = &self.services.connections {
+            let drain = async {
+                let connections = registry.all();
+                let _ = future::join_all(connections.iter().map(|connection| async move {
+                    if let Err(error) = connection.send_go_away().await {
+                        trace!(
+                            "Failed to tell {} we're going away: {}",
+                            connection.remote_address(),
+                            error
+                        );
+                    }
+                }))
+                .await;
+                drop(connections);
+
+                while !registry.all().is_empty() {
+                    tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+                }
+            };
+            if timeout(grace, drain).await.is_err() {
+                debug!(
+                    "Closing endpoint with connections still open after {:?}",
+                    grace
+                );
+            }
+        }
+
+        self.close();
+    }
+
     /// Attempt a connection to a node_addr.
     ///
     /// All failures are retried with exponential back-off. This doesn't use the connection pool, it
@@ -353,36 +1643,117 @@ impl Endpoint {
         node_addr: &SocketAddr,
     ) -> Result<(Connection, ConnectionIncoming), ConnectionError> {
         self.retry_config
+            .get()
             .retry(|| async {
-                trace!("Attempting to connect to {:?}", node_addr);
-                let connecting = match self.quinn_endpoint.connect(*node_addr, SERVER_NAME) {
-                    Ok(conn) => Ok(conn),
-                    Err(error) => {
-                        warn!("Connection attempt failed due to {:?}", error);
-                        Err(ConnectionError::from(error))
-                    }
-                }?;
+                self.attempt_connection(node_addr)
+                    .await
+                    .map_err(|error| match error {
+                        // the peer is reachable, but the application doesn't want the connection
+                        ConnectionError::Hello(_) => backoff::Error::Permanent(error),
+                        // the peer has failed too often to keep trying for now
+                        ConnectionError::CircuitOpen(_) => backoff::Error::Permanent(error),
+                        error => backoff::Error::Transient(error),
+                    })
+            })
+            .await
+    }
 
-                let new_conn = match connecting.await {
-                    Ok(new_conn) => {
-                        trace!("Successfully connected to peer: {}", node_addr);
+    // Choose the socket to connect to `node_addr` from (see `local_addrs`).
+    fn source_endpoint(&self, node_addr: &SocketAddr) -> &QuinnEndpoint {
+        let suitability = |local_addr: &SocketAddr| {
+            let ip = local_addr.ip();
+            let same_version = ip.is_ipv4() == node_addr.is_ipv4();
+            let same_scope =
+                ip.is_unspecified() || ip.is_loopback() == node_addr.ip().is_loopback();
+            (same_version, same_scope)
+        };
 
-                        let (connection, connection_incoming) = Connection::new(
-                            self.quinn_endpoint.clone(),
-                            Some(self.retry_config.clone()),
-                            new_conn,
-                        );
+        let primary = self.primary_source_endpoint();
+        std::iter::once((&self.local_addr, primary))
+            .chain(self.secondary_endpoints.iter().map(|(addr, e)| (addr, e)))
+            // `max_by_key` returns the last maximum, but we want the first
+            .rev()
+            .max_by_key(|(local_addr, _)| suitability(local_addr))
+            .map_or(primary, |(_, quinn_endpoint)| quinn_endpoint)
+    }
 
-                        Ok((connection, connection_incoming))
-                    }
-                    Err(error) => Err(ConnectionError::from(error)),
-                }?;
+    // Choose the socket to connect from in place of the socket bound to `local_addr`.
+    //
+    // If workers share that socket's port, the operating system may deliver the peer's packets to
+    // the wrong worker, so the outgoing sockets are used in turn instead. They're also used if
+    // they're configured.
+    fn primary_source_endpoint(&self) -> &QuinnEndpoint {
+        if self.outgoing_endpoints.is_empty() {
+            return &self.quinn_endpoint;
+        }
+        let next = self.next_outgoing.fetch_add(1, Ordering::Relaxed);
+        &self.outgoing_endpoints[next % self.outgoing_endpoints.len()]
+    }
 
-                Ok(new_conn)
+    /// Attempt to connect to each of `addrs` in order, returning the first successful connection.
+    ///
+    /// If every address fails, the whole sequence is retried with exponential back-off.
+    async fn connect_to_first(
+        &self,
+        addrs: &[SocketAddr],
+    ) -> Result<(SocketAddr, (Connection, ConnectionIncoming)), ConnectionError> {
+        self.retry_config
+            .get()
+            .retry(|| async {
+                let mut last_error = None;
+                let mut all_open = true;
+                for addr in addrs {
+                    match self.attempt_connection(addr).await {
+                        Ok(connection) => return Ok((*addr, connection)),
+                        Err(error) => {
+                            trace!("Failed to connect to {}: {}", addr, error);
+                            all_open &= matches!(error, ConnectionError::CircuitOpen(_));
+                            last_error = Some(error);
+                        }
+                    }
+                }
+                let error = last_error.unwrap_or_else(|| {
+                    ConnectionError::Resolve("no addresses to connect to".to_string())
+                });
+                if all_open {
+                    // every address has failed too often to keep trying for now
+                    Err(backoff::Error::Permanent(error))
+                } else {
+                    Err(backoff::Error::Transient(error))
+                }
             })
             .await
     }
 
+    /// Make a single attempt to connect to a node_addr, without retries.
+    ///
+    /// The attempt is recorded by the circuit breaker, and fails immediately if the circuit for
+    /// `node_addr` is open.
+    async fn attempt_connection(
+        &self,
+        node_addr: &SocketAddr,
+    ) -> Result<(Connection, ConnectionIncoming), ConnectionError> {
+        self.circuit_breaker.check(node_addr)?;
+        let result = self.connect_once(node_addr).await;
+        self.circuit_breaker.record(node_addr, &result);
+        result
+    }
+
+    async fn connect_once(
+        &self,
+        node_addr: &SocketAddr,
+    ) -> Result<(Connection, ConnectionIncoming), ConnectionError> {
+        trace!("Attempting to connect to {:?}", node_addr);
+        dial(
+            self.source_endpoint(node_addr),
+            Some(self.transport.client_config_for(*node_addr)),
+            Some(self.retry_config.clone()),
+            &self.services,
+            node_addr,
+        )
+        .await
+    }
+
     // set an appropriate public address based on `config` and a reachability check.
     async fn resolve_public_addr(
         &mut self,
@@ -443,6 +1814,12 @@ impl Endpoint {
                     );
                 }
             }
+        } else if !self.outgoing_endpoints.is_empty() {
+            // the peer saw one of the outgoing sockets, not the port we listen on
+            trace!(
+                "Using local port ({}) as public port, since outgoing connections use other ports",
+                public_addr.port()
+            );
         } else if let Some(visible_addr) = visible_addr {
             // set the public port based on that seen by the peer
             public_addr.set_port(visible_addr.port());
@@ -462,11 +1839,11 @@ impl Endpoint {
 
     /// Perform the endpoint echo RPC with the given contact.
     async fn endpoint_echo(&self, contact: &Connection) -> Result<SocketAddr, RpcError> {
-        let (mut send, mut recv) = contact.open_bi().await?;
+        let (mut send, mut recv) = contact.open_bi().await.map_err(PeerError::into_inner)?;
 
         send.send_wire_msg(WireMsg::EndpointEchoReq).await?;
 
-        match timeout(ECHO_SERVICE_QUERY_TIMEOUT, recv.next_wire_msg()).await?? {
+        match self.timeout_rpc(contact, recv.next_wire_msg()).await?? {
             Some(WireMsg::EndpointEchoResp(addr)) => Ok(addr),
             msg => Err(RecvError::Serialization(SerializationError::unexpected(&msg)).into()),
         }
@@ -478,47 +1855,226 @@ impl Endpoint {
         contact: &Connection,
         public_addr: SocketAddr,
     ) -> Result<bool, RpcError> {
-        let (mut send, mut recv) = contact.open_bi().await?;
+        let (mut send, mut recv) = contact.open_bi().await.map_err(PeerError::into_inner)?;
 
         send.send_wire_msg(WireMsg::EndpointVerificationReq(public_addr))
             .await?;
 
-        match timeout(ECHO_SERVICE_QUERY_TIMEOUT, recv.next_wire_msg()).await?? {
+        match self.timeout_rpc(contact, recv.next_wire_msg()).await?? {
             Some(WireMsg::EndpointVerificationResp(valid)) => Ok(valid),
             msg => Err(RecvError::Serialization(SerializationError::unexpected(&msg)).into()),
         }
     }
+
+    /// Populate the routing table by querying `contacts` and then looking up our own identifier.
+    #[cfg(feature = "dht")]
+    async fn dht_bootstrap(&self, contacts: &[SocketAddr]) {
+        let local_id = self.dht.local_id();
+        let _ = future::join_all(
+            contacts
+                .iter()
+                .map(|contact| self.dht_find_node(*contact, local_id)),
+        )
+        .await;
+
+        let found = self.dht_lookup(local_id).await;
+        info!("DHT bootstrap complete, found {} peers", found.len());
+    }
+
+    /// Iteratively query the closest known peers to `target`, returning the closest peers found.
+    #[cfg(feature = "dht")]
+    async fn dht_lookup(&self, target: NodeId) -> Vec<Contact> {
+        let local_id = self.dht.local_id();
+        let mut shortlist = self.dht.closest(&target, BUCKET_SIZE);
+        let mut queried = HashSet::new();
+
+        loop {
+            let pending: Vec<_> = shortlist
+                .iter()
+                .filter(|contact| !queried.contains(&contact.id))
+                .take(LOOKUP_CONCURRENCY)
+                .copied()
+                .collect();
+            if pending.is_empty() {
+                break;
+            }
+
+            for contact in &pending {
+                let _ = queried.insert(contact.id);
+            }
+
+            let responses = future::join_all(
+                pending
+                    .iter()
+                    .map(|contact| self.dht_find_node(contact.addr, target)),
+            )
+            .await;
+
+            for (contact, response) in pending.iter().zip(responses) {
+                match response {
+                    Ok(found) => {
+                        for found in found {
+                            if found.id != local_id && !shortlist.iter().any(|c| c.id == found.id) {
+                                shortlist.push(found);
+                            }
+                        }
+                    }
+                    Err(error) => {
+                        trace!("DHT query to {} failed: {}", contact.addr, error);
+                        self.dht.remove(&contact.id);
+                        shortlist.retain(|c| c.id != contact.id);
+                    }
+                }
+            }
+
+            shortlist.sort_by_key(|contact| contact.id.distance(&target));
+            shortlist.truncate(BUCKET_SIZE);
+        }
+
+        shortlist
+    }
+
+    /// Perform the DHT find node RPC with the given peer.
+    ///
+    /// An open connection to the peer is reused if there is one, and otherwise the new connection
+    /// is kept for later lookups (see `pooled_connection`), rather than dialing for every query.
+    #[cfg(feature = "dht")]
+    async fn dht_find_node(
+        &self,
+        peer_addr: SocketAddr,
+        target: NodeId,
+    ) -> Result<Vec<Contact>, RpcError> {
+        let connection = self.pooled_connection(&peer_addr).await?;
+        let (mut send, mut recv) = connection.open_bi().await.map_err(PeerError::into_inner)?;
+
+        // client endpoints aren't reachable, so shouldn't be added to anyone's routing table
+        let sender = self.public_addr.map(|_| self.dht.local_id());
+        send.send_wire_msg(WireMsg::DhtFindNodeReq { sender, target })
+            .await?;
+
+        match self
+            .timeout_rpc(&connection, recv.next_wire_msg())
+            .await??
+        {
+            Some(WireMsg::DhtFindNodeResp {
+                responder,
+                contacts,
+            }) => {
+                self.dht.observe(Contact {
+                    id: responder,
+                    addr: peer_addr,
+                });
+                Ok(contacts)
+            }
+            msg => Err(RecvError::Serialization(SerializationError::unexpected(&msg)).into()),
+        }
+    }
+
+    /// Get an open connection to `addr`, or connect to it and hold the connection for reuse.
+    async fn pooled_connection(&self, addr: &SocketAddr) -> Result<Connection, ConnectionError> {
+        if let Some(connection) = self.get_connection_by_addr(addr) {
+            return Ok(connection);
+        }
+
+        // dials to the same address are serialised, so concurrent sends share a connection
+        let slot = self
+            .dialed
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .entry(*addr)
+            .or_default()
+            .clone();
+        let mut held = slot.lock().await;
+        if let Some(connection) = self.get_connection_by_addr(addr) {
+            return Ok(connection);
+        }
+
+        let (connection, incoming) = self.connect_to(addr).await?;
+        self.deliver_pooled(connection.clone(), incoming);
+        *held = Some(connection.clone());
+        Ok(connection)
+    }
+
+    /// Bound `f` by the endpoint's [`Config::connect_timeout`], if there is one.
+    async fn within_connect_timeout<T, F>(&self, f: F) -> Result<T, ConnectionError>
+    where
+        F: Future<Output = Result<T, ConnectionError>>,
+    {
+        match self.connect_timeout {
+            Some(connect_timeout) => timeout(connect_timeout, f)
+                .await
+                .map_err(|_| ConnectionError::ConnectTimedOut)?,
+            None => f.await,
+        }
+    }
+
+    /// Await an RPC response, reporting a slow stream if it doesn't arrive in time.
+    async fn timeout_rpc<F: Future>(
+        &self,
+        connection: &Connection,
+        f: F,
+    ) -> Result<F::Output, Elapsed> {
+        let result = timeout(ECHO_SERVICE_QUERY_TIMEOUT, f).await;
+        if result.is_err() {
+            scoring::report(
+                &self.services.peer_scoring,
+                connection.remote_address(),
+                connection.peer_id(),
+                PeerEvent::SlowStream,
+            );
+        }
+        result
+    }
 }
 
 pub(super) fn listen_for_incoming_connections(
     mut quinn_incoming: quinn::Incoming,
     connection_tx: mpsc::Sender<(Connection, ConnectionIncoming)>,
     quinn_endpoint: quinn::Endpoint,
-    retry_config: Arc<RetryConfig>,
+    retry_config: SharedRetryConfig,
+    services: ConnectionServices,
 ) {
     let _ = tokio::spawn(async move {
         loop {
             match quinn_incoming.next().await {
-                Some(quinn_conn) => match quinn_conn.await {
-                    Ok(connection) => {
-                        let (connection, connection_incoming) = Connection::new(
-                            quinn_endpoint.clone(),
-                            Some(retry_config.clone()),
-                            connection,
-                        );
-
-                        if connection_tx
-                            .send((connection, connection_incoming))
-                            .await
-                            .is_err()
-                        {
-                            warn!("Dropping incoming connection because receiver was dropped");
-                        }
-                    }
-                    Err(err) => {
-                        warn!("An incoming connection failed because of: {:?}", err);
-                    }
-                },
+                Some(quinn_conn)
+                    if !is_acceptable(
+                        &services.peer_scoring,
+                        &ScoredPeer::from(quinn_conn.remote_address()),
+                    ) =>
+                {
+                    // dropping the handshake will close the connection
+                    info!(
+                        "Rejecting incoming connection from {}: peer score is too low",
+                        quinn_conn.remote_address()
+                    );
+                }
+                Some(quinn_conn) if services.pause.connections_paused() => {
+                    debug!(
+                        "Rejecting incoming connection from {}: incoming traffic is paused",
+                        quinn_conn.remote_address()
+                    );
+                }
+                Some(quinn_conn)
+                    if !within_accept_rate(&services, &quinn_conn.remote_address()) =>
+                {
+                    // logged at debug level, so a flood of connections doesn't flood the logs too
+                    debug!(
+                        "Rejecting incoming connection from {}: accept rate exceeded",
+                        quinn_conn.remote_address()
+                    );
+                }
+                Some(quinn_conn) => {
+                    // the handshake is completed in the background, so slow or hostile peers
+                    // can't hold up other incoming connections
+                    let _ = tokio::spawn(accept(
+                        quinn_conn,
+                        connection_tx.clone(),
+                        quinn_endpoint.clone(),
+                        retry_config.clone(),
+                        services.clone(),
+                    ));
+                }
                 None => {
                     trace!("quinn::Incoming::next() returned None. There will be no more incoming connections");
                     break;
@@ -528,6 +2084,156 @@ pub(super) fn listen_for_incoming_connections(
     });
 }
 
+// Complete the handshake (and hello exchange, if required) of an incoming connection, and pass it
+// on to `connection_tx`.
+async fn accept(
+    quinn_conn: quinn::Connecting,
+    connection_tx: mpsc::Sender<(Connection, ConnectionIncoming)>,
+    quinn_endpoint: quinn::Endpoint,
+    retry_config: SharedRetryConfig,
+    services: ConnectionServices,
+) {
+    let peer_addr = quinn_conn.remote_address();
+    let handshake = within_handshake_timeout(&services, async {
+        let mut connection = quinn_conn.await?;
+        let exchange = if hello::required(&services) {
+            Some(hello::respond(&services, &mut connection).await?)
+        } else {
+            None
+        };
+        Ok((connection, exchange))
+    });
+    let (connection, exchange) = match handshake.await {
+        Ok(handshake) => handshake,
+        Err(error @ (ConnectionError::Hello(_) | ConnectionError::HandshakeTimedOut)) => {
+            info!(
+                "Rejecting incoming connection from {}: {}",
+                peer_addr, error
+            );
+            return;
+        }
+        Err(error) => {
+            warn!(
+                "An incoming connection from {} failed because of: {:?}",
+                peer_addr, error
+            );
+            return;
+        }
+    };
+
+    let peer_scoring = services.peer_scoring.clone();
+    let (connection, connection_incoming) = Connection::new(
+        quinn_endpoint,
+        Some(retry_config),
+        services,
+        connection,
+        exchange,
+    );
+
+    // the peer was only checked by address before the handshake, so check it again by identity,
+    // in case a peer with a low score is connecting from a new address
+    if let Some(id) = connection.peer_id() {
+        let peer = ScoredPeer {
+            addr: peer_addr,
+            id: Some(id),
+        };
+        if !is_acceptable(&peer_scoring, &peer) {
+            info!(
+                "Rejecting incoming connection from {}: peer score is too low",
+                peer_addr
+            );
+            connection.close(Some("peer score is too low".to_string()));
+            return;
+        }
+    }
+
+    if connection_tx
+        .send((connection, connection_incoming))
+        .await
+        .is_err()
+    {
+        warn!("Dropping incoming connection because receiver was dropped");
+    }
+}
+
+// Open a connection to `node_addr` from `quinn_endpoint` with `client_config` (or the endpoint's
+// default), exchanging hellos if required.
+//
+// Every outgoing connection is made this way, so that it's added to the endpoint's connection
+// registry like any other.
+pub(crate) async fn dial(
+    quinn_endpoint: &QuinnEndpoint,
+    client_config: Option<quinn::ClientConfig>,
+    retry_config: Option<SharedRetryConfig>,
+    services: &ConnectionServices,
+    node_addr: &SocketAddr,
+) -> Result<(Connection, ConnectionIncoming), ConnectionError> {
+    let connecting = match client_config {
+        Some(client_config) => quinn_endpoint.connect_with(client_config, *node_addr, SERVER_NAME),
+        None => quinn_endpoint.connect(*node_addr, SERVER_NAME),
+    };
+    let connecting = match connecting {
+        Ok(conn) => Ok(conn),
+        Err(error) => {
+            warn!("Connection attempt failed due to {:?}", error);
+            Err(ConnectionError::from(error))
+        }
+    }?;
+
+    let (new_conn, exchange) = within_handshake_timeout(services, async {
+        let new_conn = connecting.await?;
+        trace!("Successfully connected to peer: {}", node_addr);
+
+        let exchange = if hello::required(services) {
+            Some(hello::initiate(services, &new_conn).await?)
+        } else {
+            None
+        };
+        Ok((new_conn, exchange))
+    })
+    .await?;
+
+    let (connection, incoming) = Connection::new(
+        quinn_endpoint.clone(),
+        retry_config,
+        services.clone(),
+        new_conn,
+        exchange,
+    );
+    connection.metadata().mark_dialed();
+    Ok((connection, incoming))
+}
+
+// Complete a handshake within `services.handshake_timeout`, if set. The handshake is abandoned
+// (closing the connection) if it times out.
+async fn within_handshake_timeout<T, F>(
+    services: &ConnectionServices,
+    f: F,
+) -> Result<T, ConnectionError>
+where
+    F: Future<Output = Result<T, ConnectionError>>,
+{
+    match services.handshake_timeout {
+        Some(handshake_timeout) => timeout(handshake_timeout, f)
+            .await
+            .map_err(|_| ConnectionError::HandshakeTimedOut)?,
+        None => f.await,
+    }
+}
+
+fn within_accept_rate(services: &ConnectionServices, peer_addr: &SocketAddr) -> bool {
+    services
+        .accept_limiter
+        .as_ref()
+        .is_none_or(|limiter| limiter.allow(peer_addr.ip()))
+}
+
+fn is_acceptable(peer_scoring: &Option<Arc<dyn PeerScoring>>, peer: &ScoredPeer) -> bool {
+    peer_scoring
+        .as_ref()
+        .is_none_or(|scoring| scoring.is_acceptable(peer))
+}
+
 #[cfg(test)]
 mod tests {
     use super::Endpoint;
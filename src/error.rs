@@ -8,13 +8,29 @@
 // Software.
 
 use super::wire_msg::WireMsg;
-use crate::config::ConfigError;
 #[cfg(feature = "igd")]
-use crate::igd::IgdError;
+use crate::port_mapping::PortMappingError;
+use crate::{address_book::PeerId, config::ConfigError};
 use bytes::Bytes;
 use std::{fmt, io, net::SocketAddr};
 use thiserror::Error;
 
+/// Whether an error is worth retrying, as returned by the `kind` method of each error type.
+///
+/// This gives applications a stable way to decide whether to retry an operation or give up on it
+/// (and e.g. evict the peer), without matching on the details of each error, which change as the
+/// library evolves.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// Retrying may succeed, possibly on a new connection (e.g. the connection timed out or was
+    /// lost, or the peer was too busy).
+    Transient,
+
+    /// Retrying the same operation won't succeed (e.g. the peer rejected it, violated the
+    /// protocol, or the input was invalid).
+    Permanent,
+}
+
 /// Errors returned from [`Endpoint::new`](crate::Endpoint::new).
 #[derive(Debug, Error)]
 pub enum EndpointError {
@@ -66,8 +82,8 @@ pub enum EndpointError {
 }
 
 #[cfg(feature = "igd")]
-impl From<IgdError> for EndpointError {
-    fn from(error: IgdError) -> Self {
+impl From<PortMappingError> for EndpointError {
+    fn from(error: PortMappingError) -> Self {
         Self::Upnp(UpnpError(error))
     }
 }
@@ -138,9 +154,84 @@ pub enum ConnectionError {
     /// The connection was closed.
     #[error("The connection was closed by {0}")]
     Closed(Close),
+
+    /// There are no known addresses for the peer.
+    #[error("There are no known addresses for peer {0}")]
+    UnknownPeer(PeerId),
+
+    /// The peer's address could not be resolved.
+    #[error("Failed to resolve peer address: {0}")]
+    Resolve(String),
+
+    /// The application hello exchange failed or the hello was rejected.
+    #[error("The hello exchange with the peer failed: {0}")]
+    Hello(String),
+
+    /// Opening a stream timed out.
+    ///
+    /// This happens when the peer's limit on concurrent streams remains exhausted for longer than
+    /// [`Config::stream_open_timeout`](crate::Config::stream_open_timeout).
+    #[error("Timed out waiting to open a stream")]
+    StreamOpenTimedOut,
+
+    /// Connecting to the peer took longer than
+    /// [`Config::connect_timeout`](crate::Config::connect_timeout).
+    #[error("Timed out connecting to the peer")]
+    ConnectTimedOut,
+
+    /// The QUIC handshake (and hello exchange, if any) with the peer took longer than
+    /// [`Config::handshake_timeout`](crate::Config::handshake_timeout).
+    #[error("Timed out completing the handshake with the peer")]
+    HandshakeTimedOut,
+
+    /// The connection has no control stream to send a control frame on.
+    ///
+    /// See [`Connection::peer_state`](crate::Connection::peer_state).
+    #[error("The connection has no control stream")]
+    NoControlStream,
+
+    /// Connecting to the peer failed too many times in a row, so further attempts are suspended.
+    ///
+    /// See [`RetryConfig::circuit_breaker_threshold`](crate::RetryConfig::circuit_breaker_threshold).
+    #[error("Not connecting to {0}, since recent connection attempts failed repeatedly")]
+    CircuitOpen(SocketAddr),
 }
 
 impl ConnectionError {
+    /// Whether the error is worth retrying.
+    ///
+    /// A connection that was closed by the peer, or lost, is transient, since connecting again may
+    /// succeed. A connection rejected by the peer (e.g. in the [hello](crate::HelloProvider)
+    /// exchange), or closed by this endpoint, is permanent.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::TooManyConnections
+            | Self::Reset
+            | Self::TimedOut
+            | Self::Closed(Close::Application { .. })
+            | Self::Closed(Close::Transport { .. })
+            | Self::Resolve(_)
+            | Self::StreamOpenTimedOut
+            | Self::ConnectTimedOut
+            | Self::HandshakeTimedOut
+            | Self::CircuitOpen(_) => ErrorKind::Transient,
+            Self::Stopped
+            | Self::InvalidAddress(_)
+            | Self::InternalConfigError(_)
+            | Self::VersionMismatch
+            | Self::TransportError(_)
+            | Self::Closed(Close::Local)
+            | Self::UnknownPeer(_)
+            | Self::Hello(_)
+            | Self::NoControlStream => ErrorKind::Permanent,
+        }
+    }
+
+    /// Whether the error is worth retrying (see [`kind`](Self::kind)).
+    pub fn is_transient(&self) -> bool {
+        self.kind() == ErrorKind::Transient
+    }
+
     // A 'benign' connection error is one that does not represent an error on the connection, but
     // rather a natural change of state. This may still be an error for the caller (e.g. if the
     // connection is closed when trying to send a message), but not always (e.g. if the connection
@@ -285,6 +376,85 @@ impl fmt::Display for TransportErrorCode {
     }
 }
 
+/// An error that occurred on a connection, along with the peer and connection it occurred on.
+///
+/// Errors from [`Connection`](crate::Connection), its streams, and
+/// [`ConnectionIncoming`](crate::ConnectionIncoming) are wrapped in this, so they can be attributed
+/// when many connections are active. The wrapped error is available as [`error`](Self::error), and
+/// the wrapper is otherwise transparent: its `Display` adds the peer and connection ID to the
+/// wrapped error's, and its `source` is the wrapped error's `source`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerError<E> {
+    /// The address of the peer.
+    pub peer: SocketAddr,
+
+    /// The [`id`](crate::Connection::id) of the connection.
+    pub connection_id: usize,
+
+    /// The error that occurred.
+    pub error: E,
+}
+
+impl<E> PeerError<E> {
+    /// Discard the context, returning the error that occurred.
+    pub fn into_inner(self) -> E {
+        self.error
+    }
+}
+
+macro_rules! impl_peer_error_kind {
+    ($($error:ty),*) => {
+        $(
+            impl PeerError<$error> {
+                /// Whether the wrapped error is worth retrying (see [`ErrorKind`]).
+                pub fn kind(&self) -> ErrorKind {
+                    self.error.kind()
+                }
+
+                /// Whether the wrapped error is worth retrying (see [`ErrorKind`]).
+                pub fn is_transient(&self) -> bool {
+                    self.error.is_transient()
+                }
+            }
+        )*
+    };
+}
+
+impl_peer_error_kind!(ConnectionError, RpcError, SendError, RecvError, StreamError);
+
+impl<E: fmt::Display> fmt::Display for PeerError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (peer: {}, connection: {})",
+            self.error, self.peer, self.connection_id
+        )
+    }
+}
+
+impl<E: std::error::Error> std::error::Error for PeerError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+// The peer and connection that errors are attributed to (see `PeerError`).
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ErrorContext {
+    pub(crate) peer: SocketAddr,
+    pub(crate) connection_id: usize,
+}
+
+impl ErrorContext {
+    pub(crate) fn wrap<E>(self, error: E) -> PeerError<E> {
+        PeerError {
+            peer: self.peer,
+            connection_id: self.connection_id,
+            error,
+        }
+    }
+}
+
 /// Errors that can occur when performing an RPC operation.
 ///
 /// QuicP2P uses a number of RPC operations when establishing endpoints, in order to verify public
@@ -305,6 +475,22 @@ pub enum RpcError {
     Recv(#[from] RecvError),
 }
 
+impl RpcError {
+    /// Whether the error is worth retrying.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::TimedOut => ErrorKind::Transient,
+            Self::Send(error) => error.kind(),
+            Self::Recv(error) => error.kind(),
+        }
+    }
+
+    /// Whether the error is worth retrying (see [`kind`](Self::kind)).
+    pub fn is_transient(&self) -> bool {
+        self.kind() == ErrorKind::Transient
+    }
+}
+
 // Treating `ConnectionError`s as happening on send works because we would only encounter them
 // directly (e.g. not part of `SendError` or `RecvError`) when establishing outgoing connections
 // before sending.
@@ -344,6 +530,35 @@ pub enum SendError {
     /// Stream was lost when trying to send a message.
     #[error("Stream was lost when trying to send a message")]
     StreamLost(#[source] StreamError),
+
+    /// An [`Interceptor`](crate::Interceptor) rejected the message.
+    #[error("The message was rejected: {0}")]
+    Rejected(String),
+
+    /// The message couldn't be sent before the deadline given in its
+    /// [`SendOptions`](crate::SendOptions).
+    #[error("The message couldn't be sent before its deadline")]
+    DeadlineExceeded,
+}
+
+impl SendError {
+    /// Whether the error is worth retrying.
+    ///
+    /// A message that was lost along with its stream or connection, or ran out of time, may be
+    /// sent again, but one that couldn't be serialized or was rejected can't.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Serialization(_) | Self::Rejected(_) => ErrorKind::Permanent,
+            Self::DeadlineExceeded => ErrorKind::Transient,
+            Self::ConnectionLost(error) => error.kind(),
+            Self::StreamLost(error) => error.kind(),
+        }
+    }
+
+    /// Whether the error is worth retrying (see [`kind`](Self::kind)).
+    pub fn is_transient(&self) -> bool {
+        self.kind() == ErrorKind::Transient
+    }
 }
 
 impl From<bincode::Error> for SendError {
@@ -385,6 +600,82 @@ pub enum RecvError {
     /// Stream was lost when trying to receive a message.
     #[error("Stream was lost when trying to receive a message")]
     StreamLost(#[source] StreamError),
+
+    /// No message was received within the expected time.
+    ///
+    /// See [`RecvStream::next_timeout`](crate::RecvStream::next_timeout).
+    #[error("No message was received within the expected time")]
+    TimedOut,
+
+    /// The peer sent more than the given limit of bytes.
+    ///
+    /// See [`RecvStream::read_to_end`](crate::RecvStream::read_to_end).
+    #[error("The peer sent more than {0} bytes")]
+    TooLong(usize),
+
+    /// An [`Interceptor`](crate::Interceptor) rejected the message.
+    #[error("The message was rejected: {0}")]
+    Rejected(String),
+
+    /// The message wasn't signed, or its signature was invalid.
+    ///
+    /// Only reported if [`Config::signing_key`](crate::Config::signing_key) is set.
+    #[error("The message's signature is missing or invalid")]
+    InvalidSignature,
+
+    /// The message couldn't be decrypted.
+    ///
+    /// Only reported if [`Config::e2e_encryption`](crate::Config::e2e_encryption) is set.
+    #[error("The message couldn't be decrypted")]
+    DecryptionFailed,
+
+    /// The stream ended without the peer saying it had finished sending messages.
+    ///
+    /// The peer may have crashed or been cut off part way through. See
+    /// [`RecvStream::try_next`](crate::RecvStream::try_next).
+    #[error("The stream ended before the peer finished sending messages")]
+    Truncated,
+
+    /// The message's checksum didn't match its contents, so it was corrupted along the way.
+    ///
+    /// Only detected for messages sent with the `wire-checksum` feature.
+    #[error("The message's checksum doesn't match its contents")]
+    CorruptFrame,
+
+    /// A message was discarded because the buffer of incoming messages was full.
+    ///
+    /// Only reported if [`Config::buffer_overflow`](crate::Config::buffer_overflow) is
+    /// [`OverflowPolicy::Error`](crate::OverflowPolicy::Error).
+    #[error("A message was discarded because the incoming message buffer was full")]
+    BufferFull,
+}
+
+impl RecvError {
+    /// Whether the error is worth retrying.
+    ///
+    /// Timeouts, truncated or corrupted streams, full buffers, and lost streams or connections are
+    /// transient.
+    /// Messages that are malformed, too long, rejected, or fail verification are permanent, since
+    /// receiving them again would fail the same way.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::TimedOut | Self::Truncated | Self::CorruptFrame | Self::BufferFull => {
+                ErrorKind::Transient
+            }
+            Self::ConnectionLost(error) => error.kind(),
+            Self::StreamLost(error) => error.kind(),
+            Self::Serialization(_)
+            | Self::TooLong(_)
+            | Self::Rejected(_)
+            | Self::InvalidSignature
+            | Self::DecryptionFailed => ErrorKind::Permanent,
+        }
+    }
+
+    /// Whether the error is worth retrying (see [`kind`](Self::kind)).
+    pub fn is_transient(&self) -> bool {
+        self.kind() == ErrorKind::Transient
+    }
 }
 
 impl From<quinn::ConnectionError> for RecvError {
@@ -460,6 +751,13 @@ pub enum StreamError {
     #[error("The stream was already stopped, finished, or reset")]
     Gone,
 
+    /// An error code was too large to send to the peer.
+    ///
+    /// Error codes given to [`SendStream::reset`](crate::SendStream::reset) and
+    /// [`RecvStream::stop`](crate::RecvStream::stop) must be less than 2^62.
+    #[error("The error code {0} is too large to send to the peer")]
+    InvalidErrorCode(u64),
+
     /// An error was caused by an unsupported operation.
     ///
     /// Additional stream errors can arise from the use of 0-RTT connections or unordered reads,
@@ -468,13 +766,92 @@ pub enum StreamError {
     Unsupported(#[source] UnsupportedStreamOperation),
 }
 
+impl StreamError {
+    /// Whether the error is worth retrying.
+    ///
+    /// A stream that was stopped or is gone can't be used again, but the operation may succeed on
+    /// a new stream, so these are transient.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Stopped(_) | Self::Gone => ErrorKind::Transient,
+            Self::InvalidErrorCode(_) | Self::Unsupported(_) => ErrorKind::Permanent,
+        }
+    }
+
+    /// Whether the error is worth retrying (see [`kind`](Self::kind)).
+    pub fn is_transient(&self) -> bool {
+        self.kind() == ErrorKind::Transient
+    }
+}
+
 /// An error caused by an unsupported operation.
 #[derive(Debug, Error)]
 #[error(transparent)]
 pub struct UnsupportedStreamOperation(Box<dyn std::error::Error + Send + Sync>);
 
+/// Errors that can occur when transferring a file.
+#[derive(Debug, Error)]
+pub enum TransferError {
+    /// Failed to read or write the file.
+    #[error("Failed to read or write the file")]
+    Io(#[from] io::Error),
+
+    /// Failed to open the transfer stream.
+    #[error("Failed to open the transfer stream")]
+    Connection(#[from] PeerError<ConnectionError>),
+
+    /// Failed to send to the peer.
+    #[error("Failed to send to the peer")]
+    Send(#[from] PeerError<SendError>),
+
+    /// Failed to receive from the peer.
+    #[error("Failed to receive from the peer")]
+    Recv(#[from] PeerError<RecvError>),
+
+    /// The peer declined the transfer.
+    #[error("The peer declined the transfer")]
+    Rejected,
+
+    /// The peer asked to resume from beyond the end of the file.
+    #[error("The peer asked to resume from offset {offset}, but the file is {size} bytes")]
+    InvalidOffset {
+        /// The offset requested by the peer.
+        offset: u64,
+
+        /// The size of the file.
+        size: u64,
+    },
+
+    /// The received file did not match the hash of the sent file.
+    ///
+    /// The partially received file is removed, so retrying the transfer starts from the beginning.
+    #[error("The received file did not match the hash of the sent file")]
+    Integrity,
+}
+
+/// Errors that can occur when loading or saving peers with a [`PeerStore`](crate::PeerStore).
+#[derive(Debug, Error)]
+pub enum PeerStoreError {
+    /// Failed to read or write the store.
+    #[error("Failed to read or write the peer store")]
+    Io(#[from] io::Error),
+
+    /// The stored peers could not be (de)serialized.
+    #[error("Failed to serialize or deserialize stored peers")]
+    Serialization(#[from] bincode::Error),
+
+    /// An error from a store implemented by the application.
+    #[error("Peer store error: {0}")]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Failed to generate or load a [`SigningKey`](crate::SigningKey).
+#[derive(Debug, Error)]
+#[error("Invalid signing key: {0}")]
+pub struct SigningKeyError(pub(crate) String);
+
 /// Failed to establish UPnP port forwarding.
 #[cfg(feature = "igd")]
 #[derive(Debug, Error)]
 #[error("Failed to establish UPnP port forwarding")]
-pub struct UpnpError(#[source] IgdError);
+pub struct UpnpError(#[source] PortMappingError);
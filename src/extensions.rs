@@ -0,0 +1,140 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Application data attached to connections.
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    sync::{Mutex, MutexGuard},
+};
+
+type AnyMap = HashMap<TypeId, Box<dyn Any + Send + Sync>>;
+
+/// A map holding one value of each type, attached to a connection.
+///
+/// Returned by [`Connection::extensions`](crate::Connection::extensions), and shared by every
+/// handle to the connection, so layers of an application can attach data to a connection (e.g. the
+/// result of authenticating the peer, or rate limiter state) without keeping their own map of
+/// connections. Values are dropped along with the last handle to the connection.
+///
+/// Values are returned by cloning, so data that's updated should be inserted as (for example) an
+/// `Arc<Mutex<T>>`.
+#[derive(Default)]
+pub struct Extensions {
+    map: Mutex<AnyMap>,
+}
+
+impl Extensions {
+    /// Insert `value`, returning the previous value of the same type, if any.
+    pub fn insert<T: Send + Sync + 'static>(&self, value: T) -> Option<T> {
+        self.lock()
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(downcast)
+    }
+
+    /// A clone of the value of type `T`, if there is one.
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.lock()
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref::<T>())
+            .cloned()
+    }
+
+    /// A clone of the value of type `T`, inserting the result of `f` if there isn't one.
+    ///
+    /// The map is locked while `f` runs, so `f` shouldn't use these extensions.
+    pub fn get_or_insert_with<T, F>(&self, f: F) -> T
+    where
+        T: Clone + Send + Sync + 'static,
+        F: FnOnce() -> T,
+    {
+        let mut map = self.lock();
+        let value = map
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(f()));
+        match value.downcast_ref::<T>() {
+            Some(value) => value.clone(),
+            None => unreachable!("extensions are keyed by their type"),
+        }
+    }
+
+    /// Remove the value of type `T`, returning it if there was one.
+    pub fn remove<T: Send + Sync + 'static>(&self) -> Option<T> {
+        self.lock().remove(&TypeId::of::<T>()).and_then(downcast)
+    }
+
+    /// Whether there's a value of type `T`.
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.lock().contains_key(&TypeId::of::<T>())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, AnyMap> {
+        self.map.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.lock().len())
+            .finish()
+    }
+}
+
+fn downcast<T: 'static>(value: Box<dyn Any + Send + Sync>) -> Option<T> {
+    value.downcast().ok().map(|value| *value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Extensions;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Authenticated(String);
+
+    #[test]
+    fn typed_values() {
+        let extensions = Extensions::default();
+        assert_eq!(extensions.get::<Authenticated>(), None);
+        assert!(!extensions.contains::<u32>());
+
+        assert_eq!(extensions.insert(Authenticated("alice".into())), None);
+        assert_eq!(extensions.insert(7u32), None);
+        assert_eq!(
+            extensions.get::<Authenticated>(),
+            Some(Authenticated("alice".into()))
+        );
+        assert_eq!(extensions.get::<u32>(), Some(7));
+
+        // values of the same type replace each other
+        assert_eq!(
+            extensions.insert(Authenticated("bob".into())),
+            Some(Authenticated("alice".into()))
+        );
+        assert_eq!(extensions.remove::<u32>(), Some(7));
+        assert!(!extensions.contains::<u32>());
+        assert!(extensions.contains::<Authenticated>());
+    }
+
+    #[test]
+    fn shared_state() {
+        let extensions = Extensions::default();
+        let counter = extensions.get_or_insert_with(|| Arc::new(AtomicUsize::new(0)));
+        let _ = counter.fetch_add(1, Ordering::Relaxed);
+
+        let counter = extensions.get_or_insert_with(|| Arc::new(AtomicUsize::new(0)));
+        assert_eq!(counter.load(Ordering::Relaxed), 1);
+    }
+}
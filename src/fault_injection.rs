@@ -0,0 +1,358 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Fault injection between endpoints, for stress and soak testing.
+//!
+//! This module is only available with the `fault-injection` feature.
+//!
+//! A [`FaultyLink`] is a UDP relay that forwards datagrams to a target endpoint, dropping,
+//! delaying, duplicating, or corrupting them as configured by [`Faults`]. Endpoints connect to
+//! [`FaultyLink::addr`] rather than the target, and their datagrams (and the target's replies) are
+//! subjected to the faults in both directions:
+//!
+//! ```text
+//! client ---> FaultyLink::addr() ---> target
+//!        <---                    <---
+//! ```
+//!
+//! Each client address is relayed from a socket of its own, so the target sees one address per
+//! client, as it would without the relay.
+//!
+//! Delays follow tokio's clock, like the rest of qp2p's timing, so a link with a fixed
+//! [`latency`](Faults::latency) behaves the same from run to run, however loaded the machine
+//! running the test is.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+use tokio::{net::UdpSocket, task::JoinHandle, time::sleep};
+use tracing::{trace, warn};
+
+// Large enough for any datagram quinn will send.
+const MAX_DATAGRAM_LEN: usize = 64 * 1024;
+
+/// The faults a [`FaultyLink`] injects.
+///
+/// Probabilities are in `[0, 1]`, and are applied independently to each datagram, in each
+/// direction. The default injects no faults.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Faults {
+    /// The probability of dropping a datagram.
+    pub drop: f64,
+
+    /// The probability of sending a datagram twice.
+    pub duplicate: f64,
+
+    /// The probability of flipping a random bit in a datagram.
+    pub corrupt: f64,
+
+    /// The probability of delaying a datagram, which reorders it with respect to later datagrams.
+    pub delay: f64,
+
+    /// The longest a datagram is delayed by. Delays are uniformly distributed up to this.
+    pub max_delay: Duration,
+
+    /// A fixed delay added to every datagram, in each direction, as if the link were that long.
+    ///
+    /// Unlike [`delay`](Self::delay), this doesn't reorder datagrams, and doesn't count as
+    /// [delaying](FaultStats::delayed) them.
+    pub latency: Duration,
+
+    /// The seed for the random decisions, so that a failing run can be reproduced (to the extent
+    /// that the traffic itself is reproducible).
+    pub seed: u64,
+}
+
+/// Counts of what a [`FaultyLink`] has done with the datagrams it relayed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FaultStats {
+    /// Datagrams received by the link, in either direction.
+    pub received: u64,
+
+    /// Datagrams dropped.
+    pub dropped: u64,
+
+    /// Datagrams sent twice.
+    pub duplicated: u64,
+
+    /// Datagrams corrupted.
+    pub corrupted: u64,
+
+    /// Datagrams delayed.
+    pub delayed: u64,
+}
+
+/// A UDP relay to an endpoint, which injects [`Faults`] into the datagrams it relays.
+///
+/// The relay stops when the link is dropped.
+#[derive(Debug)]
+pub struct FaultyLink {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+    task: JoinHandle<()>,
+}
+
+impl FaultyLink {
+    /// Start relaying to `target`, from a new socket on the loopback interface.
+    pub async fn new(target: SocketAddr, faults: Faults) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(loopback(target)).await?);
+        let addr = socket.local_addr()?;
+        let shared = Arc::new(Shared {
+            rng: Mutex::new(Rng::new(faults.seed)),
+            faults: Mutex::new(faults),
+            stats: Stats::default(),
+            tasks: Mutex::default(),
+        });
+        let task = tokio::spawn(relay(socket, target, shared.clone()));
+
+        Ok(Self { addr, shared, task })
+    }
+
+    /// The address to connect to, in place of the target.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Change the faults injected from now on (e.g. to partition the endpoints, by dropping
+    /// everything).
+    ///
+    /// The random decisions are reseeded from [`Faults::seed`].
+    pub fn set_faults(&self, faults: Faults) {
+        *lock(&self.shared.rng) = Rng::new(faults.seed);
+        *lock(&self.shared.faults) = faults;
+    }
+
+    /// What the link has done so far.
+    pub fn stats(&self) -> FaultStats {
+        self.shared.stats.snapshot()
+    }
+}
+
+impl Drop for FaultyLink {
+    fn drop(&mut self) {
+        self.task.abort();
+        for task in lock(&self.shared.tasks).drain(..) {
+            task.abort();
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    faults: Mutex<Faults>,
+    rng: Mutex<Rng>,
+    stats: Stats,
+    // the tasks relaying replies
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    received: AtomicU64,
+    dropped: AtomicU64,
+    duplicated: AtomicU64,
+    corrupted: AtomicU64,
+    delayed: AtomicU64,
+}
+
+impl Stats {
+    fn snapshot(&self) -> FaultStats {
+        FaultStats {
+            received: self.received.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            duplicated: self.duplicated.load(Ordering::Relaxed),
+            corrupted: self.corrupted.load(Ordering::Relaxed),
+            delayed: self.delayed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// What to do with a single datagram.
+#[derive(Debug, Default)]
+struct Fate {
+    drop: bool,
+    duplicate: bool,
+    corrupt: Option<usize>,
+    delay: Option<Duration>,
+    latency: Duration,
+}
+
+impl Shared {
+    fn decide(&self, len: usize) -> Fate {
+        let faults = lock(&self.faults).clone();
+        let mut rng = lock(&self.rng);
+        let mut fate = Fate {
+            latency: faults.latency,
+            ..Fate::default()
+        };
+        if rng.chance(faults.drop) {
+            fate.drop = true;
+            return fate;
+        }
+        fate.duplicate = rng.chance(faults.duplicate);
+        if len > 0 && rng.chance(faults.corrupt) {
+            fate.corrupt = Some((rng.next() % (len as u64 * 8)) as usize);
+        }
+        if rng.chance(faults.delay) {
+            fate.delay = Some(faults.max_delay.mul_f64(rng.fraction()));
+        }
+        fate
+    }
+
+    // Forward `datagram` to `to` via `socket`, subject to the faults.
+    async fn forward(&self, socket: &Arc<UdpSocket>, mut datagram: Vec<u8>, to: SocketAddr) {
+        let _ = self.stats.received.fetch_add(1, Ordering::Relaxed);
+        let fate = self.decide(datagram.len());
+        if fate.drop {
+            let _ = self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if let Some(bit) = fate.corrupt {
+            datagram[bit / 8] ^= 1 << (bit % 8);
+            let _ = self.stats.corrupted.fetch_add(1, Ordering::Relaxed);
+        }
+        let copies = if fate.duplicate {
+            let _ = self.stats.duplicated.fetch_add(1, Ordering::Relaxed);
+            2
+        } else {
+            1
+        };
+
+        if fate.delay.is_some() {
+            let _ = self.stats.delayed.fetch_add(1, Ordering::Relaxed);
+        }
+        let delay = fate.latency + fate.delay.unwrap_or_default();
+        if delay.is_zero() {
+            send(socket, &datagram, to, copies).await;
+        } else {
+            // delayed datagrams are sent in the background, so later datagrams with shorter delays
+            // overtake them
+            let socket = socket.clone();
+            let _ = tokio::spawn(async move {
+                sleep(delay).await;
+                send(&socket, &datagram, to, copies).await;
+            });
+        }
+    }
+}
+
+async fn send(socket: &UdpSocket, datagram: &[u8], to: SocketAddr, copies: usize) {
+    for _ in 0..copies {
+        if let Err(error) = socket.send_to(datagram, to).await {
+            trace!("Faulty link failed to send to {}: {}", to, error);
+        }
+    }
+}
+
+// Relay datagrams from clients on `socket` to `target`, and replies back.
+async fn relay(socket: Arc<UdpSocket>, target: SocketAddr, shared: Arc<Shared>) {
+    let mut upstreams: HashMap<SocketAddr, Arc<UdpSocket>> = HashMap::new();
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+    loop {
+        let (len, client) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(error) => {
+                // e.g. ICMP port unreachable, reported on the next receive
+                trace!("Faulty link failed to receive: {}", error);
+                continue;
+            }
+        };
+
+        let upstream = match upstreams.get(&client) {
+            Some(upstream) => upstream.clone(),
+            None => match UdpSocket::bind(loopback(target)).await {
+                Ok(upstream) => {
+                    let upstream = Arc::new(upstream);
+                    let _ = upstreams.insert(client, upstream.clone());
+                    lock(&shared.tasks).push(tokio::spawn(reply(
+                        upstream.clone(),
+                        socket.clone(),
+                        client,
+                        shared.clone(),
+                    )));
+                    upstream
+                }
+                Err(error) => {
+                    warn!(
+                        "Faulty link failed to bind a socket for {}: {}",
+                        client, error
+                    );
+                    continue;
+                }
+            },
+        };
+
+        shared.forward(&upstream, buf[..len].to_vec(), target).await;
+    }
+}
+
+// Relay replies to `client` that arrive on `upstream`.
+async fn reply(
+    upstream: Arc<UdpSocket>,
+    socket: Arc<UdpSocket>,
+    client: SocketAddr,
+    shared: Arc<Shared>,
+) {
+    let mut buf = vec![0; MAX_DATAGRAM_LEN];
+    loop {
+        match upstream.recv_from(&mut buf).await {
+            Ok((len, _)) => shared.forward(&socket, buf[..len].to_vec(), client).await,
+            Err(error) => trace!("Faulty link failed to receive for {}: {}", client, error),
+        }
+    }
+}
+
+fn loopback(target: SocketAddr) -> SocketAddr {
+    match target {
+        SocketAddr::V4(_) => (Ipv4Addr::LOCALHOST, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::LOCALHOST, 0).into(),
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // the locks are never held across anything that can panic
+    mutex.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+// A small, seedable PRNG (xorshift64*), so runs can be reproduced.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero, so mix the seed with a constant
+        match seed ^ 0x9e37_79b9_7f4a_7c15 {
+            0 => Self(0x9e37_79b9_7f4a_7c15),
+            state => Self(state),
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // A random number in [0, 1).
+    fn fraction(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1_u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.fraction() < probability
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Application-level handshake performed when connections are established.
+
+use crate::{
+    connection::ConnectionServices,
+    e2e::{self, KeyShare, PeerShare, Session},
+    error::ConnectionError,
+    wire_msg::WireMsg,
+};
+use bytes::Bytes;
+use futures::StreamExt;
+use std::{fmt, net::SocketAddr, sync::Arc};
+use tokio::time::{timeout, Duration};
+
+// Time allowed for the peer to complete the hello exchange.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Error code used when closing a connection because its hello was rejected.
+const HELLO_REJECTED: u32 = 1;
+
+/// Supplies and validates the hello payloads exchanged on new connections.
+///
+/// When configured (via [`Config::hello_provider`](crate::Config::hello_provider)), the first
+/// message on every new connection is a hello from each side. Connections are only returned from
+/// [`Endpoint::connect_to`](crate::Endpoint::connect_to) or yielded by
+/// [`IncomingConnections`](crate::IncomingConnections) once the peer's hello has been validated,
+/// after which it's available from [`Connection::peer_hello`](crate::Connection::peer_hello).
+///
+/// Both peers must be configured with a hello provider, otherwise connections between them will
+/// fail.
+pub trait HelloProvider: fmt::Debug + Send + Sync {
+    /// The hello to send to `peer`.
+    fn hello(&self, peer: SocketAddr) -> Bytes;
+
+    /// Whether to accept a connection with `peer`, given its hello.
+    ///
+    /// The default implementation accepts every hello.
+    fn validate(&self, peer: SocketAddr, hello: &Bytes) -> bool {
+        let _ = (peer, hello);
+        true
+    }
+}
+
+// The outcome of a hello exchange.
+#[derive(Debug, Default)]
+pub(crate) struct Exchange {
+    // the peer's hello, if there's a hello provider
+    pub(crate) peer_hello: Option<Bytes>,
+    // keys for end-to-end encryption, if enabled
+    pub(crate) session: Option<Arc<Session>>,
+}
+
+// Whether connections need a hello exchange, for the hello provider or for key agreement.
+pub(crate) fn required(services: &ConnectionServices) -> bool {
+    services.hello_provider.is_some() || services.e2e_encryption
+}
+
+// Perform the hello exchange for an outgoing connection.
+//
+// With end-to-end encryption, if we have a signing key, we confirm the key agreement with a
+// signature once we have the peer's hello (see `e2e`).
+pub(crate) async fn initiate(
+    services: &ConnectionServices,
+    connection: &quinn::NewConnection,
+) -> Result<Exchange, ConnectionError> {
+    let peer_addr = connection.connection.remote_address();
+    let signing_key = services.signing_key.as_deref();
+    let key_share = key_share(services)?;
+    let hello = own_hello(services, peer_addr);
+    let hello = match &key_share {
+        Some(key_share) => key_share.offer(hello, signing_key),
+        None => hello,
+    };
+    let exchange = async {
+        let (mut send_stream, mut recv_stream) = connection.connection.open_bi().await?;
+        WireMsg::Hello(hello)
+            .write_to_stream(&mut send_stream, &services.buffer_pool)
+            .await
+            .map_err(hello_error)?;
+
+        let hello = read_hello(&mut recv_stream).await?;
+        let (hello, peer_share) = match &key_share {
+            Some(key_share) => {
+                let (hello, peer_share) =
+                    e2e::accept_reply(&hello, key_share, signing_key).map_err(hello_error)?;
+                if let Some(confirmation) = key_share.confirm(&peer_share, signing_key) {
+                    WireMsg::Hello(confirmation)
+                        .write_to_stream(&mut send_stream, &services.buffer_pool)
+                        .await
+                        .map_err(hello_error)?;
+                }
+                (hello, Some(peer_share))
+            }
+            None => (hello, None),
+        };
+        let _ = send_stream.finish().await;
+        Ok::<_, ConnectionError>((hello, peer_share))
+    };
+
+    let (hello, peer_share) = timeout(HELLO_TIMEOUT, exchange)
+        .await
+        .map_err(|_| ConnectionError::Hello("timed out waiting for hello".to_string()))??;
+    let session = agree(key_share, peer_share, true)?;
+    let peer_hello = validate(services, connection, hello)?;
+    Ok(Exchange {
+        peer_hello,
+        session,
+    })
+}
+
+// Perform the hello exchange for an incoming connection.
+//
+// The peer's hello is validated before ours is sent, so we don't reveal anything to rejected peers.
+pub(crate) async fn respond(
+    services: &ConnectionServices,
+    connection: &mut quinn::NewConnection,
+) -> Result<Exchange, ConnectionError> {
+    let peer_addr = connection.connection.remote_address();
+    let signing_key = services.signing_key.as_deref();
+    let bi_streams = &mut connection.bi_streams;
+    let receive = async {
+        match bi_streams.next().await {
+            Some(Ok((send_stream, mut recv_stream))) => {
+                let hello = read_hello(&mut recv_stream).await?;
+                Ok((send_stream, recv_stream, hello))
+            }
+            Some(Err(error)) => Err(ConnectionError::from(error)),
+            None => Err(ConnectionError::Hello(
+                "connection closed before hello".to_string(),
+            )),
+        }
+    };
+
+    let (mut send_stream, mut recv_stream, peer_hello) =
+        timeout(HELLO_TIMEOUT, receive)
+            .await
+            .map_err(|_| ConnectionError::Hello("timed out waiting for hello".to_string()))??;
+    let key_share = key_share(services)?;
+    let (peer_hello, peer_share) = match &key_share {
+        Some(_) => {
+            let (hello, peer_share) = e2e::accept_offer(&peer_hello).map_err(hello_error)?;
+            (hello, Some(peer_share))
+        }
+        None => (peer_hello, None),
+    };
+    let peer_hello = validate(services, connection, peer_hello)?;
+
+    let hello = own_hello(services, peer_addr);
+    let hello = match (&key_share, &peer_share) {
+        (Some(key_share), Some(peer_share)) => key_share.reply(hello, peer_share, signing_key),
+        _ => hello,
+    };
+    WireMsg::Hello(hello)
+        .write_to_stream(&mut send_stream, &services.buffer_pool)
+        .await
+        .map_err(hello_error)?;
+
+    let peer_share = match (&key_share, peer_share) {
+        (Some(key_share), Some(mut peer_share)) if peer_share.awaits_confirmation() => {
+            let confirmation = timeout(HELLO_TIMEOUT, read_hello(&mut recv_stream))
+                .await
+                .map_err(|_| {
+                    ConnectionError::Hello("timed out waiting for key confirmation".to_string())
+                })??;
+            key_share
+                .verify_confirmation(&mut peer_share, &confirmation, signing_key)
+                .map_err(hello_error)?;
+            Some(peer_share)
+        }
+        (_, peer_share) => peer_share,
+    };
+    let _ = send_stream.finish().await;
+
+    Ok(Exchange {
+        peer_hello,
+        session: agree(key_share, peer_share, false)?,
+    })
+}
+
+// Our hello for `peer`, if there's a hello provider.
+fn own_hello(services: &ConnectionServices, peer: SocketAddr) -> Bytes {
+    services
+        .hello_provider
+        .as_ref()
+        .map(|provider| provider.hello(peer))
+        .unwrap_or_default()
+}
+
+// A key share to append to our hello, if end-to-end encryption is enabled.
+fn key_share(services: &ConnectionServices) -> Result<Option<KeyShare>, ConnectionError> {
+    if !services.e2e_encryption {
+        return Ok(None);
+    }
+    KeyShare::generate()
+        .map(Some)
+        .map_err(ConnectionError::Hello)
+}
+
+// Agree keys with the peer, if end-to-end encryption is enabled.
+fn agree(
+    key_share: Option<KeyShare>,
+    peer_share: Option<PeerShare>,
+    initiator: bool,
+) -> Result<Option<Arc<Session>>, ConnectionError> {
+    match (key_share, peer_share) {
+        (Some(key_share), Some(peer_share)) => key_share
+            .agree(peer_share, initiator)
+            .map(|session| Some(Arc::new(session)))
+            .map_err(ConnectionError::Hello),
+        _ => Ok(None),
+    }
+}
+
+// Validate the peer's hello, if there's a hello provider.
+fn validate(
+    services: &ConnectionServices,
+    connection: &quinn::NewConnection,
+    hello: Bytes,
+) -> Result<Option<Bytes>, ConnectionError> {
+    let provider = match &services.hello_provider {
+        Some(provider) => provider,
+        None => return Ok(None),
+    };
+    if provider.validate(connection.connection.remote_address(), &hello) {
+        Ok(Some(hello))
+    } else {
+        connection
+            .connection
+            .close(HELLO_REJECTED.into(), b"hello rejected");
+        Err(ConnectionError::Hello("hello rejected".to_string()))
+    }
+}
+
+async fn read_hello(recv_stream: &mut quinn::RecvStream) -> Result<Bytes, ConnectionError> {
+    match WireMsg::read_from_stream(recv_stream).await {
+        Ok(Some(WireMsg::Hello(hello))) => Ok(hello),
+        Ok(msg) => Err(ConnectionError::Hello(format!(
+            "expected hello, got {}",
+            msg.map_or_else(|| "end of stream".to_string(), |msg| msg.to_string())
+        ))),
+        Err(error) => Err(hello_error(error)),
+    }
+}
+
+fn hello_error(error: impl fmt::Display) -> ConnectionError {
+    ConnectionError::Hello(error.to_string())
+}
@@ -0,0 +1,73 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Serde support for durations in a human-friendly form, such as `"1m 30s"` or `"500ms"`.
+//!
+//! For use with `#[serde(with = "...")]`. Human-readable formats (e.g. JSON or TOML) get the
+//! human-friendly form, and also accept serde's usual `{ secs, nanos }` form, so configs written
+//! before durations were formatted this way still load. Other formats (e.g. bincode) are
+//! unaffected.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::time::Duration;
+
+pub(crate) fn serialize<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        serializer.collect_str(&humantime::format_duration(*duration))
+    } else {
+        duration.serialize(serializer)
+    }
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    if deserializer.is_human_readable() {
+        match AnyDuration::deserialize(deserializer)? {
+            AnyDuration::Human(text) => humantime::parse_duration(&text).map_err(de::Error::custom),
+            AnyDuration::Struct(duration) => Ok(duration),
+        }
+    } else {
+        Duration::deserialize(deserializer)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AnyDuration {
+    Human(String),
+    Struct(Duration),
+}
+
+// A duration that (de)serializes in the human-friendly form.
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+struct Human(#[serde(with = "crate::human_duration")] Duration);
+
+pub(crate) mod option {
+    use super::Human;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::Duration;
+
+    pub(crate) fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        duration.map(Human).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<Human>::deserialize(deserializer)?.map(|Human(duration)| duration))
+    }
+}
@@ -0,0 +1,49 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Identification of the peers of connections, independent of their addresses.
+
+use crate::{address_book::PeerId, connection::Connection};
+use ring::digest::{digest, SHA256};
+use std::fmt;
+
+/// Derives the identity of a connection's peer.
+///
+/// When configured (via [`Config::peer_identifier`](crate::Config::peer_identifier)), every new
+/// connection is identified once it's established (and after any hello exchange), and its
+/// identity is available from [`Connection::peer_id`](crate::Connection::peer_id). Connections can
+/// then be looked up with
+/// [`Endpoint::get_connection_by_peer`](crate::Endpoint::get_connection_by_peer), which finds a
+/// peer's connection even if it has reconnected from a different address.
+pub trait PeerIdentifier: fmt::Debug + Send + Sync {
+    /// The identity of the peer of `connection`, or `None` if it can't be identified.
+    ///
+    /// This is called before the connection is returned to the application, so it could, for
+    /// example, derive the identity from the peer's [hello](Connection::peer_hello).
+    fn identify(&self, connection: &Connection) -> Option<PeerId>;
+}
+
+/// A [`PeerIdentifier`] that identifies peers by the SHA-256 hash of their TLS certificate.
+///
+/// qp2p doesn't ask connecting peers for a certificate, so only the peers of outgoing connections
+/// (i.e. those accepting the connection) can be identified this way. Peers should use a persistent
+/// certificate (see [`Endpoint::reload_tls`](crate::Endpoint::reload_tls)), since by default a new
+/// one is generated for each endpoint.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CertificateIdentifier;
+
+impl PeerIdentifier for CertificateIdentifier {
+    fn identify(&self, connection: &Connection) -> Option<PeerId> {
+        let certificates = connection.peer_certificates()?;
+        let certificate = certificates.first()?;
+        let mut id = [0; 32];
+        id.copy_from_slice(digest(&SHA256, &certificate.0).as_ref());
+        Some(PeerId(id))
+    }
+}
@@ -0,0 +1,404 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Buffering incoming messages until the application receives them, within limits on their size.
+
+use crate::{
+    config::{OverflowPolicy, DEFAULT_MAX_BUFFERED_BYTES_PER_CONNECTION},
+    error::RecvError,
+};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Notify;
+
+// TODO: this seems arbitrary - it may need tuned or made configurable.
+const INCOMING_MESSAGE_BUFFER_LEN: usize = 10_000;
+
+/// The incoming messages waiting to be received, as returned by
+/// [`Endpoint::incoming_buffer`](crate::Endpoint::incoming_buffer) and
+/// [`ConnectionIncoming::buffered`](crate::ConnectionIncoming::buffered).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferUsage {
+    /// The number of messages buffered.
+    pub messages: usize,
+
+    /// The total size of the messages buffered, in bytes.
+    pub bytes: usize,
+
+    /// The number of messages discarded so far because the buffer was full.
+    ///
+    /// Only messages discarded by the [`OverflowPolicy`] are counted, and not those left unread
+    /// when the receiver is dropped.
+    pub dropped: u64,
+}
+
+impl BufferUsage {
+    fn add(&mut self, len: usize) {
+        self.messages += 1;
+        self.bytes += len;
+    }
+
+    fn remove(&mut self, len: usize) {
+        self.messages -= 1;
+        self.bytes -= len;
+    }
+}
+
+// The limits on buffered messages, and the usage of all of an endpoint's connections together.
+#[derive(Debug)]
+pub(crate) struct InboxLimits {
+    max_bytes_per_connection: usize,
+    max_bytes: Option<usize>,
+    policy: OverflowPolicy,
+    usage: Mutex<BufferUsage>,
+    // notified whenever a message leaves any of the endpoint's buffers
+    space: Notify,
+}
+
+impl Default for InboxLimits {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_MAX_BUFFERED_BYTES_PER_CONNECTION,
+            None,
+            OverflowPolicy::default(),
+        )
+    }
+}
+
+impl InboxLimits {
+    pub(crate) fn new(
+        max_bytes_per_connection: usize,
+        max_bytes: Option<usize>,
+        policy: OverflowPolicy,
+    ) -> Self {
+        Self {
+            max_bytes_per_connection,
+            max_bytes,
+            policy,
+            usage: Mutex::default(),
+            space: Notify::new(),
+        }
+    }
+
+    pub(crate) fn usage(&self) -> BufferUsage {
+        *self.usage.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+// A buffered message (or error), along with its size.
+struct Entry<T> {
+    item: Result<T, RecvError>,
+    len: usize,
+}
+
+struct State<T> {
+    entries: VecDeque<Entry<T>>,
+    usage: BufferUsage,
+    senders: usize,
+    receiver: bool,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    limits: Arc<InboxLimits>,
+    // notified when a message is pushed, or the last sender is dropped
+    pushed: Notify,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> std::sync::MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+// A connection's buffer of incoming messages, as a channel from its listeners to its
+// `ConnectionIncoming`.
+//
+// Unlike a plain channel, the buffer is limited by the size of its messages as well as their
+// number, both per connection and across the endpoint, and the endpoint's `OverflowPolicy` decides
+// what happens to messages that don't fit. Errors always fit, so they're never lost or held up.
+pub(crate) fn channel<T>(limits: Arc<InboxLimits>) -> (InboxSender<T>, InboxReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            entries: VecDeque::new(),
+            usage: BufferUsage::default(),
+            senders: 1,
+            receiver: true,
+        }),
+        limits,
+        pushed: Notify::new(),
+    });
+    (
+        InboxSender {
+            shared: shared.clone(),
+        },
+        InboxReceiver { shared },
+    )
+}
+
+pub(crate) struct InboxSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> InboxSender<T> {
+    // Buffer a message of `len` bytes, or an error.
+    //
+    // Returns `false` if the receiver is gone.
+    pub(crate) async fn send(&self, item: Result<T, RecvError>, len: usize) -> bool {
+        let limits = &self.shared.limits;
+        loop {
+            // registered before checking for room, so room made in the meantime isn't missed
+            let space = limits.space.notified();
+            {
+                let mut state = self.shared.lock();
+                if !state.receiver {
+                    return false;
+                }
+                if item.is_err() {
+                    self.push(&mut state, Entry { item, len: 0 });
+                    return true;
+                }
+
+                let mut endpoint = limits
+                    .usage
+                    .lock()
+                    .unwrap_or_else(|error| error.into_inner());
+                if limits.policy == OverflowPolicy::DropOldest {
+                    // only this connection's messages can be dropped, so there may still be no
+                    // room if the endpoint's buffer is full of other connections' messages
+                    while !fits(limits, &state.usage, &endpoint, len) {
+                        let oldest = match state.entries.iter().position(|entry| entry.item.is_ok())
+                        {
+                            Some(index) => state.entries.remove(index),
+                            None => break,
+                        };
+                        if let Some(oldest) = oldest {
+                            state.usage.remove(oldest.len);
+                            endpoint.remove(oldest.len);
+                            state.usage.dropped += 1;
+                            endpoint.dropped += 1;
+                        }
+                    }
+                }
+
+                if fits(limits, &state.usage, &endpoint, len) {
+                    state.usage.add(len);
+                    endpoint.add(len);
+                    drop(endpoint);
+                    self.push(&mut state, Entry { item, len });
+                    return true;
+                }
+
+                if limits.policy != OverflowPolicy::Wait {
+                    state.usage.dropped += 1;
+                    endpoint.dropped += 1;
+                    drop(endpoint);
+                    if limits.policy == OverflowPolicy::Error {
+                        self.push(
+                            &mut state,
+                            Entry {
+                                item: Err(RecvError::BufferFull),
+                                len: 0,
+                            },
+                        );
+                    }
+                    return true;
+                }
+            }
+            space.await;
+        }
+    }
+
+    fn push(&self, state: &mut State<T>, entry: Entry<T>) {
+        state.entries.push_back(entry);
+        self.shared.pushed.notify_one();
+    }
+}
+
+// Whether a message of `len` bytes fits in a connection's buffer and the endpoint's.
+//
+// A message always fits in an empty buffer, however large, so messages over the limit are still
+// received, one at a time.
+fn fits(
+    limits: &InboxLimits,
+    connection: &BufferUsage,
+    endpoint: &BufferUsage,
+    len: usize,
+) -> bool {
+    let within = |usage: &BufferUsage, max_bytes: Option<usize>| {
+        usage.messages == 0 || max_bytes.is_none_or(|max_bytes| usage.bytes + len <= max_bytes)
+    };
+    connection.messages < INCOMING_MESSAGE_BUFFER_LEN
+        && within(connection, Some(limits.max_bytes_per_connection))
+        && within(endpoint, limits.max_bytes)
+}
+
+impl<T> Clone for InboxSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for InboxSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.pushed.notify_one();
+        }
+    }
+}
+
+pub(crate) struct InboxReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> InboxReceiver<T> {
+    // The next message or error, or `None` once every sender is gone and the buffer is empty.
+    pub(crate) async fn recv(&mut self) -> Option<Result<T, RecvError>> {
+        loop {
+            {
+                let mut state = self.shared.lock();
+                if let Some(entry) = state.entries.pop_front() {
+                    if entry.item.is_ok() {
+                        state.usage.remove(entry.len);
+                        self.release(entry.len);
+                    }
+                    return Some(entry.item);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            // a notification sent in the meantime is kept, so it isn't missed
+            self.shared.pushed.notified().await;
+        }
+    }
+
+    pub(crate) fn usage(&self) -> BufferUsage {
+        self.shared.lock().usage
+    }
+
+    fn release(&self, len: usize) {
+        let limits = &self.shared.limits;
+        limits
+            .usage
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .remove(len);
+        limits.space.notify_waiters();
+    }
+}
+
+impl<T> Drop for InboxReceiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.receiver = false;
+        let mut endpoint = self
+            .shared
+            .limits
+            .usage
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        for entry in state.entries.drain(..) {
+            if entry.item.is_ok() {
+                endpoint.remove(entry.len);
+            }
+        }
+        drop(endpoint);
+        // wake senders waiting for room, so they see the receiver is gone
+        self.shared.limits.space.notify_waiters();
+    }
+}
+
+impl<T> std::fmt::Debug for InboxReceiver<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InboxReceiver")
+            .field("usage", &self.usage())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel, InboxLimits};
+    use crate::{config::OverflowPolicy, error::RecvError};
+    use std::sync::Arc;
+
+    fn limits(policy: OverflowPolicy) -> Arc<InboxLimits> {
+        Arc::new(InboxLimits::new(10, Some(15), policy))
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let (tx, mut rx) = channel(limits(OverflowPolicy::DropOldest));
+        for i in 0..4 {
+            assert!(tx.send(Ok(i), 4).await);
+        }
+        let usage = rx.usage();
+        assert_eq!((usage.messages, usage.bytes, usage.dropped), (2, 8, 2));
+        assert!(matches!(rx.recv().await, Some(Ok(2))));
+        assert!(matches!(rx.recv().await, Some(Ok(3))));
+    }
+
+    #[tokio::test]
+    async fn drop_newest_and_error() {
+        let (tx, mut rx) = channel(limits(OverflowPolicy::DropNewest));
+        for i in 0..3 {
+            assert!(tx.send(Ok(i), 4).await);
+        }
+        assert_eq!(rx.usage().dropped, 1);
+        assert!(matches!(rx.recv().await, Some(Ok(0))));
+
+        let (tx, mut rx) = channel(limits(OverflowPolicy::Error));
+        for i in 0..3 {
+            assert!(tx.send(Ok(i), 4).await);
+        }
+        assert!(matches!(rx.recv().await, Some(Ok(0))));
+        assert!(matches!(rx.recv().await, Some(Ok(1))));
+        assert!(matches!(rx.recv().await, Some(Err(RecvError::BufferFull))));
+    }
+
+    #[tokio::test]
+    async fn endpoint_limit_is_shared() {
+        let limits = limits(OverflowPolicy::DropNewest);
+        let (tx1, rx1) = channel(limits.clone());
+        let (tx2, _rx2) = channel(limits.clone());
+        assert!(tx1.send(Ok(()), 8).await);
+        assert!(tx2.send(Ok(()), 6).await);
+        assert!(tx2.send(Ok(()), 2).await);
+        assert_eq!(limits.usage().bytes, 14);
+        assert_eq!(limits.usage().dropped, 1);
+
+        // dropping a receiver frees its messages
+        drop(rx1);
+        assert_eq!(limits.usage().bytes, 6);
+        assert!(!tx1.send(Ok(()), 1).await);
+    }
+
+    #[tokio::test]
+    async fn wait_for_room() {
+        let (tx, mut rx) = channel(limits(OverflowPolicy::Wait));
+        assert!(tx.send(Ok(0), 8).await);
+        // an oversized message is accepted once the buffer is empty
+        let sent = tokio::spawn(async move { tx.send(Ok(1), 20).await });
+        tokio::task::yield_now().await;
+        assert_eq!(rx.usage().messages, 1);
+
+        assert!(matches!(rx.recv().await, Some(Ok(0))));
+        assert!(matches!(rx.recv().await, Some(Ok(1))));
+        assert!(sent.await.expect("sender panicked"));
+        assert!(rx.recv().await.is_none());
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Hooks for inspecting and transforming messages as they're sent and received.
+
+use crate::{
+    address_book::PeerId,
+    e2e::Session,
+    error::RecvError,
+    extensions::Extensions,
+    signing::{self, SigningKey},
+};
+use bytes::Bytes;
+use std::{fmt, net::SocketAddr, sync::Arc};
+
+/// The fate of an intercepted message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Intercepted {
+    /// Pass the message (possibly changed) on to the next interceptor, and then on to the peer or
+    /// the application.
+    Continue(Bytes),
+
+    /// Silently drop the message.
+    ///
+    /// A dropped outgoing message is reported to the sender as sent, and a dropped incoming message
+    /// is never seen by the application (though it's still acknowledged, if it was sent with
+    /// [`Connection::send_with_ack`](crate::Connection::send_with_ack)).
+    Drop,
+
+    /// Refuse the message, for the given reason.
+    ///
+    /// A rejected outgoing message fails to send with
+    /// [`SendError::Rejected`](crate::SendError::Rejected), and a rejected incoming message is
+    /// returned to the application as [`RecvError::Rejected`](crate::RecvError::Rejected).
+    Reject(String),
+}
+
+/// The connection a message is being sent or received on.
+#[derive(Clone, Debug)]
+pub struct MessageContext {
+    peer: SocketAddr,
+    connection_id: usize,
+    extensions: Arc<Extensions>,
+}
+
+impl MessageContext {
+    pub(crate) fn new(peer: SocketAddr, connection_id: usize, extensions: Arc<Extensions>) -> Self {
+        Self {
+            peer,
+            connection_id,
+            extensions,
+        }
+    }
+
+    /// The address of the peer.
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// The [`id`](crate::Connection::id) of the connection.
+    pub fn connection_id(&self) -> usize {
+        self.connection_id
+    }
+
+    /// The connection's [extensions](crate::Connection::extensions).
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+}
+
+/// A hook invoked on every user message sent and received by an endpoint.
+///
+/// Interceptors are registered with [`Config::interceptors`](crate::Config::interceptors), and
+/// can be used for cross-cutting concerns such as signing, metrics, or audit logging. Outgoing
+/// messages pass through the interceptors in the order they were registered, and incoming messages
+/// in the reverse order, so an interceptor that transforms messages (e.g. by signing them) sees
+/// incoming messages as it left outgoing ones.
+///
+/// Messages sent with [`Connection::send`](crate::Connection::send) (and its variants) and
+/// received from [`ConnectionIncoming`](crate::ConnectionIncoming) are intercepted. Messages sent
+/// and received directly on streams (such as responses on a bidirectional stream, or file
+/// transfers) are not.
+///
+/// If [`Config::signing_key`](crate::Config::signing_key) is set, outgoing messages are signed
+/// after they've passed through the interceptors, and incoming messages are verified before. The
+/// same goes for encryption, if [`Config::e2e_encryption`](crate::Config::e2e_encryption) is set,
+/// so interceptors always see plaintext.
+///
+/// Interceptors are called synchronously in the send and receive paths, so they should be quick.
+pub trait Interceptor: fmt::Debug + Send + Sync {
+    /// Intercept a message being sent to the peer.
+    fn outgoing(&self, context: &MessageContext, msg: Bytes) -> Intercepted {
+        let _ = context;
+        Intercepted::Continue(msg)
+    }
+
+    /// Intercept a message received from the peer.
+    fn incoming(&self, context: &MessageContext, msg: Bytes) -> Intercepted {
+        let _ = context;
+        Intercepted::Continue(msg)
+    }
+}
+
+// A connection's interceptors, signing key and encryption keys, along with the context to invoke
+// interceptors with.
+#[derive(Clone, Debug)]
+pub(crate) struct Interception {
+    interceptors: Arc<[Arc<dyn Interceptor>]>,
+    signing_key: Option<Arc<SigningKey>>,
+    session: Option<Arc<Session>>,
+    context: MessageContext,
+}
+
+impl Interception {
+    pub(crate) fn new(
+        interceptors: Arc<[Arc<dyn Interceptor>]>,
+        signing_key: Option<Arc<SigningKey>>,
+        session: Option<Arc<Session>>,
+        context: MessageContext,
+    ) -> Self {
+        Self {
+            interceptors,
+            signing_key,
+            session,
+            context,
+        }
+    }
+
+    // The connection the messages are sent and received on.
+    pub(crate) fn context(&self) -> &MessageContext {
+        &self.context
+    }
+
+    // The identity the peer signed its end-to-end encryption key share with, if it did.
+    pub(crate) fn e2e_signer(&self) -> Option<PeerId> {
+        self.session.as_ref().and_then(|session| session.peer())
+    }
+
+    // Pass an outgoing message through the interceptors, and then sign and encrypt it if enabled.
+    pub(crate) fn outgoing(&self, msg: Bytes) -> Intercepted {
+        let msg = match (self.intercept_outgoing(msg), &self.signing_key) {
+            (Intercepted::Continue(msg), Some(signing_key)) => signing_key.sign(&msg),
+            (Intercepted::Continue(msg), None) => msg,
+            (outcome, _) => return outcome,
+        };
+        match &self.session {
+            Some(session) => match session.seal(&msg) {
+                Some(sealed) => Intercepted::Continue(sealed),
+                None => Intercepted::Reject("the message is too large to encrypt".to_string()),
+            },
+            None => Intercepted::Continue(msg),
+        }
+    }
+
+    // Decrypt an incoming message and verify its signature, if enabled, before it's intercepted.
+    pub(crate) fn verify(&self, msg: Bytes) -> Result<(Bytes, Option<PeerId>), RecvError> {
+        let msg = match &self.session {
+            Some(session) => session.open(&msg).ok_or(RecvError::DecryptionFailed)?,
+            None => msg,
+        };
+        if self.signing_key.is_none() {
+            return Ok((msg, None));
+        }
+        signing::verify(&msg)
+            .map(|(msg, signer)| (msg, Some(signer)))
+            .ok_or(RecvError::InvalidSignature)
+    }
+
+    fn intercept_outgoing(&self, msg: Bytes) -> Intercepted {
+        let result = self.interceptors.iter().try_fold(msg, |msg, interceptor| {
+            match interceptor.outgoing(&self.context, msg) {
+                Intercepted::Continue(msg) => Ok(msg),
+                outcome => Err(outcome),
+            }
+        });
+        match result {
+            Ok(msg) => Intercepted::Continue(msg),
+            Err(outcome) => outcome,
+        }
+    }
+
+    // Pass an incoming message through the interceptors.
+    pub(crate) fn incoming(&self, msg: Bytes) -> Intercepted {
+        let result = self
+            .interceptors
+            .iter()
+            .rev()
+            .try_fold(msg, |msg, interceptor| {
+                match interceptor.incoming(&self.context, msg) {
+                    Intercepted::Continue(msg) => Ok(msg),
+                    outcome => Err(outcome),
+                }
+            });
+        match result {
+            Ok(msg) => Intercepted::Continue(msg),
+            Err(outcome) => outcome,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Intercepted, Interception, Interceptor, MessageContext};
+    use bytes::{Bytes, BytesMut};
+    use std::sync::Arc;
+
+    // appends its tag to outgoing messages, and strips it from incoming ones
+    #[derive(Debug)]
+    struct Tag(u8);
+
+    impl Interceptor for Tag {
+        fn outgoing(&self, _: &MessageContext, msg: Bytes) -> Intercepted {
+            let mut msg = BytesMut::from(&msg[..]);
+            msg.extend_from_slice(&[self.0]);
+            Intercepted::Continue(msg.freeze())
+        }
+
+        fn incoming(&self, _: &MessageContext, msg: Bytes) -> Intercepted {
+            match msg.split_last() {
+                Some((tag, rest)) if *tag == self.0 => {
+                    Intercepted::Continue(Bytes::copy_from_slice(rest))
+                }
+                _ => Intercepted::Reject(format!("missing tag {}", self.0)),
+            }
+        }
+    }
+
+    fn interception(interceptors: Vec<Arc<dyn Interceptor>>) -> Interception {
+        Interception::new(
+            interceptors.into(),
+            None,
+            None,
+            MessageContext::new(([127, 0, 0, 1], 1000).into(), 0, Arc::default()),
+        )
+    }
+
+    #[test]
+    fn chain_order() {
+        let interception = interception(vec![Arc::new(Tag(1)), Arc::new(Tag(2))]);
+
+        let msg = match interception.outgoing(Bytes::from_static(b"msg")) {
+            Intercepted::Continue(msg) => msg,
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        };
+        assert_eq!(&msg[..], b"msg\x01\x02");
+
+        // incoming messages are unwrapped in reverse
+        assert_eq!(
+            interception.incoming(msg),
+            Intercepted::Continue(Bytes::from_static(b"msg"))
+        );
+        assert_eq!(
+            interception.incoming(Bytes::from_static(b"msg\x02\x01")),
+            Intercepted::Reject("missing tag 2".to_string())
+        );
+    }
+
+    #[test]
+    fn no_interceptors() {
+        let interception = interception(Vec::new());
+        let msg = Bytes::from_static(b"msg");
+        assert_eq!(
+            interception.outgoing(msg.clone()),
+            Intercepted::Continue(msg.clone())
+        );
+        assert_eq!(
+            interception.incoming(msg.clone()),
+            Intercepted::Continue(msg)
+        );
+    }
+}
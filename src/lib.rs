@@ -33,25 +33,113 @@
     clippy::unicode_not_nfc
 )]
 
+mod address_book;
+mod buffer_pool;
+mod builder;
+mod circuit_breaker;
 pub mod config;
 mod connection;
+mod control;
+mod dedup;
+#[cfg(feature = "dht")]
+mod dht;
+mod e2e;
 mod endpoint;
 mod error;
+mod extensions;
+#[cfg(any(test, feature = "fault-injection"))]
+pub mod fault_injection;
+mod hello;
+mod human_duration;
+mod identity;
 #[cfg(feature = "igd")]
 mod igd;
+mod inbox;
+mod interceptor;
+#[cfg(feature = "igd")]
+mod natpmp;
+mod observed;
+mod observer;
+mod pause;
+mod peer_messages;
+mod peer_store;
+mod pex;
+#[cfg(feature = "igd")]
+mod port_mapping;
+mod rate_limit;
+mod raw;
+mod reachability;
+mod reconnect;
+mod registry;
+mod resolver;
+mod resumption;
+mod retry;
+mod rpc_server;
+mod scheduler;
+mod scoring;
+mod signing;
+mod sink;
+mod socket;
+mod split;
+mod stats;
+#[cfg(any(test, feature = "sync"))]
+pub mod sync;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+pub mod transfer;
 mod utils;
 mod wire_msg;
 
-pub use config::{Config, ConfigError, RetryConfig};
-pub use connection::{Connection, ConnectionIncoming, RecvStream, SendStream};
+pub use address_book::{AddressBook, AddressKind, PeerAddress, PeerId};
+pub use buffer_pool::BufferPoolStats;
+pub use builder::{ClientEndpoint, EndpointBuilder, PeerEndpoint, ServerEndpoint};
+pub use config::{
+    Config, ConfigDiff, ConfigError, CryptoProvider, MessageOrdering, OverflowPolicy,
+    PartialConfig, PortRange, RetryConfig, SocketConfig,
+};
+pub use connection::{
+    Connection, ConnectionIncoming, RecvStream, SendOptions, SendStream, TransportInfo,
+};
+pub use control::PeerState;
+#[cfg(feature = "dht")]
+pub use dht::{Contact, NodeId, BUCKET_SIZE};
 pub use endpoint::{Endpoint, IncomingConnections};
 #[cfg(feature = "igd")]
 pub use error::UpnpError;
 pub use error::{
-    ClientEndpointError, Close, ConnectionError, EndpointError, InternalConfigError, RecvError,
-    RpcError, SendError, SerializationError, StreamError, TransportErrorCode,
-    UnsupportedStreamOperation,
+    ClientEndpointError, Close, ConnectionError, EndpointError, ErrorKind, InternalConfigError,
+    PeerError, PeerStoreError, RecvError, RpcError, SendError, SerializationError, SigningKeyError,
+    StreamError, TransferError, TransportErrorCode, UnsupportedStreamOperation,
 };
+pub use extensions::Extensions;
+pub use hello::HelloProvider;
+pub use identity::{CertificateIdentifier, PeerIdentifier};
+pub use inbox::BufferUsage;
+pub use interceptor::{Intercepted, Interceptor, MessageContext};
+pub use observed::{ObservedAddress, ObservedAddresses};
+pub use observer::ConnectionObserver;
+pub use peer_messages::PeerMessages;
+pub use peer_store::{FilePeerStore, MemoryPeerStore, PeerCache, PeerStore};
+pub use pex::ExchangedPeer;
+#[cfg(feature = "igd")]
+pub use port_mapping::{PortMappingEvents, PortMappingProtocol, PortMappingStatus};
+pub use raw::{IncomingRawStreams, RawRecvStream, RawSendStream, RawStream};
+pub use reachability::{NatType, Reachability};
+pub use reconnect::{ReconnectEvents, ReconnectingConnection, ReconnectingIncoming, Reconnection};
+pub use registry::{ConnectionClass, ConnectionInfo, ConnectionPath, Traffic};
+pub use resolver::{PeerAddrs, Resolver, SystemResolver, ToPeerAddrs};
+pub use resumption::{ResumptionStats, SessionStore};
+pub use retry::{RetryEvent, RetryHook, RetryJitter};
+pub use rpc_server::RpcServer;
+pub use scheduler::{PriorityClass, QueueDepth};
+pub use scoring::{PeerEvent, PeerScoring, ScoredPeer};
+pub use signing::SigningKey;
+pub use sink::MessageSink;
+pub use stats::{Histogram, MessageStats};
+pub use transfer::{IncomingTransfer, IncomingTransfers, TransferProgress};
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub use wire_msg::fuzzing;
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,418 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Port mapping using NAT-PMP ([RFC 6886]) and its successor PCP ([RFC 6887]).
+//!
+//! Both protocols send requests over UDP to port 5351 on the gateway. Only UDP mappings for IPv4
+//! are supported.
+//!
+//! [RFC 6886]: https://datatracker.ietf.org/doc/html/rfc6886
+//! [RFC 6887]: https://datatracker.ietf.org/doc/html/rfc6887
+
+use crate::port_mapping::{PortMapping, PortMappingError, PortMappingProtocol};
+use futures::future::BoxFuture;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, time::timeout};
+
+// The port gateways listen for NAT-PMP and PCP requests on.
+const SERVER_PORT: u16 = 5351;
+
+// Requests are retransmitted with this initial timeout, doubling on each attempt.
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const MAX_ATTEMPTS: u32 = 4;
+
+// A lifetime of 0 deletes a mapping in both protocols, so 'unlimited' leases are requested with
+// this lifetime instead (the value recommended by RFC 6886).
+const DEFAULT_LIFETIME: u32 = 7200;
+
+const UDP: u8 = 17;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum NatPmpError {
+    #[error("Failed to communicate with the gateway")]
+    Io(#[from] io::Error),
+
+    #[error("The gateway did not respond")]
+    NoResponse,
+
+    #[error("The gateway rejected the request with result code {0}")]
+    Rejected(u16),
+}
+
+/// Port mapping using NAT-PMP.
+#[derive(Debug)]
+pub(crate) struct NatPmp {
+    gateway: Ipv4Addr,
+}
+
+impl NatPmp {
+    pub(crate) fn new(gateway: Ipv4Addr) -> Self {
+        Self { gateway }
+    }
+
+    async fn map(
+        &self,
+        ext_port: u16,
+        local_addr: SocketAddrV4,
+        lifetime: u32,
+    ) -> Result<SocketAddr, NatPmpError> {
+        let socket = gateway_socket(self.gateway, *local_addr.ip()).await?;
+
+        let external_ip = transact(&socket, &[0, 0], nat_pmp::decode_external_addr).await??;
+
+        let request = nat_pmp::encode_map(local_addr.port(), ext_port, lifetime);
+        let mapped_port = transact(&socket, &request, |response| {
+            nat_pmp::decode_map(response, local_addr.port())
+        })
+        .await??;
+
+        Ok(SocketAddrV4::new(external_ip, mapped_port).into())
+    }
+}
+
+impl PortMapping for NatPmp {
+    fn protocol(&self) -> PortMappingProtocol {
+        PortMappingProtocol::NatPmp
+    }
+
+    fn map_port(
+        &self,
+        ext_port: u16,
+        local_addr: SocketAddr,
+        lease_duration: Duration,
+    ) -> BoxFuture<'_, Result<SocketAddr, PortMappingError>> {
+        Box::pin(async move {
+            let local_addr = match local_addr {
+                SocketAddr::V4(local_addr) => local_addr,
+                SocketAddr::V6(_) => return Err(PortMappingError::NotSupported),
+            };
+            Ok(self
+                .map(ext_port, local_addr, lifetime(lease_duration))
+                .await?)
+        })
+    }
+}
+
+/// Port mapping using PCP.
+#[derive(Debug)]
+pub(crate) struct Pcp {
+    gateway: Ipv4Addr,
+    // Identifies our mapping to the gateway, so it must be the same for renewals.
+    nonce: [u8; 12],
+}
+
+impl Pcp {
+    pub(crate) fn new(gateway: Ipv4Addr) -> Self {
+        // `RandomState` is randomly seeded, which is all we need from the nonce.
+        let mut nonce = [0; 12];
+        for chunk in nonce.chunks_mut(4) {
+            let random = RandomState::new().build_hasher().finish().to_ne_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+
+        Self { gateway, nonce }
+    }
+
+    async fn map(
+        &self,
+        ext_port: u16,
+        local_addr: SocketAddrV4,
+        lifetime: u32,
+    ) -> Result<SocketAddr, NatPmpError> {
+        let socket = gateway_socket(self.gateway, *local_addr.ip()).await?;
+
+        // the gateway checks that the client address matches the request's source address
+        let client_ip = match socket.local_addr()?.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => return Err(io::Error::from(io::ErrorKind::AddrNotAvailable).into()),
+        };
+
+        let request = pcp::encode_map(
+            client_ip,
+            &self.nonce,
+            local_addr.port(),
+            ext_port,
+            lifetime,
+        );
+        let external_addr = transact(&socket, &request, |response| {
+            pcp::decode_map(response, &self.nonce, local_addr.port())
+        })
+        .await??;
+
+        Ok(external_addr.into())
+    }
+}
+
+impl PortMapping for Pcp {
+    fn protocol(&self) -> PortMappingProtocol {
+        PortMappingProtocol::Pcp
+    }
+
+    fn map_port(
+        &self,
+        ext_port: u16,
+        local_addr: SocketAddr,
+        lease_duration: Duration,
+    ) -> BoxFuture<'_, Result<SocketAddr, PortMappingError>> {
+        Box::pin(async move {
+            let local_addr = match local_addr {
+                SocketAddr::V4(local_addr) => local_addr,
+                SocketAddr::V6(_) => return Err(PortMappingError::NotSupported),
+            };
+            Ok(self
+                .map(ext_port, local_addr, lifetime(lease_duration))
+                .await?)
+        })
+    }
+}
+
+fn lifetime(lease_duration: Duration) -> u32 {
+    match lease_duration.as_secs() {
+        0 => DEFAULT_LIFETIME,
+        secs => secs.min(u32::MAX.into()) as u32,
+    }
+}
+
+// A socket for talking to the gateway, bound to the same IP as the mapping's local address so the
+// gateway maps the right host.
+async fn gateway_socket(gateway: Ipv4Addr, local_ip: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((local_ip, 0)).await?;
+    socket.connect((gateway, SERVER_PORT)).await?;
+    Ok(socket)
+}
+
+// Send `request` until `decode` accepts a response (returning `Some`), or we give up.
+async fn transact<T>(
+    socket: &UdpSocket,
+    request: &[u8],
+    mut decode: impl FnMut(&[u8]) -> Option<T>,
+) -> Result<T, NatPmpError> {
+    // large enough for any PCP message
+    let mut buf = [0; 1100];
+    let mut wait = INITIAL_TIMEOUT;
+
+    for _ in 0..MAX_ATTEMPTS {
+        let _ = socket.send(request).await?;
+
+        let receive = async {
+            loop {
+                let len = socket.recv(&mut buf).await?;
+                // ignore anything that isn't a response to our request
+                if let Some(response) = decode(&buf[..len]) {
+                    return Ok::<_, io::Error>(response);
+                }
+            }
+        };
+        if let Ok(response) = timeout(wait, receive).await {
+            return Ok(response?);
+        }
+
+        wait *= 2;
+    }
+
+    Err(NatPmpError::NoResponse)
+}
+
+// NAT-PMP message formats (RFC 6886, section 3).
+mod nat_pmp {
+    use super::NatPmpError;
+    use std::net::Ipv4Addr;
+
+    const VERSION: u8 = 0;
+    const OP_EXTERNAL_ADDR: u8 = 0;
+    const OP_MAP_UDP: u8 = 1;
+    const RESPONSE: u8 = 128;
+
+    pub(super) fn encode_map(internal_port: u16, ext_port: u16, lifetime: u32) -> [u8; 12] {
+        let mut request = [0; 12];
+        request[0] = VERSION;
+        request[1] = OP_MAP_UDP;
+        request[4..6].copy_from_slice(&internal_port.to_be_bytes());
+        request[6..8].copy_from_slice(&ext_port.to_be_bytes());
+        request[8..12].copy_from_slice(&lifetime.to_be_bytes());
+        request
+    }
+
+    // Decode an external address response, returning `None` if `response` isn't one.
+    pub(super) fn decode_external_addr(response: &[u8]) -> Option<Result<Ipv4Addr, NatPmpError>> {
+        let response = decode(response, OP_EXTERNAL_ADDR, 12)?;
+        Some(response.map(|response| {
+            let ip: [u8; 4] = response[8..12].try_into().unwrap_or_default();
+            Ipv4Addr::from(ip)
+        }))
+    }
+
+    // Decode a mapping response for `internal_port`, returning the mapped external port, or
+    // `None` if `response` isn't one.
+    pub(super) fn decode_map(
+        response: &[u8],
+        internal_port: u16,
+    ) -> Option<Result<u16, NatPmpError>> {
+        let response = decode(response, OP_MAP_UDP, 16)?;
+        match response {
+            Ok(response) if read_u16(&response[8..10]) != internal_port => None,
+            Ok(response) => Some(Ok(read_u16(&response[10..12]))),
+            Err(error) => Some(Err(error)),
+        }
+    }
+
+    fn decode(response: &[u8], op: u8, len: usize) -> Option<Result<&[u8], NatPmpError>> {
+        if response.len() < 4 || response[0] != VERSION || response[1] != RESPONSE + op {
+            return None;
+        }
+        match read_u16(&response[2..4]) {
+            0 if response.len() >= len => Some(Ok(response)),
+            0 => None,
+            result => Some(Err(NatPmpError::Rejected(result))),
+        }
+    }
+
+    fn read_u16(bytes: &[u8]) -> u16 {
+        u16::from_be_bytes(bytes.try_into().unwrap_or_default())
+    }
+}
+
+// PCP message formats (RFC 6887, sections 7 and 11).
+mod pcp {
+    use super::{NatPmpError, UDP};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
+
+    const VERSION: u8 = 2;
+    const OP_MAP: u8 = 1;
+    const RESPONSE: u8 = 0x80;
+    const MAP_LEN: usize = 60;
+
+    pub(super) fn encode_map(
+        client_ip: Ipv4Addr,
+        nonce: &[u8; 12],
+        internal_port: u16,
+        ext_port: u16,
+        lifetime: u32,
+    ) -> [u8; MAP_LEN] {
+        let mut request = [0; MAP_LEN];
+        // common request header
+        request[0] = VERSION;
+        request[1] = OP_MAP;
+        request[4..8].copy_from_slice(&lifetime.to_be_bytes());
+        request[8..24].copy_from_slice(&client_ip.to_ipv6_mapped().octets());
+        // MAP opcode
+        request[24..36].copy_from_slice(nonce);
+        request[36] = UDP;
+        request[40..42].copy_from_slice(&internal_port.to_be_bytes());
+        request[42..44].copy_from_slice(&ext_port.to_be_bytes());
+        // suggest any external address, of the same family as the client
+        request[44..60].copy_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+        request
+    }
+
+    // Decode a MAP response to our request, returning the assigned external address, or `None`
+    // if `response` isn't one.
+    pub(super) fn decode_map(
+        response: &[u8],
+        nonce: &[u8; 12],
+        internal_port: u16,
+    ) -> Option<Result<SocketAddrV4, NatPmpError>> {
+        if response.len() < MAP_LEN
+            || response[0] != VERSION
+            || response[1] != RESPONSE + OP_MAP
+            || &response[24..36] != nonce
+            || response[36] != UDP
+            || read_u16(&response[40..42]) != internal_port
+        {
+            return None;
+        }
+
+        match response[3] {
+            0 => {
+                let ip: [u8; 16] = response[44..60].try_into().ok()?;
+                let ip = Ipv6Addr::from(ip).to_ipv4()?;
+                Some(Ok(SocketAddrV4::new(ip, read_u16(&response[42..44]))))
+            }
+            result => Some(Err(NatPmpError::Rejected(result.into()))),
+        }
+    }
+
+    fn read_u16(bytes: &[u8]) -> u16 {
+        u16::from_be_bytes(bytes.try_into().unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{nat_pmp, pcp, NatPmpError};
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    #[test]
+    fn nat_pmp_map() {
+        assert_eq!(
+            nat_pmp::encode_map(5000, 6000, 7200),
+            [0, 1, 0, 0, 0x13, 0x88, 0x17, 0x70, 0, 0, 0x1c, 0x20]
+        );
+
+        let response = [
+            0, 129, 0, 0, 0, 0, 0, 1, 0x13, 0x88, 0x17, 0x71, 0, 0, 0x1c, 0x20,
+        ];
+        assert!(matches!(
+            nat_pmp::decode_map(&response, 5000),
+            Some(Ok(6001))
+        ));
+        // responses for other ports are ignored
+        assert!(nat_pmp::decode_map(&response, 5001).is_none());
+
+        let rejected = [0, 129, 0, 2, 0, 0, 0, 1, 0x13, 0x88, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(
+            nat_pmp::decode_map(&rejected, 5000),
+            Some(Err(NatPmpError::Rejected(2)))
+        ));
+    }
+
+    #[test]
+    fn nat_pmp_external_addr() {
+        let response = [0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7];
+        assert!(matches!(
+            nat_pmp::decode_external_addr(&response),
+            Some(Ok(ip)) if ip == Ipv4Addr::new(203, 0, 113, 7)
+        ));
+        assert!(nat_pmp::decode_external_addr(&response[..8]).is_none());
+    }
+
+    #[test]
+    fn pcp_map() {
+        let nonce = [7; 12];
+        let request = pcp::encode_map(Ipv4Addr::new(192, 168, 1, 2), &nonce, 5000, 6000, 7200);
+        assert_eq!(&request[..2], &[2, 1]);
+        assert_eq!(&request[20..24], &[192, 168, 1, 2]);
+        assert_eq!(&request[24..36], &nonce);
+
+        // a successful response echoes the request, with the assigned port and address
+        let mut response = request;
+        response[1] = 0x81;
+        response[42..44].copy_from_slice(&6001u16.to_be_bytes());
+        response[44..60].copy_from_slice(&Ipv4Addr::new(203, 0, 113, 7).to_ipv6_mapped().octets());
+        assert!(matches!(
+            pcp::decode_map(&response, &nonce, 5000),
+            Some(Ok(addr)) if addr == SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 7), 6001)
+        ));
+
+        // responses to other requests are ignored
+        assert!(pcp::decode_map(&response, &[8; 12], 5000).is_none());
+
+        response[3] = 8; // NO_RESOURCES
+        assert!(matches!(
+            pcp::decode_map(&response, &nonce, 5000),
+            Some(Err(NatPmpError::Rejected(8)))
+        ));
+    }
+}
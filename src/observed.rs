@@ -0,0 +1,235 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! The addresses other peers observe for an endpoint.
+
+use crate::{
+    connection::Connection,
+    error::{PeerError, RecvError, RpcError, SerializationError},
+    wire_msg::WireMsg,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::{timeout, Instant};
+use tracing::{debug, trace, warn};
+
+// How long a peer's report is kept. Reports are refreshed by each new connection to the peer, so
+// this bounds how long a stale address (e.g. from before a NAT rebinding) can count.
+const REPORT_TTL: Duration = Duration::from_secs(5 * 60);
+
+// How long to wait for a peer to report the address it sees.
+const OBSERVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The fewest peers that must agree on an address for it to be the consensus.
+const MIN_CONSENSUS_REPORTERS: usize = 2;
+
+/// The addresses other peers have observed for an endpoint, as returned by
+/// [`Endpoint::observed_addresses`](crate::Endpoint::observed_addresses).
+///
+/// When a connection is established, each side asks the other for the address it sees the
+/// connection coming from. The most recent report from each peer is kept for a few minutes, so a
+/// change of address (e.g. because a NAT rebound the endpoint's port) shows up as peers
+/// reconnect.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ObservedAddresses {
+    addrs: Vec<ObservedAddress>,
+    reporters: usize,
+}
+
+/// An address observed for an endpoint, and how many peers observed it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ObservedAddress {
+    /// The observed address.
+    pub addr: SocketAddr,
+
+    /// The number of peers that reported this address.
+    pub reporters: usize,
+}
+
+impl ObservedAddresses {
+    /// The observed addresses, most reported first.
+    pub fn addrs(&self) -> &[ObservedAddress] {
+        &self.addrs
+    }
+
+    /// The number of peers that reported an address.
+    pub fn reporters(&self) -> usize {
+        self.reporters
+    }
+
+    /// The fraction of reporting peers that observed the given address, from 0 to 1.
+    pub fn confidence(&self, addr: SocketAddr) -> f64 {
+        if self.reporters == 0 {
+            return 0.0;
+        }
+        let reporters = self
+            .addrs
+            .iter()
+            .find(|observed| observed.addr == addr)
+            .map_or(0, |observed| observed.reporters);
+        reporters as f64 / self.reporters as f64
+    }
+
+    /// The address observed by a majority of peers, if at least two peers agree on it.
+    ///
+    /// Comparing this with [`Endpoint::public_addr`](crate::Endpoint::public_addr) shows whether
+    /// the endpoint's address has changed since it was created.
+    pub fn consensus(&self) -> Option<SocketAddr> {
+        let first = self.addrs.first()?;
+        (first.reporters >= MIN_CONSENSUS_REPORTERS && first.reporters * 2 > self.reporters)
+            .then_some(first.addr)
+    }
+}
+
+// The latest address reported by each peer.
+#[derive(Debug, Default)]
+pub(crate) struct AddressObservations {
+    reports: Mutex<HashMap<SocketAddr, Report>>,
+}
+
+#[derive(Debug)]
+struct Report {
+    addr: SocketAddr,
+    at: Instant,
+}
+
+impl AddressObservations {
+    pub(crate) fn record(&self, peer: SocketAddr, addr: SocketAddr) {
+        self.record_at(peer, addr, Instant::now());
+    }
+
+    pub(crate) fn snapshot(&self) -> ObservedAddresses {
+        self.snapshot_at(Instant::now())
+    }
+
+    fn record_at(&self, peer: SocketAddr, addr: SocketAddr, at: Instant) {
+        let mut reports = self
+            .reports
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        if let Some(previous) = reports.insert(peer, Report { addr, at }) {
+            if previous.addr != addr {
+                warn!(
+                    "{} now sees us at {} (previously {}), our address may have changed",
+                    peer, addr, previous.addr
+                );
+            }
+        }
+    }
+
+    fn snapshot_at(&self, now: Instant) -> ObservedAddresses {
+        let mut reports = self
+            .reports
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        reports.retain(|_, report| now.saturating_duration_since(report.at) < REPORT_TTL);
+
+        let mut counts = HashMap::<SocketAddr, usize>::new();
+        for report in reports.values() {
+            *counts.entry(report.addr).or_default() += 1;
+        }
+
+        let mut addrs: Vec<_> = counts
+            .into_iter()
+            .map(|(addr, reporters)| ObservedAddress { addr, reporters })
+            .collect();
+        // ties are broken by address, so the order is stable
+        addrs.sort_by(|a, b| b.reporters.cmp(&a.reporters).then(a.addr.cmp(&b.addr)));
+
+        ObservedAddresses {
+            addrs,
+            reporters: reports.len(),
+        }
+    }
+}
+
+// Ask the peer of a new connection for the address it sees, in the background.
+pub(crate) fn observe(connection: Connection, observations: Arc<AddressObservations>) {
+    let _ = tokio::spawn(async move {
+        let peer = connection.remote_address();
+        match timeout(OBSERVE_TIMEOUT, echo(&connection)).await {
+            Ok(Ok(addr)) => {
+                trace!("{} sees us at {}", peer, addr);
+                observations.record(peer, addr);
+            }
+            Ok(Err(error)) => debug!("Failed to get our address as seen by {}: {}", peer, error),
+            Err(_) => debug!("Timed out getting our address as seen by {}", peer),
+        }
+    });
+}
+
+async fn echo(connection: &Connection) -> Result<SocketAddr, RpcError> {
+    let (mut send, mut recv) = connection.open_bi().await.map_err(PeerError::into_inner)?;
+    send.send_wire_msg(WireMsg::EndpointEchoReq).await?;
+
+    match recv.next_wire_msg().await? {
+        Some(WireMsg::EndpointEchoResp(addr)) => Ok(addr),
+        msg => Err(RecvError::Serialization(SerializationError::unexpected(&msg)).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AddressObservations, ObservedAddress, REPORT_TTL};
+    use std::net::SocketAddr;
+    use tokio::time::Instant;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn consensus() {
+        let observations = AddressObservations::default();
+        let now = Instant::now();
+        assert_eq!(observations.snapshot_at(now).consensus(), None);
+
+        // a single report isn't enough
+        observations.record_at(addr(1), addr(100), now);
+        assert_eq!(observations.snapshot_at(now).consensus(), None);
+
+        observations.record_at(addr(2), addr(100), now);
+        observations.record_at(addr(3), addr(200), now);
+        let snapshot = observations.snapshot_at(now);
+        assert_eq!(snapshot.reporters(), 3);
+        assert_eq!(
+            snapshot.addrs(),
+            &[
+                ObservedAddress {
+                    addr: addr(100),
+                    reporters: 2
+                },
+                ObservedAddress {
+                    addr: addr(200),
+                    reporters: 1
+                },
+            ]
+        );
+        assert_eq!(snapshot.consensus(), Some(addr(100)));
+        assert!((snapshot.confidence(addr(200)) - 1.0 / 3.0).abs() < f64::EPSILON);
+
+        // a peer changing its report breaks the majority
+        observations.record_at(addr(2), addr(200), now);
+        assert_eq!(observations.snapshot_at(now).consensus(), Some(addr(200)));
+        observations.record_at(addr(4), addr(100), now);
+        assert_eq!(observations.snapshot_at(now).consensus(), None);
+
+        // old reports expire
+        let later = now + REPORT_TTL;
+        observations.record_at(addr(3), addr(200), later);
+        observations.record_at(addr(5), addr(200), later);
+        let snapshot = observations.snapshot_at(later);
+        assert_eq!(snapshot.reporters(), 2);
+        assert_eq!(snapshot.consensus(), Some(addr(200)));
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Callbacks for connection lifecycle events.
+
+use crate::{connection::Connection, error::ConnectionError};
+use futures::future::{self, BoxFuture};
+use std::{fmt, net::SocketAddr};
+
+/// Async callbacks invoked as an endpoint's connections are established and closed.
+///
+/// Callbacks for each connection are run in order on a background task: `on_disconnect` is only
+/// called once `on_connect` has completed, even if the connection closes in the meantime.
+/// Callbacks for different connections may run concurrently.
+///
+/// This applies to every connection, including those opened internally (e.g. to verify
+/// reachability).
+pub trait ConnectionObserver: fmt::Debug + Send + Sync {
+    /// Called when a connection is established.
+    ///
+    /// If the observer keeps hold of `connection`, it will stay open until the observer drops it.
+    fn on_connect(&self, connection: Connection) -> BoxFuture<'static, ()>;
+
+    /// Called when the connection with the given [`id`](Connection::id) has closed.
+    ///
+    /// If the connection was closed because all of its handles were dropped, `reason` will be
+    /// [`ConnectionError::Closed`] with [`Close::Local`](crate::Close::Local).
+    fn on_disconnect(
+        &self,
+        id: usize,
+        peer: SocketAddr,
+        reason: ConnectionError,
+    ) -> BoxFuture<'static, ()>;
+
+    /// Called when the peer of the connection with the given [`id`](Connection::id) announces
+    /// that its endpoint is shutting down (see [`Endpoint::shutdown`](crate::Endpoint::shutdown)).
+    ///
+    /// The connection stays open while the peer drains, but new requests should be sent
+    /// elsewhere. Unlike the other callbacks, this may run concurrently with `on_connect`. The
+    /// default implementation does nothing.
+    fn on_go_away(&self, id: usize, peer: SocketAddr) -> BoxFuture<'static, ()> {
+        let _ = (id, peer);
+        Box::pin(future::ready(()))
+    }
+}
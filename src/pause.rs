@@ -0,0 +1,96 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Pausing an endpoint's incoming traffic, to shed load without dropping connections.
+
+use futures::{
+    future,
+    stream::{self, BoxStream, Stream, StreamExt},
+};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+// What's paused.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Paused {
+    connections: bool,
+    streams: bool,
+}
+
+// The pause switch of an endpoint's incoming traffic, shared by its listeners. Clones share the
+// switch.
+#[derive(Clone, Debug)]
+pub(crate) struct IncomingPause {
+    tx: Arc<watch::Sender<Paused>>,
+    // kept so the switch can be flipped while no listeners are watching
+    rx: watch::Receiver<Paused>,
+}
+
+impl Default for IncomingPause {
+    fn default() -> Self {
+        let (tx, rx) = watch::channel(Paused::default());
+        Self {
+            tx: Arc::new(tx),
+            rx,
+        }
+    }
+}
+
+impl IncomingPause {
+    // Pause incoming connections, and new streams on existing connections if `streams` is set.
+    pub(crate) fn pause(&self, streams: bool) {
+        let _ = self.tx.send(Paused {
+            connections: true,
+            streams,
+        });
+    }
+
+    pub(crate) fn resume(&self) {
+        let _ = self.tx.send(Paused::default());
+    }
+
+    pub(crate) fn connections_paused(&self) -> bool {
+        self.rx.borrow().connections
+    }
+
+    // `streams`, holding back new streams while streams are paused.
+    //
+    // Streams the peer opens in the meantime wait to be accepted, so the peer runs into its stream
+    // limit and can't open more until streams are resumed.
+    pub(crate) fn pausable<S>(&self, streams: S) -> BoxStream<'static, S::Item>
+    where
+        S: Stream + Send + Unpin + 'static,
+        S::Item: Send,
+    {
+        stream::unfold(
+            (streams, self.rx.clone()),
+            |(mut streams, mut rx)| async move {
+                loop {
+                    while rx.borrow().streams {
+                        if rx.changed().await.is_err() {
+                            // the switch is gone, so it can't be resumed
+                            break;
+                        }
+                    }
+
+                    // stop waiting for a stream if streams are paused in the meantime
+                    let next = match future::select(streams.next(), Box::pin(rx.changed())).await {
+                        future::Either::Left((item, _)) => Some(item),
+                        future::Either::Right((Ok(()), _)) => None,
+                        future::Either::Right((Err(_), next)) => Some(next.await),
+                    };
+                    if let Some(item) = next {
+                        return item.map(|item| (item, (streams, rx)));
+                    }
+                }
+            },
+        )
+        .boxed()
+    }
+}
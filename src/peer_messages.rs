@@ -0,0 +1,99 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Delivery of each peer's messages to a receiver of their own.
+
+use crate::connection::SendStream;
+use bytes::Bytes;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex as StdMutex, MutexGuard},
+};
+use tokio::sync::{mpsc, Mutex};
+
+// The number of messages buffered for a peer's receiver, as for a connection's messages.
+const PEER_MESSAGE_BUFFER_LEN: usize = 10_000;
+
+type PeerMsg = (Bytes, Option<Arc<Mutex<SendStream>>>);
+
+/// Messages from a single peer, as returned by
+/// [`Endpoint::messages_from`](crate::Endpoint::messages_from).
+///
+/// Messages from the peer are delivered here rather than to its connections'
+/// [`ConnectionIncoming`](crate::ConnectionIncoming), for as long as this receiver exists. Messages
+/// are received in the order they're read from the peer's streams, across all of its connections.
+/// Errors receiving messages are still reported by the connection's `ConnectionIncoming`.
+#[derive(Debug)]
+pub struct PeerMessages {
+    peer: SocketAddr,
+    rx: mpsc::Receiver<PeerMsg>,
+}
+
+impl PeerMessages {
+    /// The address of the peer.
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Get the next message from the peer.
+    ///
+    /// Returns `None` if the endpoint is dropped, or another receiver replaced this one.
+    pub async fn next(&mut self) -> Option<Bytes> {
+        self.rx.recv().await.map(|(msg, _)| msg)
+    }
+
+    /// Get the next message from the peer, along with the stream to respond with if it was sent on
+    /// a bidirectional stream.
+    ///
+    /// See [`ConnectionIncoming::next_with_stream`](crate::ConnectionIncoming::next_with_stream).
+    pub async fn next_with_stream(&mut self) -> Option<(Bytes, Option<Arc<Mutex<SendStream>>>)> {
+        self.rx.recv().await
+    }
+}
+
+// Routes messages to the receivers of the peers they're from.
+#[derive(Debug, Default)]
+pub(crate) struct PeerRouter {
+    routes: StdMutex<HashMap<SocketAddr, mpsc::Sender<PeerMsg>>>,
+}
+
+impl PeerRouter {
+    // Create a receiver for `peer`'s messages, replacing any existing receiver.
+    pub(crate) fn subscribe(&self, peer: SocketAddr) -> PeerMessages {
+        let (tx, rx) = mpsc::channel(PEER_MESSAGE_BUFFER_LEN);
+        let _ = self.lock().insert(peer, tx);
+        PeerMessages { peer, rx }
+    }
+
+    // Deliver a message to `peer`'s receiver, returning it if there isn't one.
+    pub(crate) async fn route(&self, peer: SocketAddr, msg: PeerMsg) -> Option<PeerMsg> {
+        let tx = match self.lock().get(&peer) {
+            Some(tx) => tx.clone(),
+            None => return Some(msg),
+        };
+        match tx.send(msg).await {
+            Ok(()) => None,
+            Err(mpsc::error::SendError(msg)) => {
+                // the receiver was dropped, so the connections get the peer's messages again
+                let mut routes = self.lock();
+                if routes.get(&peer).is_some_and(|tx| tx.is_closed()) {
+                    let _ = routes.remove(&peer);
+                }
+                Some(msg)
+            }
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<SocketAddr, mpsc::Sender<PeerMsg>>> {
+        self.routes
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
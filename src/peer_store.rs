@@ -0,0 +1,307 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Persistent storage for known peers and bootstrap contacts.
+
+use crate::{
+    address_book::{PeerAddress, PeerId},
+    error::PeerStoreError,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt, fs, io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+};
+use tracing::warn;
+
+// The most bootstrap contacts to remember.
+const MAX_BOOTSTRAP_CONTACTS: usize = 64;
+
+/// The peers kept by a [`PeerStore`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerCache {
+    /// The known addresses of each peer, as kept by the endpoint's
+    /// [`AddressBook`](crate::AddressBook).
+    pub peers: HashMap<PeerId, Vec<PeerAddress>>,
+
+    /// Contacts that were successfully bootstrapped against, most recent first.
+    ///
+    /// These are tried alongside the contacts given to
+    /// [`Endpoint::new_peer`](crate::Endpoint::new_peer), so a node can rejoin the network even if
+    /// its configured contacts have gone away.
+    pub bootstrap_contacts: Vec<SocketAddr>,
+}
+
+impl PeerCache {
+    // Replace the addresses of `peer`, removing it if there are none.
+    fn set_addresses(&mut self, peer: PeerId, addresses: &[PeerAddress]) {
+        if addresses.is_empty() {
+            let _ = self.peers.remove(&peer);
+        } else {
+            let _ = self.peers.insert(peer, addresses.to_vec());
+        }
+    }
+}
+
+/// Storage for the peers an endpoint knows about, so they survive restarts.
+///
+/// The store used by an endpoint is set via [`Config::peer_store`](crate::Config::peer_store).
+/// It's loaded when the endpoint is created, and updated as the endpoint's
+/// [`AddressBook`](crate::AddressBook) changes and as bootstrapping succeeds. qp2p provides
+/// [`FilePeerStore`] and [`MemoryPeerStore`], and applications can implement this to keep peers in
+/// their own database.
+///
+/// Methods are called synchronously from the endpoint, so they should be quick.
+pub trait PeerStore: fmt::Debug + Send + Sync {
+    /// Load the stored peers.
+    ///
+    /// A store that has never been saved to should return an empty cache, rather than an error.
+    fn load(&self) -> Result<PeerCache, PeerStoreError>;
+
+    /// Replace the stored peers with `cache`.
+    fn save(&self, cache: &PeerCache) -> Result<(), PeerStoreError>;
+
+    /// Store the addresses of a single peer, or remove it if `addresses` is empty.
+    ///
+    /// The default implementation loads and saves the whole cache. Stores that can update a single
+    /// peer (e.g. a database) should override this.
+    fn update(&self, peer: PeerId, addresses: &[PeerAddress]) -> Result<(), PeerStoreError> {
+        let mut cache = self.load()?;
+        cache.set_addresses(peer, addresses);
+        self.save(&cache)
+    }
+
+    /// Store the bootstrap contacts, most recent first.
+    ///
+    /// The default implementation loads and saves the whole cache.
+    fn update_bootstrap_contacts(&self, contacts: &[SocketAddr]) -> Result<(), PeerStoreError> {
+        let mut cache = self.load()?;
+        cache.bootstrap_contacts = contacts.to_vec();
+        self.save(&cache)
+    }
+}
+
+/// A [`PeerStore`] that keeps peers in memory, e.g. for tests or to share peers between endpoints
+/// in one process.
+#[derive(Debug, Default)]
+pub struct MemoryPeerStore {
+    cache: Mutex<PeerCache>,
+}
+
+impl MemoryPeerStore {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PeerCache> {
+        self.cache.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl PeerStore for MemoryPeerStore {
+    fn load(&self) -> Result<PeerCache, PeerStoreError> {
+        Ok(self.lock().clone())
+    }
+
+    fn save(&self, cache: &PeerCache) -> Result<(), PeerStoreError> {
+        *self.lock() = cache.clone();
+        Ok(())
+    }
+
+    fn update(&self, peer: PeerId, addresses: &[PeerAddress]) -> Result<(), PeerStoreError> {
+        let mut cache = self.lock();
+        cache.set_addresses(peer, addresses);
+        Ok(())
+    }
+
+    fn update_bootstrap_contacts(&self, contacts: &[SocketAddr]) -> Result<(), PeerStoreError> {
+        self.lock().bootstrap_contacts = contacts.to_vec();
+        Ok(())
+    }
+}
+
+/// A [`PeerStore`] that keeps peers in a file.
+///
+/// The file is rewritten on every update, by writing a temporary file next to it and renaming it
+/// into place, so an interrupted write doesn't lose the previous contents.
+#[derive(Debug)]
+pub struct FilePeerStore {
+    path: PathBuf,
+    // serializes updates, which read and then write the file
+    lock: Mutex<()>,
+}
+
+impl FilePeerStore {
+    /// Create a store that keeps peers at `path`.
+    ///
+    /// The file is created on the first update, if it doesn't already exist.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn read(&self) -> Result<PeerCache, PeerStoreError> {
+        match fs::read(&self.path) {
+            Ok(bytes) => Ok(bincode::deserialize(&bytes)?),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(PeerCache::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn write(&self, cache: &PeerCache) -> Result<(), PeerStoreError> {
+        let mut temp_path = OsString::from(&self.path);
+        temp_path.push(".tmp");
+        fs::write(&temp_path, bincode::serialize(cache)?)?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl PeerStore for FilePeerStore {
+    fn load(&self) -> Result<PeerCache, PeerStoreError> {
+        let _guard = self.lock();
+        self.read()
+    }
+
+    fn save(&self, cache: &PeerCache) -> Result<(), PeerStoreError> {
+        let _guard = self.lock();
+        self.write(cache)
+    }
+
+    fn update(&self, peer: PeerId, addresses: &[PeerAddress]) -> Result<(), PeerStoreError> {
+        let _guard = self.lock();
+        let mut cache = self.read()?;
+        cache.set_addresses(peer, addresses);
+        self.write(&cache)
+    }
+
+    fn update_bootstrap_contacts(&self, contacts: &[SocketAddr]) -> Result<(), PeerStoreError> {
+        let _guard = self.lock();
+        let mut cache = self.read()?;
+        cache.bootstrap_contacts = contacts.to_vec();
+        self.write(&cache)
+    }
+}
+
+// Load the stored peers, starting afresh if they can't be loaded.
+pub(crate) fn load(store: Option<&dyn PeerStore>) -> PeerCache {
+    store
+        .map(|store| {
+            store.load().unwrap_or_else(|error| {
+                warn!(
+                    "Failed to load stored peers, starting without them: {}",
+                    error
+                );
+                PeerCache::default()
+            })
+        })
+        .unwrap_or_default()
+}
+
+// Move `contact` to the front of the stored bootstrap contacts.
+pub(crate) fn remember_contact(
+    store: &dyn PeerStore,
+    contacts: &[SocketAddr],
+    contact: SocketAddr,
+) {
+    let contacts: Vec<_> = std::iter::once(contact)
+        .chain(contacts.iter().copied().filter(|addr| *addr != contact))
+        .take(MAX_BOOTSTRAP_CONTACTS)
+        .collect();
+    if let Err(error) = store.update_bootstrap_contacts(&contacts) {
+        warn!("Failed to store bootstrap contact {}: {}", contact, error);
+    }
+}
+
+// Add `contacts` to the end of the stored bootstrap contacts, so they're tried after the contacts
+// that were bootstrapped against.
+pub(crate) fn add_contacts(store: &dyn PeerStore, contacts: &[SocketAddr]) {
+    let stored = match store.load() {
+        Ok(cache) => cache.bootstrap_contacts,
+        Err(error) => {
+            warn!("Failed to load bootstrap contacts: {}", error);
+            return;
+        }
+    };
+    let mut updated = stored.clone();
+    for contact in contacts {
+        if updated.len() >= MAX_BOOTSTRAP_CONTACTS {
+            break;
+        }
+        if !updated.contains(contact) {
+            updated.push(*contact);
+        }
+    }
+    if updated != stored {
+        if let Err(error) = store.update_bootstrap_contacts(&updated) {
+            warn!("Failed to store bootstrap contacts: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FilePeerStore, MemoryPeerStore, PeerCache, PeerStore};
+    use crate::{AddressKind, PeerAddress, PeerId};
+    use color_eyre::eyre::Result;
+
+    fn round_trip(store: &dyn PeerStore) -> Result<()> {
+        assert_eq!(store.load()?, PeerCache::default());
+
+        let peer = PeerId([1; 32]);
+        let addresses = [PeerAddress {
+            addr: "1.2.3.4:1000".parse()?,
+            kind: AddressKind::Wan,
+        }];
+        store.update(peer, &addresses)?;
+        store.update_bootstrap_contacts(&["5.6.7.8:1000".parse()?])?;
+
+        let cache = store.load()?;
+        assert_eq!(
+            cache.peers.get(&peer).map(Vec::as_slice),
+            Some(&addresses[..])
+        );
+        assert_eq!(cache.bootstrap_contacts, vec!["5.6.7.8:1000".parse()?]);
+
+        store.update(peer, &[])?;
+        assert!(store.load()?.peers.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn memory_store() -> Result<()> {
+        round_trip(&MemoryPeerStore::new())
+    }
+
+    #[test]
+    fn file_store() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("qp2p-peers-{}", rand::random::<u64>()));
+        let result = round_trip(&FilePeerStore::new(&path));
+
+        // a new store for the same file sees the same peers
+        let contacts = FilePeerStore::new(&path).load()?.bootstrap_contacts;
+        std::fs::remove_file(&path)?;
+        result?;
+        assert_eq!(contacts, vec!["5.6.7.8:1000".parse()?]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Peer exchange: sharing known-good peer addresses between connected nodes.
+//!
+//! A node asks a peer for the addresses it knows with a `PexReq` on a bi-stream, and the peer
+//! replies with a `PexResp` of up to `MAX_RECORDS` records. Each record is either:
+//!
+//! - an address the responder dialed itself, so it knows the address is reachable, or
+//! - a signed record, in which a node with a [`Config::signing_key`](crate::Config::signing_key)
+//!   vouches for its own public address. Signed records are forwarded as they were received, so
+//!   the address can be attributed to the signer's [`PeerId`] however many hops away it is.
+//!
+//! A signed record is the bincode encoding of the address and the UNIX time (in seconds) it was
+//! signed at, with the signer's public key and signature appended as for user messages (see
+//! `signing`).
+
+use crate::{
+    address_book::{AddressKind, PeerId},
+    signing::{self, SigningKey},
+    utils,
+};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Instant;
+use tracing::trace;
+
+// The most records in a response. Further records in a response are ignored.
+const MAX_RECORDS: usize = 32;
+
+// How long a connection must wait between requests. Requests that come sooner are answered with
+// no records.
+pub(crate) const MIN_REQUEST_INTERVAL: Duration = Duration::from_secs(30);
+
+// The most signed records of other peers to remember and pass on.
+const MAX_SIGNED_RECORDS: usize = 256;
+
+// How long a signed record is passed on for, so addresses that peers no longer vouch for die out.
+const MAX_RECORD_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+// How far ahead of ours the clock of a record's signer may be.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(10 * 60);
+
+/// A peer address learned by [peer exchange](crate::Endpoint::exchange_peers).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ExchangedPeer {
+    /// The peer's address.
+    pub addr: SocketAddr,
+
+    /// The peer's identity, if the peer signed the address itself.
+    ///
+    /// Addresses without one were vouched for by the peer they were learned from, which had
+    /// dialed them.
+    pub peer_id: Option<PeerId>,
+}
+
+impl ExchangedPeer {
+    // The kind of address to add to the address book.
+    pub(crate) fn address_kind(&self) -> AddressKind {
+        let lan = match self.addr.ip() {
+            IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+            IpAddr::V6(ip) => ip.is_loopback(),
+        };
+        if lan {
+            AddressKind::Lan
+        } else {
+            AddressKind::Wan
+        }
+    }
+}
+
+// A record in a `PexResp`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) enum PexRecord {
+    // an address the responder dialed
+    Addr(SocketAddr),
+    // an address signed by the peer it belongs to
+    Signed(Bytes),
+}
+
+// A verified signed record of another peer.
+#[derive(Debug)]
+struct SignedRecord {
+    peer: ExchangedPeer,
+    signed_at: u64,
+    record: Bytes,
+}
+
+// An endpoint's peer exchange state.
+#[derive(Debug)]
+pub(crate) struct Pex {
+    enabled: bool,
+    signing_key: Option<Arc<SigningKey>>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // the address to sign for peers, if the endpoint is reachable
+    public_addr: Option<SocketAddr>,
+    // most recently signed first
+    signed: VecDeque<SignedRecord>,
+    // when each connection's last request was answered
+    answered: HashMap<SocketAddr, Instant>,
+}
+
+impl Pex {
+    pub(crate) fn new(enabled: bool, signing_key: Option<Arc<SigningKey>>) -> Self {
+        Self {
+            enabled,
+            signing_key,
+            state: Mutex::default(),
+        }
+    }
+
+    // Whether this endpoint shares addresses, and exchanges them periodically.
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(crate) fn set_public_addr(&self, addr: SocketAddr) {
+        self.lock().public_addr = Some(addr);
+    }
+
+    // The records to send in response to a request from `requester`, given the addresses this
+    // endpoint has dialed.
+    pub(crate) fn respond(
+        &self,
+        requester: SocketAddr,
+        dialed: impl IntoIterator<Item = SocketAddr>,
+    ) -> Vec<PexRecord> {
+        if !self.enabled {
+            return Vec::new();
+        }
+
+        let mut state = self.lock();
+        let now = Instant::now();
+        state
+            .answered
+            .retain(|_, answered| now.duration_since(*answered) < MIN_REQUEST_INTERVAL);
+        if state.answered.contains_key(&requester) {
+            trace!("Not sharing peers with {}: asked too recently", requester);
+            return Vec::new();
+        }
+        let _ = state.answered.insert(requester, now);
+
+        let mut records = Vec::new();
+        if let (Some(signing_key), Some(public_addr)) = (&self.signing_key, state.public_addr) {
+            records.push(PexRecord::Signed(sign(signing_key, public_addr)));
+        }
+
+        let cutoff = unix_time().saturating_sub(MAX_RECORD_AGE.as_secs());
+        state.signed.retain(|signed| signed.signed_at >= cutoff);
+        let signed = state
+            .signed
+            .iter()
+            .filter(|signed| signed.peer.addr != requester);
+        records.extend(signed.map(|signed| PexRecord::Signed(signed.record.clone())));
+
+        let mut dialed: Vec<_> = dialed
+            .into_iter()
+            .filter(|addr| *addr != requester && !state.signed.iter().any(|s| s.peer.addr == *addr))
+            .collect();
+        dialed.sort_unstable();
+        dialed.dedup();
+        utils::shuffle(&mut dialed);
+        records.extend(dialed.into_iter().map(PexRecord::Addr));
+
+        records.truncate(MAX_RECORDS);
+        records
+    }
+
+    // The peers in a response, remembering valid signed records to pass on. Invalid, expired and
+    // unusable records are dropped, as are records of this endpoint.
+    pub(crate) fn receive(&self, records: Vec<PexRecord>) -> Vec<ExchangedPeer> {
+        let local_id = self.signing_key.as_ref().map(|key| key.peer_id());
+        let mut state = self.lock();
+        let mut peers: Vec<ExchangedPeer> = Vec::new();
+
+        for record in records.into_iter().take(MAX_RECORDS) {
+            let peer = match record {
+                PexRecord::Addr(addr) => ExchangedPeer {
+                    addr,
+                    peer_id: None,
+                },
+                PexRecord::Signed(record) => match verify(&record) {
+                    Some((peer, _)) if peer.peer_id == local_id => continue,
+                    Some((peer, signed_at)) => {
+                        state.remember(SignedRecord {
+                            peer,
+                            signed_at,
+                            record,
+                        });
+                        peer
+                    }
+                    None => {
+                        trace!("Ignoring invalid or expired signed peer record");
+                        continue;
+                    }
+                },
+            };
+
+            if usable(&peer.addr)
+                && Some(peer.addr) != state.public_addr
+                && !peers.iter().any(|known| known.addr == peer.addr)
+            {
+                peers.push(peer);
+            }
+        }
+
+        peers
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl State {
+    // Keep `record` to pass on, in place of any older record of the same peer.
+    fn remember(&mut self, record: SignedRecord) {
+        let newer = self.signed.iter().any(|known| {
+            known.peer.peer_id == record.peer.peer_id && known.signed_at >= record.signed_at
+        });
+        if newer {
+            return;
+        }
+        self.signed
+            .retain(|known| known.peer.peer_id != record.peer.peer_id);
+        self.signed.push_front(record);
+        self.signed.truncate(MAX_SIGNED_RECORDS);
+    }
+}
+
+// Sign `addr` as this endpoint's address.
+fn sign(signing_key: &SigningKey, addr: SocketAddr) -> Bytes {
+    // encoding a socket address and an integer can't fail
+    let record = bincode::serialize(&(addr, unix_time())).unwrap_or_default();
+    signing_key.sign(&record)
+}
+
+// Verify a signed record, returning the peer and when it signed the record if the signature is
+// valid and the record is current.
+fn verify(record: &Bytes) -> Option<(ExchangedPeer, u64)> {
+    let (record, peer_id) = signing::verify(record)?;
+    let (addr, signed_at): (SocketAddr, u64) = bincode::deserialize(&record).ok()?;
+
+    let now = unix_time();
+    let current = signed_at.saturating_add(MAX_RECORD_AGE.as_secs()) >= now
+        && signed_at <= now.saturating_add(MAX_CLOCK_SKEW.as_secs());
+    current.then_some((
+        ExchangedPeer {
+            addr,
+            peer_id: Some(peer_id),
+        },
+        signed_at,
+    ))
+}
+
+// Whether `addr` could be connected to.
+fn usable(addr: &SocketAddr) -> bool {
+    addr.port() != 0 && !addr.ip().is_unspecified() && !addr.ip().is_multicast()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sign, unix_time, Pex, PexRecord, MAX_RECORD_AGE};
+    use crate::{signing::SigningKey, tests::local_addr};
+    use color_eyre::eyre::Result;
+    use std::{net::SocketAddr, sync::Arc};
+
+    #[test]
+    fn signed_records_are_passed_on() -> Result<()> {
+        let key = SigningKey::generate()?;
+        let peer_id = key.peer_id();
+        let signer = Pex::new(true, Some(Arc::new(key)));
+        let signer_addr: SocketAddr = "10.0.0.1:4000".parse()?;
+        signer.set_public_addr(signer_addr);
+
+        // the signer vouches for its own address
+        let records = signer.respond(local_addr(), None);
+        let relay = Pex::new(true, None);
+        let peers = relay.receive(records);
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].addr, signer_addr);
+        assert_eq!(peers[0].peer_id, Some(peer_id));
+
+        // the relay passes the record on, alongside the addresses it dialed
+        let dialed: SocketAddr = "10.0.0.2:4000".parse()?;
+        let records = relay.respond(local_addr(), vec![dialed, dialed]);
+        let peers = Pex::new(false, None).receive(records);
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].peer_id, Some(peer_id));
+        assert_eq!(peers[1].addr, dialed);
+        assert_eq!(peers[1].peer_id, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_records_are_dropped() -> Result<()> {
+        let key = SigningKey::generate()?;
+        let addr: SocketAddr = "10.0.0.1:4000".parse()?;
+
+        let mut tampered = sign(&key, addr).to_vec();
+        tampered[0] ^= 1;
+
+        let expired = bincode::serialize(&(addr, unix_time() - MAX_RECORD_AGE.as_secs() - 1))?;
+        let unusable: SocketAddr = "0.0.0.0:4000".parse()?;
+
+        let pex = Pex::new(true, None);
+        let peers = pex.receive(vec![
+            PexRecord::Signed(tampered.into()),
+            PexRecord::Signed(key.sign(&expired)),
+            PexRecord::Addr(unusable),
+        ]);
+        assert!(peers.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn requests_are_rate_limited() -> Result<()> {
+        let dialed: SocketAddr = "10.0.0.2:4000".parse()?;
+        let pex = Pex::new(true, None);
+        assert_eq!(pex.respond(local_addr(), Some(dialed)).len(), 1);
+        assert!(pex.respond(local_addr(), Some(dialed)).is_empty());
+
+        // only enabled endpoints share addresses
+        assert!(Pex::new(false, None)
+            .respond(local_addr(), Some(dialed))
+            .is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,326 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Automatic port forwarding, via whichever port mapping protocol the gateway supports.
+
+use crate::{
+    igd::{Igd, IgdError},
+    natpmp::{NatPmp, NatPmpError, Pcp},
+};
+use futures::future::BoxFuture;
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::sync::broadcast::{error::TryRecvError, Receiver};
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
+use tracing::{debug, info, warn};
+
+// Interval at which to check that a mapping without a lease expiry still exists.
+const UNLIMITED_LEASE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// Maximum interval between attempts to restore a lost mapping.
+const LOST_MAPPING_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum PortMappingError {
+    #[error("Timed out waiting for the operation to complete")]
+    TimedOut,
+
+    #[error("Port mapping is not supported for IPv6")]
+    NotSupported,
+
+    #[error(transparent)]
+    Igd(#[from] IgdError),
+
+    #[error(transparent)]
+    NatPmp(#[from] NatPmpError),
+
+    #[error("No port mapping protocol succeeded ({0})")]
+    Unavailable(String),
+}
+
+/// A protocol used to map ports on a gateway.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortMappingProtocol {
+    /// UPnP [Internet Gateway Device Protocol](https://en.wikipedia.org/wiki/Internet_Gateway_Device_Protocol).
+    Igd,
+
+    /// [Port Control Protocol](https://datatracker.ietf.org/doc/html/rfc6887).
+    Pcp,
+
+    /// [NAT Port Mapping Protocol](https://datatracker.ietf.org/doc/html/rfc6886).
+    NatPmp,
+}
+
+impl fmt::Display for PortMappingProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Igd => write!(f, "UPnP IGD"),
+            Self::Pcp => write!(f, "PCP"),
+            Self::NatPmp => write!(f, "NAT-PMP"),
+        }
+    }
+}
+
+/// The state of an endpoint's port mapping.
+///
+/// See [`Endpoint::port_mapping_status`](crate::Endpoint::port_mapping_status).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PortMappingStatus {
+    /// The port is mapped, and the endpoint is reachable at `external_addr`.
+    Active {
+        /// The gateway's external address for the mapping.
+        external_addr: SocketAddr,
+
+        /// The protocol used to map the port.
+        protocol: PortMappingProtocol,
+    },
+
+    /// The mapping could not be renewed, e.g. because the gateway is restarting.
+    ///
+    /// The endpoint keeps trying to restore the mapping, and the status will become
+    /// [`Active`](Self::Active) again if it succeeds.
+    Lost {
+        /// A description of the last error.
+        error: String,
+    },
+}
+
+/// Changes to an endpoint's port mapping.
+///
+/// See [`Endpoint::port_mapping_events`](crate::Endpoint::port_mapping_events).
+#[derive(Debug)]
+pub struct PortMappingEvents(pub(crate) watch::Receiver<PortMappingStatus>);
+
+impl PortMappingEvents {
+    /// Blocks until the port mapping status changes, and returns the new status.
+    ///
+    /// Intermediate changes may be skipped if the status changes again before this is called, so
+    /// the latest status is always returned. Returns `None` once the endpoint is closed.
+    pub async fn next(&mut self) -> Option<PortMappingStatus> {
+        self.0.changed().await.ok()?;
+        Some(self.0.borrow().clone())
+    }
+}
+
+/// A way of asking the gateway to forward an external port.
+pub(crate) trait PortMapping: fmt::Debug + Send + Sync {
+    /// The protocol this backend speaks.
+    fn protocol(&self) -> PortMappingProtocol;
+
+    /// Map `ext_port` on the gateway to `local_addr` for `lease_duration`, returning the external
+    /// address of the mapping. A `lease_duration` of 0 requests a mapping that doesn't expire.
+    ///
+    /// Mapping a port that is already mapped to `local_addr` renews its lease.
+    fn map_port(
+        &self,
+        ext_port: u16,
+        local_addr: SocketAddr,
+        lease_duration: Duration,
+    ) -> BoxFuture<'_, Result<SocketAddr, PortMappingError>>;
+}
+
+/// The port mapping protocols to try, in order of preference.
+///
+/// NAT-PMP and PCP need the gateway's address. If `gateway` is not given, it's looked up in the
+/// system's routing table, and those protocols are skipped if that fails.
+pub(crate) fn default_backends(gateway: Option<Ipv4Addr>) -> Vec<Box<dyn PortMapping>> {
+    let mut backends: Vec<Box<dyn PortMapping>> = vec![Box::new(Igd)];
+
+    match gateway.or_else(default_gateway) {
+        Some(gateway) => {
+            backends.push(Box::new(Pcp::new(gateway)));
+            backends.push(Box::new(NatPmp::new(gateway)));
+        }
+        None => debug!("No default gateway found, skipping PCP and NAT-PMP"),
+    }
+
+    backends
+}
+
+/// Automatically forwards a port and setups a tokio task to renew it periodically.
+///
+/// Each of `backends` is tried in order until one succeeds. Renewals use the same backend, falling
+/// back to the others if it fails (e.g. if the gateway has been replaced).
+///
+/// Returns a receiver for the status of the mapping, which is updated whenever the external
+/// address changes or the mapping is lost or restored.
+pub(crate) async fn forward_port(
+    ext_port: u16,
+    local_addr: SocketAddr,
+    lease_interval: Duration,
+    backends: Vec<Box<dyn PortMapping>>,
+    mut termination_rx: Receiver<()>,
+) -> Result<watch::Receiver<PortMappingStatus>, PortMappingError> {
+    if local_addr.is_ipv6() {
+        info!("IPv6 for port mapping is not supported");
+        return Err(PortMappingError::NotSupported);
+    }
+
+    // Cap `lease_interval` at `u32::MAX` seconds due to limits on the mapping protocols. Since this
+    // is an outrageous length of time (~136 years) we just do so silently.
+    let lease_interval = lease_interval.min(Duration::from_secs(u32::MAX.into()));
+
+    let (mut current, external_addr) =
+        map_with_any(&backends, None, ext_port, local_addr, lease_interval).await?;
+    let (status_tx, status_rx) = watch::channel(PortMappingStatus::Active {
+        external_addr,
+        protocol: backends[current].protocol(),
+    });
+
+    // Renew the lease at half its duration, so a single failed renewal doesn't let it lapse. A
+    // lease duration of 0 means the mapping doesn't expire, but we still check it periodically in
+    // case the gateway has restarted and lost it.
+    let renew_interval = if lease_interval.as_secs() == 0 {
+        UNLIMITED_LEASE_CHECK_INTERVAL
+    } else {
+        (lease_interval / 2).max(Duration::from_secs(1))
+    };
+    let retry_interval = renew_interval.min(LOST_MAPPING_RETRY_INTERVAL);
+
+    // Start a tokio task to renew the lease periodically.
+    let _ = tokio::spawn(async move {
+        let mut next_renewal = Instant::now() + renew_interval;
+
+        loop {
+            time::sleep_until(next_renewal).await;
+            if termination_rx.try_recv() != Err(TryRecvError::Empty) {
+                break;
+            }
+            debug!("Renewing port mapping lease for {}", local_addr);
+
+            // Renewing re-adds the mapping, so this also restores mappings lost when the gateway
+            // restarts.
+            let result = map_with_any(
+                &backends,
+                Some(current),
+                ext_port,
+                local_addr,
+                lease_interval,
+            )
+            .await;
+            let status = match result {
+                Ok((backend, external_addr)) => {
+                    current = backend;
+                    next_renewal = Instant::now() + renew_interval;
+                    PortMappingStatus::Active {
+                        external_addr,
+                        protocol: backends[backend].protocol(),
+                    }
+                }
+                Err(error) => {
+                    warn!("Failed to renew port mapping: {} - {:?}", error, error);
+                    next_renewal = Instant::now() + retry_interval;
+                    PortMappingStatus::Lost {
+                        error: error.to_string(),
+                    }
+                }
+            };
+
+            if *status_tx.borrow() != status {
+                info!("Port mapping for {} is now {:?}", local_addr, status);
+                if status_tx.send(status).is_err() {
+                    // the endpoint has been dropped
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(status_rx)
+}
+
+// Map the port with the `preferred` backend, or else the first of `backends` that succeeds.
+async fn map_with_any(
+    backends: &[Box<dyn PortMapping>],
+    preferred: Option<usize>,
+    ext_port: u16,
+    local_addr: SocketAddr,
+    lease_duration: Duration,
+) -> Result<(usize, SocketAddr), PortMappingError> {
+    let order = preferred
+        .into_iter()
+        .chain((0..backends.len()).filter(|index| Some(*index) != preferred));
+
+    let mut errors = Vec::new();
+    for index in order {
+        let backend = &backends[index];
+        match backend.map_port(ext_port, local_addr, lease_duration).await {
+            Ok(external_addr) => {
+                debug!(
+                    "Mapped {} -> {} using {}",
+                    external_addr,
+                    local_addr,
+                    backend.protocol()
+                );
+                return Ok((index, external_addr));
+            }
+            Err(error) => {
+                debug!("Failed to map port using {}: {}", backend.protocol(), error);
+                errors.push(format!("{}: {}", backend.protocol(), error));
+            }
+        }
+    }
+
+    Err(PortMappingError::Unavailable(errors.join("; ")))
+}
+
+// Look up the IPv4 default gateway in the routing table.
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    parse_default_gateway(&routes)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+// Parse the default gateway from the contents of `/proc/net/route`.
+#[cfg_attr(not(any(test, target_os = "linux")), allow(dead_code))]
+fn parse_default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            // addresses are printed as hex in native byte order
+            [_iface, "00000000", gateway, ..] => u32::from_str_radix(gateway, 16)
+                .ok()
+                .map(|gateway| Ipv4Addr::from(gateway.to_ne_bytes()))
+                .filter(|gateway| !gateway.is_unspecified()),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_default_gateway;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn default_gateway_from_route_table() {
+        let gateway = u32::from_ne_bytes([192, 168, 1, 254]);
+        let routes = format!(
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+             eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n\
+             eth0\t00000000\t{:08X}\t0003\t0\t0\t0\t00000000\t0\t0\t0\n",
+            gateway
+        );
+
+        assert_eq!(
+            parse_default_gateway(&routes),
+            Some(Ipv4Addr::new(192, 168, 1, 254))
+        );
+        assert_eq!(parse_default_gateway("Iface\tDestination\tGateway\n"), None);
+    }
+}
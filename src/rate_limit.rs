@@ -0,0 +1,251 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Rate limiting of incoming connections.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, MutexGuard},
+};
+use tokio::time::Instant;
+
+// The most source IPs tracked at once. Beyond this, IPs whose buckets have refilled are forgotten,
+// since they'd be treated the same as a new IP anyway.
+const MAX_TRACKED_IPS: usize = 16 * 1024;
+
+// Limits on the rate at which incoming connections are accepted, globally and per source IP.
+//
+// Each limit is a token bucket holding up to a second's worth of connections, so a burst of that
+// many connections is accepted at once, and then connections are accepted at the given rate.
+#[derive(Debug)]
+pub(crate) struct AcceptLimiter {
+    global: Mutex<Option<TokenBucket>>,
+    per_ip: Mutex<Option<PerIp>>,
+}
+
+#[derive(Debug)]
+struct PerIp {
+    rate: u32,
+    buckets: HashMap<IpAddr, TokenBucket>,
+}
+
+impl AcceptLimiter {
+    pub(crate) fn new(global_rate: Option<u32>, per_ip_rate: Option<u32>) -> Self {
+        Self {
+            global: Mutex::new(global_rate.map(|rate| TokenBucket::new(rate, Instant::now()))),
+            per_ip: Mutex::new(per_ip_rate.map(|rate| PerIp {
+                rate,
+                buckets: HashMap::new(),
+            })),
+        }
+    }
+
+    // Change the global limit, or remove it with `None`.
+    //
+    // The bucket doesn't gain any tokens from the change, so raising the limit doesn't allow a
+    // burst of connections beyond the new rate.
+    pub(crate) fn set_global_rate(&self, rate: Option<u32>) {
+        let now = Instant::now();
+        let mut global = lock(&self.global);
+        *global = match (global.take(), rate) {
+            (Some(mut bucket), Some(rate)) => {
+                bucket.set_rate(rate, now);
+                Some(bucket)
+            }
+            (None, Some(rate)) => Some(TokenBucket::new(rate, now)),
+            (_, None) => None,
+        };
+    }
+
+    // Change the per-IP limit, or remove it with `None`, as with `set_global_rate`.
+    pub(crate) fn set_per_ip_rate(&self, rate: Option<u32>) {
+        let now = Instant::now();
+        let mut per_ip = lock(&self.per_ip);
+        *per_ip = match (per_ip.take(), rate) {
+            (Some(mut per_ip), Some(rate)) => {
+                per_ip.rate = rate;
+                for bucket in per_ip.buckets.values_mut() {
+                    bucket.set_rate(rate, now);
+                }
+                Some(per_ip)
+            }
+            (None, Some(rate)) => Some(PerIp {
+                rate,
+                buckets: HashMap::new(),
+            }),
+            (_, None) => None,
+        };
+    }
+
+    // Whether a connection from `ip` may be accepted now, taking a token from each limit if so.
+    //
+    // The per-IP limit is checked first, so a single hostile IP can't use up the global limit.
+    pub(crate) fn allow(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+
+        if let Some(per_ip) = lock(&self.per_ip).as_mut() {
+            let rate = per_ip.rate;
+            let buckets = &mut per_ip.buckets;
+            if buckets.len() >= MAX_TRACKED_IPS && !buckets.contains_key(&ip) {
+                buckets.retain(|_, bucket| !bucket.is_full(now));
+            }
+            if !buckets
+                .entry(ip)
+                .or_insert_with(|| TokenBucket::new(rate, now))
+                .try_take(now)
+            {
+                return false;
+            }
+        }
+
+        match lock(&self.global).as_mut() {
+            Some(global) => global.try_take(now),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    // tokens added per second, and the most the bucket holds
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, now: Instant) -> Self {
+        Self {
+            rate: rate.into(),
+            tokens: rate.into(),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    fn set_rate(&mut self, rate: u32, now: Instant) {
+        self.refill(now);
+        self.rate = rate.into();
+        self.tokens = self.tokens.min(self.rate);
+    }
+
+    fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.rate
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // the locks are never held across anything that can panic
+    mutex.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AcceptLimiter, TokenBucket};
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+    use tokio::time::Instant;
+
+    #[test]
+    fn token_bucket_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, start);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+
+        // half a second at 2 per second is one token
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+
+        // the bucket never holds more than a second's worth
+        let much_later = later + Duration::from_secs(60);
+        assert!(bucket.is_full(much_later));
+        assert!(bucket.try_take(much_later));
+        assert!(bucket.try_take(much_later));
+        assert!(!bucket.try_take(much_later));
+    }
+
+    #[test]
+    fn per_ip_limits_come_first() {
+        let hostile = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let limiter = AcceptLimiter::new(Some(3), Some(2));
+
+        assert!(limiter.allow(hostile));
+        assert!(limiter.allow(hostile));
+        // rejected by the per-IP limit, without using up the global limit
+        assert!(!limiter.allow(hostile));
+        assert!(!limiter.allow(hostile));
+
+        assert!(limiter.allow(other));
+        // now the global limit is used up
+        assert!(!limiter.allow(other));
+
+        let unlimited = AcceptLimiter::new(None, None);
+        assert!((0..1000).all(|_| unlimited.allow(hostile)));
+    }
+
+    #[test]
+    fn limits_can_be_changed() {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let limiter = AcceptLimiter::new(None, Some(1000));
+        assert!(limiter.allow(ip));
+
+        // lowering the limit empties the bucket down to the new rate
+        limiter.set_per_ip_rate(Some(1));
+        assert!(limiter.allow(ip));
+        assert!(!limiter.allow(ip));
+
+        limiter.set_per_ip_rate(None);
+        assert!((0..1000).all(|_| limiter.allow(ip)));
+
+        limiter.set_global_rate(Some(2));
+        assert!(limiter.allow(ip));
+        assert!(limiter.allow(ip));
+        assert!(!limiter.allow(ip));
+    }
+
+    #[tokio::test]
+    async fn limits_follow_tokio_clock() {
+        use crate::test_utils::{advance_clock, pause_clock};
+
+        pause_clock();
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let limiter = AcceptLimiter::new(Some(1), None);
+        assert!(limiter.allow(ip));
+        assert!(!limiter.allow(ip));
+
+        // no real time passes, so the bucket only refills when the clock is advanced
+        advance_clock(Duration::from_millis(500)).await;
+        assert!(!limiter.allow(ip));
+        advance_clock(Duration::from_millis(500)).await;
+        assert!(limiter.allow(ip));
+    }
+}
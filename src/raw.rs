@@ -0,0 +1,166 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Streams without qp2p's message framing, for speaking other protocols to peers.
+//!
+//! Raw streams are opened with [`Connection::open_uni_raw`](crate::Connection::open_uni_raw) and
+//! [`Connection::open_bi_raw`](crate::Connection::open_bi_raw), and implement tokio's
+//! [`AsyncRead`] and [`AsyncWrite`], so any protocol can be spoken over them. A qp2p peer only
+//! accepts raw streams if it has set [`Config::raw_streams`](crate::Config::raw_streams), in which
+//! case it receives them from
+//! [`ConnectionIncoming::take_raw_streams`](crate::ConnectionIncoming::take_raw_streams).
+
+use crate::error::{ErrorContext, PeerError, SendError, StreamError};
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::mpsc,
+};
+
+/// The sending side of a raw stream.
+pub struct RawSendStream {
+    inner: quinn::SendStream,
+    context: ErrorContext,
+}
+
+impl RawSendStream {
+    pub(crate) fn new(inner: quinn::SendStream, context: ErrorContext) -> Self {
+        Self { inner, context }
+    }
+
+    /// The ID of the stream.
+    ///
+    /// See [`SendStream::id`](crate::SendStream::id).
+    pub fn id(&self) -> u64 {
+        quinn::VarInt::from(self.inner.id()).into_inner()
+    }
+
+    /// Shut down the stream gracefully.
+    ///
+    /// The returned future will complete once the peer has acknowledged all sent data.
+    pub async fn finish(&mut self) -> Result<(), PeerError<SendError>> {
+        self.inner
+            .finish()
+            .await
+            .map_err(|error| self.context.wrap(error.into()))
+    }
+
+    /// Abandon the stream, telling the peer with `error_code`.
+    ///
+    /// See [`SendStream::reset`](crate::SendStream::reset).
+    pub fn reset(&mut self, error_code: u64) -> Result<(), PeerError<StreamError>> {
+        let error_code = quinn::VarInt::from_u64(error_code)
+            .map_err(|_| self.context.wrap(StreamError::InvalidErrorCode(error_code)))?;
+        self.inner
+            .reset(error_code)
+            .map_err(|_| self.context.wrap(StreamError::Gone))
+    }
+}
+
+impl AsyncWrite for RawSendStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(ctx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(ctx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(ctx)
+    }
+}
+
+impl fmt::Debug for RawSendStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RawSendStream").finish_non_exhaustive()
+    }
+}
+
+/// The receiving side of a raw stream.
+pub struct RawRecvStream {
+    inner: quinn::RecvStream,
+    context: ErrorContext,
+}
+
+impl RawRecvStream {
+    pub(crate) fn new(inner: quinn::RecvStream, context: ErrorContext) -> Self {
+        Self { inner, context }
+    }
+
+    /// The ID of the stream.
+    ///
+    /// See [`SendStream::id`](crate::SendStream::id).
+    pub fn id(&self) -> u64 {
+        quinn::VarInt::from(self.inner.id()).into_inner()
+    }
+
+    /// Stop receiving from the stream, telling the peer with `error_code`.
+    ///
+    /// See [`RecvStream::stop`](crate::RecvStream::stop).
+    pub fn stop(&mut self, error_code: u64) -> Result<(), PeerError<StreamError>> {
+        let error_code = quinn::VarInt::from_u64(error_code)
+            .map_err(|_| self.context.wrap(StreamError::InvalidErrorCode(error_code)))?;
+        self.inner
+            .stop(error_code)
+            .map_err(|_| self.context.wrap(StreamError::Gone))
+    }
+}
+
+impl AsyncRead for RawRecvStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(ctx, buf)
+    }
+}
+
+impl fmt::Debug for RawRecvStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RawRecvStream").finish_non_exhaustive()
+    }
+}
+
+/// A raw stream opened by a peer.
+#[derive(Debug)]
+pub enum RawStream {
+    /// A unidirectional stream, which the peer only sends on.
+    Uni(RawRecvStream),
+
+    /// A bidirectional stream.
+    Bi(RawSendStream, RawRecvStream),
+}
+
+/// Raw streams opened by a peer, as returned by
+/// [`ConnectionIncoming::take_raw_streams`](crate::ConnectionIncoming::take_raw_streams).
+#[derive(Debug)]
+pub struct IncomingRawStreams(mpsc::Receiver<RawStream>);
+
+impl IncomingRawStreams {
+    pub(crate) fn new(raw_rx: mpsc::Receiver<RawStream>) -> Self {
+        Self(raw_rx)
+    }
+
+    /// Wait for the peer to open a stream.
+    ///
+    /// Returns `None` once the connection is closed.
+    pub async fn next(&mut self) -> Option<RawStream> {
+        self.0.recv().await
+    }
+}
@@ -0,0 +1,125 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Classification of an endpoint's reachability, from the reports of other peers.
+
+use std::net::SocketAddr;
+
+/// Whether an endpoint can be reached by other peers, as determined by
+/// [`Endpoint::check_reachability`](crate::Endpoint::check_reachability).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reachability {
+    /// Peers could connect to the endpoint's public address, without a port mapping.
+    Public {
+        /// The address peers connected to.
+        addr: SocketAddr,
+    },
+
+    /// Peers could connect to the endpoint's public address via a port mapping set up by the
+    /// endpoint (see [`Config::forward_port`](crate::Config::forward_port)).
+    Mapped {
+        /// The address peers connected to.
+        addr: SocketAddr,
+    },
+
+    /// No peer could connect to the endpoint's public address.
+    Unreachable {
+        /// An estimate of the type of NAT the endpoint is behind, based on the addresses peers
+        /// saw its connections coming from.
+        nat: NatType,
+    },
+}
+
+/// An estimate of the type of NAT an endpoint is behind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NatType {
+    /// The endpoint's connections came from its local address, so it's not behind a NAT (but may
+    /// be behind a firewall).
+    None,
+
+    /// Every peer saw the same address, so the NAT reuses mappings for different destinations.
+    /// Hole punching is likely to succeed.
+    EndpointIndependent,
+
+    /// Peers saw different addresses, so the NAT creates a mapping for each destination
+    /// ('symmetric' NAT). Hole punching is unlikely to succeed, so a relay may be needed.
+    EndpointDependent,
+
+    /// Too few peers responded to tell.
+    Unknown,
+}
+
+// Classify reachability given the addresses peers saw for us, and whether any could connect back.
+pub(crate) fn classify(
+    local_addrs: &[SocketAddr],
+    public_addr: SocketAddr,
+    mapped: bool,
+    observed_addrs: &[SocketAddr],
+    verified: bool,
+) -> Reachability {
+    if verified {
+        return if mapped {
+            Reachability::Mapped { addr: public_addr }
+        } else {
+            Reachability::Public { addr: public_addr }
+        };
+    }
+
+    let nat = match observed_addrs {
+        [] => NatType::Unknown,
+        [first, rest @ ..] => {
+            if observed_addrs.iter().all(|addr| local_addrs.contains(addr)) {
+                NatType::None
+            } else if rest.iter().any(|addr| addr != first) {
+                NatType::EndpointDependent
+            } else if rest.is_empty() {
+                // a single observation can't distinguish the NAT behaviours
+                NatType::Unknown
+            } else {
+                NatType::EndpointIndependent
+            }
+        }
+    };
+
+    Reachability::Unreachable { nat }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, NatType, Reachability};
+    use color_eyre::eyre::Result;
+
+    #[test]
+    fn classify_reachability() -> Result<()> {
+        let local = "192.168.1.2:5000".parse()?;
+        let public = "203.0.113.7:5000".parse()?;
+        let other = "203.0.113.7:6000".parse()?;
+
+        assert_eq!(
+            classify(&[local], public, false, &[public], true),
+            Reachability::Public { addr: public }
+        );
+        assert_eq!(
+            classify(&[local], public, true, &[public], true),
+            Reachability::Mapped { addr: public }
+        );
+
+        let nat = |observed: &[_]| match classify(&[local], public, false, observed, false) {
+            Reachability::Unreachable { nat } => nat,
+            reachability => panic!("unexpected {:?}", reachability),
+        };
+        assert_eq!(nat(&[]), NatType::Unknown);
+        assert_eq!(nat(&[public]), NatType::Unknown);
+        assert_eq!(nat(&[local, local]), NatType::None);
+        assert_eq!(nat(&[public, public]), NatType::EndpointIndependent);
+        assert_eq!(nat(&[public, other]), NatType::EndpointDependent);
+
+        Ok(())
+    }
+}
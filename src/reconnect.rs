@@ -0,0 +1,380 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Connections that transparently re-dial the peer when they are lost.
+
+use crate::{
+    config::RetryConfig,
+    connection::{Connection, ConnectionIncoming, RecvStream, SendOptions, SendStream},
+    endpoint::Endpoint,
+    error::{Close, ConnectionError, PeerError, RecvError, SendError},
+};
+use bytes::Bytes;
+use std::{fmt, future::Future, net::SocketAddr, sync::Arc};
+use tokio::sync::{broadcast, Mutex};
+use tracing::{debug, info};
+
+// Capacity of the reconnection event channel. Slow subscribers miss the oldest events.
+const RECONNECT_EVENT_CAPACITY: usize = 16;
+
+/// A connection that re-dials the peer when it's lost, as returned by
+/// [`Endpoint::connect_reconnecting`].
+///
+/// When a send or opening a stream fails because the connection was lost, the peer is connected
+/// to again (retrying according to the endpoint's [`RetryConfig`]) and the operation is retried
+/// once on the new connection. Messages that were in flight on the old connection may be lost.
+///
+/// The underlying [`Connection`] changes with each reconnection, but the handle's
+/// [`id`](Self::id) remains that of the first connection.
+#[derive(Clone)]
+pub struct ReconnectingConnection {
+    shared: Arc<Shared>,
+}
+
+/// The receiving API for a [`ReconnectingConnection`].
+///
+/// Messages are received from whichever connection is current, switching to the new connection
+/// after a reconnection.
+pub struct ReconnectingIncoming {
+    shared: Arc<Shared>,
+    current_id: usize,
+    current: ConnectionIncoming,
+}
+
+/// A reconnection made by a [`ReconnectingConnection`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reconnection {
+    /// The [`id`](ReconnectingConnection::id) of the reconnecting handle.
+    pub id: usize,
+
+    /// The [`id`](Connection::id) of the connection that was lost.
+    pub previous_connection_id: usize,
+
+    /// The [`id`](Connection::id) of the new connection.
+    pub connection_id: usize,
+}
+
+/// Reconnections made by a [`ReconnectingConnection`].
+///
+/// See [`ReconnectingConnection::reconnections`].
+#[derive(Debug)]
+pub struct ReconnectEvents(broadcast::Receiver<Reconnection>);
+
+impl ReconnectEvents {
+    /// Wait for the next reconnection.
+    ///
+    /// If reconnections happen faster than they are received, the oldest are skipped. Returns
+    /// `None` once every handle to the connection has been dropped.
+    pub async fn next(&mut self) -> Option<Reconnection> {
+        loop {
+            match self.0.recv().await {
+                Ok(reconnection) => return Some(reconnection),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+struct Shared {
+    endpoint: Endpoint,
+    peer: SocketAddr,
+    id: usize,
+    state: Mutex<State>,
+    events: broadcast::Sender<Reconnection>,
+}
+
+struct State {
+    connection: Connection,
+    // the incoming side of the current connection, until `ReconnectingIncoming` picks it up
+    incoming: Option<ConnectionIncoming>,
+}
+
+impl ReconnectingConnection {
+    pub(crate) fn new(
+        endpoint: Endpoint,
+        connection: Connection,
+        incoming: ConnectionIncoming,
+    ) -> (Self, ReconnectingIncoming) {
+        let (events, _) = broadcast::channel(RECONNECT_EVENT_CAPACITY);
+        let id = connection.id();
+        let shared = Arc::new(Shared {
+            endpoint,
+            peer: connection.remote_address(),
+            id,
+            state: Mutex::new(State {
+                connection,
+                incoming: None,
+            }),
+            events,
+        });
+
+        (
+            Self {
+                shared: shared.clone(),
+            },
+            ReconnectingIncoming {
+                shared,
+                current_id: id,
+                current: incoming,
+            },
+        )
+    }
+
+    /// A stable identifier for the connection, which doesn't change on reconnection.
+    ///
+    /// This is the [`id`](Connection::id) of the first underlying connection.
+    pub fn id(&self) -> usize {
+        self.shared.id
+    }
+
+    /// The address of the remote peer.
+    pub fn remote_address(&self) -> SocketAddr {
+        self.shared.peer
+    }
+
+    /// The current underlying connection.
+    pub async fn connection(&self) -> Connection {
+        self.shared.state.lock().await.connection.clone()
+    }
+
+    /// Subscribe to reconnections made from now on.
+    pub fn reconnections(&self) -> ReconnectEvents {
+        ReconnectEvents(self.shared.events.subscribe())
+    }
+
+    /// Send a message to the peer with default retry configuration, reconnecting if the connection
+    /// has been lost.
+    ///
+    /// See [`Connection::send`].
+    pub async fn send(&self, msg: Bytes) -> Result<(), PeerError<SendError>> {
+        self.send_with(msg, 0, None).await
+    }
+
+    /// Send a message to the peer using the given configuration, reconnecting if the connection
+    /// has been lost.
+    ///
+    /// See [`Connection::send_with`].
+    pub async fn send_with(
+        &self,
+        msg: Bytes,
+        priority: i32,
+        retry_config: Option<&RetryConfig>,
+    ) -> Result<(), PeerError<SendError>> {
+        let opts = SendOptions {
+            priority,
+            retry: retry_config.cloned(),
+            ..SendOptions::default()
+        };
+        self.send_with_opts(msg, &opts).await
+    }
+
+    /// Send a message to the peer using the given [`SendOptions`], reconnecting if the connection
+    /// has been lost.
+    ///
+    /// Reconnecting isn't bound by the options' deadline, but sending on the new connection is.
+    /// See [`Connection::send_with_opts`].
+    pub async fn send_with_opts(
+        &self,
+        msg: Bytes,
+        opts: &SendOptions,
+    ) -> Result<(), PeerError<SendError>> {
+        self.with_reconnect(is_lost, |connection| {
+            let msg = msg.clone();
+            async move { connection.send_with_opts(msg, opts).await }
+        })
+        .await
+    }
+
+    /// Open a unidirectional stream to the peer, reconnecting if the connection has been lost.
+    ///
+    /// The stream belongs to the connection it was opened on, so writes to it fail if that
+    /// connection is lost later. Responses the peer sends on streams of its own are received by
+    /// [`ReconnectingIncoming`]. See [`Connection::open_uni`].
+    pub async fn open_uni(&self) -> Result<SendStream, PeerError<ConnectionError>> {
+        self.with_reconnect(is_lost_connection, |connection| async move {
+            connection.open_uni().await
+        })
+        .await
+    }
+
+    /// Open a bidirectional stream to the peer, reconnecting if the connection has been lost.
+    ///
+    /// As with [`open_uni`](Self::open_uni), the stream isn't moved to a new connection if its
+    /// connection is lost later. See [`Connection::open_bi`].
+    pub async fn open_bi(&self) -> Result<(SendStream, RecvStream), PeerError<ConnectionError>> {
+        self.with_reconnect(is_lost_connection, |connection| async move {
+            connection.open_bi().await
+        })
+        .await
+    }
+
+    /// Close the connection, without reconnecting.
+    ///
+    /// See [`Connection::close`].
+    pub async fn close(&self, reason: Option<String>) {
+        self.connection().await.close(reason);
+    }
+
+    // Run `op` on the current connection, and again on a new connection if it failed because the
+    // connection was lost.
+    async fn with_reconnect<T, E, F, Fut>(
+        &self,
+        is_lost: fn(&E) -> bool,
+        op: F,
+    ) -> Result<T, PeerError<E>>
+    where
+        E: From<ConnectionError> + fmt::Display,
+        F: Fn(Connection) -> Fut,
+        Fut: Future<Output = Result<T, PeerError<E>>>,
+    {
+        let connection = self.connection().await;
+        let error = match op(connection.clone()).await {
+            Err(error) if is_lost(&error.error) => error,
+            result => return result,
+        };
+
+        debug!(
+            "Connection {} to {} was lost, reconnecting: {}",
+            connection.id(),
+            self.shared.peer,
+            error
+        );
+        let connection =
+            self.shared
+                .reconnect(connection.id())
+                .await
+                .map_err(|reconnect_error| PeerError {
+                    error: reconnect_error.into(),
+                    ..error
+                })?;
+        op(connection).await
+    }
+}
+
+impl ReconnectingIncoming {
+    /// Get the next message sent by the peer, over any stream.
+    ///
+    /// If the connection is lost, the peer is re-dialled and messages are received from the new
+    /// connection. If the connection closes gracefully (e.g. because it was idle), this returns
+    /// `None`, but calling it again will pick up a connection re-established by
+    /// [`ReconnectingConnection::send`].
+    pub async fn next(&mut self) -> Result<Option<Bytes>, PeerError<RecvError>> {
+        let result = self.next_with_stream().await?;
+        Ok(result.map(|(msg, _)| msg))
+    }
+
+    /// Get the next message sent by the peer, along with the stream to respond with if it was sent
+    /// on a bidirectional stream.
+    ///
+    /// This follows reconnections like [`next`](Self::next). See
+    /// [`ConnectionIncoming::next_with_stream`].
+    pub async fn next_with_stream(
+        &mut self,
+    ) -> Result<Option<(Bytes, Option<Arc<Mutex<SendStream>>>)>, PeerError<RecvError>> {
+        loop {
+            let lost = match self.current.next_with_stream().await {
+                Ok(Some(msg)) => return Ok(Some(msg)),
+                Ok(None) => None,
+                Err(PeerError {
+                    error: RecvError::ConnectionLost(error),
+                    ..
+                }) if error != ConnectionError::Closed(Close::Local) => Some(error),
+                Err(error) => return Err(error),
+            };
+
+            let mut state = match lost {
+                Some(error) => {
+                    debug!(
+                        "Connection {} to {} was lost, reconnecting: {}",
+                        self.current_id, self.shared.peer, error
+                    );
+                    if let Err(error) = self.shared.reconnect(self.current_id).await {
+                        return Err(PeerError {
+                            peer: self.shared.peer,
+                            connection_id: self.current_id,
+                            error: RecvError::ConnectionLost(error),
+                        });
+                    }
+                    self.shared.state.lock().await
+                }
+                None => {
+                    let state = self.shared.state.lock().await;
+                    if state.connection.id() == self.current_id {
+                        // closed without being replaced
+                        return Ok(None);
+                    }
+                    state
+                }
+            };
+
+            if let Some(incoming) = state.incoming.take() {
+                self.current_id = state.connection.id();
+                self.current = incoming;
+            }
+        }
+    }
+}
+
+impl Shared {
+    // Re-dial the peer, unless the connection with `lost_id` has already been replaced.
+    async fn reconnect(&self, lost_id: usize) -> Result<Connection, ConnectionError> {
+        let mut state = self.state.lock().await;
+        if state.connection.id() != lost_id {
+            return Ok(state.connection.clone());
+        }
+
+        let (connection, incoming) = self.endpoint.connect_to(&self.peer).await?;
+        info!(
+            "Reconnected to {} (connection {} replaces {})",
+            self.peer,
+            connection.id(),
+            lost_id
+        );
+
+        state.connection = connection.clone();
+        state.incoming = Some(incoming);
+        // there may be no subscribers
+        let _ = self.events.send(Reconnection {
+            id: self.id,
+            previous_connection_id: lost_id,
+            connection_id: connection.id(),
+        });
+
+        Ok(connection)
+    }
+}
+
+// Whether a send failed because the connection was lost, other than by closing it ourselves.
+fn is_lost(error: &SendError) -> bool {
+    matches!(error, SendError::ConnectionLost(error) if is_lost_connection(error))
+}
+
+// Whether the connection was lost, other than by closing it ourselves.
+fn is_lost_connection(error: &ConnectionError) -> bool {
+    *error != ConnectionError::Closed(Close::Local)
+}
+
+impl fmt::Debug for ReconnectingConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectingConnection")
+            .field("id", &self.shared.id)
+            .field("remote_address", &self.shared.peer)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for ReconnectingIncoming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReconnectingIncoming")
+            .field("id", &self.shared.id)
+            .field("connection_id", &self.current_id)
+            .finish_non_exhaustive()
+    }
+}
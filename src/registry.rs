@@ -0,0 +1,577 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Tracking of an endpoint's live connections.
+
+use crate::{
+    address_book::{AddressKind, PeerId},
+    connection::Connection,
+    scoring::{PeerScoring, ScoredPeer},
+    stats::MessageStatsRecorder,
+};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    mem,
+    net::SocketAddr,
+    ops::AddAssign,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex, MutexGuard, Weak,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::broadcast::{error::TryRecvError, Receiver},
+    time::Instant,
+};
+use tracing::{debug, info};
+
+// Reason given to peers when closing a connection to stay within `Config::max_connections`.
+const EVICTED: &str = "The connection was evicted to make room for others.";
+
+// Reason given to peers when closing a relayed connection in favour of a direct one.
+const UPGRADED: &str = "The connection was replaced by a direct connection.";
+
+/// How important a connection is, for deciding which connections to sacrifice under pressure.
+///
+/// See [`Connection::set_class`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ConnectionClass {
+    /// Closed first when the endpoint has too many connections.
+    Background,
+
+    /// The default class.
+    #[default]
+    Normal,
+
+    /// Never closed to make room for other connections, and kept alive even when idle.
+    Critical,
+}
+
+impl ConnectionClass {
+    fn from_u8(class: u8) -> Self {
+        match class {
+            0 => Self::Background,
+            2 => Self::Critical,
+            _ => Self::Normal,
+        }
+    }
+}
+
+/// The path a connection takes to its peer, as returned by [`Connection::path`].
+///
+/// Variants are ordered by preference, so when there are several connections to a peer, a
+/// [`Direct`](Self::Direct) connection is preferred over a [`Relayed`](Self::Relayed) one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum ConnectionPath {
+    /// The connection goes straight to the peer.
+    Direct,
+
+    /// The connection goes via a relay, i.e. to an address the
+    /// [address book](crate::Endpoint::address_book) has as
+    /// [`AddressKind::Relayed`].
+    Relayed,
+}
+
+impl ConnectionPath {
+    // The path of a connection to an address of the given kind (if known).
+    pub(crate) fn of(kind: Option<AddressKind>) -> Self {
+        match kind {
+            Some(AddressKind::Relayed) => Self::Relayed,
+            Some(AddressKind::Lan) | Some(AddressKind::Wan) | None => Self::Direct,
+        }
+    }
+}
+
+/// Metadata about a connection, as returned by [`Connection::info`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// The connection's [`id`](Connection::id).
+    pub id: usize,
+
+    /// The address of the remote peer.
+    pub remote_address: SocketAddr,
+
+    /// How long ago the connection was established.
+    pub age: Duration,
+
+    /// How long ago a message was last sent on the connection, or received from it by the
+    /// application.
+    pub idle: Duration,
+
+    /// The number of bytes sent on the connection, including QUIC overhead.
+    pub bytes_sent: u64,
+
+    /// The number of bytes received on the connection, including QUIC overhead.
+    pub bytes_received: u64,
+}
+
+/// The number of bytes exchanged with a peer, as returned by
+/// [`Endpoint::traffic_for`](crate::Endpoint::traffic_for).
+///
+/// Like [`ConnectionInfo`], this includes QUIC overhead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Traffic {
+    /// The number of bytes sent to the peer.
+    pub bytes_sent: u64,
+
+    /// The number of bytes received from the peer.
+    pub bytes_received: u64,
+}
+
+impl Traffic {
+    fn of(connection: &Connection) -> Self {
+        let stats = connection.stats();
+        Self {
+            bytes_sent: stats.udp_tx.bytes,
+            bytes_received: stats.udp_rx.bytes,
+        }
+    }
+
+    fn since(self, baseline: Self) -> Self {
+        Self {
+            bytes_sent: self.bytes_sent.saturating_sub(baseline.bytes_sent),
+            bytes_received: self.bytes_received.saturating_sub(baseline.bytes_received),
+        }
+    }
+}
+
+impl AddAssign for Traffic {
+    fn add_assign(&mut self, other: Self) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+    }
+}
+
+// Metadata about a connection, shared by its handles.
+#[derive(Debug)]
+pub(crate) struct Metadata {
+    path: ConnectionPath,
+    created: Instant,
+    // milliseconds between `created` and the last message activity
+    last_active: AtomicU64,
+    class: AtomicU8,
+    // whether this endpoint opened the connection, rather than the peer
+    dialed: AtomicBool,
+    // the peer's identity, for the listeners to report to `PeerScoring`
+    peer_id: Mutex<Option<PeerId>>,
+    message_stats: MessageStatsRecorder,
+}
+
+impl Metadata {
+    pub(crate) fn new(path: ConnectionPath) -> Self {
+        Self {
+            path,
+            created: Instant::now(),
+            last_active: AtomicU64::new(0),
+            class: AtomicU8::new(ConnectionClass::default() as u8),
+            dialed: AtomicBool::new(false),
+            peer_id: Mutex::new(None),
+            message_stats: MessageStatsRecorder::default(),
+        }
+    }
+
+    pub(crate) fn path(&self) -> ConnectionPath {
+        self.path
+    }
+
+    pub(crate) fn class(&self) -> ConnectionClass {
+        ConnectionClass::from_u8(self.class.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_class(&self, class: ConnectionClass) {
+        self.class.store(class as u8, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dialed(&self) -> bool {
+        self.dialed.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn mark_dialed(&self) {
+        self.dialed.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn peer_id(&self) -> Option<PeerId> {
+        *self
+            .peer_id
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+
+    pub(crate) fn set_peer_id(&self, peer_id: Option<PeerId>) {
+        *self
+            .peer_id
+            .lock()
+            .unwrap_or_else(|error| error.into_inner()) = peer_id;
+    }
+
+    pub(crate) fn message_stats(&self) -> &MessageStatsRecorder {
+        &self.message_stats
+    }
+
+    pub(crate) fn touch(&self) {
+        let millis = self.created.elapsed().as_millis() as u64;
+        let _ = self.last_active.fetch_max(millis, Ordering::Relaxed);
+    }
+
+    pub(crate) fn created(&self) -> Instant {
+        self.created
+    }
+
+    pub(crate) fn age(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    pub(crate) fn idle(&self) -> Duration {
+        let last_active = Duration::from_millis(self.last_active.load(Ordering::Relaxed));
+        self.age().saturating_sub(last_active)
+    }
+}
+
+// The live connections of an endpoint.
+//
+// The registry holds a handle for each connection, but it doesn't keep the connection open: each
+// entry is removed once the connection closes, or when every handle outside the registry has been
+// dropped (tracked by `Registration`).
+//
+// If there are more than `max_connections`, the least important connection (by class, then peer
+// score, then idle time) is closed whenever a connection is added. Adding a direct connection to an identified peer
+// also closes the peer's relayed connections, since the direct path supersedes them.
+//
+// The registry also accounts for the traffic exchanged with each peer address. The traffic of a
+// connection is added to its peer's total when the connection is removed, so totals survive
+// reconnections. `traffic` is only locked while holding the `connections` lock, so a connection is
+// never counted both as live and removed.
+#[derive(Debug)]
+pub(crate) struct ConnectionRegistry {
+    connections: Mutex<BTreeMap<usize, Entry>>,
+    traffic: Mutex<HashMap<SocketAddr, Traffic>>,
+    max_connections: Option<usize>,
+    peer_scoring: Option<Arc<dyn PeerScoring>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    // a handle without a registration, so it doesn't keep itself registered
+    connection: Connection,
+    registration: Weak<Registration>,
+    // the connection's traffic when the totals were last reset
+    baseline: Traffic,
+}
+
+impl ConnectionRegistry {
+    // Create a registry, and start a background task to send keep-alives on idle critical
+    // connections every `keep_alive_interval`, if given.
+    //
+    // The task will stop when a value is sent on `termination_rx`.
+    pub(crate) fn start(
+        max_connections: Option<usize>,
+        peer_scoring: Option<Arc<dyn PeerScoring>>,
+        keep_alive_interval: Option<Duration>,
+        mut termination_rx: Receiver<()>,
+    ) -> Arc<Self> {
+        let registry = Arc::new(Self {
+            connections: Mutex::default(),
+            traffic: Mutex::default(),
+            max_connections,
+            peer_scoring,
+        });
+
+        let interval = match keep_alive_interval {
+            Some(interval) => interval,
+            None => return registry,
+        };
+        let weak = Arc::downgrade(&registry);
+        let _ = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if termination_rx.try_recv() != Err(TryRecvError::Empty) {
+                    break;
+                }
+                let registry = match weak.upgrade() {
+                    Some(registry) => registry,
+                    None => break,
+                };
+
+                for connection in registry.all() {
+                    let metadata = connection.metadata();
+                    if metadata.class() != ConnectionClass::Critical || metadata.idle() < interval {
+                        continue;
+                    }
+                    let _ = tokio::spawn(async move {
+                        if let Err(error) = connection.keep_alive().await {
+                            debug!(
+                                "Failed to send keep-alive to {}: {}",
+                                connection.remote_address(),
+                                error
+                            );
+                        }
+                    });
+                }
+            }
+        });
+
+        registry
+    }
+
+    // Add `connection` to the registry, returning the registration to hold in its handles.
+    //
+    // `connection` should not itself hold a registration.
+    pub(crate) fn insert(self: &Arc<Self>, connection: Connection) -> Arc<Registration> {
+        let id = connection.id();
+        let direct_peer = connection
+            .peer_id()
+            .filter(|_| connection.metadata().path() == ConnectionPath::Direct);
+        let registration = Arc::new(Registration {
+            id,
+            registry: Arc::downgrade(self),
+        });
+
+        let mut connections = self.lock();
+        let _ = connections.insert(
+            id,
+            Entry {
+                connection,
+                registration: Arc::downgrade(&registration),
+                baseline: Traffic::default(),
+            },
+        );
+        let evicted = self.evict(&mut connections);
+        let superseded: Vec<_> = match direct_peer {
+            Some(peer) => connections
+                .values()
+                .map(|entry| &entry.connection)
+                .filter(|connection| {
+                    connection.peer_id() == Some(peer)
+                        && connection.metadata().path() == ConnectionPath::Relayed
+                })
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        drop(connections);
+
+        for connection in superseded {
+            info!(
+                "Closing relayed connection {} to {}, as there's now a direct connection",
+                connection.id(),
+                connection.remote_address()
+            );
+            connection.close(Some(UPGRADED.to_string()));
+        }
+
+        if let Some(entry) = evicted {
+            info!(
+                "Evicting connection {} to {} ({:?}), as there are more than {:?} connections",
+                entry.connection.id(),
+                entry.connection.remote_address(),
+                entry.connection.metadata().class(),
+                self.max_connections,
+            );
+            entry.connection.close(Some(EVICTED.to_string()));
+        }
+
+        registration
+    }
+
+    // Remove the least important connection if we're over the limit: the lowest class, then the
+    // peer with the lowest score, then the most idle. Critical connections are never removed, even
+    // if that leaves us over the limit.
+    fn evict(&self, connections: &mut BTreeMap<usize, Entry>) -> Option<Entry> {
+        if connections.len() <= self.max_connections? {
+            return None;
+        }
+
+        let victim = connections
+            .iter()
+            .map(|(id, entry)| (*id, &entry.connection))
+            .filter(|(_, connection)| connection.metadata().class() != ConnectionClass::Critical)
+            .max_by_key(|(_, connection)| {
+                let metadata = connection.metadata();
+                (
+                    Reverse(metadata.class()),
+                    Reverse(self.score(connection)),
+                    metadata.idle(),
+                )
+            })
+            .map(|(id, _)| id)?;
+        let entry = connections.remove(&victim)?;
+        self.retire(&entry);
+        Some(entry)
+    }
+
+    // The score of a connection's peer, or `0` if there's no scoring.
+    fn score(&self, connection: &Connection) -> i64 {
+        self.peer_scoring.as_ref().map_or(0, |scoring| {
+            scoring.score(&ScoredPeer {
+                addr: connection.remote_address(),
+                id: connection.peer_id(),
+            })
+        })
+    }
+
+    // Remove the connection with the given `id`, e.g. because it has closed.
+    pub(crate) fn remove(&self, id: usize) {
+        let mut connections = self.lock();
+        let entry = connections.remove(&id);
+        if let Some(entry) = &entry {
+            self.retire(entry);
+        }
+        // drop the entry after releasing the lock
+        drop(connections);
+        drop(entry);
+    }
+
+    // Add the traffic of a removed connection to its peer's total. The `connections` lock must be
+    // held.
+    fn retire(&self, entry: &Entry) {
+        let traffic = Traffic::of(&entry.connection).since(entry.baseline);
+        *self
+            .lock_traffic()
+            .entry(entry.connection.remote_address())
+            .or_default() += traffic;
+    }
+
+    // The traffic exchanged with `addr` since the totals were last reset.
+    pub(crate) fn traffic_for(&self, addr: &SocketAddr) -> Traffic {
+        let connections = self.lock();
+        let mut total = self.lock_traffic().get(addr).copied().unwrap_or_default();
+        for entry in connections.values() {
+            if entry.connection.remote_address() == *addr {
+                total += Traffic::of(&entry.connection).since(entry.baseline);
+            }
+        }
+        total
+    }
+
+    // The traffic exchanged with every peer since the totals were last reset.
+    pub(crate) fn traffic(&self) -> HashMap<SocketAddr, Traffic> {
+        let connections = self.lock();
+        let mut totals = self.lock_traffic().clone();
+        for entry in connections.values() {
+            *totals.entry(entry.connection.remote_address()).or_default() +=
+                Traffic::of(&entry.connection).since(entry.baseline);
+        }
+        totals
+    }
+
+    // Reset the totals to zero, returning the traffic exchanged with every peer until now.
+    pub(crate) fn reset_traffic(&self) -> HashMap<SocketAddr, Traffic> {
+        let mut connections = self.lock();
+        let mut totals = mem::take(&mut *self.lock_traffic());
+        for entry in connections.values_mut() {
+            let current = Traffic::of(&entry.connection);
+            *totals.entry(entry.connection.remote_address()).or_default() +=
+                current.since(entry.baseline);
+            entry.baseline = current;
+        }
+        totals
+    }
+
+    pub(crate) fn get(&self, id: usize) -> Option<Connection> {
+        self.lock().get(&id).and_then(Entry::connection)
+    }
+
+    pub(crate) fn get_by_addr(&self, addr: &SocketAddr) -> Option<Connection> {
+        // filter outside of the lock, since dropping a handle may drop its registration
+        self.all()
+            .into_iter()
+            .filter(|connection| connection.remote_address() == *addr)
+            .max_by_key(|connection| connection.metadata().created())
+    }
+
+    // The most recent connection this endpoint dialed to `addr`, which shows that `addr` is
+    // reachable (unlike a connection the peer opened from it).
+    pub(crate) fn get_dialed(&self, addr: &SocketAddr) -> Option<Connection> {
+        self.all()
+            .into_iter()
+            .filter(|connection| {
+                connection.remote_address() == *addr && connection.metadata().dialed()
+            })
+            .max_by_key(|connection| connection.metadata().created())
+    }
+
+    pub(crate) fn get_by_peer(&self, peer: &PeerId) -> Option<Connection> {
+        self.all_for_peer(peer).into_iter().next()
+    }
+
+    // The connections to `peer`, best path first, and then most recently established first.
+    pub(crate) fn all_for_peer(&self, peer: &PeerId) -> Vec<Connection> {
+        let mut connections: Vec<_> = self
+            .all()
+            .into_iter()
+            .filter(|connection| connection.peer_id().as_ref() == Some(peer))
+            .collect();
+        connections.sort_by_key(|connection| {
+            let metadata = connection.metadata();
+            (metadata.path(), Reverse(metadata.created()))
+        });
+        connections
+    }
+
+    pub(crate) fn all(&self) -> Vec<Connection> {
+        self.lock().values().filter_map(Entry::connection).collect()
+    }
+
+    // Whether another connection would put the registry over `max_connections`.
+    pub(crate) fn is_full(&self) -> bool {
+        self.max_connections
+            .is_some_and(|max_connections| self.lock().len() >= max_connections)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<usize, Entry>> {
+        // entries are only inserted and removed under the lock, so it can't be left inconsistent
+        self.connections
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+
+    fn lock_traffic(&self) -> MutexGuard<'_, HashMap<SocketAddr, Traffic>> {
+        self.traffic
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl Entry {
+    fn connection(&self) -> Option<Connection> {
+        let registration = self.registration.upgrade()?;
+        Some(self.connection.with_registration(registration))
+    }
+}
+
+// Keeps a connection registered while any handle to it exists.
+#[derive(Debug)]
+pub(crate) struct Registration {
+    id: usize,
+    registry: Weak<ConnectionRegistry>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let registry = match self.registry.upgrade() {
+            Some(registry) => registry,
+            None => return,
+        };
+
+        let mut connections = registry.lock();
+        // the ID may have been reused by a new connection if this one was already removed
+        let entry = match connections.get(&self.id) {
+            Some(entry) if entry.registration.strong_count() == 0 => connections.remove(&self.id),
+            _ => None,
+        };
+        if let Some(entry) = &entry {
+            registry.retire(entry);
+        }
+        drop(connections);
+        drop(entry);
+    }
+}
@@ -0,0 +1,152 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Name resolution for peer addresses.
+
+use crate::error::ConnectionError;
+use futures::future::BoxFuture;
+use std::{fmt, io, net::SocketAddr};
+
+/// Resolves host names to socket addresses.
+///
+/// The resolver used by an endpoint can be set via
+/// [`Config::resolver`](crate::Config::resolver), e.g. to use a specific DNS server or a name
+/// service other than DNS. By default, [`SystemResolver`] is used.
+pub trait Resolver: fmt::Debug + Send + Sync {
+    /// Resolve `host` to the addresses it can be reached at, with the given `port`.
+    fn resolve(&self, host: &str, port: u16) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>>;
+}
+
+/// A [`Resolver`] that uses the operating system's resolver (e.g. `getaddrinfo`).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        let host = host.to_string();
+        Box::pin(async move {
+            Ok(tokio::net::lookup_host((host.as_str(), port))
+                .await?
+                .collect())
+        })
+    }
+}
+
+/// Addresses to connect to, which may need resolving.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerAddrs {
+    /// Socket addresses, which can be connected to directly.
+    Resolved(Vec<SocketAddr>),
+
+    /// A host name that must be resolved first.
+    Unresolved {
+        /// The host name.
+        host: String,
+
+        /// The port to connect to.
+        port: u16,
+    },
+}
+
+/// Types that can be used as a peer address with
+/// [`Endpoint::connect_to`](crate::Endpoint::connect_to).
+///
+/// This is implemented for socket addresses, `(host, port)` pairs and `"host:port"` strings. IP
+/// addresses in host position are used directly, without resolution.
+pub trait ToPeerAddrs {
+    /// Convert `self` to [`PeerAddrs`].
+    fn to_peer_addrs(&self) -> Result<PeerAddrs, ConnectionError>;
+}
+
+impl ToPeerAddrs for SocketAddr {
+    fn to_peer_addrs(&self) -> Result<PeerAddrs, ConnectionError> {
+        Ok(PeerAddrs::Resolved(vec![*self]))
+    }
+}
+
+impl ToPeerAddrs for [SocketAddr] {
+    fn to_peer_addrs(&self) -> Result<PeerAddrs, ConnectionError> {
+        Ok(PeerAddrs::Resolved(self.to_vec()))
+    }
+}
+
+impl ToPeerAddrs for (&str, u16) {
+    fn to_peer_addrs(&self) -> Result<PeerAddrs, ConnectionError> {
+        let (host, port) = *self;
+        // strip brackets from IPv6 literals, as used in URLs
+        let ip = host.trim_start_matches('[').trim_end_matches(']');
+        Ok(match ip.parse() {
+            Ok(ip) => PeerAddrs::Resolved(vec![SocketAddr::new(ip, port)]),
+            Err(_) => PeerAddrs::Unresolved {
+                host: host.to_string(),
+                port,
+            },
+        })
+    }
+}
+
+impl ToPeerAddrs for (String, u16) {
+    fn to_peer_addrs(&self) -> Result<PeerAddrs, ConnectionError> {
+        (self.0.as_str(), self.1).to_peer_addrs()
+    }
+}
+
+impl ToPeerAddrs for str {
+    fn to_peer_addrs(&self) -> Result<PeerAddrs, ConnectionError> {
+        if let Ok(addr) = self.parse::<SocketAddr>() {
+            return addr.to_peer_addrs();
+        }
+
+        let invalid = || ConnectionError::Resolve(format!("invalid address: {}", self));
+        let (host, port) = self.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse().map_err(|_| invalid())?;
+        (host, port).to_peer_addrs()
+    }
+}
+
+impl ToPeerAddrs for String {
+    fn to_peer_addrs(&self) -> Result<PeerAddrs, ConnectionError> {
+        self.as_str().to_peer_addrs()
+    }
+}
+
+impl<T: ToPeerAddrs + ?Sized> ToPeerAddrs for &T {
+    fn to_peer_addrs(&self) -> Result<PeerAddrs, ConnectionError> {
+        (**self).to_peer_addrs()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PeerAddrs, ToPeerAddrs};
+    use color_eyre::eyre::Result;
+
+    #[test]
+    fn to_peer_addrs() -> Result<()> {
+        assert_eq!(
+            "127.0.0.1:1234".to_peer_addrs()?,
+            PeerAddrs::Resolved(vec!["127.0.0.1:1234".parse()?])
+        );
+        assert_eq!(
+            ("::1", 1234).to_peer_addrs()?,
+            PeerAddrs::Resolved(vec!["[::1]:1234".parse()?])
+        );
+        assert_eq!(
+            "example.com:443".to_peer_addrs()?,
+            PeerAddrs::Unresolved {
+                host: "example.com".to_string(),
+                port: 443
+            }
+        );
+        assert!("example.com".to_peer_addrs().is_err());
+        assert!("example.com:http".to_peer_addrs().is_err());
+
+        Ok(())
+    }
+}
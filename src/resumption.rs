@@ -0,0 +1,206 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! TLS session resumption, so that reconnecting to a peer can skip part of the handshake.
+
+use rustls::{
+    client::{ClientSessionMemoryCache, NoClientSessionStorage, StoresClientSessions},
+    server::{NoServerSessionStorage, ServerSessionMemoryCache, StoresServerSessions},
+};
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+// rustls prefixes the keys of resumable sessions with this, to tell them apart from other data it
+// caches (such as key exchange hints).
+const SESSION_KEY_PREFIX: &[u8] = b"session";
+
+/// Storage for the TLS sessions an endpoint can resume with peers, as
+/// [`Config::session_store`](crate::Config::session_store).
+///
+/// Keys identify the peer a session was established with, and both keys and values should be
+/// treated as opaque. Values are secret, since they allow resuming the session.
+pub trait SessionStore: fmt::Debug + Send + Sync {
+    /// Store `value` for `key`, replacing any previous value, and return whether it was stored.
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool;
+
+    /// The latest value stored for `key`, if any.
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Counts of TLS session resumptions, as returned by
+/// [`Endpoint::resumption_stats`](crate::Endpoint::resumption_stats).
+///
+/// quinn doesn't reveal whether an individual connection was resumed, so these count handshakes
+/// for the whole endpoint.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResumptionStats {
+    /// The number of outgoing handshakes that offered a cached session to the peer.
+    ///
+    /// The peer may still decline to resume the session, e.g. if it has since restarted.
+    pub offered: u64,
+
+    /// The number of incoming handshakes that resumed a session this endpoint had cached.
+    pub resumed: u64,
+}
+
+// An endpoint's caches of resumable sessions, for outgoing and incoming connections.
+#[derive(Clone, Debug)]
+pub(crate) struct SessionCaches {
+    client: Arc<ClientSessions>,
+    server: Arc<ServerSessions>,
+}
+
+impl SessionCaches {
+    // Caches holding up to `size` sessions, or sessions kept in `store` for outgoing connections.
+    // A size of 0 disables resumption, unless there's a store.
+    pub(crate) fn new(size: usize, store: Option<Arc<dyn SessionStore>>) -> Self {
+        let client: Arc<dyn StoresClientSessions> = match store {
+            Some(store) => Arc::new(CustomStore(store)),
+            None if size == 0 => Arc::new(NoClientSessionStorage {}),
+            None => ClientSessionMemoryCache::new(size),
+        };
+        let server: Arc<dyn StoresServerSessions> = if size == 0 {
+            Arc::new(NoServerSessionStorage {})
+        } else {
+            ServerSessionMemoryCache::new(size)
+        };
+        Self {
+            client: Arc::new(ClientSessions {
+                inner: client,
+                offered: AtomicU64::new(0),
+            }),
+            server: Arc::new(ServerSessions {
+                inner: server,
+                resumed: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    // A copy of `crypto` that resumes sessions with `peer`.
+    //
+    // rustls keys sessions by server name, which qp2p shares between every peer, so each peer's
+    // sessions are kept under keys of their own.
+    pub(crate) fn client_crypto_for(
+        &self,
+        crypto: &rustls::ClientConfig,
+        peer: SocketAddr,
+    ) -> rustls::ClientConfig {
+        let mut crypto = crypto.clone();
+        crypto.session_storage = Arc::new(PeerSessions {
+            sessions: self.client.clone(),
+            prefix: format!("{}/", peer).into_bytes(),
+        });
+        crypto
+    }
+
+    pub(crate) fn configure_server(&self, crypto: &mut rustls::ServerConfig) {
+        crypto.session_storage = self.server.clone();
+    }
+
+    pub(crate) fn stats(&self) -> ResumptionStats {
+        ResumptionStats {
+            offered: self.client.offered.load(Ordering::Relaxed),
+            resumed: self.server.resumed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// The sessions for outgoing connections, counting those offered for resumption.
+struct ClientSessions {
+    inner: Arc<dyn StoresClientSessions>,
+    offered: AtomicU64,
+}
+
+// A view of `ClientSessions` for a single peer.
+struct PeerSessions {
+    sessions: Arc<ClientSessions>,
+    prefix: Vec<u8>,
+}
+
+impl PeerSessions {
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        [&self.prefix, key].concat()
+    }
+}
+
+impl StoresClientSessions for PeerSessions {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.sessions.inner.put(self.key(&key), value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.sessions.inner.get(&self.key(key))?;
+        if key.starts_with(SESSION_KEY_PREFIX) {
+            let _ = self.sessions.offered.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(value)
+    }
+}
+
+// The sessions for incoming connections, counting those resumed.
+struct ServerSessions {
+    inner: Arc<dyn StoresServerSessions>,
+    resumed: AtomicU64,
+}
+
+impl StoresServerSessions for ServerSessions {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.inner.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.inner.get(key)
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        // sessions are taken to resume them, so they can't be resumed twice
+        let value = self.inner.take(key)?;
+        let _ = self.resumed.fetch_add(1, Ordering::Relaxed);
+        Some(value)
+    }
+
+    fn can_cache(&self) -> bool {
+        self.inner.can_cache()
+    }
+}
+
+// Adapts a `SessionStore` for rustls.
+struct CustomStore(Arc<dyn SessionStore>);
+
+impl StoresClientSessions for CustomStore {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.0.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.0.get(key)
+    }
+}
+
+impl fmt::Debug for ClientSessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientSessions")
+            .field("offered", &self.offered)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for ServerSessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerSessions")
+            .field("resumed", &self.resumed)
+            .finish_non_exhaustive()
+    }
+}
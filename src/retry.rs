@@ -0,0 +1,336 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Retrying with exponential back-off, as configured by [`RetryConfig`].
+
+use crate::config::RetryConfig;
+use futures::future::BoxFuture;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::time::{sleep, Instant};
+use tracing::trace;
+
+/// How retry delays are randomised, so that many peers retrying at once don't do so in lockstep.
+///
+/// Each strategy randomises the current back-off interval, which starts at
+/// [`RetryConfig::initial_retry_interval`] and grows by
+/// [`RetryConfig::retry_delay_multiplier`] up to [`RetryConfig::max_retry_interval`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RetryJitter {
+    /// A delay within [`RetryConfig::retry_delay_rand_factor`] of the interval, either side.
+    #[default]
+    Proportional,
+
+    /// A delay between zero and the interval.
+    ///
+    /// This spreads retries out the most, at the cost of some retries being almost immediate.
+    Full,
+
+    /// Half the interval, plus a delay of up to the other half.
+    Equal,
+
+    /// A delay between the initial interval and three times the previous delay, up to the maximum
+    /// interval.
+    ///
+    /// The interval (and so the multiplier) is ignored, since each delay follows from the last.
+    Decorrelated,
+}
+
+impl std::str::FromStr for RetryJitter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "proportional" => Ok(Self::Proportional),
+            "full" => Ok(Self::Full),
+            "equal" => Ok(Self::Equal),
+            "decorrelated" => Ok(Self::Decorrelated),
+            _ => Err(format!(
+                "invalid retry jitter '{}', expected 'proportional', 'full', 'equal' or \
+                 'decorrelated'",
+                s
+            )),
+        }
+    }
+}
+
+/// A failed attempt that's about to be retried, as passed to [`RetryHook::before_retry`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryEvent {
+    /// The number of the retry about to be made, starting from 1.
+    pub retry: u32,
+
+    /// How long until the retry.
+    pub delay: Duration,
+
+    /// How long has passed since the first attempt.
+    pub elapsed: Duration,
+
+    /// The error the last attempt failed with, in its `Debug` representation.
+    pub error: String,
+}
+
+/// An async hook invoked before each retry, as set with [`RetryConfig::retry_hook`].
+///
+/// This makes retries visible, e.g. for logging or metrics, and lets the application give up on an
+/// operation early.
+pub trait RetryHook: fmt::Debug + Send + Sync {
+    /// Called after a failed attempt, before waiting to retry.
+    ///
+    /// Resolving to `false` aborts the retries, so the operation fails with the last attempt's
+    /// error.
+    fn before_retry(&self, event: RetryEvent) -> BoxFuture<'static, bool>;
+}
+
+// An endpoint's retry config, which can be replaced while the endpoint is running.
+//
+// Clones share the config. Each operation uses the config that's current when it starts.
+#[derive(Clone, Debug)]
+pub(crate) struct SharedRetryConfig(Arc<RwLock<Arc<RetryConfig>>>);
+
+impl SharedRetryConfig {
+    pub(crate) fn new(config: Arc<RetryConfig>) -> Self {
+        Self(Arc::new(RwLock::new(config)))
+    }
+
+    pub(crate) fn get(&self) -> Arc<RetryConfig> {
+        // the lock is never held across anything that can panic
+        self.0
+            .read()
+            .unwrap_or_else(|error| error.into_inner())
+            .clone()
+    }
+
+    pub(crate) fn set(&self, config: RetryConfig) {
+        *self.0.write().unwrap_or_else(|error| error.into_inner()) = Arc::new(config);
+    }
+}
+
+// Perform `op`, retrying transient errors as configured.
+//
+// With a round-trip time, the first interval may follow from it rather than being fixed (see
+// `RetryConfig::initial_retry_rtt_multiplier`).
+pub(crate) async fn retry<R, E, Fn, Fut>(
+    config: RetryConfig,
+    rtt: Option<Duration>,
+    mut op: Fn,
+) -> Result<R, E>
+where
+    Fn: FnMut() -> Fut,
+    Fut: Future<Output = Result<R, backoff::Error<E>>>,
+    E: fmt::Debug,
+{
+    let start = Instant::now();
+    let mut backoff = Backoff::new(&config, config.initial_interval(rtt));
+    let mut retries = 0;
+    loop {
+        let error = match op().await {
+            Ok(result) => return Ok(result),
+            Err(backoff::Error::Permanent(error)) => return Err(error),
+            Err(backoff::Error::Transient(error)) => error,
+        };
+
+        retries += 1;
+        let elapsed = start.elapsed();
+        if elapsed > config.retrying_max_elapsed_time
+            || config.max_retry_attempts.is_some_and(|max| retries > max)
+        {
+            return Err(error);
+        }
+
+        let delay = backoff.next_delay();
+        if let Some(hook) = &config.retry_hook {
+            let event = RetryEvent {
+                retry: retries,
+                delay,
+                elapsed,
+                error: format!("{:?}", error),
+            };
+            if !hook.before_retry(event).await {
+                trace!("Retry hook aborted retrying after {:?}", error);
+                return Err(error);
+            }
+        }
+        sleep(delay).await;
+    }
+}
+
+// The sequence of delays between retries.
+struct Backoff<'a> {
+    config: &'a RetryConfig,
+    // the first interval, and the least delay with decorrelated jitter
+    initial: Duration,
+    // the current interval, before jitter
+    interval: Duration,
+    // the previous delay, for decorrelated jitter
+    previous: Duration,
+}
+
+impl<'a> Backoff<'a> {
+    fn new(config: &'a RetryConfig, initial: Duration) -> Self {
+        Self {
+            config,
+            initial,
+            interval: initial,
+            previous: initial,
+        }
+    }
+
+    // The next interval, before jitter.
+    fn next_interval(&mut self) -> Duration {
+        let interval = self.interval;
+        self.interval = interval
+            .mul_f64(self.config.retry_delay_multiplier.max(1.0))
+            .min(self.config.max_retry_interval);
+        interval
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let config = self.config;
+        let interval = self.next_interval();
+
+        match config.retry_jitter {
+            RetryJitter::Proportional => {
+                let factor = config.retry_delay_rand_factor;
+                let scale = 1.0 - factor + 2.0 * factor * random_fraction();
+                Duration::from_secs_f64((interval.as_secs_f64() * scale).max(0.0))
+            }
+            RetryJitter::Full => interval.mul_f64(random_fraction()),
+            RetryJitter::Equal => interval / 2 + (interval / 2).mul_f64(random_fraction()),
+            RetryJitter::Decorrelated => {
+                let lower = self.initial;
+                let upper = (self.previous * 3)
+                    .min(config.max_retry_interval)
+                    .max(lower);
+                self.previous = lower + (upper - lower).mul_f64(random_fraction());
+                self.previous
+            }
+        }
+    }
+}
+
+// The intervals between retries before jitter, as returned by `RetryConfig::schedule`.
+pub(crate) fn schedule(config: &RetryConfig, rtt: Option<Duration>) -> Vec<Duration> {
+    let mut backoff = Backoff::new(config, config.initial_interval(rtt));
+    let mut intervals = Vec::new();
+    let mut elapsed = Duration::ZERO;
+    while elapsed <= config.retrying_max_elapsed_time
+        && config
+            .max_retry_attempts
+            .is_none_or(|max| intervals.len() < max as usize)
+    {
+        let interval = backoff.next_interval();
+        intervals.push(interval);
+        if interval.is_zero() {
+            // retries are only limited by the time their attempts take
+            break;
+        }
+        elapsed += interval;
+    }
+    intervals
+}
+
+// A random number in [0, 1).
+fn random_fraction() -> f64 {
+    let mut bytes = [0; 8];
+    if SystemRandom::new().fill(&mut bytes).is_err() {
+        return 0.5;
+    }
+    // the top 53 bits fill an `f64`'s mantissa
+    (u64::from_le_bytes(bytes) >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{schedule, Backoff, RetryJitter};
+    use crate::RetryConfig;
+    use std::time::Duration;
+
+    fn config(retry_jitter: RetryJitter) -> RetryConfig {
+        RetryConfig {
+            initial_retry_interval: Duration::from_millis(100),
+            max_retry_interval: Duration::from_millis(1000),
+            retry_delay_multiplier: 2.0,
+            retry_delay_rand_factor: 0.5,
+            retry_jitter,
+            ..RetryConfig::default()
+        }
+    }
+
+    fn delays(config: &RetryConfig) -> Vec<Duration> {
+        let mut backoff = Backoff::new(config, config.initial_retry_interval);
+        (0..6).map(|_| backoff.next_delay()).collect()
+    }
+
+    #[test]
+    fn jitter_bounds() {
+        let intervals = [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis);
+
+        for _ in 0..100 {
+            let proportional = delays(&config(RetryJitter::Proportional));
+            for (delay, interval) in proportional.iter().zip(intervals) {
+                assert!(*delay >= interval / 2 && *delay <= interval * 3 / 2);
+            }
+
+            let full = delays(&config(RetryJitter::Full));
+            for (delay, interval) in full.iter().zip(intervals) {
+                assert!(*delay <= interval);
+            }
+
+            let equal = delays(&config(RetryJitter::Equal));
+            for (delay, interval) in equal.iter().zip(intervals) {
+                assert!(*delay >= interval / 2 && *delay <= interval);
+            }
+
+            let mut previous = Duration::from_millis(100);
+            for delay in delays(&config(RetryJitter::Decorrelated)) {
+                assert!(delay >= Duration::from_millis(100));
+                assert!(delay <= (previous * 3).min(Duration::from_millis(1000)));
+                previous = delay;
+            }
+        }
+    }
+
+    #[test]
+    fn rtt_schedule() {
+        let ms = Duration::from_millis;
+        let config = RetryConfig {
+            initial_retry_rtt_multiplier: Some(3.0),
+            retrying_max_elapsed_time: ms(1000),
+            ..config(RetryJitter::Full)
+        };
+        assert_eq!(
+            schedule(&config, Some(ms(10))),
+            [30, 60, 120, 240, 480, 960].map(ms)
+        );
+        // without a round-trip time, the initial interval is used
+        assert_eq!(schedule(&config, None), [100, 200, 400, 800].map(ms));
+        // slow links start from the maximum interval
+        assert_eq!(schedule(&config, Some(ms(500))), [1000, 1000].map(ms));
+
+        let config = RetryConfig {
+            max_retry_attempts: Some(2),
+            ..config
+        };
+        assert_eq!(schedule(&config, Some(ms(10))), [30, 60].map(ms));
+    }
+
+    #[test]
+    fn parse_jitter() {
+        assert_eq!("full".parse(), Ok(RetryJitter::Full));
+        assert_eq!("decorrelated".parse(), Ok(RetryJitter::Decorrelated));
+        assert!("none".parse::<RetryJitter>().is_err());
+    }
+}
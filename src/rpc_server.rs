@@ -0,0 +1,161 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Serving request/response exchanges with a handler function.
+
+use crate::{
+    connection::{Connection, ConnectionIncoming},
+    endpoint::IncomingConnections,
+    error::{PeerError, RecvError, SendError},
+};
+use bytes::Bytes;
+use futures::{
+    future::FutureExt,
+    stream::{BoxStream, Stream, StreamExt},
+};
+use std::{fmt, future::Future, sync::Arc};
+use tracing::{trace, warn};
+
+type Handler = dyn Fn(Bytes) -> BoxStream<'static, Bytes> + Send + Sync;
+
+/// Answers peers' requests with a handler function.
+///
+/// Each message received is a request, which is passed to the handler, and the handler's result
+/// is sent back as the response:
+///
+/// - Requests sent on a bidirectional stream (e.g. with [`Connection::open_bi`] and
+///   [`SendStream::send_user_msg`](crate::SendStream::send_user_msg)) are answered on the same
+///   stream. The peer may continue the exchange with further requests on the stream, each
+///   answered in turn, so long as it waits for each response before sending the next request.
+/// - Requests sent on their own (e.g. with [`Connection::send`]) are answered the same way, on a
+///   stream of their own, so the peer receives the response from its
+///   [`ConnectionIncoming`](crate::ConnectionIncoming).
+///
+/// This is the pattern qp2p uses internally for its own requests, such as the echo requests made
+/// by [`Endpoint::is_reachable`](crate::Endpoint::is_reachable). Requests are handled
+/// concurrently, both across connections and within each connection.
+///
+/// A server created with [`streaming`](Self::streaming) answers each request with any number of
+/// messages instead.
+#[derive(Clone)]
+pub struct RpcServer {
+    handler: Arc<Handler>,
+    streaming: bool,
+}
+
+impl RpcServer {
+    /// Create a server that answers requests with `handler`.
+    pub fn new<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(Bytes) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Bytes> + Send + 'static,
+    {
+        Self {
+            handler: Arc::new(move |request| handler(request).into_stream().boxed()),
+            streaming: false,
+        }
+    }
+
+    /// Create a server that answers each request with the stream of messages from `handler`.
+    ///
+    /// On a bidirectional stream, the messages are followed by an end-of-response marker (see
+    /// [`SendStream::end_response`](crate::SendStream::end_response)), so the peer can read them
+    /// with [`RecvStream::next_response`](crate::RecvStream::next_response) until it returns
+    /// `None`. The peer may then send its next request on the stream.
+    ///
+    /// Requests sent on their own are answered with each message on a stream of its own, without
+    /// a marker, so the peer can't tell when the response has ended. Peers that need to should
+    /// make their requests on bidirectional streams.
+    pub fn streaming<F, S>(handler: F) -> Self
+    where
+        F: Fn(Bytes) -> S + Send + Sync + 'static,
+        S: Stream<Item = Bytes> + Send + 'static,
+    {
+        Self {
+            handler: Arc::new(move |request| handler(request).boxed()),
+            streaming: true,
+        }
+    }
+
+    /// Answer requests on every connection from `incoming_connections`.
+    ///
+    /// This returns once the endpoint stops accepting connections. Each connection is served in a
+    /// task of its own (see [`serve_connection`](Self::serve_connection)).
+    pub async fn serve(&self, mut incoming_connections: IncomingConnections) {
+        while let Some((connection, incoming)) = incoming_connections.next().await {
+            let server = self.clone();
+            let _ = tokio::spawn(async move {
+                let peer_addr = connection.remote_address();
+                if let Err(error) = server.serve_connection(connection, incoming).await {
+                    warn!("Stopped serving requests from {}: {}", peer_addr, error);
+                }
+            });
+        }
+    }
+
+    /// Answer requests received on `connection` until it closes.
+    ///
+    /// Returns an error if receiving a request fails, in which case the connection may still be
+    /// open, but no further requests are answered.
+    pub async fn serve_connection(
+        &self,
+        connection: Connection,
+        mut incoming: ConnectionIncoming,
+    ) -> Result<(), PeerError<RecvError>> {
+        while let Some((request, stream)) = incoming.next_with_stream().await? {
+            let handler = self.handler.clone();
+            let streaming = self.streaming;
+            let connection = connection.clone();
+            let _ = tokio::spawn(async move {
+                let peer_addr = connection.remote_address();
+                let mut responses = handler(request);
+                let result = match stream {
+                    Some(stream) => {
+                        // hold the stream throughout, so responses to requests on the same stream
+                        // aren't interleaved
+                        let mut stream = stream.lock().await;
+                        async {
+                            while let Some(response) = responses.next().await {
+                                stream.send_user_msg(response).await?;
+                            }
+                            if streaming {
+                                stream.end_response().await?;
+                            }
+                            Ok::<_, PeerError<SendError>>(())
+                        }
+                        .await
+                    }
+                    None => {
+                        async {
+                            while let Some(response) = responses.next().await {
+                                connection.send(response).await?;
+                            }
+                            Ok::<_, PeerError<SendError>>(())
+                        }
+                        .await
+                    }
+                };
+                match result {
+                    Ok(()) => trace!("Answered request from {}", peer_addr),
+                    Err(error) => warn!("Failed to answer request from {}: {}", peer_addr, error),
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for RpcServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcServer")
+            .field("handler", &"<handler>")
+            .finish()
+    }
+}
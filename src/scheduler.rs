@@ -0,0 +1,399 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Endpoint-level scheduling of outgoing messages.
+
+use crate::{
+    connection::Connection,
+    error::{ConnectionError, PeerError, SendError},
+};
+use bytes::Bytes;
+use futures::future;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, MutexGuard},
+};
+use tokio::sync::{broadcast, oneshot, Notify, Semaphore};
+use tracing::trace;
+
+// Number of bytes each connection may send per round, within a priority class.
+const QUANTUM: usize = 64 * 1024;
+
+// Maximum number of scheduled messages being written to QUIC at once. This bounds how much the
+// scheduler's decisions can be undone by buffering further down the stack.
+const MAX_IN_FLIGHT_SENDS: usize = 64;
+
+// Number of times in a row a class with queued messages can be passed over for higher classes
+// before it gets a turn, so lower classes aren't starved.
+const MAX_PASSED_OVER: usize = 8;
+
+/// The priority class of a queued message.
+///
+/// Messages in a higher class are dispatched before messages in a lower class, except that a class
+/// that has been passed over 8 times in a row gets the next turn, so a busy higher class can slow
+/// lower classes down but not starve them. Within a class, connections are served in turn, with
+/// each getting an equal share of bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PriorityClass {
+    /// Control messages, which should never be delayed by other traffic.
+    Control,
+
+    /// Latency-sensitive application messages.
+    Realtime,
+
+    /// Background transfers.
+    Bulk,
+}
+
+impl PriorityClass {
+    // All classes, in dispatch order.
+    const ALL: [PriorityClass; 3] = [Self::Control, Self::Realtime, Self::Bulk];
+
+    fn index(self) -> usize {
+        match self {
+            Self::Control => 0,
+            Self::Realtime => 1,
+            Self::Bulk => 2,
+        }
+    }
+
+    // The QUIC stream priority used for messages in this class.
+    pub(crate) fn stream_priority(self) -> i32 {
+        match self {
+            Self::Control => 2,
+            Self::Realtime => 1,
+            Self::Bulk => 0,
+        }
+    }
+}
+
+/// The number of messages waiting to be dispatched, by [`PriorityClass`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueDepth {
+    /// Messages queued in [`PriorityClass::Control`].
+    pub control: usize,
+
+    /// Messages queued in [`PriorityClass::Realtime`].
+    pub realtime: usize,
+
+    /// Messages queued in [`PriorityClass::Bulk`].
+    pub bulk: usize,
+}
+
+struct Job {
+    connection: Connection,
+    msg: Bytes,
+    class: PriorityClass,
+    result_tx: oneshot::Sender<Result<(), PeerError<SendError>>>,
+}
+
+#[derive(Default)]
+struct Flow {
+    jobs: VecDeque<Job>,
+    deficit: usize,
+}
+
+// A deficit round-robin queue across connections.
+#[derive(Default)]
+struct ClassQueue {
+    order: VecDeque<usize>,
+    flows: HashMap<usize, Flow>,
+    len: usize,
+    // how many messages of higher classes have been dispatched since this class's last turn
+    passed_over: usize,
+}
+
+impl ClassQueue {
+    fn push(&mut self, job: Job) {
+        let id = job.connection.id();
+        let flow = self.flows.entry(id).or_insert_with(|| {
+            self.order.push_back(id);
+            Flow::default()
+        });
+        flow.jobs.push_back(job);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Job> {
+        loop {
+            let id = *self.order.front()?;
+            let flow = self.flows.get_mut(&id)?;
+            let next_len = flow.jobs.front()?.msg.len();
+
+            if next_len <= flow.deficit {
+                flow.deficit -= next_len;
+                let job = flow.jobs.pop_front();
+                if flow.jobs.is_empty() {
+                    // idle flows don't accumulate credit
+                    let _ = self.flows.remove(&id);
+                    let _ = self.order.pop_front();
+                }
+                self.len -= 1;
+                return job;
+            }
+
+            flow.deficit += QUANTUM;
+            self.order.rotate_left(1);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queues {
+    classes: [ClassQueue; 3],
+    stopped: bool,
+}
+
+/// Queues of outgoing messages shared by all connections of an endpoint.
+#[derive(Default)]
+pub(crate) struct Scheduler {
+    queues: Mutex<Queues>,
+    notify: Notify,
+}
+
+impl std::fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Scheduler")
+            .field("depth", &self.depth())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Scheduler {
+    /// Create a scheduler and start dispatching its messages in a background task.
+    ///
+    /// The task will stop when a value is sent on `termination_rx`.
+    pub(crate) fn start(mut termination_rx: broadcast::Receiver<()>) -> Arc<Self> {
+        let scheduler = Arc::new(Self::default());
+        let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT_SENDS));
+
+        let dispatch = {
+            let scheduler = scheduler.clone();
+            async move {
+                loop {
+                    let permit = match in_flight.clone().acquire_owned().await {
+                        Ok(permit) => permit,
+                        Err(_) => break,
+                    };
+                    let job = scheduler.next().await;
+
+                    let _ = tokio::spawn(async move {
+                        let result = job
+                            .connection
+                            .send_with(job.msg, job.class.stream_priority(), None)
+                            .await;
+                        let _ = job.result_tx.send(result);
+                        drop(permit);
+                    });
+                }
+            }
+        };
+
+        let _ = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move {
+                let _ = future::select(Box::pin(dispatch), Box::pin(termination_rx.recv())).await;
+
+                // drop anything left in the queues, which will fail the pending sends
+                let mut queues = scheduler.lock();
+                queues.stopped = true;
+                queues.classes = Default::default();
+                trace!("Stopped outgoing message scheduler");
+            }
+        });
+
+        scheduler
+    }
+
+    /// Queue `msg` to be sent over `connection`, returning the result of the send.
+    pub(crate) async fn send(
+        &self,
+        connection: Connection,
+        msg: Bytes,
+        class: PriorityClass,
+    ) -> Result<(), PeerError<SendError>> {
+        let context = connection.error_context();
+        let (result_tx, result_rx) = oneshot::channel();
+        {
+            let mut queues = self.lock();
+            if !queues.stopped {
+                queues.classes[class.index()].push(Job {
+                    connection,
+                    msg,
+                    class,
+                    result_tx,
+                });
+            }
+        }
+        self.notify.notify_one();
+
+        // the job is only dropped without a result if the scheduler has been stopped
+        result_rx.await.unwrap_or_else(|_| {
+            Err(context.wrap(SendError::ConnectionLost(ConnectionError::Stopped)))
+        })
+    }
+
+    pub(crate) fn depth(&self) -> QueueDepth {
+        let queues = self.lock();
+        QueueDepth {
+            control: queues.classes[PriorityClass::Control.index()].len,
+            realtime: queues.classes[PriorityClass::Realtime.index()].len,
+            bulk: queues.classes[PriorityClass::Bulk.index()].len,
+        }
+    }
+
+    async fn next(&self) -> Job {
+        loop {
+            if let Some(job) = self.pop() {
+                return job;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    fn pop(&self) -> Option<Job> {
+        let mut queues = self.lock();
+        let waiting = |queue: &ClassQueue| queue.len > 0;
+        let class = PriorityClass::ALL
+            .iter()
+            .rev()
+            .find(|class| {
+                let queue = &queues.classes[class.index()];
+                waiting(queue) && queue.passed_over >= MAX_PASSED_OVER
+            })
+            .or_else(|| {
+                PriorityClass::ALL
+                    .iter()
+                    .find(|class| waiting(&queues.classes[class.index()]))
+            })?
+            .index();
+
+        let job = queues.classes[class].pop()?;
+        queues.classes[class].passed_over = 0;
+        for queue in queues.classes[class + 1..]
+            .iter_mut()
+            .filter(|queue| waiting(queue))
+        {
+            queue.passed_over += 1;
+        }
+        Some(job)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queues> {
+        self.queues
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Job, PriorityClass, Scheduler, MAX_PASSED_OVER, QUANTUM};
+    use crate::{tests::new_endpoint, Connection};
+    use bytes::Bytes;
+    use color_eyre::eyre::{eyre, Result};
+    use tokio::sync::oneshot;
+
+    fn queue(scheduler: &Scheduler, connection: &Connection, class: PriorityClass, len: usize) {
+        let (result_tx, _) = oneshot::channel();
+        scheduler.lock().classes[class.index()].push(Job {
+            connection: connection.clone(),
+            msg: Bytes::from(vec![0; len]),
+            class,
+            result_tx,
+        });
+    }
+
+    fn dispatched(scheduler: &Scheduler) -> Vec<(usize, PriorityClass)> {
+        std::iter::from_fn(|| scheduler.pop())
+            .map(|job| (job.connection.id(), job.class))
+            .collect()
+    }
+
+    async fn connections() -> Result<(Connection, Connection)> {
+        let (peer, _, _) = new_endpoint().await?;
+        let mut connections = Vec::new();
+        for _ in 0..2 {
+            let (dialer, _, _) = new_endpoint().await?;
+            let (connection, _) = dialer.connect_to(&peer.public_addr()).await?;
+            connections.push(connection);
+        }
+        let second = connections.pop().ok_or_else(|| eyre!("no connection"))?;
+        let first = connections.pop().ok_or_else(|| eyre!("no connection"))?;
+        Ok((first, second))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn higher_classes_first() -> Result<()> {
+        let (connection, _) = connections().await?;
+        let scheduler = Scheduler::default();
+        for class in [
+            PriorityClass::Bulk,
+            PriorityClass::Realtime,
+            PriorityClass::Control,
+        ] {
+            queue(&scheduler, &connection, class, 1024);
+        }
+
+        let id = connection.id();
+        assert_eq!(
+            dispatched(&scheduler),
+            [
+                (id, PriorityClass::Control),
+                (id, PriorityClass::Realtime),
+                (id, PriorityClass::Bulk)
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lower_classes_not_starved() -> Result<()> {
+        let (connection, _) = connections().await?;
+        let scheduler = Scheduler::default();
+        queue(&scheduler, &connection, PriorityClass::Bulk, 1024);
+        for _ in 0..2 * MAX_PASSED_OVER {
+            queue(&scheduler, &connection, PriorityClass::Control, 1024);
+        }
+
+        let classes: Vec<_> = dispatched(&scheduler)
+            .into_iter()
+            .map(|(_, class)| class)
+            .collect();
+        let bulk = classes
+            .iter()
+            .position(|class| *class == PriorityClass::Bulk)
+            .ok_or_else(|| eyre!("bulk message was not dispatched"))?;
+        assert_eq!(bulk, MAX_PASSED_OVER);
+        assert_eq!(classes.len(), 2 * MAX_PASSED_OVER + 1);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn connections_take_turns() -> Result<()> {
+        let (busy, quiet) = connections().await?;
+        let scheduler = Scheduler::default();
+        for _ in 0..8 {
+            queue(&scheduler, &busy, PriorityClass::Bulk, QUANTUM);
+        }
+        for _ in 0..2 {
+            queue(&scheduler, &quiet, PriorityClass::Bulk, QUANTUM);
+        }
+
+        // the quiet connection's messages are interleaved with the busy one's, rather than
+        // waiting behind all of them
+        let ids: Vec<_> = dispatched(&scheduler)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(&ids[..4], [busy.id(), quiet.id(), busy.id(), quiet.id()]);
+        assert!(ids[4..].iter().all(|id| *id == busy.id()));
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Hooks for tracking the reputation of peers.
+
+use crate::address_book::PeerId;
+use std::{fmt, net::SocketAddr, sync::Arc};
+
+/// An event observed by an [`Endpoint`](crate::Endpoint) that may affect a peer's reputation.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum PeerEvent {
+    /// A user message was received from the peer.
+    MessageReceived {
+        /// The length of the message, in bytes.
+        len: usize,
+    },
+
+    /// Sending a message to the peer failed.
+    SendFailed,
+
+    /// The peer sent something that doesn't conform to the wire protocol.
+    ProtocolViolation,
+
+    /// The peer did not respond within the expected time, or a stream with the peer stalled (see
+    /// [`Config::slow_stream_threshold`](crate::Config::slow_stream_threshold)).
+    SlowStream,
+}
+
+/// A peer whose reputation is being tracked, as passed to [`PeerScoring`].
+///
+/// Scores should be keyed by [`id`](Self::id) when it's known, so that a peer keeps its reputation
+/// when it reconnects from another address, and peers sharing an address (e.g. behind a NAT) aren't
+/// confused. The [`addr`](Self::addr) is the fallback for peers without a known identity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ScoredPeer {
+    /// The peer's address.
+    pub addr: SocketAddr,
+
+    /// The peer's identity, if it's known (see [`Connection::peer_id`](crate::Connection::peer_id)).
+    pub id: Option<PeerId>,
+}
+
+impl From<SocketAddr> for ScoredPeer {
+    fn from(addr: SocketAddr) -> Self {
+        Self { addr, id: None }
+    }
+}
+
+/// A reputation tracker invoked by the endpoint as events happen on its connections.
+///
+/// The endpoint never modifies scores itself – it only reports [`PeerEvent`]s via
+/// [`on_event`](Self::on_event) and consults [`is_acceptable`](Self::is_acceptable) before handing
+/// out new incoming connections. Scores may be queried via
+/// [`Endpoint::peer_score`](crate::Endpoint::peer_score).
+///
+/// Scores also decide which connections are closed first when the endpoint has more than
+/// [`Config::max_connections`](crate::Config::max_connections): among connections of the same
+/// [class](crate::ConnectionClass), the one with the lowest score goes first. `score` is called
+/// while the endpoint's connections are locked, so it mustn't call back into the endpoint.
+pub trait PeerScoring: fmt::Debug + Send + Sync {
+    /// Record an event for `peer`.
+    fn on_event(&self, peer: &ScoredPeer, event: &PeerEvent);
+
+    /// The current score for `peer`.
+    fn score(&self, peer: &ScoredPeer) -> i64;
+
+    /// Whether a new connection with `peer` should be accepted.
+    ///
+    /// This is asked before the connection's handshake, when only the peer's address is known, and
+    /// asked again once the handshake has identified the peer (see
+    /// [`Connection::peer_id`](crate::Connection::peer_id)), so a peer can't get around a low score
+    /// by connecting from a new address. The default implementation accepts every peer.
+    fn is_acceptable(&self, peer: &ScoredPeer) -> bool {
+        let _ = peer;
+        true
+    }
+}
+
+// Convenience helper for call-sites where scoring is optional.
+pub(crate) fn report(
+    scoring: &Option<Arc<dyn PeerScoring>>,
+    addr: SocketAddr,
+    id: Option<PeerId>,
+    event: PeerEvent,
+) {
+    if let Some(scoring) = scoring {
+        scoring.on_event(&ScoredPeer { addr, id }, &event);
+    }
+}
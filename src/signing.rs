@@ -0,0 +1,150 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! End-to-end signatures on user messages.
+//!
+//! When [`Config::signing_key`](crate::Config::signing_key) is set, each user message is sent with
+//! the sender's Ed25519 public key and a signature over the message and key appended:
+//!
+//! ```text
+//! | message | public key (32 bytes) | signature (64 bytes) |
+//! ```
+//!
+//! Unlike TLS, which only authenticates the hop between two endpoints, the signature stays valid
+//! wherever the message is forwarded.
+
+use crate::{address_book::PeerId, error::SigningKeyError};
+use bytes::{Bytes, BytesMut};
+use ring::{
+    rand::SystemRandom,
+    signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey},
+};
+use std::fmt;
+
+const PUBLIC_KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+/// An Ed25519 key pair used to sign messages.
+///
+/// The public key doubles as the signer's [`PeerId`], so peers that sign their messages are
+/// identified by their key.
+pub struct SigningKey {
+    key_pair: Ed25519KeyPair,
+    pkcs8: Vec<u8>,
+}
+
+impl SigningKey {
+    /// Generate a new random key.
+    pub fn generate() -> Result<Self, SigningKeyError> {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|error| SigningKeyError(error.to_string()))?;
+        Self::from_pkcs8(pkcs8.as_ref())
+    }
+
+    /// Load a key from its PKCS#8 encoding, as returned by [`to_pkcs8`](Self::to_pkcs8).
+    pub fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, SigningKeyError> {
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8)
+            .map_err(|error| SigningKeyError(error.to_string()))?;
+        Ok(Self {
+            key_pair,
+            pkcs8: pkcs8.to_vec(),
+        })
+    }
+
+    /// The PKCS#8 encoding of the key, so it can be stored and reused.
+    pub fn to_pkcs8(&self) -> &[u8] {
+        &self.pkcs8
+    }
+
+    /// The public key, as the identity of peers receiving messages signed with this key.
+    pub fn peer_id(&self) -> PeerId {
+        let mut id = [0; PUBLIC_KEY_LEN];
+        id.copy_from_slice(self.key_pair.public_key().as_ref());
+        PeerId(id)
+    }
+
+    // Append the public key and a signature to `msg`.
+    pub(crate) fn sign(&self, msg: &[u8]) -> Bytes {
+        let mut signed = BytesMut::with_capacity(msg.len() + PUBLIC_KEY_LEN + SIGNATURE_LEN);
+        signed.extend_from_slice(msg);
+        signed.extend_from_slice(self.key_pair.public_key().as_ref());
+        let signature = self.key_pair.sign(&signed);
+        signed.extend_from_slice(signature.as_ref());
+        signed.freeze()
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("peer_id", &self.peer_id())
+            .finish_non_exhaustive()
+    }
+}
+
+// Verify the signature on `signed`, returning the message and its signer.
+pub(crate) fn verify(signed: &Bytes) -> Option<(Bytes, PeerId)> {
+    let msg_len = signed.len().checked_sub(PUBLIC_KEY_LEN + SIGNATURE_LEN)?;
+    let (signed_part, signature) = signed.split_at(msg_len + PUBLIC_KEY_LEN);
+    let public_key = &signed_part[msg_len..];
+
+    UnparsedPublicKey::new(&signature::ED25519, public_key)
+        .verify(signed_part, signature)
+        .ok()?;
+
+    let mut signer = [0; PUBLIC_KEY_LEN];
+    signer.copy_from_slice(public_key);
+    Some((signed.slice(..msg_len), PeerId(signer)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify, SigningKey};
+    use bytes::Bytes;
+    use color_eyre::eyre::Result;
+
+    #[test]
+    fn sign_and_verify() -> Result<()> {
+        let key = SigningKey::generate()?;
+        let signed = key.sign(b"hello");
+        assert_eq!(
+            verify(&signed),
+            Some((Bytes::from_static(b"hello"), key.peer_id()))
+        );
+
+        // the key survives a round trip through its encoding
+        let key = SigningKey::from_pkcs8(key.to_pkcs8())?;
+        assert_eq!(
+            verify(&key.sign(b"")).map(|(_, signer)| signer),
+            Some(key.peer_id())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn tampering() -> Result<()> {
+        let signed = SigningKey::generate()?.sign(b"hello");
+
+        let mut tampered = signed.to_vec();
+        tampered[0] ^= 1;
+        assert_eq!(verify(&tampered.into()), None);
+
+        // substituting another key invalidates the signature
+        let other = SigningKey::generate()?.peer_id();
+        let mut substituted = signed.to_vec();
+        substituted[5..37].copy_from_slice(&other.0);
+        assert_eq!(verify(&substituted.into()), None);
+
+        assert_eq!(verify(&signed.slice(1..)), None);
+        assert_eq!(verify(&Bytes::from_static(b"hello")), None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Delivery of received messages straight to the application's own queues.
+
+use crate::{address_book::PeerId, connection::SendStream, interceptor::MessageContext};
+use bytes::Bytes;
+use std::{fmt, sync::Arc};
+use tokio::sync::Mutex;
+
+/// A destination for the messages received on an endpoint's connections.
+///
+/// By default, each connection's messages are queued for its
+/// [`ConnectionIncoming`](crate::ConnectionIncoming). With
+/// [`Config::message_sink`](crate::Config::message_sink), they're handed to the sink instead, as
+/// soon as they've been read and passed through the [interceptors](crate::Interceptor), so that
+/// applications can put them straight onto their own queues (e.g. a crossbeam channel or an actor's
+/// mailbox) without a hop through qp2p's channel.
+///
+/// Messages from a peer with a receiver from
+/// [`Endpoint::messages_from`](crate::Endpoint::messages_from) are still delivered there, and
+/// errors receiving messages are still reported by the connection's `ConnectionIncoming`.
+///
+/// The sink is called from the connections' background listeners, so it must not block. Messages
+/// read from each stream are delivered in order.
+pub trait MessageSink: fmt::Debug + Send + Sync {
+    /// Deliver a message received on the connection described by `context`.
+    ///
+    /// `stream` is the stream to respond on, if the message was sent on a bidirectional stream,
+    /// and `signer` is the key the message was signed with, as returned by
+    /// [`ConnectionIncoming::next_with_signer`](crate::ConnectionIncoming::next_with_signer).
+    ///
+    /// Returning `false` means the sink is closed, which stops the connection's listener as if its
+    /// `ConnectionIncoming` had been dropped.
+    fn deliver(
+        &self,
+        context: &MessageContext,
+        msg: Bytes,
+        stream: Option<Arc<Mutex<SendStream>>>,
+        signer: Option<PeerId>,
+    ) -> bool;
+}
@@ -0,0 +1,200 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Creation of UDP sockets with the configured options.
+
+use crate::config::{PortRange, SocketConfig};
+use quinn::{EndpointConfig, Incoming, ServerConfig};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+};
+use tracing::warn;
+
+// Create a quinn endpoint that accepts incoming connections, like `quinn::Endpoint::server` but
+// with the given endpoint config and a socket configured by `socket_config`.
+pub(crate) fn server(
+    addr: SocketAddr,
+    endpoint_config: EndpointConfig,
+    server_config: ServerConfig,
+    socket_config: &SocketConfig,
+) -> io::Result<(quinn::Endpoint, Incoming)> {
+    quinn::Endpoint::new(
+        endpoint_config,
+        Some(server_config),
+        bind(addr, socket_config)?,
+    )
+}
+
+// Create a quinn endpoint that can only make outgoing connections, like `quinn::Endpoint::client`
+// but with the given endpoint config and a socket configured by `socket_config`.
+pub(crate) fn client(
+    addr: SocketAddr,
+    endpoint_config: EndpointConfig,
+    socket_config: &SocketConfig,
+) -> io::Result<quinn::Endpoint> {
+    let (endpoint, _) = quinn::Endpoint::new(endpoint_config, None, bind(addr, socket_config)?)?;
+    Ok(endpoint)
+}
+
+// Create a quinn endpoint like `client`, but bound to the first free port of `ports` if there's a
+// range and `addr` doesn't give a port.
+pub(crate) fn client_in_range(
+    addr: SocketAddr,
+    ports: Option<PortRange>,
+    endpoint_config: EndpointConfig,
+    socket_config: &SocketConfig,
+) -> io::Result<quinn::Endpoint> {
+    let ports = match ports {
+        Some(ports) if addr.port() == 0 => ports,
+        _ => return client(addr, endpoint_config, socket_config),
+    };
+    let socket = bind_in_range(addr, ports, socket_config)?;
+    let (endpoint, _) = quinn::Endpoint::new(endpoint_config, None, socket)?;
+    Ok(endpoint)
+}
+
+// Bind a UDP socket to the first free port of `ports` at `addr`'s IP.
+fn bind_in_range(
+    addr: SocketAddr,
+    ports: PortRange,
+    config: &SocketConfig,
+) -> io::Result<UdpSocket> {
+    for port in ports.ports() {
+        match bind(SocketAddr::new(addr.ip(), port), config) {
+            Err(error) if error.kind() == io::ErrorKind::AddrInUse => continue,
+            result => return result,
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("No free port in {} at {}", ports, addr.ip()),
+    ))
+}
+
+// Bind a UDP socket to `addr`, with the options in `config`.
+pub(crate) fn bind(addr: SocketAddr, config: &SocketConfig) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if let Some(size) = config.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = config.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+
+    if let Some(dscp) = config.dscp {
+        if addr.is_ipv4() {
+            // DSCP is the upper 6 bits of the TOS byte, the rest are for ECN
+            socket.set_tos(u32::from(dscp) << 2)?;
+        } else {
+            warn!("Not setting DSCP on IPv6 socket {}: unsupported", addr);
+        }
+    }
+
+    if config.reuse_port {
+        set_reuse_port(&socket)?;
+    }
+
+    socket.bind(&addr.into())?;
+
+    Ok(socket.into())
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is only supported on Unix platforms",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind, bind_in_range};
+    use crate::{
+        config::{PortRange, SocketConfig},
+        tests::local_addr,
+    };
+    use socket2::Socket;
+
+    #[test]
+    fn buffer_sizes() -> Result<(), std::io::Error> {
+        let size = 16 * 1024;
+        let config = SocketConfig {
+            send_buffer_size: Some(size),
+            recv_buffer_size: Some(size),
+            ..Default::default()
+        };
+        let socket = Socket::from(bind(local_addr(), &config)?);
+
+        // some platforms (e.g. Linux) double the requested size to allow for bookkeeping
+        assert!((size..=2 * size).contains(&socket.send_buffer_size()?));
+        assert!((size..=2 * size).contains(&socket.recv_buffer_size()?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn dscp() -> Result<(), std::io::Error> {
+        let config = SocketConfig {
+            dscp: Some(46), // expedited forwarding
+            ..Default::default()
+        };
+        let socket = Socket::from(bind(local_addr(), &config)?);
+        assert_eq!(socket.tos()?, 46 << 2);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reuse_port() -> Result<(), std::io::Error> {
+        let config = SocketConfig {
+            reuse_port: true,
+            ..Default::default()
+        };
+        let first = bind(local_addr(), &config)?;
+        let second = bind(first.local_addr()?, &config)?;
+        assert_eq!(first.local_addr()?, second.local_addr()?);
+
+        // without the option, the port can't be shared
+        assert!(bind(first.local_addr()?, &SocketConfig::default()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn port_range() -> Result<(), std::io::Error> {
+        let config = SocketConfig::default();
+        let taken = bind(local_addr(), &config)?;
+        let port = taken.local_addr()?.port();
+        let ports = PortRange {
+            start: port,
+            end: port.saturating_add(1),
+        };
+
+        // the taken port is skipped
+        let socket = bind_in_range(local_addr(), ports, &config)?;
+        assert_eq!(socket.local_addr()?.port(), ports.end);
+
+        // and the range is exhausted once every port is taken
+        let exhausted = bind_in_range(local_addr(), ports, &config)
+            .expect_err("bound a port in an exhausted range");
+        assert_eq!(exhausted.kind(), std::io::ErrorKind::AddrInUse);
+
+        Ok(())
+    }
+}
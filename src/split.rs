@@ -0,0 +1,197 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Splitting large messages across several streams, and putting them back together.
+
+use crate::error::{RecvError, SerializationError};
+use bytes::{Bytes, BytesMut};
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
+
+// The most split messages a peer can have part way through at once. The oldest is dropped to make
+// room for another, so a peer can't make us hold on to unfinished messages without limit.
+const MAX_PARTIAL_MESSAGES: usize = 64;
+
+// How long after its first chunk arrived an unfinished message is dropped. A chunk whose send
+// failed never arrives, so the rest of its message would otherwise be kept forever.
+const PARTIAL_MESSAGE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The most chunks a message can be split into.
+pub(crate) const MAX_CHUNKS: usize = 1024;
+
+// Split `msg` into at most `streams` chunks of (nearly) equal size, returning each chunk with its
+// index and the number of chunks.
+pub(crate) fn chunks(msg: &Bytes, streams: usize) -> impl Iterator<Item = (u32, u32, Bytes)> + '_ {
+    let streams = streams.clamp(1, MAX_CHUNKS);
+    let chunk_len = msg.len().div_ceil(streams).max(1);
+    let count = msg.len().div_ceil(chunk_len);
+    (0..count).map(move |index| {
+        let start = index * chunk_len;
+        let end = (start + chunk_len).min(msg.len());
+        // `count` is at most `MAX_CHUNKS`, so these can't truncate
+        (index as u32, count as u32, msg.slice(start..end))
+    })
+}
+
+// A message whose chunks are still arriving.
+struct Partial {
+    chunks: Vec<Option<Bytes>>,
+    received: usize,
+    started: Instant,
+}
+
+// The split messages being received on a connection.
+#[derive(Default)]
+pub(crate) struct Reassembly {
+    partials: HashMap<u64, Partial>,
+}
+
+impl Reassembly {
+    // Add a chunk of the message with the given id, returning the whole message (and when its
+    // first chunk arrived) once all of its chunks have arrived.
+    pub(crate) fn add(
+        &mut self,
+        id: u64,
+        index: u32,
+        count: u32,
+        data: Bytes,
+    ) -> Result<Option<(Bytes, Instant)>, RecvError> {
+        let (index, count) = (index as usize, count as usize);
+        if count == 0 || count > MAX_CHUNKS || index >= count {
+            return Err(SerializationError::new(format!(
+                "Invalid chunk {} of {} for split message {}",
+                index, count, id
+            ))
+            .into());
+        }
+
+        if !self.partials.contains_key(&id) {
+            self.evict();
+        }
+        let partial = self.partials.entry(id).or_insert_with(|| Partial {
+            chunks: vec![None; count],
+            received: 0,
+            started: Instant::now(),
+        });
+        if partial.chunks.len() != count || partial.chunks[index].is_some() {
+            let _ = self.partials.remove(&id);
+            return Err(SerializationError::new(format!(
+                "Inconsistent chunk {} of {} for split message {}",
+                index, count, id
+            ))
+            .into());
+        }
+        partial.chunks[index] = Some(data);
+        partial.received += 1;
+        if partial.received < count {
+            return Ok(None);
+        }
+
+        let partial = match self.partials.remove(&id) {
+            Some(partial) => partial,
+            None => return Ok(None),
+        };
+        let len = partial.chunks.iter().flatten().map(Bytes::len).sum();
+        let mut msg = BytesMut::with_capacity(len);
+        for chunk in partial.chunks.iter().flatten() {
+            msg.extend_from_slice(chunk);
+        }
+        Ok(Some((msg.freeze(), partial.started)))
+    }
+
+    // Drop unfinished messages that have timed out, and the oldest if there's still no room for
+    // another.
+    fn evict(&mut self) {
+        self.partials
+            .retain(|_, partial| partial.started.elapsed() < PARTIAL_MESSAGE_TIMEOUT);
+        if self.partials.len() < MAX_PARTIAL_MESSAGES {
+            return;
+        }
+        let oldest = self
+            .partials
+            .iter()
+            .min_by_key(|(_, partial)| partial.started)
+            .map(|(id, _)| *id);
+        if let Some(oldest) = oldest {
+            let _ = self.partials.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunks, Reassembly, MAX_PARTIAL_MESSAGES, PARTIAL_MESSAGE_TIMEOUT};
+    use bytes::Bytes;
+    use std::time::Duration;
+
+    #[test]
+    fn split_and_reassemble() {
+        let msg = Bytes::from((0..=255).cycle().take(1001).collect::<Vec<u8>>());
+        let mut parts: Vec<_> = chunks(&msg, 4).collect();
+        assert_eq!(parts.len(), 4);
+        assert!(parts.iter().all(|(_, count, _)| *count == 4));
+
+        // chunks can arrive in any order
+        parts.reverse();
+        let mut reassembly = Reassembly::default();
+        let last = parts.pop().expect("no chunks");
+        for (index, count, data) in parts {
+            assert!(matches!(reassembly.add(7, index, count, data), Ok(None)));
+        }
+        let (index, count, data) = last;
+        match reassembly.add(7, index, count, data) {
+            Ok(Some((reassembled, _))) => assert_eq!(reassembled, msg),
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+
+        // short messages aren't split into empty chunks
+        assert_eq!(chunks(&Bytes::from_static(b"ab"), 4).count(), 2);
+    }
+
+    #[test]
+    fn invalid_chunks_are_rejected() {
+        let mut reassembly = Reassembly::default();
+        assert!(reassembly.add(1, 2, 2, Bytes::new()).is_err());
+        assert!(reassembly.add(1, 0, 0, Bytes::new()).is_err());
+
+        assert!(matches!(reassembly.add(1, 0, 2, Bytes::new()), Ok(None)));
+        assert!(reassembly.add(1, 0, 2, Bytes::new()).is_err());
+        assert!(matches!(reassembly.add(2, 0, 2, Bytes::new()), Ok(None)));
+        assert!(reassembly.add(2, 1, 3, Bytes::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn unfinished_messages_are_dropped() {
+        tokio::time::pause();
+        let msg = Bytes::from_static(b"split message");
+        let mut reassembly = Reassembly::default();
+
+        // every message is missing its last chunk, as if sending it failed
+        for id in 0..MAX_PARTIAL_MESSAGES as u64 {
+            assert!(matches!(reassembly.add(id, 0, 2, msg.clone()), Ok(None)));
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+        assert_eq!(reassembly.partials.len(), MAX_PARTIAL_MESSAGES);
+
+        // there's still room for another message, at the expense of the oldest
+        let id = MAX_PARTIAL_MESSAGES as u64;
+        for (index, count, data) in chunks(&msg, 2) {
+            if let Some((reassembled, _)) = reassembly.add(id, index, count, data).unwrap() {
+                assert_eq!(reassembled, msg);
+            }
+        }
+        assert_eq!(reassembly.partials.len(), MAX_PARTIAL_MESSAGES - 1);
+        assert!(!reassembly.partials.contains_key(&0));
+
+        // and the rest are dropped once they time out
+        tokio::time::advance(PARTIAL_MESSAGE_TIMEOUT).await;
+        assert!(matches!(reassembly.add(id + 1, 0, 2, msg), Ok(None)));
+        assert_eq!(reassembly.partials.len(), 1);
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Distributions of the messages exchanged on a connection.
+
+use std::{sync::Mutex, time::Duration};
+
+// One bucket for zero, and one for each bit length of a `u64`.
+const BUCKETS: usize = 65;
+
+/// A distribution of values, counted in power-of-two buckets.
+///
+/// Bucket `i` counts values whose bit length is `i`, i.e. values from `2^(i-1)` up to `2^i - 1`,
+/// with bucket 0 counting zeros. This keeps the histogram small while still showing the shape of
+/// the distribution, e.g. whether a peer sends mostly small messages with the odd huge one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    // empty until the first value is recorded
+    buckets: Vec<u64>,
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    /// The number of values recorded.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The sum of the values recorded, saturating at `u64::MAX`.
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+
+    /// The smallest value recorded, if any.
+    pub fn min(&self) -> Option<u64> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    /// The largest value recorded, if any.
+    pub fn max(&self) -> Option<u64> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    /// The mean of the values recorded, if any.
+    pub fn mean(&self) -> Option<u64> {
+        (self.count > 0).then(|| self.sum / self.count)
+    }
+
+    /// The non-empty buckets, in increasing order, as the largest value each bucket can hold and
+    /// the number of values in it.
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(bucket, count)| (bucket_limit(bucket), *count))
+    }
+
+    /// An upper bound for the given quantile (between 0 and 1) of the values recorded, if any.
+    ///
+    /// This is the limit of the bucket holding the quantile, capped at [`max`](Self::max), so
+    /// e.g. `quantile(0.99)` is at most twice the true 99th percentile.
+    pub fn quantile(&self, quantile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (limit, count) in self.buckets() {
+            seen += count;
+            if seen >= rank {
+                return Some(limit.min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    pub(crate) fn record(&mut self, value: u64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; BUCKETS];
+        }
+        self.buckets[(u64::BITS - value.leading_zeros()) as usize] += 1;
+        if self.count == 0 || value < self.min {
+            self.min = value;
+        }
+        self.max = self.max.max(value);
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+    }
+}
+
+// The largest value counted by `bucket`.
+fn bucket_limit(bucket: usize) -> u64 {
+    match bucket {
+        0 => 0,
+        _ => u64::MAX >> (u64::BITS as usize - bucket),
+    }
+}
+
+/// Distributions of the messages exchanged on a connection, as returned by
+/// [`Connection::message_stats`](crate::Connection::message_stats).
+///
+/// Only user messages are counted, and sizes exclude qp2p's framing. Durations are how long each
+/// message took to write to its stream, or to arrive once its first bytes had been received, so
+/// time spent waiting for a peer to start sending isn't counted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MessageStats {
+    /// The sizes of messages sent, in bytes.
+    pub sent_sizes: Histogram,
+
+    /// The sizes of messages received, in bytes.
+    pub received_sizes: Histogram,
+
+    /// How long messages took to write, in microseconds.
+    pub write_durations: Histogram,
+
+    /// How long messages took to read, in microseconds.
+    pub read_durations: Histogram,
+
+    /// The number of reads and writes that took longer than
+    /// [`Config::slow_stream_threshold`](crate::Config::slow_stream_threshold).
+    pub slow_streams: u64,
+}
+
+// The `MessageStats` of a connection, shared by its handles and listeners.
+#[derive(Debug, Default)]
+pub(crate) struct MessageStatsRecorder(Mutex<MessageStats>);
+
+impl MessageStatsRecorder {
+    pub(crate) fn record_sent(&self, len: usize, duration: Duration) {
+        let mut stats = self.lock();
+        stats.sent_sizes.record(len as u64);
+        stats.write_durations.record(micros(duration));
+    }
+
+    pub(crate) fn record_received(&self, len: usize, duration: Duration) {
+        let mut stats = self.lock();
+        stats.received_sizes.record(len as u64);
+        stats.read_durations.record(micros(duration));
+    }
+
+    pub(crate) fn record_slow_stream(&self) {
+        self.lock().slow_streams += 1;
+    }
+
+    pub(crate) fn snapshot(&self) -> MessageStats {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MessageStats> {
+        self.0.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+// A duration in whole microseconds.
+fn micros(duration: Duration) -> u64 {
+    u64::try_from(duration.as_micros()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Histogram;
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.min(), None);
+        assert_eq!(histogram.quantile(0.5), None);
+        assert_eq!(histogram.buckets().count(), 0);
+
+        for value in [0, 1, 2, 3, 4, 1000, u64::MAX] {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.count(), 7);
+        assert_eq!(histogram.sum(), u64::MAX);
+        assert_eq!(histogram.min(), Some(0));
+        assert_eq!(histogram.max(), Some(u64::MAX));
+        assert_eq!(
+            histogram.buckets().collect::<Vec<_>>(),
+            [(0, 1), (1, 1), (3, 2), (7, 1), (1023, 1), (u64::MAX, 1)]
+        );
+    }
+
+    #[test]
+    fn histogram_quantiles() {
+        let mut histogram = Histogram::default();
+        for value in 1..=100 {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.mean(), Some(50));
+        assert_eq!(histogram.quantile(0.0), Some(1));
+        assert_eq!(histogram.quantile(0.5), Some(63));
+        assert_eq!(histogram.quantile(0.99), Some(100));
+        assert_eq!(histogram.quantile(1.0), Some(100));
+    }
+}
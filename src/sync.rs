@@ -0,0 +1,236 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! A blocking API, for applications that aren't async.
+//!
+//! [`SyncEndpoint`] and [`SyncConnection`] wrap an [`Endpoint`] and its [`Connection`]s, running
+//! them on a runtime of their own, so that e.g. a command line tool can send a message without
+//! setting up tokio:
+//!
+//! ```no_run
+//! use bytes::Bytes;
+//! use qp2p::{sync::SyncEndpoint, Config};
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let endpoint = SyncEndpoint::new_client(([0, 0, 0, 0], 0), Config::default())?;
+//! let connection = endpoint.connect_to("127.0.0.1:5000")?;
+//! connection.send(Bytes::from_static(b"hello"))?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Anything not covered here can be done with the wrapped endpoint and connections, by passing
+//! their futures to [`SyncEndpoint::block_on`].
+//!
+//! The methods block the calling thread, so they must not be called from async code (tokio panics
+//! if a runtime is blocked on from within another). This module is only available with the `sync`
+//! feature.
+
+use crate::{
+    config::Config,
+    connection::{Connection, ConnectionIncoming},
+    endpoint::{Endpoint, IncomingConnections},
+    error::{ClientEndpointError, ConnectionError, EndpointError, PeerError, RecvError, SendError},
+    resolver::ToPeerAddrs,
+};
+use bytes::Bytes;
+use std::{future::Future, io, net::SocketAddr, sync::Arc};
+use tokio::runtime::Runtime;
+
+/// A blocking wrapper of an [`Endpoint`].
+#[derive(Debug)]
+pub struct SyncEndpoint {
+    runtime: Arc<Runtime>,
+    endpoint: Endpoint,
+    incoming_connections: Option<IncomingConnections>,
+}
+
+impl SyncEndpoint {
+    /// Create a peer endpoint at the given address, as with [`Endpoint::new_peer`].
+    ///
+    /// The connection to the contact bootstrapped against is returned, if any.
+    pub fn new_peer(
+        local_addr: impl Into<SocketAddr>,
+        contacts: &[SocketAddr],
+        config: Config,
+    ) -> Result<(Self, Option<SyncConnection>), EndpointError> {
+        let runtime = Arc::new(new_runtime()?);
+        let (endpoint, incoming_connections, contact) =
+            runtime.block_on(Endpoint::new_peer(local_addr, contacts, config))?;
+        let contact = contact.map(|(connection, incoming)| {
+            SyncConnection::new(runtime.clone(), connection, incoming)
+        });
+        let endpoint = Self {
+            runtime,
+            endpoint,
+            incoming_connections: Some(incoming_connections),
+        };
+        Ok((endpoint, contact))
+    }
+
+    /// Create a client endpoint at the given address, as with [`Endpoint::new_client`].
+    pub fn new_client(
+        local_addr: impl Into<SocketAddr>,
+        config: Config,
+    ) -> Result<Self, ClientEndpointError> {
+        let runtime = Arc::new(new_runtime()?);
+        // the endpoint starts background tasks, so it must be created within the runtime
+        let endpoint = {
+            let _guard = runtime.enter();
+            Endpoint::new_client(local_addr, config)?
+        };
+        Ok(Self {
+            runtime,
+            endpoint,
+            incoming_connections: None,
+        })
+    }
+
+    /// The wrapped endpoint.
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Run a future on the endpoint's runtime, blocking until it completes.
+    ///
+    /// This gives access to the rest of the async API, e.g.
+    /// `endpoint.block_on(endpoint.endpoint().is_reachable(&addr))`.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    /// The endpoint's local address. See [`Endpoint::local_addr`].
+    pub fn local_addr(&self) -> SocketAddr {
+        self.endpoint.local_addr()
+    }
+
+    /// The endpoint's public address. See [`Endpoint::public_addr`].
+    pub fn public_addr(&self) -> SocketAddr {
+        self.endpoint.public_addr()
+    }
+
+    /// Connect to a peer, as with [`Endpoint::connect_to`].
+    pub fn connect_to(&self, peer: impl ToPeerAddrs) -> Result<SyncConnection, ConnectionError> {
+        let (connection, incoming) = self.block_on(self.endpoint.connect_to(peer))?;
+        Ok(SyncConnection::new(
+            self.runtime.clone(),
+            connection,
+            incoming,
+        ))
+    }
+
+    /// Send a message to `addr`, connecting to it first if need be, as with
+    /// [`Endpoint::send_to`].
+    pub fn send_to(&self, addr: &SocketAddr, msg: Bytes) -> Result<(), SendError> {
+        self.block_on(self.endpoint.send_to(addr, msg))
+    }
+
+    /// Wait for the next incoming connection.
+    ///
+    /// Returns `None` once the endpoint is closed, or straight away for client endpoints, since
+    /// they don't accept connections.
+    pub fn accept(&mut self) -> Option<SyncConnection> {
+        let incoming_connections = self.incoming_connections.as_mut()?;
+        let (connection, incoming) = self.runtime.block_on(incoming_connections.next())?;
+        Some(SyncConnection::new(
+            self.runtime.clone(),
+            connection,
+            incoming,
+        ))
+    }
+
+    /// Close the endpoint and all its connections. See [`Endpoint::close`].
+    pub fn close(&self) {
+        self.endpoint.close()
+    }
+}
+
+/// A blocking wrapper of a [`Connection`] and its [`ConnectionIncoming`].
+///
+/// The connection can be used after its [`SyncEndpoint`] is dropped, since they share the runtime.
+#[derive(Debug)]
+pub struct SyncConnection {
+    runtime: Arc<Runtime>,
+    connection: Connection,
+    incoming: ConnectionIncoming,
+}
+
+impl SyncConnection {
+    fn new(runtime: Arc<Runtime>, connection: Connection, incoming: ConnectionIncoming) -> Self {
+        Self {
+            runtime,
+            connection,
+            incoming,
+        }
+    }
+
+    /// The wrapped connection.
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// The address of the connected peer.
+    pub fn remote_address(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+
+    /// Send a message to the peer. See [`Connection::send`].
+    pub fn send(&self, msg: Bytes) -> Result<(), PeerError<SendError>> {
+        self.runtime.block_on(self.connection.send(msg))
+    }
+
+    /// Wait for the next message from the peer.
+    ///
+    /// Returns `None` once the connection is closed. See [`ConnectionIncoming::next`].
+    pub fn recv(&mut self) -> Result<Option<Bytes>, PeerError<RecvError>> {
+        self.runtime.block_on(self.incoming.next())
+    }
+
+    /// Close the connection. See [`Connection::close`].
+    pub fn close(&self, reason: Option<String>) {
+        self.connection.close(reason)
+    }
+}
+
+// A runtime for the blocking API.
+fn new_runtime() -> io::Result<Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SyncEndpoint;
+    use crate::{tests::local_addr, Config};
+    use bytes::Bytes;
+    use color_eyre::eyre::{eyre, Result};
+
+    #[test]
+    fn send_and_receive() -> Result<()> {
+        let (mut server, _) = SyncEndpoint::new_peer(local_addr(), &[], Config::default())?;
+        let client = SyncEndpoint::new_client(local_addr(), Config::default())?;
+
+        let connection = client.connect_to(server.public_addr())?;
+        connection.send(Bytes::from_static(b"hello"))?;
+
+        let mut accepted = server
+            .accept()
+            .ok_or_else(|| eyre!("connection was not accepted"))?;
+        assert_eq!(accepted.remote_address(), client.public_addr());
+        assert_eq!(accepted.recv()?, Some(Bytes::from_static(b"hello")));
+
+        // the accepted connection outlives the endpoint
+        drop(server);
+        accepted.close(None);
+        assert_eq!(accepted.recv()?, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Utilities for testing applications built on qp2p.
+//!
+//! This module is only available with the `test-utils` feature, which is intended to be enabled
+//! from `[dev-dependencies]`.
+
+use crate::{
+    config::Config,
+    connection::{Connection, ConnectionIncoming},
+    endpoint::{Endpoint, IncomingConnections},
+    error::{ConnectionError, EndpointError},
+};
+use bytes::Bytes;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{sync::Notify, task::JoinHandle, time::error::Elapsed};
+
+/// One side of a pair of connected peers, as returned by [`connected_peer_pair`].
+#[derive(Debug)]
+pub struct TestPeer {
+    /// The peer's endpoint.
+    pub endpoint: Endpoint,
+
+    /// Further connections made to the peer.
+    ///
+    /// This must be kept alive for the endpoint to accept more connections.
+    pub incoming_connections: IncomingConnections,
+
+    /// The peer's side of the connection with the other peer.
+    pub connection: Connection,
+
+    /// Messages received from the other peer.
+    pub incoming_messages: ConnectionIncoming,
+}
+
+/// Errors that can occur when setting up a [`connected_peer_pair`].
+#[derive(Debug, Error)]
+pub enum PeerPairError {
+    /// Failed to create an endpoint.
+    #[error("Failed to create endpoint")]
+    Endpoint(#[from] EndpointError),
+
+    /// Failed to connect the peers.
+    #[error("Failed to connect peers")]
+    Connection(#[from] ConnectionError),
+
+    /// The connection was not accepted by the other peer.
+    #[error("Connection was not accepted by the other peer")]
+    NotAccepted,
+}
+
+/// Create two peers on the loopback interface, and connect them to each other.
+///
+/// The second peer connects to the first, so the first peer's [`TestPeer::connection`] is the
+/// incoming side.
+pub async fn connected_peer_pair(config: Config) -> Result<(TestPeer, TestPeer), PeerPairError> {
+    let local_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+    let (endpoint1, mut incoming_connections1, _) =
+        Endpoint::new_peer(local_addr, &[], config.clone()).await?;
+    let (endpoint2, incoming_connections2, _) = Endpoint::new_peer(local_addr, &[], config).await?;
+
+    let (connection2, incoming_messages2) = endpoint2.connect_to(&endpoint1.public_addr()).await?;
+    let (connection1, incoming_messages1) = incoming_connections1
+        .next()
+        .await
+        .ok_or(PeerPairError::NotAccepted)?;
+
+    Ok((
+        TestPeer {
+            endpoint: endpoint1,
+            incoming_connections: incoming_connections1,
+            connection: connection1,
+            incoming_messages: incoming_messages1,
+        },
+        TestPeer {
+            endpoint: endpoint2,
+            incoming_connections: incoming_connections2,
+            connection: connection2,
+            incoming_messages: incoming_messages2,
+        },
+    ))
+}
+
+/// Collects the messages received on a connection in the background.
+///
+/// Collection stops when the connection closes, or when the collector is dropped.
+#[derive(Debug)]
+pub struct MessageCollector {
+    messages: Arc<Mutex<Vec<Bytes>>>,
+    received: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+impl MessageCollector {
+    /// Start collecting messages from `incoming_messages`.
+    pub fn new(mut incoming_messages: ConnectionIncoming) -> Self {
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::new(Notify::new());
+
+        let task = tokio::spawn({
+            let messages = messages.clone();
+            let received = received.clone();
+            async move {
+                while let Ok(Some(msg)) = incoming_messages.next().await {
+                    lock(&messages).push(msg);
+                    received.notify_waiters();
+                }
+            }
+        });
+
+        Self {
+            messages,
+            received,
+            task,
+        }
+    }
+
+    /// The messages collected so far, in the order they were received.
+    pub fn messages(&self) -> Vec<Bytes> {
+        lock(&self.messages).clone()
+    }
+
+    /// Wait until at least `count` messages have been collected, then return them.
+    ///
+    /// Returns an error if `timeout` elapses first.
+    pub async fn wait_for(&self, count: usize, timeout: Duration) -> Result<Vec<Bytes>, Elapsed> {
+        tokio::time::timeout(timeout, async {
+            loop {
+                // register for notifications before checking, so we can't miss a message
+                let received = self.received.notified();
+                let messages = self.messages();
+                if messages.len() >= count {
+                    return messages;
+                }
+                received.await;
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for MessageCollector {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn lock(messages: &Mutex<Vec<Bytes>>) -> MutexGuard<'_, Vec<Bytes>> {
+    // the lock is never held across anything that can panic
+    messages.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+/// Pause tokio's clock, so that time only advances via [`advance_clock`].
+///
+/// qp2p's own timing follows tokio's clock: retries (see [`RetryConfig`](crate::RetryConfig)),
+/// timeouts, heartbeats, accept rate limits, deduplication windows and the delays of
+/// `fault_injection` links. This makes it possible to test them deterministically, without waiting
+/// for real delays. QUIC's own timers (e.g. the idle timeout, keep-alives and loss detection) are
+/// run by quinn on the system clock, and aren't affected. Note that while the clock is paused,
+/// tokio will automatically advance it whenever the runtime has no work to do.
+///
+/// This must be called from a `current_thread` runtime (see [`tokio::time::pause`]).
+pub fn pause_clock() {
+    tokio::time::pause();
+}
+
+/// Advance tokio's paused clock by `duration`, firing any timers that elapse.
+///
+/// See [`pause_clock`].
+pub async fn advance_clock(duration: Duration) {
+    tokio::time::advance(duration).await;
+}
@@ -739,7 +739,10 @@ async fn client() -> Result<()> {
 
     // sending should now fail, since the connection was closed at the peer
     match client_to_server.send(b"world"[..].into()).await {
-        Err(crate::SendError::ConnectionLost(_)) => {}
+        Err(crate::PeerError {
+            error: crate::SendError::ConnectionLost(_),
+            ..
+        }) => {}
         result => bail!(
             "expected connection loss when sending message, but got: {:?}",
             result
@@ -752,6 +755,3389 @@ async fn client() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test(flavor = "multi_thread")]
+async fn send_with_ack() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let msg = random_msg(1024);
+    let ack = tokio::spawn({
+        let msg = msg.clone();
+        async move { connection.send_with_ack(msg).await }
+    });
+
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+
+    // the message is acknowledged once it's delivered to our channel
+    ack.timeout().await???;
+
+    assert_eq!(peer1_incoming_messages.next().timeout().await??, Some(msg));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn buffer_pooling() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            buffer_pool_size: Some(0),
+            ..Config::default()
+        },
+    )
+    .await?;
+    let (peer2, _, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            buffer_pool_size: Some(1),
+            max_pooled_buffer_size: Some(4096),
+            ..Config::default()
+        },
+    )
+    .await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let sends = tokio::spawn(async move {
+        // messages sent with acknowledgements are encoded into pooled buffers, and the last is
+        // too large for its buffer to be kept
+        for len in [64, 64, 64, 8192] {
+            connection.send_with_ack(random_msg(len)).await?;
+        }
+        Ok::<_, Report>(())
+    });
+
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    for _ in 0..4 {
+        let _ = peer1_incoming_messages.next().timeout().await??;
+    }
+    sends.timeout().await???;
+
+    let stats = peer2.buffer_pool_stats();
+    assert!(stats.reused >= 3, "unexpected stats: {:?}", stats);
+    assert!(stats.discarded >= 1, "unexpected stats: {:?}", stats);
+
+    // without a pool, every acknowledgement gets a new buffer
+    let stats = peer1.buffer_pool_stats();
+    assert_eq!(stats.reused, 0);
+    assert!(stats.allocated >= 4, "unexpected stats: {:?}", stats);
+    assert_eq!(stats.discarded, stats.allocated);
+    assert_eq!(stats.idle, 0);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn send_queued() -> Result<()> {
+    use crate::{PriorityClass, QueueDepth};
+
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let msgs: Vec<_> = (0..3).map(|_| random_msg(1024)).collect();
+    for (msg, class) in msgs.iter().zip([
+        PriorityClass::Control,
+        PriorityClass::Realtime,
+        PriorityClass::Bulk,
+    ]) {
+        connection
+            .send_queued(msg.clone(), class)
+            .timeout()
+            .await??;
+    }
+    assert_eq!(peer2.queue_depth(), QueueDepth::default());
+
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+
+    let mut received = BTreeSet::new();
+    for _ in 0..msgs.len() {
+        if let Some(msg) = peer1_incoming_messages.next().timeout().await?? {
+            let _ = received.insert(msg);
+        }
+    }
+    assert_eq!(received, msgs.into_iter().collect());
+
+    // under contention, a control message overtakes bulk messages queued before it
+    let bulk_sends: Vec<_> = (0..256)
+        .map(|_| {
+            let connection = connection.clone();
+            tokio::spawn(async move {
+                connection
+                    .send_queued(random_msg(16 * 1024), PriorityClass::Bulk)
+                    .await
+            })
+        })
+        .collect();
+    async {
+        while peer2.queue_depth().bulk < 64 {
+            tokio::task::yield_now().await;
+        }
+    }
+    .timeout()
+    .await?;
+    let control_msg = random_msg(64);
+    connection
+        .send_queued(control_msg.clone(), PriorityClass::Control)
+        .timeout()
+        .await??;
+
+    let mut arrivals = Vec::new();
+    for _ in 0..=bulk_sends.len() {
+        if let Some(msg) = peer1_incoming_messages.next().timeout().await?? {
+            arrivals.push(msg);
+        }
+    }
+    let control_arrival = arrivals
+        .iter()
+        .position(|msg| *msg == control_msg)
+        .ok_or_else(|| eyre!("control message was not received"))?;
+    // at least 64 bulk messages were still queued when it was sent
+    assert!(control_arrival < arrivals.len() - 64);
+    for send in bulk_sends {
+        send.await??;
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn alpn_protocol_negotiation() -> Result<()> {
+    let config = |protocols: &[&str]| Config {
+        alpn_protocols: protocols.iter().map(|p| p.to_string()).collect(),
+        retry_config: RetryConfig {
+            retrying_max_elapsed_time: Duration::from_millis(500),
+            ..RetryConfig::default()
+        },
+        ..Config::default()
+    };
+
+    let (peer1, mut peer1_incoming_connections, _) =
+        Endpoint::new_peer(local_addr(), &[], config(&["app/2", "app/1"])).await?;
+    let (peer2, _, _) = Endpoint::new_peer(local_addr(), &[], config(&["app/1"])).await?;
+    let (peer3, _, _) = Endpoint::new_peer(local_addr(), &[], config(&["other"])).await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    assert_eq!(connection.alpn_protocol().as_deref(), Some("app/1"));
+
+    let (incoming, _) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    assert_eq!(incoming.alpn_protocol().as_deref(), Some("app/1"));
+
+    // no protocol in common
+    assert!(peer3.connect_to(&peer1.public_addr()).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reload_tls() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let (before, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let _ = peer1_incoming_connections.next().timeout().await?;
+
+    let cert = rcgen::generate_simple_self_signed(vec!["maidsafe.net".to_string()])?;
+    peer1.reload_tls(
+        vec![cert.serialize_der()?],
+        cert.serialize_private_key_der(),
+    )?;
+
+    // existing connections are kept, and new connections succeed with the new certificate
+    let msg = random_msg(1024);
+    before.send(msg.clone()).await?;
+    let (after, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    after.send(msg).await?;
+
+    assert!(peer1
+        .reload_tls(vec![b"not a cert".to_vec()], vec![])
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn hello_exchange() -> Result<()> {
+    use crate::HelloProvider;
+    use bytes::Bytes;
+    use std::net::SocketAddr;
+
+    // sends a fixed hello, and only accepts hellos starting with "ok"
+    #[derive(Debug)]
+    struct Hello(&'static [u8]);
+
+    impl HelloProvider for Hello {
+        fn hello(&self, _peer: SocketAddr) -> Bytes {
+            Bytes::from_static(self.0)
+        }
+
+        fn validate(&self, _peer: SocketAddr, hello: &Bytes) -> bool {
+            hello.starts_with(b"ok")
+        }
+    }
+
+    let config = |hello| Config {
+        hello_provider: Some(Arc::new(Hello(hello))),
+        ..Config::default()
+    };
+
+    let (peer1, mut peer1_incoming_connections, _) =
+        Endpoint::new_peer(local_addr(), &[], config(b"ok peer1")).await?;
+    let (peer2, _, _) = Endpoint::new_peer(local_addr(), &[], config(b"ok peer2")).await?;
+    let (peer3, _, _) = Endpoint::new_peer(local_addr(), &[], config(b"bad peer3")).await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    assert_eq!(
+        connection.peer_hello(),
+        Some(&Bytes::from_static(b"ok peer1"))
+    );
+
+    let (incoming, _) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    assert_eq!(
+        incoming.peer_hello(),
+        Some(&Bytes::from_static(b"ok peer2"))
+    );
+
+    // peer3's hello is rejected, so the connection is never handed out
+    assert!(peer3.connect_to(&peer1.public_addr()).await.is_err());
+    assert!(peer1_incoming_connections.next().timeout().await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connection_observer() -> Result<()> {
+    use crate::{Connection, ConnectionError, ConnectionObserver};
+    use futures::future::BoxFuture;
+    use std::net::SocketAddr;
+    use tokio::sync::mpsc;
+
+    #[derive(Debug)]
+    enum Event {
+        Connected(usize),
+        Disconnected(usize, ConnectionError),
+    }
+
+    #[derive(Debug)]
+    struct Observer(mpsc::UnboundedSender<Event>);
+
+    impl ConnectionObserver for Observer {
+        fn on_connect(&self, connection: Connection) -> BoxFuture<'static, ()> {
+            let _ = self.0.send(Event::Connected(connection.id()));
+            Box::pin(async {})
+        }
+
+        fn on_disconnect(
+            &self,
+            id: usize,
+            _peer: SocketAddr,
+            reason: ConnectionError,
+        ) -> BoxFuture<'static, ()> {
+            let _ = self.0.send(Event::Disconnected(id, reason));
+            Box::pin(async {})
+        }
+    }
+
+    let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+    let (peer1, _, _) = new_endpoint().await?;
+    let (peer2, _, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            connection_observer: Some(Arc::new(Observer(events_tx))),
+            ..Config::default()
+        },
+    )
+    .await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let id = connection.id();
+
+    match events_rx.recv().timeout().await? {
+        Some(Event::Connected(connected)) => assert_eq!(connected, id),
+        event => bail!("unexpected event: {:?}", event),
+    }
+
+    connection.close(None);
+
+    match events_rx.recv().timeout().await? {
+        Some(Event::Disconnected(disconnected, reason)) => {
+            assert_eq!(disconnected, id);
+            assert!(matches!(
+                reason,
+                ConnectionError::Closed(crate::Close::Local)
+            ));
+        }
+        event => bail!("unexpected event: {:?}", event),
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn additional_local_addrs() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            additional_local_addrs: vec![local_addr()],
+            ..Config::default()
+        },
+    )
+    .await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let local_addrs = peer1.local_addrs();
+    assert_eq!(local_addrs.len(), 2);
+    assert_eq!(local_addrs[0], peer1.local_addr());
+
+    // connections to either socket are yielded by the same `IncomingConnections`
+    for addr in local_addrs {
+        let msg = random_msg(1024);
+        let (connection, _) = peer2.connect_to(&addr).await?;
+        connection.send(msg.clone()).await?;
+
+        let (_, mut incoming_messages) = peer1_incoming_connections
+            .next()
+            .timeout()
+            .await?
+            .ok_or_else(|| eyre!("did not receive expected connection"))?;
+        assert_eq!(incoming_messages.next().timeout().await??, Some(msg));
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connect_to_host_name() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let host = format!("localhost:{}", peer1.public_addr().port());
+    let (connection, _) = peer2.connect_to(&host).await?;
+    assert_eq!(connection.remote_address(), peer1.public_addr());
+
+    let (incoming, _) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    assert_eq!(incoming.remote_address(), peer2.public_addr());
+
+    assert!(peer2.connect_to("localhost").await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ordered_delivery() -> Result<()> {
+    use crate::MessageOrdering;
+
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    assert_eq!(connection.message_ordering(), MessageOrdering::Unordered);
+    connection
+        .set_message_ordering(MessageOrdering::Ordered)
+        .await?;
+    assert_eq!(connection.message_ordering(), MessageOrdering::Ordered);
+
+    let msgs: Vec<_> = (0..20).map(|_| random_msg(1024)).collect();
+    for msg in &msgs {
+        connection.send(msg.clone()).timeout().await??;
+    }
+
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+
+    for msg in &msgs {
+        let received = peer1_incoming_messages.next().timeout().await??;
+        assert_eq!(received.as_ref(), Some(msg));
+    }
+
+    // switching back finishes the ordered stream, so later messages aren't stuck behind it
+    connection
+        .set_message_ordering(MessageOrdering::Unordered)
+        .await?;
+    let msg = random_msg(1024);
+    connection.send(msg.clone()).timeout().await??;
+    assert_eq!(peer1_incoming_messages.next().timeout().await??, Some(msg));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn typed_stream_messages() -> Result<()> {
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    enum Request {
+        Get(String),
+    }
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Response {
+        key: String,
+        value: Option<u64>,
+    }
+
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+    send_stream
+        .send_as(&Request::Get("answer".to_string()))
+        .timeout()
+        .await??;
+
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    let (msg, stream) = peer1_incoming_messages
+        .next_with_stream()
+        .timeout()
+        .await??
+        .ok_or_else(|| eyre!("did not receive expected message"))?;
+    let stream = stream.ok_or_else(|| eyre!("message was not received on a bi stream"))?;
+
+    let Request::Get(key) = bincode::deserialize(&msg)?;
+    assert_eq!(key, "answer");
+    stream
+        .lock()
+        .await
+        .send_as(&Response {
+            key,
+            value: Some(42),
+        })
+        .timeout()
+        .await??;
+
+    let response: Response = recv_stream.next_as().timeout().await??;
+    assert_eq!(
+        response,
+        Response {
+            key: "answer".to_string(),
+            value: Some(42)
+        }
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stream_cancellation() -> Result<()> {
+    use crate::{RecvError, StreamError};
+
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+    assert_eq!(send_stream.id(), recv_stream.id());
+    send_stream
+        .send_user_msg(random_msg(1024))
+        .timeout()
+        .await??;
+
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    let (_, stream) = peer1_incoming_messages
+        .next_with_stream()
+        .timeout()
+        .await??
+        .ok_or_else(|| eyre!("did not receive expected message"))?;
+    let stream = stream.ok_or_else(|| eyre!("message was not received on a bi stream"))?;
+    assert_eq!(stream.lock().await.id(), send_stream.id());
+
+    // peer1 abandons its side of the stream
+    stream.lock().await.reset(7)?;
+    match recv_stream
+        .next()
+        .timeout()
+        .await?
+        .map_err(|error| error.error)
+    {
+        Err(RecvError::StreamLost(StreamError::Stopped(7))) => {}
+        result => bail!("expected stream to be reset, got {:?}", result),
+    }
+
+    // peer2 stops receiving on another stream, and the connection is unaffected
+    let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+    recv_stream.stop(9)?;
+    assert!(matches!(
+        recv_stream.stop(u64::MAX).map_err(|error| error.error),
+        Err(StreamError::InvalidErrorCode(u64::MAX))
+    ));
+    send_stream
+        .send_user_msg(random_msg(1024))
+        .timeout()
+        .await??;
+    assert!(peer1_incoming_messages.next().timeout().await??.is_some());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stream_reads() -> Result<()> {
+    use crate::{wire_msg::SENT_HEADER_LEN, RecvError};
+    use bytes::Bytes;
+
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+
+    // peer1 doesn't reply to the first request, and replies with `hello` to the others
+    for (i, limit) in [0, usize::MAX, 4].into_iter().enumerate() {
+        let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+        send_stream
+            .send_user_msg(random_msg(1024))
+            .timeout()
+            .await??;
+        let (_, stream) = peer1_incoming_messages
+            .next_with_stream()
+            .timeout()
+            .await??
+            .ok_or_else(|| eyre!("did not receive expected message"))?;
+        let stream = stream.ok_or_else(|| eyre!("message was not received on a bi stream"))?;
+
+        if i == 0 {
+            match recv_stream
+                .next_timeout(Duration::from_millis(100))
+                .await
+                .map_err(|error| error.error)
+            {
+                Err(RecvError::TimedOut) => continue,
+                result => bail!("expected receiving to time out, got {:?}", result),
+            }
+        }
+
+        let mut stream = stream.lock().await;
+        stream.send_user_msg(Bytes::from_static(b"hello")).await?;
+        stream.finish().await?;
+
+        // the reply is read raw, including its header
+        let header = recv_stream.read_exact(SENT_HEADER_LEN).timeout().await??;
+        assert_eq!(header.len(), SENT_HEADER_LEN);
+        match recv_stream
+            .read_to_end(limit)
+            .timeout()
+            .await?
+            .map_err(|error| error.error)
+        {
+            Ok(data) if limit == usize::MAX => assert_eq!(&data[..], b"hello"),
+            Err(RecvError::TooLong(4)) if limit == 4 => {}
+            result => bail!("unexpected result with limit {}: {:?}", limit, result),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn raw_streams() -> Result<()> {
+    use crate::RawStream;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (peer1, mut peer1_incoming_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            raw_streams: true,
+            ..Config::default()
+        },
+    )
+    .await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let (mut send_stream, mut recv_stream) = connection.open_bi_raw().await?;
+    send_stream.write_all(b"GET /").await?;
+    send_stream.shutdown().await?;
+
+    let (_, mut peer1_incoming) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    let mut raw_streams = peer1_incoming
+        .take_raw_streams()
+        .ok_or_else(|| eyre!("raw streams were already taken"))?;
+    let (mut peer1_send, mut peer1_recv) = match raw_streams.next().timeout().await? {
+        Some(RawStream::Bi(send, recv)) => (send, recv),
+        stream => bail!("expected a bi-stream, got {:?}", stream),
+    };
+    assert_eq!(peer1_recv.id(), send_stream.id());
+
+    // the data arrives without any framing
+    let mut request = Vec::new();
+    let _ = peer1_recv.read_to_end(&mut request).timeout().await??;
+    assert_eq!(request, b"GET /");
+
+    peer1_send.write_all(b"200 OK").await?;
+    peer1_send.finish().await?;
+    let mut response = Vec::new();
+    let _ = recv_stream.read_to_end(&mut response).timeout().await??;
+    assert_eq!(response, b"200 OK");
+
+    Ok(())
+}
+
+#[cfg(feature = "async-io")]
+#[tokio::test(flavor = "multi_thread")]
+async fn async_io_streams() -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+    send_stream
+        .send_user_msg(random_msg(1024))
+        .timeout()
+        .await??;
+
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    let (_, stream) = peer1_incoming_messages
+        .next_with_stream()
+        .timeout()
+        .await??
+        .ok_or_else(|| eyre!("did not receive expected message"))?;
+    let stream = stream.ok_or_else(|| eyre!("message was not received on a bi stream"))?;
+
+    // peer1 responds with raw bytes, which peer2 copies as a byte stream
+    let response = random_msg(64 * 1024);
+    let mut peer1_send = stream.lock().await;
+    peer1_send.write_all(&response).await?;
+    peer1_send.shutdown().await?;
+
+    let mut received = Vec::new();
+    let len = tokio::io::copy(&mut recv_stream, &mut received)
+        .timeout()
+        .await??;
+    assert_eq!(len as usize, response.len());
+    assert_eq!(received, response);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_utils_peer_pair() -> Result<()> {
+    use crate::test_utils::{connected_peer_pair, MessageCollector};
+
+    let (peer1, peer2) = connected_peer_pair(Config::default()).await?;
+    assert_eq!(
+        peer2.connection.remote_address(),
+        peer1.endpoint.public_addr()
+    );
+
+    let collector = MessageCollector::new(peer1.incoming_messages);
+    let msgs: Vec<_> = (0..3).map(|_| random_msg(1024)).collect();
+    for msg in &msgs {
+        peer2.connection.send(msg.clone()).timeout().await??;
+    }
+
+    let received = collector
+        .wait_for(msgs.len(), Duration::from_secs(5))
+        .await?;
+    assert_eq!(
+        received.into_iter().collect::<BTreeSet<_>>(),
+        msgs.into_iter().collect()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_utils_paused_clock() -> Result<()> {
+    use crate::test_utils::pause_clock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    pause_clock();
+    let retry_config = RetryConfig {
+        initial_retry_interval: Duration::from_secs(60),
+        retry_delay_rand_factor: 0.0,
+        retrying_max_elapsed_time: Duration::from_secs(90),
+        ..RetryConfig::default()
+    };
+
+    // with the clock paused, back off delays elapse as soon as the runtime is idle, and the
+    // elapsed time must follow the paused clock for retrying to stop
+    let attempts = AtomicUsize::new(0);
+    let started = tokio::time::Instant::now();
+    let retry = retry_config.retry(|| async {
+        let _ = attempts.fetch_add(1, Ordering::SeqCst);
+        Err::<(), _>(backoff::Error::Transient(()))
+    });
+    // `Timeout::timeout` would fire before the first back off delay on the paused clock
+    let result = tokio::time::timeout(Duration::from_secs(24 * 60 * 60), retry).await?;
+
+    assert_eq!(result, Err(()));
+    assert!(attempts.load(Ordering::SeqCst) >= 2);
+    assert!(started.elapsed() >= Duration::from_secs(60));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stream_open_timeout() -> Result<()> {
+    use crate::ConnectionError;
+
+    let config = Config {
+        stream_open_timeout: Some(Duration::from_millis(500)),
+        ..Config::default()
+    };
+    let (peer1, _peer1_incoming_connections, _) =
+        Endpoint::new_peer(local_addr(), &[], config.clone()).await?;
+    let (peer2, _, _) = Endpoint::new_peer(local_addr(), &[], config).await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+
+    // exhaust the peer's (default) limit of 100 concurrent bidirectional streams
+    let mut streams = Vec::new();
+    for _ in 0..100 {
+        streams.push(connection.open_bi().timeout().await??);
+    }
+
+    match connection.open_bi().timeout().await? {
+        Err(crate::PeerError {
+            error: ConnectionError::StreamOpenTimedOut,
+            ..
+        }) => {}
+        result => bail!(
+            "expected stream open to time out, got {:?}",
+            result.map(|_| ())
+        ),
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connection_lookup() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let msg = random_msg(1024);
+    connection.send(msg.clone()).timeout().await??;
+
+    let by_addr = peer2
+        .get_connection_by_addr(&peer1.public_addr())
+        .ok_or_else(|| eyre!("connection not found by address"))?;
+    assert_eq!(by_addr.id(), connection.id());
+    let by_id = peer2
+        .get_connection_by_id(connection.id())
+        .ok_or_else(|| eyre!("connection not found by id"))?;
+    assert_eq!(by_id.remote_address(), peer1.public_addr());
+
+    let connections = peer2.connections();
+    assert_eq!(connections.len(), 1);
+    let info = connections[0].info();
+    assert_eq!(info.id, connection.id());
+    assert_eq!(info.remote_address, peer1.public_addr());
+    assert!(info.bytes_sent >= msg.len() as u64);
+    assert!(info.idle <= info.age);
+
+    // the incoming side is tracked too
+    let (incoming, _) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    assert_eq!(
+        peer1.get_connection_by_id(incoming.id()).map(|c| c.id()),
+        Some(incoming.id())
+    );
+
+    // connections are no longer tracked once every handle is dropped
+    drop((connection, by_addr, by_id, connections));
+    assert!(peer2.connections().is_empty());
+    assert!(peer2.get_connection_by_addr(&peer1.public_addr()).is_none());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn check_reachability() -> Result<()> {
+    use crate::Reachability;
+
+    let (peer1, _peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _peer2_incoming_connections, _) = new_endpoint().await?;
+    let (peer3, _peer3_incoming_connections, _) = new_endpoint().await?;
+
+    let reachability = peer3
+        .check_reachability(&[peer1.public_addr(), peer2.public_addr()])
+        .timeout()
+        .await??;
+    assert_eq!(
+        reachability,
+        Reachability::Public {
+            addr: peer3.public_addr()
+        }
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reachability_connections_are_pooled() -> Result<()> {
+    use crate::Reachability;
+
+    // verification connections exchange hellos like any other connection
+    let config = || Config {
+        e2e_encryption: true,
+        ..Config::default()
+    };
+    let (peer1, mut peer1_incoming_connections, _) =
+        Endpoint::new_peer(local_addr(), &[], config()).await?;
+    let (peer2, _peer2_incoming_connections, _) =
+        Endpoint::new_peer(local_addr(), &[], config()).await?;
+
+    let _connection = peer2.connect_to(&peer1.public_addr()).await?;
+    let _peer1_connection = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+
+    let reachability = peer2
+        .check_reachability(&[peer1.public_addr()])
+        .timeout()
+        .await??;
+    assert_eq!(
+        reachability,
+        Reachability::Public {
+            addr: peer2.public_addr()
+        }
+    );
+
+    // the open connection was used for the check
+    assert!(tokio::time::timeout(
+        Duration::from_millis(100),
+        peer1_incoming_connections.next()
+    )
+    .await
+    .is_err());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn verification_reuses_dialed_connections() -> Result<()> {
+    use crate::Reachability;
+
+    let (peer1, _peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, mut peer2_incoming_connections, _) = new_endpoint().await?;
+
+    // peer1 has a connection to peer2, so it can verify peer2 without connecting again
+    let _connection = peer1.connect_to(&peer2.public_addr()).await?;
+    let _peer2_connection = peer2_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+
+    let reachability = peer2
+        .check_reachability(&[peer1.public_addr()])
+        .timeout()
+        .await??;
+    assert_eq!(
+        reachability,
+        Reachability::Public {
+            addr: peer2.public_addr()
+        }
+    );
+    assert!(tokio::time::timeout(
+        Duration::from_millis(100),
+        peer2_incoming_connections.next()
+    )
+    .await
+    .is_err());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connection_classes() -> Result<()> {
+    use crate::{Connection, ConnectionClass, IncomingConnections};
+
+    let (peer, mut incoming_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            max_connections: Some(2),
+            ..Config::default()
+        },
+    )
+    .await?;
+
+    // connect a new peer, returning its side of the connection (to keep it open) and ours
+    async fn connect(
+        peer: &Endpoint,
+        incoming_connections: &mut IncomingConnections,
+    ) -> Result<(Connection, Connection)> {
+        let (dialer, _, _) = new_endpoint().await?;
+        let (dialed, _) = dialer.connect_to(&peer.public_addr()).await?;
+        let (connection, _) = incoming_connections
+            .next()
+            .timeout()
+            .await?
+            .ok_or_else(|| eyre!("did not receive expected connection"))?;
+        // make sure connections differ in idle time
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        Ok((dialed, connection))
+    }
+
+    let (_b, b) = connect(&peer, &mut incoming_connections).await?;
+    assert_eq!(b.class(), ConnectionClass::Normal);
+    b.set_class(ConnectionClass::Background);
+    let (_c, c) = connect(&peer, &mut incoming_connections).await?;
+    let (_d, d) = connect(&peer, &mut incoming_connections).await?;
+
+    // the background connection goes first, even though it's not the most idle
+    assert!(peer.get_connection_by_id(b.id()).is_none());
+    assert!(peer.get_connection_by_id(c.id()).is_some());
+    assert!(peer.get_connection_by_id(d.id()).is_some());
+
+    // critical connections are never evicted, so the most idle normal connection goes instead
+    c.set_class(ConnectionClass::Critical);
+    let (_e, e) = connect(&peer, &mut incoming_connections).await?;
+    assert!(peer.get_connection_by_id(c.id()).is_some());
+    assert!(peer.get_connection_by_id(d.id()).is_none());
+    assert!(peer.get_connection_by_id(e.id()).is_some());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transport_info() -> Result<()> {
+    let (peer1, _peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _peer2_incoming_connections, _) = new_endpoint().await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let info = connection.transport_info();
+    assert_eq!(info.keep_alive_interval, Some(Duration::from_secs(5)));
+    assert_eq!(info.idle_timeout, crate::config::DEFAULT_IDLE_TIMEOUT);
+    assert_eq!(info.max_concurrent_bidi_streams, 100);
+    assert_eq!(info.max_concurrent_uni_streams, 100);
+    assert_eq!(info.congestion_controller, "cubic");
+    assert!(info.migration);
+    assert!(info.rtt > Duration::ZERO);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn message_stats() -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let (peer1, mut peer1_incoming_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            slow_stream_threshold: Some(Duration::from_millis(100)),
+            ..Config::default()
+        },
+    )
+    .await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    connection.send(random_msg(1024)).await?;
+    connection.send(random_msg(10)).await?;
+
+    let (incoming, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    for _ in 0..2 {
+        let _ = peer1_incoming_messages.next().timeout().await??;
+    }
+
+    let sent = connection.message_stats();
+    assert_eq!(sent.sent_sizes.count(), 2);
+    assert_eq!(sent.sent_sizes.min(), Some(10));
+    assert_eq!(sent.sent_sizes.max(), Some(1024));
+    assert_eq!(sent.write_durations.count(), 2);
+    assert_eq!(sent.received_sizes.count(), 0);
+
+    let received = incoming.message_stats();
+    assert_eq!(received.received_sizes, sent.sent_sizes);
+    assert_eq!(received.read_durations.count(), 2);
+    assert_eq!(received.slow_streams, 0);
+
+    // a message that starts but never finishes stalls its stream
+    let mut stream = connection.open_uni_raw().await?;
+    stream.write_all(&[0]).await?;
+    async {
+        while incoming.message_stats().slow_streams == 0 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+    .timeout()
+    .await?;
+    assert_eq!(incoming.message_stats().received_sizes.count(), 2);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn update_config() -> Result<()> {
+    use crate::{ConfigError, PartialConfig};
+
+    let (peer1, _peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _peer2_incoming_connections, _) = new_endpoint().await?;
+
+    let (before, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    peer2.update_config(PartialConfig {
+        keep_alive_interval: Some(Some(Duration::from_secs(2))),
+        accept_rate: Some(Some(100)),
+        ..PartialConfig::default()
+    })?;
+
+    // an invalid update changes nothing
+    let update = peer2.update_config(PartialConfig {
+        retry_config: Some(RetryConfig::default()),
+        keep_alive_interval: Some(Some(crate::config::DEFAULT_IDLE_TIMEOUT)),
+        ..PartialConfig::default()
+    });
+    assert!(matches!(
+        update,
+        Err(ConfigError::InvalidKeepAliveInterval { .. })
+    ));
+
+    // only new connections use the new interval
+    let (peer3, _peer3_incoming_connections, _) = new_endpoint().await?;
+    let (after, _) = peer2.connect_to(&peer3.public_addr()).await?;
+    assert_eq!(
+        before.transport_info().keep_alive_interval,
+        Some(Duration::from_secs(5))
+    );
+    assert_eq!(
+        after.transport_info().keep_alive_interval,
+        Some(Duration::from_secs(2))
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn circuit_breaker() -> Result<()> {
+    use crate::ConnectionError;
+
+    let (peer, _peer_incoming_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            idle_timeout: Some(Duration::from_millis(500)),
+            retry_config: RetryConfig {
+                initial_retry_interval: Duration::from_millis(10),
+                retrying_max_elapsed_time: Duration::from_secs(60),
+                circuit_breaker_threshold: Some(2),
+                circuit_breaker_cooldown: Duration::from_secs(60),
+                ..RetryConfig::default()
+            },
+            ..Config::default()
+        },
+    )
+    .await?;
+
+    // a socket that never responds
+    let dead_peer = std::net::UdpSocket::bind(local_addr())?;
+    let dead_addr = dead_peer.local_addr()?;
+
+    // the circuit opens after two failed attempts, cutting the back off short
+    match peer.connect_to(&dead_addr).timeout().await? {
+        Err(ConnectionError::CircuitOpen(addr)) => assert_eq!(addr, dead_addr),
+        result => bail!("expected open circuit, got {:?}", result.map(|_| ())),
+    }
+
+    // further attempts fail immediately
+    match tokio::time::timeout(Duration::from_millis(100), peer.connect_to(&dead_addr)).await? {
+        Err(ConnectionError::CircuitOpen(_)) => {}
+        result => bail!("expected open circuit, got {:?}", result.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn endpoint_builder() -> Result<()> {
+    let mut server = Endpoint::builder()
+        .listen(local_addr())
+        .build_server()
+        .await?;
+    let server_addr = server.endpoint().public_addr();
+
+    let mut peer = Endpoint::builder()
+        .listen(local_addr())
+        .with_bootstrap(&[server_addr])
+        .build()
+        .await?;
+    let (bootstrap_connection, _) = peer
+        .take_bootstrap_connection()
+        .ok_or_else(|| eyre!("peer did not bootstrap"))?;
+    assert_eq!(bootstrap_connection.remote_address(), server_addr);
+    assert!(peer.take_bootstrap_connection().is_none());
+
+    let client = Endpoint::builder().listen(local_addr()).build_client()?;
+    let (connection, _) = client.endpoint().connect_to(&server_addr).await?;
+    let msg = random_msg(1024);
+    connection.send(msg.clone()).await?;
+
+    // both the peer's bootstrap connection and the client's are accepted
+    let mut remote_addrs = BTreeSet::new();
+    for _ in 0..2 {
+        let (connection, mut incoming) = server
+            .accept()
+            .timeout()
+            .await?
+            .ok_or_else(|| eyre!("did not receive expected connection"))?;
+        if connection.remote_address() == client.endpoint().local_addr() {
+            assert_eq!(incoming.next().timeout().await??, Some(msg.clone()));
+        }
+        let _ = remote_addrs.insert(connection.remote_address());
+    }
+    assert!(remote_addrs.contains(&client.endpoint().local_addr()));
+    assert!(remote_addrs.contains(&peer.endpoint().local_addr()));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reconnecting_connection() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _peer2_incoming_connections, _) = new_endpoint().await?;
+
+    let (connection, mut incoming) = peer2
+        .connect_reconnecting(&peer1.public_addr())
+        .timeout()
+        .await??;
+    let mut reconnections = connection.reconnections();
+    let first_id = connection.connection().await.id();
+    assert_eq!(connection.id(), first_id);
+
+    // a close by the remote peer counts as the connection being lost
+    let (peer1_connection, _) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    peer1_connection.close(Some("going away".to_string()));
+    drop(peer1_connection);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // sending reconnects transparently
+    let msg = random_msg(1024);
+    connection.send(msg.clone()).timeout().await??;
+    let reconnection = reconnections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("no reconnection event"))?;
+    assert_eq!(reconnection.id, first_id);
+    assert_eq!(reconnection.previous_connection_id, first_id);
+    assert_ne!(reconnection.connection_id, first_id);
+    assert_eq!(connection.id(), first_id);
+    assert_eq!(
+        connection.connection().await.id(),
+        reconnection.connection_id
+    );
+
+    let (peer1_connection, mut peer1_incoming) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected reconnection"))?;
+    assert_eq!(peer1_incoming.next().timeout().await??, Some(msg));
+
+    // and the receiving side follows the new connection
+    let reply = random_msg(1024);
+    peer1_connection.send(reply.clone()).timeout().await??;
+    assert_eq!(incoming.next().timeout().await??, Some(reply));
+
+    // messages on bi streams can be responded to
+    let msg = random_msg(1024);
+    let (mut send_stream, mut recv_stream) = peer1_connection.open_bi().await?;
+    send_stream.send_user_msg(msg.clone()).await?;
+    let (received, stream) = incoming
+        .next_with_stream()
+        .timeout()
+        .await??
+        .ok_or_else(|| eyre!("did not receive expected message"))?;
+    assert_eq!(received, msg);
+    let reply = random_msg(64);
+    stream
+        .ok_or_else(|| eyre!("message was not received on a bi stream"))?
+        .lock()
+        .await
+        .send_user_msg(reply.clone())
+        .await?;
+    assert_eq!(recv_stream.next().timeout().await??, reply);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reconnecting_bi_streams() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _peer2_incoming_connections, _) = new_endpoint().await?;
+
+    let (connection, _incoming) = peer2
+        .connect_reconnecting(&peer1.public_addr())
+        .timeout()
+        .await??;
+    let first_id = connection.connection().await.id();
+
+    let (peer1_connection, _) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    peer1_connection.close(Some("going away".to_string()));
+    drop(peer1_connection);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // opening a stream reconnects transparently
+    let (mut send_stream, mut recv_stream) = connection.open_bi().timeout().await??;
+    assert_ne!(connection.connection().await.id(), first_id);
+
+    let (_, mut peer1_incoming) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected reconnection"))?;
+    let request = random_msg(1024);
+    send_stream.send_user_msg(request.clone()).await?;
+    let (received, stream) = peer1_incoming
+        .next_with_stream()
+        .timeout()
+        .await??
+        .ok_or_else(|| eyre!("did not receive expected request"))?;
+    assert_eq!(received, request);
+    let response = random_msg(64);
+    stream
+        .ok_or_else(|| eyre!("request was not received on a bi stream"))?
+        .lock()
+        .await
+        .send_user_msg(response.clone())
+        .await?;
+    assert_eq!(recv_stream.next().timeout().await??, response);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn observed_addresses() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _peer2_incoming_connections, _) = new_endpoint().await?;
+    let (peer3, _peer3_incoming_connections, _) = new_endpoint().await?;
+
+    let _connection2 = peer2.connect_to(&peer1.public_addr()).timeout().await??;
+    let _connection3 = peer3.connect_to(&peer1.public_addr()).timeout().await??;
+    for _ in 0..2 {
+        let _ = peer1_incoming_connections
+            .next()
+            .timeout()
+            .await?
+            .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    }
+
+    // reports arrive in the background
+    async {
+        while peer1.observed_addresses().reporters() < 2
+            || peer2.observed_addresses().reporters() < 1
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+    .timeout()
+    .await?;
+
+    let observed = peer1.observed_addresses();
+    assert_eq!(observed.consensus(), Some(peer1.public_addr()));
+    assert_eq!(observed.confidence(peer1.public_addr()), 1.0);
+
+    // a single report is not a consensus
+    let observed = peer2.observed_addresses();
+    assert_eq!(observed.addrs().len(), 1);
+    assert_eq!(observed.addrs()[0].addr, peer2.local_addr());
+    assert_eq!(observed.consensus(), None);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn file_transfer_resumes() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _peer2_incoming_connections, _) = new_endpoint().await?;
+
+    let dir = std::env::temp_dir().join(format!("qp2p-transfer-{}", rand::random::<u64>()));
+    std::fs::create_dir_all(&dir)?;
+    let source = dir.join("source");
+    let dest = dir.join("dest");
+    let partial = dir.join("dest.part");
+
+    // a previous attempt was interrupted part way through
+    let contents = random_msg(300 * 1024);
+    std::fs::write(&source, &contents)?;
+    std::fs::write(&partial, &contents[..100 * 1024])?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).timeout().await??;
+    let (_, mut peer1_incoming) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    let mut transfers = peer1_incoming
+        .take_transfers()
+        .ok_or_else(|| eyre!("transfers already taken"))?;
+    assert!(peer1_incoming.take_transfers().is_none());
+
+    let receive = {
+        let dest = dest.clone();
+        tokio::spawn(async move {
+            let transfer = transfers
+                .next()
+                .await
+                .ok_or_else(|| eyre!("no transfer offered"))?;
+            assert_eq!(transfer.name(), "source");
+            assert_eq!(transfer.size(), 300 * 1024);
+            transfer.receive_file(&dest, |_| {}).await?;
+            Ok::<_, Report>(transfers)
+        })
+    };
+
+    let mut progress = Vec::new();
+    crate::transfer::send_file(&connection, &source, |p| progress.push(p.transferred))
+        .timeout()
+        .await??;
+    assert_eq!(progress.first(), Some(&(100 * 1024)));
+    assert_eq!(progress.last(), Some(&(300 * 1024)));
+
+    let mut transfers = receive.timeout().await???;
+    assert_eq!(std::fs::read(&dest)?, contents);
+    assert!(!partial.exists());
+
+    // a partial file that doesn't match is detected, and removed so the next attempt starts over
+    std::fs::write(&partial, random_msg(100 * 1024))?;
+    let receive = {
+        let dest = dest.clone();
+        tokio::spawn(async move {
+            let transfer = transfers
+                .next()
+                .await
+                .ok_or_else(|| eyre!("no transfer offered"))?;
+            Ok::<_, Report>(transfer.receive_file(&dest, |_| {}).await)
+        })
+    };
+    match crate::transfer::send_file(&connection, &source, |_| {})
+        .timeout()
+        .await?
+    {
+        Err(crate::TransferError::Integrity) => {}
+        result => bail!("unexpected transfer result: {:?}", result),
+    }
+    assert!(matches!(
+        receive.timeout().await???,
+        Err(crate::TransferError::Integrity)
+    ));
+    assert!(!partial.exists());
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn traffic_accounting() -> Result<()> {
+    let (peer1, _peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _peer2_incoming_connections, _) = new_endpoint().await?;
+    let peer1_addr = peer1.public_addr();
+    assert_eq!(peer2.traffic_for(&peer1_addr), crate::Traffic::default());
+
+    let (connection, _) = peer2.connect_to(&peer1_addr).timeout().await??;
+    connection.send(random_msg(10 * 1024)).timeout().await??;
+    let live = peer2.traffic_for(&peer1_addr);
+    assert!(live.bytes_sent > 10 * 1024);
+
+    // the totals survive the connection closing
+    connection.close(None);
+    drop(connection);
+    async {
+        while !peer2.connections().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+    .timeout()
+    .await?;
+    let closed = peer2.traffic_for(&peer1_addr);
+    assert!(closed.bytes_sent >= live.bytes_sent);
+
+    // and a new connection adds to them
+    let (connection, _) = peer2.connect_to(&peer1_addr).timeout().await??;
+    connection.send(random_msg(10 * 1024)).timeout().await??;
+    let total = peer2.traffic_for(&peer1_addr);
+    assert!(total.bytes_sent > closed.bytes_sent + 10 * 1024);
+    let all = peer2.traffic();
+    assert_eq!(all.len(), 1);
+    assert!(all[&peer1_addr].bytes_sent >= total.bytes_sent);
+
+    // resetting returns the totals so far, and starts counting from zero
+    let totals = peer2.reset_traffic();
+    let reset = totals
+        .get(&peer1_addr)
+        .ok_or_else(|| eyre!("no traffic for peer"))?;
+    assert!(reset.bytes_sent >= total.bytes_sent);
+    assert!(peer2.traffic_for(&peer1_addr).bytes_sent < 10 * 1024);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test(flavor = "multi_thread")]
+async fn workers_share_port() -> Result<()> {
+    let (server, mut server_incoming_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            workers: Some(4),
+            ..Config::default()
+        },
+    )
+    .await?;
+    assert_eq!(server.public_addr(), server.local_addr());
+
+    // incoming connections from every worker are merged
+    let mut peers = Vec::new();
+    for _ in 0..8 {
+        // the peers keep their incoming connections, or they'd close the server's connections
+        let (peer, peer_incoming_connections, _) = new_endpoint().await?;
+        let (connection, _) = peer.connect_to(&server.public_addr()).timeout().await??;
+        let msg = random_msg(1024);
+        connection.send(msg.clone()).await?;
+
+        let (_, mut incoming) = server_incoming_connections
+            .next()
+            .timeout()
+            .await?
+            .ok_or_else(|| eyre!("did not receive expected connection"))?;
+        assert_eq!(incoming.next().timeout().await??, Some(msg));
+        peers.push((peer, peer_incoming_connections, connection));
+    }
+
+    // outgoing connections work, even though they're made from other sockets
+    for (peer, _, _) in &peers {
+        let (connection, _) = server.connect_to(&peer.public_addr()).timeout().await??;
+        assert_ne!(connection.remote_address(), server.local_addr());
+        connection.send(random_msg(1024)).await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn outgoing_ports() -> Result<()> {
+    use crate::PortRange;
+
+    let start = std::net::UdpSocket::bind(local_addr())?
+        .local_addr()?
+        .port();
+    let ports = PortRange {
+        start,
+        end: start.saturating_add(9),
+    };
+    let (peer1, mut peer1_incoming_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            outgoing_ports: Some(ports),
+            ..Config::default()
+        },
+    )
+    .await?;
+    let (peer2, mut peer2_incoming_connections, _) = new_endpoint().await?;
+
+    let outgoing_addrs = peer1.outgoing_addrs();
+    assert_eq!(outgoing_addrs.len(), 1);
+    assert!(ports.ports().contains(&outgoing_addrs[0].port()));
+
+    // peer1 dials from the port range...
+    let (connection, _) = peer1.connect_to(&peer2.public_addr()).timeout().await??;
+    connection.send(random_msg(1024)).await?;
+    let (connection, _) = peer2_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    assert_eq!(connection.remote_address(), outgoing_addrs[0]);
+
+    // ...while peer2 dials from the socket it listens on
+    assert_eq!(peer2.outgoing_addrs(), vec![peer2.local_addr()]);
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).timeout().await??;
+    connection.send(random_msg(1024)).await?;
+    let (connection, _) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    assert_eq!(connection.remote_address(), peer2.local_addr());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn control_stream() -> Result<()> {
+    use crate::{Connection, ConnectionError, PeerState};
+
+    let (peer1, _peer1_incoming_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            heartbeat_interval: Some(Duration::from_millis(100)),
+            ..Config::default()
+        },
+    )
+    .await?;
+    let (peer2, mut peer2_incoming_connections, _) = new_endpoint().await?;
+
+    let (connection1, _) = peer1.connect_to(&peer2.public_addr()).timeout().await??;
+    let (connection2, _) = peer2_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+
+    let wait_for = |connection: &Connection, state: PeerState| {
+        let connection = connection.clone();
+        async move {
+            while connection.peer_state() != state {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+        .timeout()
+    };
+
+    // only one side needs the option
+    wait_for(&connection1, PeerState::Active).await?;
+    wait_for(&connection2, PeerState::Active).await?;
+
+    connection2.pause().await?;
+    wait_for(&connection1, PeerState::Paused).await?;
+    connection2.resume().await?;
+    wait_for(&connection1, PeerState::Active).await?;
+
+    connection1.go_away().await?;
+    wait_for(&connection2, PeerState::GoingAway).await?;
+
+    // heartbeats keep an otherwise idle peer active
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(connection1.peer_state(), PeerState::Active);
+
+    // without the option on either side, there's no control stream
+    let (peer3, _, _) = new_endpoint().await?;
+    let (connection3, _) = peer3.connect_to(&peer2.public_addr()).timeout().await??;
+    assert_eq!(connection3.peer_state(), PeerState::Unknown);
+    assert!(matches!(
+        connection3.pause().await,
+        Err(ConnectionError::NoControlStream)
+    ));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shutdown_notifies_peers() -> Result<()> {
+    use crate::{Connection, ConnectionError, ConnectionObserver, PeerState};
+    use futures::future::BoxFuture;
+    use std::{net::SocketAddr, time::Instant};
+    use tokio::sync::mpsc;
+
+    #[derive(Debug)]
+    struct Observer(mpsc::UnboundedSender<usize>);
+
+    impl ConnectionObserver for Observer {
+        fn on_connect(&self, _connection: Connection) -> BoxFuture<'static, ()> {
+            Box::pin(async {})
+        }
+
+        fn on_disconnect(
+            &self,
+            _id: usize,
+            _peer: SocketAddr,
+            _reason: ConnectionError,
+        ) -> BoxFuture<'static, ()> {
+            Box::pin(async {})
+        }
+
+        fn on_go_away(&self, id: usize, _peer: SocketAddr) -> BoxFuture<'static, ()> {
+            let _ = self.0.send(id);
+            Box::pin(async {})
+        }
+    }
+
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (go_away_tx, mut go_away_rx) = mpsc::unbounded_channel();
+    let (peer2, _, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            connection_observer: Some(Arc::new(Observer(go_away_tx))),
+            ..Config::default()
+        },
+    )
+    .await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).timeout().await??;
+    let _peer1_connection = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    assert_eq!(connection.peer_state(), PeerState::Unknown);
+
+    let started = Instant::now();
+    let shutdown = tokio::spawn({
+        let peer1 = peer1.clone();
+        async move { peer1.shutdown(Duration::from_secs(30)).await }
+    });
+
+    // the peer hears about it straight away, and can close the connection once it's done
+    assert_eq!(go_away_rx.recv().timeout().await?, Some(connection.id()));
+    assert_eq!(connection.peer_state(), PeerState::GoingAway);
+    connection.close(None);
+
+    // so the endpoint doesn't wait out the grace period
+    shutdown.timeout().await??;
+    assert!(started.elapsed() < Duration::from_secs(30));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_store_bootstrap_contacts() -> Result<()> {
+    use crate::{AddressKind, MemoryPeerStore, PeerId, PeerStore};
+
+    let (peer1, _peer1_incoming_connections, _) = new_endpoint().await?;
+    let store = Arc::new(MemoryPeerStore::new());
+    let config = Config {
+        peer_store: Some(store.clone()),
+        ..Config::default()
+    };
+
+    let (peer2, _, contact) =
+        Endpoint::new_peer(local_addr(), &[peer1.public_addr()], config.clone()).await?;
+    assert!(contact.is_some());
+    peer2
+        .address_book()
+        .insert(PeerId([1; 32]), peer1.public_addr(), AddressKind::Lan);
+    peer2.close();
+
+    let stored = store.load()?;
+    assert_eq!(stored.bootstrap_contacts, vec![peer1.public_addr()]);
+
+    // a restarted endpoint remembers the contact and address book
+    let (peer3, _, contact) = Endpoint::new_peer(local_addr(), &[], config).await?;
+    let (contact, _) = contact.ok_or_else(|| eyre!("did not bootstrap from stored contacts"))?;
+    assert_eq!(contact.remote_address(), peer1.public_addr());
+    assert_eq!(
+        peer3.address_book().peer_for(&peer1.public_addr()),
+        Some(PeerId([1; 32]))
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_exchange() -> Result<()> {
+    use crate::{ExchangedPeer, MemoryPeerStore, PeerStore, SigningKey};
+
+    let key = Arc::new(SigningKey::generate()?);
+    let (signer, _signer_incoming_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            signing_key: Some(key.clone()),
+            peer_exchange: true,
+            ..Config::default()
+        },
+    )
+    .await?;
+    let (hub, _hub_incoming_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            peer_exchange: true,
+            ..Config::default()
+        },
+    )
+    .await?;
+
+    // the hub learns the signer's own record, and passes it on
+    let signed = ExchangedPeer {
+        addr: signer.public_addr(),
+        peer_id: Some(key.peer_id()),
+    };
+    let peers = hub.exchange_peers(&signer.public_addr()).await?;
+    assert_eq!(peers, vec![signed]);
+
+    let store = Arc::new(MemoryPeerStore::new());
+    let newcomer = Endpoint::new_client(
+        local_addr(),
+        Config {
+            peer_store: Some(store.clone()),
+            ..Config::default()
+        },
+    )?;
+    let peers = newcomer.exchange_peers(&hub.public_addr()).await?;
+    assert_eq!(peers, vec![signed]);
+    assert_eq!(
+        newcomer.address_book().peer_for(&signer.public_addr()),
+        Some(key.peer_id())
+    );
+    assert_eq!(store.load()?.bootstrap_contacts, vec![signer.public_addr()]);
+
+    // requests are rate limited
+    assert!(newcomer
+        .exchange_peers(&hub.public_addr())
+        .await?
+        .is_empty());
+
+    // and only answered by endpoints that opt in
+    let (peer, _peer_incoming_connections, _) = new_endpoint().await?;
+    let (_peer_to_hub, _) = peer.connect_to(&hub.public_addr()).await?;
+    assert!(newcomer
+        .exchange_peers(&peer.public_addr())
+        .await?
+        .is_empty());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_identity() -> Result<()> {
+    use crate::{CertificateIdentifier, Connection, HelloProvider, PeerId, PeerIdentifier};
+    use bytes::Bytes;
+    use std::net::SocketAddr;
+
+    // peers say who they are in their hello
+    #[derive(Debug)]
+    struct Hello(u8);
+
+    impl HelloProvider for Hello {
+        fn hello(&self, _peer: SocketAddr) -> Bytes {
+            Bytes::from(vec![self.0])
+        }
+    }
+
+    #[derive(Debug)]
+    struct HelloIdentifier;
+
+    impl PeerIdentifier for HelloIdentifier {
+        fn identify(&self, connection: &Connection) -> Option<PeerId> {
+            connection.peer_hello().map(|hello| PeerId([hello[0]; 32]))
+        }
+    }
+
+    let config = |id, identifier: Arc<dyn PeerIdentifier>| Config {
+        hello_provider: Some(Arc::new(Hello(id))),
+        peer_identifier: Some(identifier),
+        ..Config::default()
+    };
+
+    let (peer1, mut peer1_incoming_connections, _) =
+        Endpoint::new_peer(local_addr(), &[], config(1, Arc::new(HelloIdentifier))).await?;
+
+    // the same peer connects from two different addresses
+    let mut incoming = Vec::new();
+    for _ in 0..2 {
+        let (peer2, _, _) = Endpoint::new_peer(
+            local_addr(),
+            &[],
+            config(2, Arc::new(CertificateIdentifier)),
+        )
+        .await?;
+        let (connection, _) = peer2.connect_to(&peer1.public_addr()).timeout().await??;
+
+        // the certificate identifies peer1 to peer2
+        let peer1_id = connection
+            .peer_id()
+            .ok_or_else(|| eyre!("peer1 was not identified"))?;
+        assert_eq!(
+            peer2
+                .get_connection_by_peer(&peer1_id)
+                .map(|connection| connection.id()),
+            Some(connection.id())
+        );
+
+        let (connection, _) = peer1_incoming_connections
+            .next()
+            .timeout()
+            .await?
+            .ok_or_else(|| eyre!("did not receive expected connection"))?;
+        assert_eq!(connection.peer_id(), Some(PeerId([2; 32])));
+        incoming.push((peer2, connection));
+    }
+
+    // the latest connection is found by identity, whatever its address
+    let latest = peer1
+        .get_connection_by_peer(&PeerId([2; 32]))
+        .ok_or_else(|| eyre!("no connection for peer2"))?;
+    assert_eq!(latest.id(), incoming[1].1.id());
+    assert_ne!(
+        incoming[0].1.remote_address(),
+        incoming[1].1.remote_address()
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn message_interceptors() -> Result<()> {
+    use crate::{Intercepted, Interceptor, MessageContext, RecvError, SendError};
+    use bytes::{Bytes, BytesMut};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // tags outgoing messages, and only accepts tagged incoming ones
+    #[derive(Debug)]
+    struct Tag;
+
+    impl Interceptor for Tag {
+        fn outgoing(&self, _: &MessageContext, msg: Bytes) -> Intercepted {
+            match &msg[..] {
+                b"spam" => Intercepted::Drop,
+                b"forbidden" => Intercepted::Reject("forbidden".to_string()),
+                _ => {
+                    let mut msg = BytesMut::from(&msg[..]);
+                    msg.extend_from_slice(b"+tag");
+                    Intercepted::Continue(msg.freeze())
+                }
+            }
+        }
+
+        fn incoming(&self, context: &MessageContext, msg: Bytes) -> Intercepted {
+            let _ = context
+                .extensions()
+                .get_or_insert_with(|| Arc::new(AtomicUsize::new(0)))
+                .fetch_add(1, Ordering::Relaxed);
+            match msg.strip_suffix(b"+tag") {
+                Some(msg) => Intercepted::Continue(Bytes::copy_from_slice(msg)),
+                None => Intercepted::Reject("untagged".to_string()),
+            }
+        }
+    }
+
+    let config = || Config {
+        interceptors: vec![Arc::new(Tag)],
+        ..Config::default()
+    };
+    let (peer1, mut peer1_incoming_connections, _) =
+        Endpoint::new_peer(local_addr(), &[], config()).await?;
+    let (peer2, _, _) = Endpoint::new_peer(local_addr(), &[], config()).await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    connection.send(Bytes::from_static(b"spam")).await?;
+    match connection
+        .send(Bytes::from_static(b"forbidden"))
+        .await
+        .map_err(|error| error.error)
+    {
+        Err(SendError::Rejected(reason)) => assert_eq!(reason, "forbidden"),
+        result => bail!("expected message to be rejected, got {:?}", result),
+    }
+    connection.send(Bytes::from_static(b"hello")).await?;
+
+    let (peer1_connection, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    let msg = peer1_incoming_messages.next().timeout().await??;
+    assert_eq!(msg.as_deref(), Some(&b"hello"[..]));
+
+    // a peer without the interceptor doesn't tag its messages
+    let (peer3, _, _) = new_endpoint().await?;
+    let (connection, _) = peer3.connect_to(&peer1.public_addr()).await?;
+    connection.send(Bytes::from_static(b"hello")).await?;
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    match peer1_incoming_messages
+        .next()
+        .timeout()
+        .await?
+        .map_err(|error| error.error)
+    {
+        Err(RecvError::Rejected(reason)) => assert_eq!(reason, "untagged"),
+        result => bail!("expected message to be rejected, got {:?}", result),
+    }
+
+    // the interceptor saw the connection's extensions
+    let received = peer1_connection
+        .extensions()
+        .get::<Arc<AtomicUsize>>()
+        .ok_or_else(|| eyre!("interceptor didn't count messages"))?;
+    assert_eq!(received.load(Ordering::Relaxed), 1);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn signed_messages() -> Result<()> {
+    use crate::{RecvError, SigningKey};
+
+    let config = |key: &Arc<SigningKey>| Config {
+        signing_key: Some(key.clone()),
+        ..Config::default()
+    };
+    let peer1_key = Arc::new(SigningKey::generate()?);
+    let peer2_key = Arc::new(SigningKey::generate()?);
+    let (peer1, mut peer1_incoming_connections, _) =
+        Endpoint::new_peer(local_addr(), &[], config(&peer1_key)).await?;
+    let (peer2, _, _) = Endpoint::new_peer(local_addr(), &[], config(&peer2_key)).await?;
+
+    let msg = random_msg(1024);
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    connection.send(msg.clone()).await?;
+
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    let received = peer1_incoming_messages
+        .next_with_signer()
+        .timeout()
+        .await??;
+    assert_eq!(received, Some((msg, Some(peer2_key.peer_id()))));
+
+    // messages from a peer that doesn't sign are refused
+    let (peer3, _, _) = new_endpoint().await?;
+    let (connection, _) = peer3.connect_to(&peer1.public_addr()).await?;
+    connection.send(random_msg(1024)).await?;
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    match peer1_incoming_messages
+        .next()
+        .timeout()
+        .await?
+        .map_err(|error| error.error)
+    {
+        Err(RecvError::InvalidSignature) => {}
+        result => bail!("expected an invalid signature, got {:?}", result),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn retry_hook() -> Result<()> {
+    use crate::{test_utils::pause_clock, RetryEvent, RetryHook};
+    use futures::future::BoxFuture;
+    use std::sync::Mutex;
+
+    // records each retry, and gives up after the second
+    #[derive(Debug, Default)]
+    struct GiveUp(Mutex<Vec<RetryEvent>>);
+
+    impl RetryHook for GiveUp {
+        fn before_retry(&self, event: RetryEvent) -> BoxFuture<'static, bool> {
+            let mut events = self.0.lock().unwrap();
+            events.push(event);
+            let retry = events.len() < 2;
+            Box::pin(async move { retry })
+        }
+    }
+
+    pause_clock();
+    let hook = Arc::new(GiveUp::default());
+    let retry_config = RetryConfig {
+        retry_hook: Some(hook.clone()),
+        ..RetryConfig::default()
+    };
+    let result = retry_config
+        .retry(|| async { Err::<(), _>(backoff::Error::Transient("refused")) })
+        .await;
+    assert_eq!(result, Err("refused"));
+
+    let events = std::mem::take(&mut *hook.0.lock().unwrap());
+    assert_eq!(
+        events.iter().map(|event| event.retry).collect::<Vec<_>>(),
+        [1, 2]
+    );
+    assert_eq!(events[0].error, "\"refused\"");
+
+    // retries can also be limited by number
+    let attempts = std::sync::atomic::AtomicUsize::new(0);
+    let retry_config = RetryConfig {
+        max_retry_attempts: Some(3),
+        ..RetryConfig::default()
+    };
+    let result = retry_config
+        .retry(|| async {
+            let _ = attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err::<(), _>(backoff::Error::Transient(()))
+        })
+        .await;
+    assert_eq!(result, Err(()));
+    assert_eq!(attempts.into_inner(), 4);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rpc_server() -> Result<()> {
+    use crate::RpcServer;
+    use bytes::Bytes;
+
+    let (server, server_incoming, _) = new_endpoint().await?;
+    let rpc_server = RpcServer::new(|request: Bytes| async move {
+        Bytes::from(request.iter().rev().copied().collect::<Vec<_>>())
+    });
+    let _ = tokio::spawn(async move { rpc_server.serve(server_incoming).await });
+
+    let (client, _, _) = new_endpoint().await?;
+    let (connection, mut incoming) = client.connect_to(&server.public_addr()).await?;
+
+    // several exchanges on the same stream
+    let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+    for request in [&b"ping"[..], b"hello", b"abc"] {
+        send_stream
+            .send_user_msg(Bytes::copy_from_slice(request))
+            .await?;
+        let response = recv_stream.next().timeout().await??;
+        assert_eq!(response.iter().rev().copied().collect::<Vec<_>>(), request);
+    }
+
+    // a request on its own is answered on the connection
+    connection.send(Bytes::from_static(b"xyz")).await?;
+    let response = incoming
+        .next()
+        .timeout()
+        .await??
+        .ok_or_else(|| eyre!("no response"))?;
+    assert_eq!(&response[..], b"zyx");
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn streamed_responses() -> Result<()> {
+    use crate::RpcServer;
+    use bytes::Bytes;
+
+    // answer each request with its bytes after the first, one per message, so that one-byte
+    // requests get an empty response (empty messages can't be sent)
+    let (server, server_incoming, _) = new_endpoint().await?;
+    let rpc_server = RpcServer::streaming(|request: Bytes| {
+        futures::stream::iter((1..request.len()).map(move |i| request.slice(i..=i)))
+    });
+    let _ = tokio::spawn(async move { rpc_server.serve(server_incoming).await });
+
+    let (client, _, _) = new_endpoint().await?;
+    let (connection, _) = client.connect_to(&server.public_addr()).await?;
+
+    // several streamed exchanges on the same stream, including an empty response
+    let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+    for request in [&b"chunks"[..], b"x", b"more"] {
+        send_stream
+            .send_user_msg(Bytes::copy_from_slice(request))
+            .await?;
+        let mut response = Vec::new();
+        while let Some(chunk) = recv_stream.next_response().timeout().await?? {
+            assert_eq!(chunk.len(), 1);
+            response.extend_from_slice(&chunk);
+        }
+        assert_eq!(response, request[1..]);
+    }
+
+    // finishing the stream also ends a response
+    send_stream.finish().await?;
+    assert!(recv_stream.next_response().timeout().await??.is_none());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn finished_and_truncated_streams() -> Result<()> {
+    use crate::RecvError;
+
+    let (server, mut server_incoming, _) = new_endpoint().await?;
+    let (client, _, _) = new_endpoint().await?;
+    let (connection, _) = client.connect_to(&server.public_addr()).await?;
+    let (_, mut incoming) = server_incoming
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("connection was not accepted"))?;
+
+    for graceful in [true, false] {
+        let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+        send_stream.send_user_msg(random_msg(16)).await?;
+        let stream = match incoming
+            .next_with_stream()
+            .timeout()
+            .await??
+            .ok_or_else(|| eyre!("no request"))?
+        {
+            (_, Some(stream)) => stream,
+            (_, None) => bail!("request wasn't received on a bi-stream"),
+        };
+
+        let msg = random_msg(32);
+        let mut stream = stream.lock().await;
+        stream.send_user_msg(msg.clone()).await?;
+        if graceful {
+            stream.finish_messages().await?;
+        } else {
+            stream.finish().await?;
+        }
+
+        assert_eq!(recv_stream.try_next().timeout().await??, Some(msg));
+        match recv_stream.try_next().timeout().await? {
+            Ok(None) if graceful => {}
+            Err(error) if !graceful && matches!(error.error, RecvError::Truncated) => {}
+            result => bail!(
+                "unexpected end of stream (graceful: {}): {:?}",
+                graceful,
+                result
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stateless_retry() -> Result<()> {
+    let (server, mut server_incoming, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            stateless_retry: true,
+            retry_token_lifetime: Some(Duration::from_secs(5)),
+            ..Config::default()
+        },
+    )
+    .await?;
+    assert!(server.stateless_retry());
+
+    // connections complete the retry and are accepted as usual, whether or not it's required
+    for enabled in [true, false] {
+        server.set_stateless_retry(enabled);
+        assert_eq!(server.stateless_retry(), enabled);
+
+        let (client, _, _) = new_endpoint().await?;
+        let (connection, _) = client.connect_to(&server.public_addr()).timeout().await??;
+        let (accepted, _) = server_incoming
+            .next()
+            .timeout()
+            .await?
+            .ok_or_else(|| eyre!("connection was not accepted"))?;
+        assert_eq!(accepted.remote_address(), client.public_addr());
+        drop(connection);
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn accept_rate_per_ip() -> Result<()> {
+    let (server, mut server_incoming, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            accept_rate_per_ip: Some(1),
+            ..Config::default()
+        },
+    )
+    .await?;
+    let client_config = || Config {
+        retry_config: RetryConfig {
+            max_retry_attempts: Some(0),
+            ..RetryConfig::default()
+        },
+        ..Config::default()
+    };
+
+    let (client1, _, _) = Endpoint::new_peer(local_addr(), &[], client_config()).await?;
+    let _connection1 = client1
+        .connect_to(&server.public_addr())
+        .timeout()
+        .await??;
+    let _ = server_incoming
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("first connection was not accepted"))?;
+
+    // a second connection from the same IP within the second is rejected; whether the client sees
+    // an error depends on how far its handshake got, so check the server didn't accept it. The
+    // client gives up before it would retransmit, since a retransmission after the second is a
+    // new attempt that's allowed
+    let (client2, _, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            handshake_timeout: Some(Duration::from_millis(500)),
+            ..client_config()
+        },
+    )
+    .await?;
+    let _ = client2.connect_to(&server.public_addr()).timeout().await;
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), server_incoming.next())
+            .await
+            .is_err(),
+        "second connection was accepted"
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fault_injection_latency() -> Result<()> {
+    use crate::fault_injection::{Faults, FaultyLink};
+
+    let (server, mut server_incoming, _) = new_endpoint().await?;
+    let (client, _, _) = new_endpoint().await?;
+    let link = FaultyLink::new(
+        server.public_addr(),
+        Faults {
+            latency: Duration::from_millis(50),
+            ..Faults::default()
+        },
+    )
+    .await?;
+
+    let (connection, _) = client.connect_to(&link.addr()).timeout().await??;
+    let msg = random_msg(1024);
+    connection.send(msg.clone()).await?;
+    let (_, mut server_messages) = server_incoming
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    assert_eq!(server_messages.next().timeout().await??, Some(msg));
+
+    // the latency is added in each direction, without reordering or counting as a delay
+    assert!(
+        connection.transport_info().rtt >= Duration::from_millis(100),
+        "unexpected rtt: {:?}",
+        connection.transport_info().rtt
+    );
+    assert_eq!(link.stats().delayed, 0);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn upgrade_relayed_connection() -> Result<()> {
+    use crate::{
+        fault_injection::{Faults, FaultyLink},
+        AddressKind, Connection, ConnectionPath, HelloProvider, PeerId, PeerIdentifier,
+    };
+    use bytes::Bytes;
+    use std::net::SocketAddr;
+
+    #[derive(Debug)]
+    struct Hello;
+
+    impl HelloProvider for Hello {
+        fn hello(&self, _peer: SocketAddr) -> Bytes {
+            Bytes::from_static(&[1])
+        }
+    }
+
+    #[derive(Debug)]
+    struct HelloIdentifier;
+
+    impl PeerIdentifier for HelloIdentifier {
+        fn identify(&self, connection: &Connection) -> Option<PeerId> {
+            connection.peer_hello().map(|hello| PeerId([hello[0]; 32]))
+        }
+    }
+
+    // both peers say hello, which identifies them as `PeerId([1; 32])`
+    let config = Config {
+        hello_provider: Some(Arc::new(Hello)),
+        peer_identifier: Some(Arc::new(HelloIdentifier)),
+        ..Config::default()
+    };
+    let (server, _server_incoming, _) =
+        Endpoint::new_peer(local_addr(), &[], config.clone()).await?;
+    let (client, _client_incoming, _) = Endpoint::new_peer(local_addr(), &[], config).await?;
+
+    // a relay that forwards datagrams unchanged
+    let relay = FaultyLink::new(server.public_addr(), Faults::default()).await?;
+    let server_id = PeerId([1; 32]);
+    client
+        .address_book()
+        .insert(server_id, relay.addr(), AddressKind::Relayed);
+
+    let (relayed, _) = client.connect_to_peer(&server_id).timeout().await??;
+    assert_eq!(relayed.path(), ConnectionPath::Relayed);
+    assert_eq!(relayed.peer_id(), Some(server_id));
+
+    // without a direct address there's nothing to upgrade to
+    assert!(client.upgrade_to_direct(&server_id).await.is_err());
+
+    client
+        .address_book()
+        .insert(server_id, server.public_addr(), AddressKind::Wan);
+    let (direct, _) = client
+        .upgrade_to_direct(&server_id)
+        .timeout()
+        .await??
+        .ok_or_else(|| eyre!("no direct connection was made"))?;
+    assert_eq!(direct.path(), ConnectionPath::Direct);
+
+    // the direct connection is preferred, and the relayed connection is closed
+    assert_eq!(
+        client
+            .get_connection_by_peer(&server_id)
+            .map(|connection| connection.id()),
+        Some(direct.id())
+    );
+    if relayed.send(random_msg(8)).timeout().await?.is_ok() {
+        bail!("relayed connection is still open");
+    }
+
+    // and there's no further upgrade to make
+    assert!(client.upgrade_to_direct(&server_id).await?.is_none());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn messages_from_peer() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = new_endpoint().await?;
+    let mut peer2_messages = peer1.messages_from(peer2.public_addr());
+    assert_eq!(peer2_messages.peer(), peer2.public_addr());
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+
+    let msgs = [random_msg(1024), random_msg(1024), random_msg(1024)];
+    for msg in &msgs {
+        connection.send(msg.clone()).await?;
+    }
+    for msg in &msgs {
+        assert_eq!(peer2_messages.next().timeout().await?.as_ref(), Some(msg));
+    }
+
+    // once the receiver is dropped, messages go to the connection again
+    drop(peer2_messages);
+    let msg = random_msg(1024);
+    connection.send(msg.clone()).await?;
+    assert_eq!(peer1_incoming_messages.next().timeout().await??, Some(msg));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn message_sink() -> Result<()> {
+    use crate::{MessageContext, MessageSink, PeerId, SendStream};
+    use bytes::Bytes;
+    use std::net::SocketAddr;
+    use tokio::sync::{mpsc, Mutex};
+
+    type SinkMsg = (SocketAddr, Bytes, Option<Arc<Mutex<SendStream>>>);
+
+    #[derive(Debug)]
+    struct ChannelSink(mpsc::UnboundedSender<SinkMsg>);
+
+    impl MessageSink for ChannelSink {
+        fn deliver(
+            &self,
+            context: &MessageContext,
+            msg: Bytes,
+            stream: Option<Arc<Mutex<SendStream>>>,
+            _signer: Option<PeerId>,
+        ) -> bool {
+            self.0.send((context.peer(), msg, stream)).is_ok()
+        }
+    }
+
+    let (sink_tx, mut sink_rx) = mpsc::unbounded_channel();
+    let (peer1, mut peer1_incoming_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            message_sink: Some(Arc::new(ChannelSink(sink_tx))),
+            ..Config::default()
+        },
+    )
+    .await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+
+    let msg = random_msg(1024);
+    connection.send(msg.clone()).await?;
+    let (peer, received, stream) = sink_rx
+        .recv()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("sink did not receive expected message"))?;
+    assert_eq!(peer, peer2.public_addr());
+    assert_eq!(received, msg);
+    assert!(stream.is_none());
+
+    // messages on bi streams can be responded to
+    let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+    send_stream.send_user_msg(msg.clone()).await?;
+    let (_, received, stream) = sink_rx
+        .recv()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("sink did not receive expected message"))?;
+    assert_eq!(received, msg);
+    let reply = random_msg(64);
+    stream
+        .ok_or_else(|| eyre!("message was not received on a bi stream"))?
+        .lock()
+        .await
+        .send_user_msg(reply.clone())
+        .await?;
+    assert_eq!(recv_stream.next().timeout().await??, reply);
+
+    // nothing was left for the connection's receiver
+    assert!(
+        tokio::time::timeout(Duration::from_millis(100), peer1_incoming_messages.next())
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn send_to_reuses_connections() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = new_endpoint().await?;
+
+    let peer1_addr = peer1.public_addr();
+    let msgs = [random_msg(1024), random_msg(1024), random_msg(1024)];
+    let sends = msgs
+        .iter()
+        .map(|msg| peer2.send_to(&peer1_addr, msg.clone()));
+    for result in future::join_all(sends).await {
+        result?;
+    }
+
+    // the concurrent sends share one connection, which is reused by later sends
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    let extra = random_msg(1024);
+    peer2.send_to(&peer1.public_addr(), extra.clone()).await?;
+
+    let mut received = Vec::new();
+    for _ in 0..4 {
+        received.push(
+            peer1_incoming_messages
+                .next()
+                .timeout()
+                .await??
+                .ok_or_else(|| eyre!("connection closed"))?,
+        );
+    }
+    for msg in msgs.iter().chain(Some(&extra)) {
+        assert!(received.contains(msg));
+    }
+    assert!(peer1_incoming_connections.try_recv().is_err());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_receives_on_pooled_connections() -> Result<()> {
+    use crate::{Config, Endpoint};
+
+    let (server, mut server_connections, _) = new_endpoint().await?;
+    let client = Endpoint::new_client(local_addr(), Config::default())?;
+    let mut client_connections = client.pooled_incoming();
+
+    let msg = random_msg(1024);
+    client.send_to(&server.public_addr(), msg.clone()).await?;
+
+    let (server_to_client, mut server_messages) = server_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    let received = server_messages
+        .next()
+        .timeout()
+        .await??
+        .ok_or_else(|| eyre!("connection closed"))?;
+    assert_eq!(received, msg);
+
+    // the server replies on the connection `send_to` opened
+    let reply = random_msg(1024);
+    server_to_client.send(reply.clone()).await?;
+
+    let (client_to_server, mut client_messages) = client_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive pooled connection"))?;
+    assert_eq!(client_to_server.remote_address(), server.public_addr());
+    let received = client_messages
+        .next()
+        .timeout()
+        .await??
+        .ok_or_else(|| eyre!("connection closed"))?;
+    assert_eq!(received, reply);
+
+    // connections that are reused aren't delivered again
+    client.send_to(&server.public_addr(), msg).await?;
+    assert!(client_connections.try_recv().is_err());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn preconnect() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, mut peer2_incoming_connections, _) = new_endpoint().await?;
+    let (peer3, _, _) = new_endpoint().await?;
+
+    let addrs = [
+        peer1.public_addr(),
+        peer2.public_addr(),
+        peer1.public_addr(),
+    ];
+    let connected = peer3.preconnect(&addrs).timeout().await??;
+    assert_eq!(connected, 3);
+    assert!(peer3.get_connection_by_addr(&peer1.public_addr()).is_some());
+    assert!(peer3.get_connection_by_addr(&peer2.public_addr()).is_some());
+
+    // a single connection was made to each peer, and sends reuse it
+    let (_, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    let _ = peer2_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    let msg = random_msg(1024);
+    peer3.send_to(&peer1.public_addr(), msg.clone()).await?;
+    assert_eq!(peer1_incoming_messages.next().timeout().await??, Some(msg));
+    assert!(peer1_incoming_connections.try_recv().is_err());
+    assert!(peer2_incoming_connections.try_recv().is_err());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn quic_versions() -> Result<()> {
+    use crate::ConfigError;
+
+    const DRAFT_29: u32 = 0xff00_001d;
+    let draft_config = Config {
+        quic_versions: vec![DRAFT_29],
+        ..Config::default()
+    };
+    let (peer1, mut peer1_incoming_connections, _) =
+        Endpoint::new_peer(local_addr(), &[], draft_config.clone()).await?;
+    let (peer2, _, _) = Endpoint::new_peer(local_addr(), &[], draft_config).await?;
+
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let (incoming, _) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    assert_eq!(connection.quic_version(), Some(DRAFT_29));
+    assert_eq!(incoming.quic_version(), Some(DRAFT_29));
+
+    // peers that don't share a version can't connect
+    let (peer3, _, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            retry_config: RetryConfig {
+                max_retry_attempts: Some(0),
+                ..RetryConfig::default()
+            },
+            ..Config::default()
+        },
+    )
+    .await?;
+    assert!(peer3
+        .connect_to(&peer1.public_addr())
+        .timeout()
+        .await?
+        .is_err());
+
+    match Endpoint::new_client(
+        local_addr(),
+        Config {
+            quic_versions: vec![2],
+            ..Config::default()
+        },
+    ) {
+        Err(crate::ClientEndpointError::Config(ConfigError::UnsupportedQuicVersion(2))) => {}
+        result => bail!("expected an unsupported version error, got {:?}", result),
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn session_resumption() -> Result<()> {
+    use crate::ResumptionStats;
+
+    // connect to a new peer twice, returning the stats of both ends
+    async fn reconnect(config: Config) -> Result<(ResumptionStats, ResumptionStats)> {
+        let (peer1, mut peer1_incoming_connections, _) =
+            Endpoint::new_peer(local_addr(), &[], config.clone()).await?;
+        let (peer2, _, _) = Endpoint::new_peer(local_addr(), &[], config).await?;
+
+        for _ in 0..2 {
+            let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+            connection.send(random_msg(16)).await?;
+            let (_, mut incoming) = peer1_incoming_connections
+                .next()
+                .timeout()
+                .await?
+                .ok_or_else(|| eyre!("did not receive expected connection"))?;
+            // the session ticket is sent after the handshake, so wait for the message to be sure
+            // it has arrived
+            let _ = incoming.next().timeout().await??;
+            connection.close(None);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        Ok((peer2.resumption_stats(), peer1.resumption_stats()))
+    }
+
+    let (client, server) = reconnect(Config::default()).await?;
+    assert_eq!(client.offered, 1);
+    assert_eq!(server.resumed, 1);
+
+    let (client, server) = reconnect(Config {
+        session_cache_size: Some(0),
+        ..Config::default()
+    })
+    .await?;
+    assert_eq!(client, ResumptionStats::default());
+    assert_eq!(server, ResumptionStats::default());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pause_incoming() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = new_endpoint().await?;
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let (_, mut incoming) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+
+    peer1.pause_incoming(true);
+    assert!(peer1.is_incoming_paused());
+
+    // new connections are rejected
+    let (peer3, _, _) = new_endpoint().await?;
+    let _ = tokio::time::timeout(
+        Duration::from_millis(500),
+        peer3.connect_to(&peer1.public_addr()),
+    )
+    .await;
+    assert!(tokio::time::timeout(
+        Duration::from_millis(500),
+        peer1_incoming_connections.next()
+    )
+    .await
+    .is_err());
+
+    // messages on new streams wait until streams are resumed, without losing the connection
+    let msg = random_msg(16);
+    connection.send(msg.clone()).await?;
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), incoming.next())
+            .await
+            .is_err()
+    );
+
+    peer1.resume_incoming();
+    assert!(!peer1.is_incoming_paused());
+    assert_eq!(incoming.next().timeout().await??, Some(msg));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn send_with_opts() -> Result<()> {
+    use crate::{MessageOrdering, SendError, SendOptions};
+    use tokio::time::Instant;
+
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = new_endpoint().await?;
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let (_, mut incoming) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+
+    // messages can be ordered without changing the connection's ordering
+    let opts = SendOptions {
+        priority: 1,
+        ordered: Some(true),
+        deadline: Some(Instant::now() + Duration::from_secs(10)),
+        ..SendOptions::default()
+    };
+    let msgs: Vec<_> = (0..10).map(|_| random_msg(1024)).collect();
+    for msg in &msgs {
+        connection.send_with_opts(msg.clone(), &opts).await?;
+    }
+    for msg in msgs {
+        assert_eq!(incoming.next().timeout().await??, Some(msg));
+    }
+    assert_eq!(connection.message_ordering(), MessageOrdering::Unordered);
+
+    // messages past their deadline aren't sent
+    let opts = SendOptions {
+        deadline: Some(Instant::now()),
+        ..SendOptions::default()
+    };
+    match connection.send_with_opts(random_msg(16), &opts).await {
+        Err(error) if matches!(error.error, SendError::DeadlineExceeded) => {
+            assert!(error.is_transient())
+        }
+        result => bail!("unexpected send result: {:?}", result),
+    }
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), incoming.next())
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn incoming_buffer_limits() -> Result<()> {
+    use crate::{MessageOrdering, OverflowPolicy};
+
+    let (peer1, mut peer1_incoming_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            max_buffered_bytes_per_connection: Some(4 * 1024),
+            buffer_overflow: OverflowPolicy::DropOldest,
+            ..Config::default()
+        },
+    )
+    .await?;
+    let (peer2, _, _) = new_endpoint().await?;
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    connection
+        .set_message_ordering(MessageOrdering::Ordered)
+        .await?;
+    let (_, mut incoming) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+
+    let msgs: Vec<_> = (0..8).map(|_| random_msg(1024)).collect();
+    for msg in &msgs {
+        connection.send(msg.clone()).await?;
+    }
+
+    // nothing is received until all the messages have arrived, so the oldest are dropped
+    async {
+        while incoming.buffered().dropped < 4 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+    .timeout()
+    .await?;
+    let buffered = incoming.buffered();
+    assert_eq!((buffered.messages, buffered.bytes), (4, 4 * 1024));
+    assert_eq!(peer1.incoming_buffer(), buffered);
+
+    for msg in &msgs[4..] {
+        assert_eq!(incoming.next().timeout().await??.as_ref(), Some(msg));
+    }
+    assert_eq!(peer1.incoming_buffer().bytes, 0);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sends_take_turns() -> Result<()> {
+    use crate::{SendError, SendOptions};
+    use tokio::time::Instant;
+
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            max_concurrent_sends: Some(1),
+            ..Config::default()
+        },
+    )
+    .await?;
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let (_, mut incoming) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+
+    // a message larger than the stream window can't be sent while the peer holds back streams
+    peer1.pause_incoming(true);
+    let big_msg = random_msg(2 * 1024 * 1024);
+    let big_send = tokio::spawn({
+        let connection = connection.clone();
+        let big_msg = big_msg.clone();
+        async move { connection.send(big_msg).await }
+    });
+    // wait until it holds the only turn
+    async {
+        while connection.free_send_turns() != Some(0) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+    .timeout()
+    .await?;
+
+    // so the next send waits for its turn until its deadline
+    let small_msg = random_msg(16);
+    let opts = SendOptions {
+        deadline: Some(Instant::now() + Duration::from_millis(500)),
+        ..SendOptions::default()
+    };
+    match connection.send_with_opts(small_msg.clone(), &opts).await {
+        Err(error) if matches!(error.error, SendError::DeadlineExceeded) => {}
+        result => bail!("unexpected send result: {:?}", result),
+    }
+
+    peer1.resume_incoming();
+    big_send.timeout().await???;
+    connection.send(small_msg.clone()).timeout().await??;
+    assert_eq!(incoming.next().timeout().await??, Some(big_msg));
+    assert_eq!(incoming.next().timeout().await??, Some(small_msg));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn split_messages() -> Result<()> {
+    let (peer1, mut peer1_incoming_connections, _) = new_endpoint().await?;
+    let (peer2, _, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            message_split_threshold: Some(64 * 1024),
+            message_split_streams: Some(3),
+            ..Config::default()
+        },
+    )
+    .await?;
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+    let (peer1_connection, mut incoming) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+
+    // large messages are split and put back together, small ones are sent as they are
+    let msgs = [
+        random_msg(1024 * 1024),
+        random_msg(1024),
+        random_msg(64 * 1024 + 1),
+    ];
+    for msg in &msgs {
+        connection.send(msg.clone()).await?;
+        assert_eq!(incoming.next().timeout().await??.as_ref(), Some(msg));
+    }
+
+    // split messages count once in the stats
+    assert_eq!(connection.message_stats().sent_sizes.count(), 3);
+    assert_eq!(peer1_connection.message_stats().received_sizes.count(), 3);
+
+    // many split messages can be sent at once
+    let msgs: Vec<_> = (0..10).map(|_| random_msg(100 * 1024)).collect();
+    let sends = msgs.iter().map(|msg| connection.send(msg.clone()));
+    for result in future::join_all(sends).timeout().await? {
+        result?;
+    }
+    let mut received = Vec::new();
+    for _ in &msgs {
+        received.extend(incoming.next().timeout().await??);
+    }
+    for msg in &msgs {
+        assert!(received.contains(msg));
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rtt_retry_schedule() -> Result<()> {
+    let retry_config = RetryConfig {
+        initial_retry_rtt_multiplier: Some(3.0),
+        ..RetryConfig::default()
+    };
+    let (peer1, _, _) = new_endpoint().await?;
+    let (peer2, _, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            retry_config: retry_config.clone(),
+            ..Config::default()
+        },
+    )
+    .await?;
+    let (connection, _) = peer2.connect_to(&peer1.public_addr()).await?;
+
+    // retries on a local connection start well within the fixed initial interval
+    let schedule = connection.retry_schedule();
+    assert!(!schedule.is_empty());
+    assert!(schedule[0] < retry_config.initial_retry_interval);
+    assert!(schedule.len() > retry_config.schedule(None).len());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connect_timeout() -> Result<()> {
+    use crate::ConnectionError;
+    use std::{net::UdpSocket, time::Instant};
+
+    // a socket that never answers, so connecting can only time out
+    let silent = UdpSocket::bind(local_addr())?;
+    let (endpoint, _, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            connect_timeout: Some(Duration::from_millis(500)),
+            ..Config::default()
+        },
+    )
+    .await?;
+
+    let start = Instant::now();
+    match endpoint.connect_to(&silent.local_addr()?).await {
+        Err(ConnectionError::ConnectTimedOut) => {}
+        result => bail!(
+            "expected connecting to time out, got {:?}",
+            result.map(|_| ())
+        ),
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn handshake_timeout() -> Result<()> {
+    use crate::ConnectionError;
+    use std::{net::UdpSocket, time::Instant};
+
+    // a socket that never answers, so the handshake stalls
+    let silent = UdpSocket::bind(local_addr())?;
+    let (endpoint, _, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            handshake_timeout: Some(Duration::from_millis(200)),
+            retry_config: RetryConfig {
+                max_retry_attempts: Some(0),
+                ..RetryConfig::default()
+            },
+            ..Config::default()
+        },
+    )
+    .await?;
+
+    let start = Instant::now();
+    match endpoint.connect_to(&silent.local_addr()?).await {
+        Err(error @ ConnectionError::HandshakeTimedOut) => assert!(error.is_transient()),
+        result => bail!(
+            "expected the handshake to time out, got {:?}",
+            result.map(|_| ())
+        ),
+    }
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn e2e_encryption() -> Result<()> {
+    use crate::SigningKey;
+
+    let config = |key: &Arc<SigningKey>| Config {
+        e2e_encryption: true,
+        signing_key: Some(key.clone()),
+        ..Config::default()
+    };
+    let peer1_key = Arc::new(SigningKey::generate()?);
+    let peer2_key = Arc::new(SigningKey::generate()?);
+    let (peer1, mut peer1_incoming_connections, _) =
+        Endpoint::new_peer(local_addr(), &[], config(&peer1_key)).await?;
+    let (peer2, _, _) = Endpoint::new_peer(local_addr(), &[], config(&peer2_key)).await?;
+
+    let msg = random_msg(1024);
+    let (connection, mut peer2_incoming_messages) = peer2.connect_to(&peer1.public_addr()).await?;
+    assert_eq!(connection.peer_id(), Some(peer1_key.peer_id()));
+    connection.send(msg.clone()).await?;
+
+    let (peer1_connection, mut peer1_incoming_messages) = peer1_incoming_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    assert_eq!(peer1_connection.peer_id(), Some(peer2_key.peer_id()));
+    assert_eq!(peer1_incoming_messages.next().timeout().await??, Some(msg));
+
+    let reply = random_msg(1024);
+    peer1_connection.send(reply.clone()).await?;
+    assert_eq!(
+        peer2_incoming_messages.next().timeout().await??,
+        Some(reply)
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn e2e_encryption_expected_identity() -> Result<()> {
+    use crate::{AddressKind, ConnectionError, SigningKey};
+
+    let config = |key: Option<Arc<SigningKey>>| Config {
+        e2e_encryption: true,
+        signing_key: key,
+        retry_config: RetryConfig {
+            max_retry_attempts: Some(0),
+            ..RetryConfig::default()
+        },
+        ..Config::default()
+    };
+    let peer_key = Arc::new(SigningKey::generate()?);
+    let peer_id = peer_key.peer_id();
+    let (peer, _peer_incoming, _) =
+        Endpoint::new_peer(local_addr(), &[], config(Some(peer_key))).await?;
+    // stand-ins for something answering at an address we think is the peer's, signing as
+    // someone else or not at all
+    let (other, _other_incoming, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        config(Some(Arc::new(SigningKey::generate()?))),
+    )
+    .await?;
+    let (unsigned, _unsigned_incoming, _) =
+        Endpoint::new_peer(local_addr(), &[], config(None)).await?;
+
+    for impostor in [&other, &unsigned] {
+        let (client, _, _) = Endpoint::new_peer(
+            local_addr(),
+            &[],
+            config(Some(Arc::new(SigningKey::generate()?))),
+        )
+        .await?;
+        client
+            .address_book()
+            .insert(peer_id, impostor.public_addr(), AddressKind::Wan);
+        assert!(matches!(
+            client.connect_to_peer(&peer_id).timeout().await?,
+            Err(ConnectionError::Hello(_))
+        ));
+        assert!(client.get_connection_by_peer(&peer_id).is_none());
+    }
+
+    // the real peer signs as the identity we expect, even if we don't sign ourselves
+    let (client, _, _) = Endpoint::new_peer(local_addr(), &[], config(None)).await?;
+    client
+        .address_book()
+        .insert(peer_id, peer.public_addr(), AddressKind::Wan);
+    let (connection, _) = client.connect_to_peer(&peer_id).timeout().await??;
+    assert_eq!(connection.peer_id(), Some(peer_id));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_scoring_events() -> Result<()> {
+    use crate::{PeerEvent, PeerScoring, ScoredPeer};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    struct RecordingScoring(Mutex<Vec<(ScoredPeer, PeerEvent)>>);
+
+    impl PeerScoring for RecordingScoring {
+        fn on_event(&self, peer: &ScoredPeer, event: &PeerEvent) {
+            self.0.lock().unwrap().push((*peer, event.clone()));
+        }
+
+        fn score(&self, peer: &ScoredPeer) -> i64 {
+            self.0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(recorded, _)| recorded == peer)
+                .count() as i64
+        }
+    }
+
+    let scoring = Arc::new(RecordingScoring::default());
+    let (server, mut server_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            peer_scoring: Some(scoring.clone()),
+            ..Config::default()
+        },
+    )
+    .await?;
+    let (client, _, _) = new_endpoint().await?;
+
+    let (client_to_server, _) = client.connect_to(&server.public_addr()).await?;
+    client_to_server.send(random_msg(64)).await?;
+
+    let (_, mut server_messages) = server_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    let _ = server_messages.next().timeout().await??;
+
+    let client_addr = client.public_addr();
+    assert_eq!(
+        scoring.0.lock().unwrap().as_slice(),
+        &[(
+            ScoredPeer::from(client_addr),
+            PeerEvent::MessageReceived { len: 64 }
+        )]
+    );
+    assert_eq!(server.peer_score(&client_addr), Some(1));
+    assert_eq!(client.peer_score(&server.public_addr()), None);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_scoring_eviction() -> Result<()> {
+    use crate::{PeerEvent, PeerScoring, ScoredPeer};
+    use std::{net::SocketAddr, sync::Mutex};
+
+    #[derive(Debug, Default)]
+    struct Blocklist(Mutex<Vec<SocketAddr>>);
+
+    impl PeerScoring for Blocklist {
+        fn on_event(&self, _: &ScoredPeer, _: &PeerEvent) {}
+
+        fn score(&self, peer: &ScoredPeer) -> i64 {
+            if self.0.lock().unwrap().contains(&peer.addr) {
+                -1
+            } else {
+                0
+            }
+        }
+    }
+
+    let scoring = Arc::new(Blocklist::default());
+    let (peer, mut incoming_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            max_connections: Some(2),
+            peer_scoring: Some(scoring.clone()),
+            ..Config::default()
+        },
+    )
+    .await?;
+
+    let mut connections = Vec::new();
+    for _ in 0..3 {
+        let (dialer, _, _) = new_endpoint().await?;
+        let (dialed, _) = dialer.connect_to(&peer.public_addr()).await?;
+        let (connection, _) = incoming_connections
+            .next()
+            .timeout()
+            .await?
+            .ok_or_else(|| eyre!("did not receive expected connection"))?;
+        // the second peer scores badly, so it goes first even though the first is more idle
+        if connections.len() == 1 {
+            scoring.0.lock().unwrap().push(connection.remote_address());
+        }
+        connections.push((dialed, connection));
+    }
+
+    let ids: Vec<_> = connections
+        .iter()
+        .map(|(_, connection)| connection.id())
+        .collect();
+    assert!(peer.get_connection_by_id(ids[0]).is_some());
+    assert!(peer.get_connection_by_id(ids[1]).is_none());
+    assert!(peer.get_connection_by_id(ids[2]).is_some());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_scoring_by_identity() -> Result<()> {
+    use crate::{
+        Connection, HelloProvider, PeerEvent, PeerId, PeerIdentifier, PeerScoring, ScoredPeer,
+    };
+    use bytes::Bytes;
+    use std::net::SocketAddr;
+
+    // peers say who they are in their hello
+    #[derive(Debug)]
+    struct Hello(u8);
+
+    impl HelloProvider for Hello {
+        fn hello(&self, _peer: SocketAddr) -> Bytes {
+            Bytes::from(vec![self.0])
+        }
+    }
+
+    #[derive(Debug)]
+    struct HelloIdentifier;
+
+    impl PeerIdentifier for HelloIdentifier {
+        fn identify(&self, connection: &Connection) -> Option<PeerId> {
+            connection.peer_hello().map(|hello| PeerId([hello[0]; 32]))
+        }
+    }
+
+    // peer 2 has a bad score, whatever address it connects from
+    #[derive(Debug)]
+    struct Banned;
+
+    impl PeerScoring for Banned {
+        fn on_event(&self, _: &ScoredPeer, _: &PeerEvent) {}
+
+        fn score(&self, peer: &ScoredPeer) -> i64 {
+            if peer.id == Some(PeerId([2; 32])) {
+                -1
+            } else {
+                0
+            }
+        }
+
+        fn is_acceptable(&self, peer: &ScoredPeer) -> bool {
+            self.score(peer) >= 0
+        }
+    }
+
+    let config = |id| Config {
+        hello_provider: Some(Arc::new(Hello(id))),
+        peer_identifier: Some(Arc::new(HelloIdentifier)),
+        ..Config::default()
+    };
+    let (server, mut server_connections, _) = Endpoint::new_peer(
+        local_addr(),
+        &[],
+        Config {
+            peer_scoring: Some(Arc::new(Banned)),
+            ..config(1)
+        },
+    )
+    .await?;
+
+    // the banned peer gets through the check by address, but not the one by identity
+    let (banned, _, _) = Endpoint::new_peer(local_addr(), &[], config(2)).await?;
+    let (connection, _) = banned.connect_to(&server.public_addr()).timeout().await??;
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), server_connections.next())
+            .await
+            .is_err(),
+        "banned peer's connection was accepted"
+    );
+    // and the server closed it
+    assert!(connection.send(random_msg(64)).timeout().await?.is_err());
+
+    let (allowed, _, _) = Endpoint::new_peer(local_addr(), &[], config(3)).await?;
+    let _connection = allowed
+        .connect_to(&server.public_addr())
+        .timeout()
+        .await??;
+    let (connection, _) = server_connections
+        .next()
+        .timeout()
+        .await?
+        .ok_or_else(|| eyre!("did not receive expected connection"))?;
+    assert_eq!(connection.peer_id(), Some(PeerId([3; 32])));
+
+    Ok(())
+}
+
 trait Timeout: Sized {
     fn timeout(self) -> tokio::time::Timeout<Self>;
 }
@@ -20,6 +20,7 @@ use tiny_keccak::{Hasher, Sha3};
 type Digest256 = [u8; 32];
 
 mod common;
+mod soak;
 
 #[ctor::ctor]
 fn setup() {
@@ -0,0 +1,208 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Stress and soak tests, which relay traffic through a [`FaultyLink`].
+//!
+//! The soak tests are long-running, so they're ignored by default. Run them with:
+//!
+//! ```text
+//! QP2P_SOAK_SECS=600 cargo test soak -- --ignored --nocapture
+//! ```
+
+use super::{local_addr, random_msg};
+use crate::{
+    fault_injection::{Faults, FaultyLink},
+    Config, Endpoint, IncomingConnections, RetryConfig,
+};
+use color_eyre::eyre::{bail, eyre, Result};
+use std::{
+    env,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+use tokio::time::{sleep, timeout};
+use tracing::info;
+
+// How long to wait for each echo, allowing for retransmission of lost datagrams.
+const ECHO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A short idle timeout, so that connections whose close was lost don't linger for long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3);
+
+fn config() -> Config {
+    Config {
+        idle_timeout: Some(IDLE_TIMEOUT),
+        keep_alive_interval: Some(Duration::from_secs(1)),
+        retry_config: RetryConfig {
+            retrying_max_elapsed_time: Duration::from_secs(5),
+            ..RetryConfig::default()
+        },
+        ..Config::default()
+    }
+}
+
+fn lossy() -> Faults {
+    Faults {
+        drop: 0.05,
+        duplicate: 0.05,
+        corrupt: 0.01,
+        delay: 0.1,
+        max_delay: Duration::from_millis(20),
+        seed: 2615,
+        ..Faults::default()
+    }
+}
+
+// Echo every message received on a bi-stream, on the same stream.
+fn echo(mut incoming_connections: IncomingConnections) {
+    let _ = tokio::spawn(async move {
+        while let Some((_connection, mut incoming)) = incoming_connections.next().await {
+            let _ = tokio::spawn(async move {
+                while let Ok(Some((msg, Some(stream)))) = incoming.next_with_stream().await {
+                    let _ = stream.lock().await.send_user_msg(msg).await;
+                }
+            });
+        }
+    });
+}
+
+// Send `msg` to `addr` on a new bi-stream, and check it's echoed back.
+async fn round_trip(endpoint: &Endpoint, addr: &SocketAddr, msg_len: usize) -> Result<()> {
+    let (connection, _) = endpoint.connect_to(addr).await?;
+    let msg = random_msg(msg_len);
+    let (mut send_stream, mut recv_stream) = connection.open_bi().await?;
+    send_stream.send_user_msg(msg.clone()).await?;
+    let echoed = timeout(ECHO_TIMEOUT, recv_stream.next()).await??;
+    if echoed != msg {
+        bail!("Echoed message differs from the message sent");
+    }
+    connection.close(None);
+    Ok(())
+}
+
+// Wait for an endpoint to release all its connections, which fails if they leak.
+async fn drained(endpoint: &Endpoint, within: Duration) -> Result<()> {
+    let start = Instant::now();
+    while !endpoint.connections().is_empty() {
+        if start.elapsed() > within {
+            bail!(
+                "{} connection(s) still held after {:?}",
+                endpoint.connections().len(),
+                within
+            );
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn messages_survive_faults() -> Result<()> {
+    let (server, incoming_connections, _) = Endpoint::new_peer(local_addr(), &[], config()).await?;
+    echo(incoming_connections);
+    let link = FaultyLink::new(
+        server.public_addr(),
+        Faults {
+            drop: 0.2,
+            ..lossy()
+        },
+    )
+    .await?;
+
+    let (client, _, _) = Endpoint::new_peer(local_addr(), &[], config()).await?;
+    for _ in 0..10 {
+        round_trip(&client, &link.addr(), 4 * 1024).await?;
+    }
+
+    let stats = link.stats();
+    info!("Fault stats: {:?}", stats);
+    assert!(stats.received > 0);
+    assert!(stats.dropped > 0);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn partition_heals() -> Result<()> {
+    let (server, incoming_connections, _) = Endpoint::new_peer(local_addr(), &[], config()).await?;
+    echo(incoming_connections);
+    let link = FaultyLink::new(server.public_addr(), Faults::default()).await?;
+    let (client, _, _) = Endpoint::new_peer(local_addr(), &[], config()).await?;
+
+    round_trip(&client, &link.addr(), 1024).await?;
+
+    link.set_faults(Faults {
+        drop: 1.0,
+        ..Faults::default()
+    });
+    if matches!(
+        timeout(
+            Duration::from_secs(2),
+            round_trip(&client, &link.addr(), 1024)
+        )
+        .await,
+        Ok(Ok(()))
+    ) {
+        bail!("Round trip succeeded across a partition");
+    }
+
+    link.set_faults(Faults::default());
+    round_trip(&client, &link.addr(), 1024).await?;
+
+    Ok(())
+}
+
+// Churn connections (and client endpoints) through a lossy link, checking that neither side holds
+// on to connections once they're closed.
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "long-running soak test"]
+async fn soak_connection_churn() -> Result<()> {
+    let duration = env::var("QP2P_SOAK_SECS")
+        .ok()
+        .map(|secs| secs.parse().map_err(|_| eyre!("invalid QP2P_SOAK_SECS")))
+        .transpose()?
+        .map_or(Duration::from_secs(60), Duration::from_secs);
+
+    let (server, incoming_connections, _) = Endpoint::new_peer(local_addr(), &[], config()).await?;
+    echo(incoming_connections);
+    let link = FaultyLink::new(server.public_addr(), lossy()).await?;
+
+    let start = Instant::now();
+    let mut rounds = 0_u64;
+    let mut failures = 0_u64;
+    while start.elapsed() < duration {
+        // a new client endpoint every so often, so endpoints churn as well as connections
+        let (client, _, _) = Endpoint::new_peer(local_addr(), &[], config()).await?;
+        for _ in 0..20 {
+            rounds += 1;
+            // individual round trips may fail when faults hit the handshake too often, but
+            // nothing should leak either way
+            if let Err(error) = round_trip(&client, &link.addr(), 16 * 1024).await {
+                failures += 1;
+                info!("Round trip {} failed: {}", rounds, error);
+            }
+        }
+        drained(&client, IDLE_TIMEOUT * 3).await?;
+        client.close();
+    }
+
+    info!(
+        "{} round trips ({} failed) in {:?}, fault stats: {:?}",
+        rounds,
+        failures,
+        start.elapsed(),
+        link.stats()
+    );
+    if failures * 10 > rounds {
+        bail!("Too many failed round trips: {} of {}", failures, rounds);
+    }
+    drained(&server, IDLE_TIMEOUT * 3).await?;
+
+    Ok(())
+}
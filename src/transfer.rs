@@ -0,0 +1,327 @@
+// Copyright 2022 MaidSafe.net limited.
+//
+// This SAFE Network Software is licensed to you under the MIT license <LICENSE-MIT
+// http://opensource.org/licenses/MIT> or the Modified BSD license <LICENSE-BSD
+// https://opensource.org/licenses/BSD-3-Clause>, at your option. This file may not be copied,
+// modified, or distributed except according to those terms. Please review the Licences for the
+// specific language governing permissions and limitations relating to use of the SAFE Network
+// Software.
+
+//! Resumable file transfers over a dedicated stream.
+//!
+//! A transfer is offered with [`send_file`], and received from
+//! [`ConnectionIncoming::take_transfers`](crate::ConnectionIncoming::take_transfers). Each
+//! transfer uses its own bidirectional stream:
+//!
+//! 1. The sender offers the file's name, size, and SHA-256 hash.
+//! 2. The receiver accepts with the number of bytes it already has (from an earlier, interrupted
+//!    transfer of the same file), or rejects the transfer.
+//! 3. The sender sends the rest of the file in chunks.
+//! 4. The receiver checks the hash of the complete file, and reports whether it matched.
+//!
+//! Data is received into a `.part` file next to the destination, which is renamed once the hash
+//! has been checked. If the connection is lost, sending the file again resumes from the end of the
+//! `.part` file.
+
+use crate::{
+    connection::{Connection, RecvStream, SendStream},
+    error::TransferError,
+    wire_msg::WireMsg,
+};
+use bytes::Bytes;
+use ring::digest::{Context, SHA256, SHA256_OUTPUT_LEN};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::mpsc,
+};
+use tracing::{debug, trace, warn};
+
+// The size of the chunks a file is sent in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// The extension added to the destination path while a file is being received.
+const PARTIAL_EXTENSION: &str = "part";
+
+type Hash = [u8; SHA256_OUTPUT_LEN];
+
+/// The progress of a transfer, as reported to progress callbacks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransferProgress {
+    /// The number of bytes of the file the receiver has, including any received by earlier
+    /// attempts.
+    pub transferred: u64,
+
+    /// The size of the file.
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Offer {
+    name: String,
+    size: u64,
+    hash: Hash,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Response {
+    Accept { offset: u64 },
+    Reject,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Outcome {
+    Verified,
+    Mismatch,
+}
+
+/// Send a file to the peer over a new stream.
+///
+/// The file is hashed before it's offered, so this reads the file twice. `progress` is called as
+/// chunks are sent. If the receiver already has part of the file from an earlier attempt, only the
+/// rest is sent.
+///
+/// This returns once the receiver has confirmed that the file it received has the same hash.
+pub async fn send_file(
+    connection: &Connection,
+    path: impl AsRef<Path>,
+    mut progress: impl FnMut(TransferProgress),
+) -> Result<(), TransferError> {
+    let path = path.as_ref();
+    let mut file = File::open(path).await?;
+    let size = file.metadata().await?.len();
+    let hash = hash_file(&mut file).await?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send.send_wire_msg(WireMsg::TransferReq)
+        .await
+        .map_err(|error| connection.error_context().wrap(error))?;
+    send.send_as(&Offer { name, size, hash }).await?;
+
+    let offset = match recv.next_as::<Response>().await? {
+        Response::Accept { offset } if offset <= size => offset,
+        Response::Accept { offset } => return Err(TransferError::InvalidOffset { offset, size }),
+        Response::Reject => return Err(TransferError::Rejected),
+    };
+    trace!(
+        "Sending {} to {} from offset {}",
+        path.display(),
+        connection.remote_address(),
+        offset
+    );
+
+    let _ = file.seek(SeekFrom::Start(offset)).await?;
+    let mut transferred = offset;
+    progress(TransferProgress { transferred, size });
+
+    let mut buf = vec![0; CHUNK_SIZE];
+    while transferred < size {
+        // only send as much as was offered, even if the file has grown since
+        let len = (size - transferred).min(CHUNK_SIZE as u64) as usize;
+        let read = file.read(&mut buf[..len]).await?;
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        send.send_user_msg(Bytes::copy_from_slice(&buf[..read]))
+            .await?;
+        transferred += read as u64;
+        progress(TransferProgress { transferred, size });
+    }
+    send.finish().await?;
+
+    match recv.next_as::<Outcome>().await? {
+        Outcome::Verified => Ok(()),
+        Outcome::Mismatch => Err(TransferError::Integrity),
+    }
+}
+
+/// Transfers offered by a peer, as returned by
+/// [`ConnectionIncoming::take_transfers`](crate::ConnectionIncoming::take_transfers).
+#[derive(Debug)]
+pub struct IncomingTransfers(mpsc::Receiver<(SendStream, RecvStream)>);
+
+impl IncomingTransfers {
+    pub(crate) fn new(transfer_rx: mpsc::Receiver<(SendStream, RecvStream)>) -> Self {
+        Self(transfer_rx)
+    }
+
+    /// Wait for the peer to offer a file.
+    ///
+    /// Returns `None` once the connection is closed.
+    pub async fn next(&mut self) -> Option<IncomingTransfer> {
+        loop {
+            let (send, mut recv) = self.0.recv().await?;
+            match recv.next_as::<Offer>().await {
+                Ok(offer) => {
+                    return Some(IncomingTransfer {
+                        send,
+                        recv,
+                        name: offer.name,
+                        size: offer.size,
+                        hash: offer.hash,
+                    })
+                }
+                Err(error) => warn!("Ignoring invalid transfer offer: {}", error),
+            }
+        }
+    }
+}
+
+/// A file offered by a peer.
+///
+/// The offer must be [received](Self::receive_file) or [rejected](Self::reject). Dropping it
+/// abandons the transfer's stream, which the sender sees as an error.
+#[derive(Debug)]
+pub struct IncomingTransfer {
+    send: SendStream,
+    recv: RecvStream,
+    name: String,
+    size: u64,
+    hash: Hash,
+}
+
+impl IncomingTransfer {
+    /// The name of the file, as given by the sender.
+    ///
+    /// This is only the sender's file name, without any directories, but it is not otherwise
+    /// sanitized and should not be trusted as a path.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The size of the file, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Receive the file to `path`.
+    ///
+    /// The file is written to `path` with a `.part` extension added, and renamed to `path` once
+    /// its hash has been checked. If the `.part` file already exists (e.g. because an earlier
+    /// transfer was interrupted), the transfer resumes from its end. `progress` is called as chunks
+    /// are received.
+    pub async fn receive_file(
+        mut self,
+        path: impl AsRef<Path>,
+        mut progress: impl FnMut(TransferProgress),
+    ) -> Result<(), TransferError> {
+        let path = path.as_ref();
+        let partial_path = partial_path(path);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // an existing partial file is resumed from
+            .truncate(false)
+            .open(&partial_path)
+            .await?;
+
+        let mut transferred = file.metadata().await?.len();
+        if transferred > self.size {
+            // not a prefix of this file, so start over
+            file.set_len(0).await?;
+            transferred = 0;
+        }
+        let _ = file.seek(SeekFrom::Start(transferred)).await?;
+        debug!(
+            "Receiving {} ({} bytes) to {} from offset {}",
+            self.name,
+            self.size,
+            path.display(),
+            transferred
+        );
+
+        self.send
+            .send_as(&Response::Accept {
+                offset: transferred,
+            })
+            .await?;
+        progress(TransferProgress {
+            transferred,
+            size: self.size,
+        });
+
+        let mut overrun = false;
+        let result = async {
+            while transferred < self.size {
+                let chunk = self.recv.next().await?;
+                if chunk.len() as u64 > self.size - transferred {
+                    overrun = true;
+                    break;
+                }
+
+                file.write_all(&chunk).await?;
+                transferred += chunk.len() as u64;
+                progress(TransferProgress {
+                    transferred,
+                    size: self.size,
+                });
+            }
+            Ok::<_, TransferError>(())
+        }
+        .await;
+
+        // keep what was received, so an interrupted transfer can be resumed
+        file.flush().await?;
+        result?;
+
+        let _ = file.seek(SeekFrom::Start(0)).await?;
+        let verified = !overrun && hash_file(&mut file).await? == self.hash;
+        drop(file);
+
+        if verified {
+            fs::rename(&partial_path, path).await?;
+            self.send.send_as(&Outcome::Verified).await?;
+            self.send.finish().await?;
+            Ok(())
+        } else {
+            fs::remove_file(&partial_path).await?;
+            self.send.send_as(&Outcome::Mismatch).await?;
+            self.send.finish().await?;
+            Err(TransferError::Integrity)
+        }
+    }
+
+    /// Decline the transfer.
+    pub async fn reject(mut self) -> Result<(), TransferError> {
+        self.send.send_as(&Response::Reject).await?;
+        self.send.finish().await?;
+        Ok(())
+    }
+}
+
+// The path a file is received to, before its hash is checked.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut partial_path = OsString::from(path);
+    partial_path.push(".");
+    partial_path.push(PARTIAL_EXTENSION);
+    partial_path.into()
+}
+
+// Hash the rest of the file.
+async fn hash_file(file: &mut File) -> Result<Hash, io::Error> {
+    let mut context = Context::new(&SHA256);
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        context.update(&buf[..read]);
+    }
+
+    let mut hash = [0; SHA256_OUTPUT_LEN];
+    hash.copy_from_slice(context.finish().as_ref());
+    Ok(hash)
+}
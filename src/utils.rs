@@ -7,6 +7,8 @@
 // specific language governing permissions and limitations relating to use of the SAFE Network
 // Software.
 
+use ring::rand::{SecureRandom, SystemRandom};
+
 /// Convert binary data to a diplay-able format
 #[inline]
 pub(crate) fn bin_data_format(data: &[u8]) -> String {
@@ -27,3 +29,17 @@ pub(crate) fn bin_data_format(data: &[u8]) -> String {
         data[len - 1]
     )
 }
+
+// Shuffle `items` into a random order, leaving the rest in place if randomness is unavailable.
+pub(crate) fn shuffle<T>(items: &mut [T]) {
+    let rng = SystemRandom::new();
+    for i in (1..items.len()).rev() {
+        let mut bytes = [0; 8];
+        if rng.fill(&mut bytes).is_err() {
+            return;
+        }
+        // the slight bias of the modulo doesn't matter for spreading requests across peers
+        let j = (u64::from_le_bytes(bytes) % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}